@@ -1,9 +1,84 @@
-use crate::blocks::BlockHash;
-use crate::Public;
+#[cfg(feature = "node")]
+use crate::node::Header;
+
+#[cfg(feature = "node")]
+use crate::node::Wire;
+
+use crate::blocks::{BlockHash, BlockType};
+use crate::bytes::Bytes;
+use crate::keys::public::{from_address, to_address};
+use crate::{Public, Signature, Work};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ChangeBlock {
-    previous: BlockHash,
-    representative: Public,
+    /// The hash of the previous block in this account.
+    pub previous: BlockHash,
+
+    /// The new representative of this account.
+    #[serde(serialize_with = "to_address", deserialize_with = "from_address")]
+    pub representative: Public,
+
+    pub work: Option<Work>,
+    pub signature: Option<Signature>,
+}
+
+impl ChangeBlock {
+    pub const LEN: usize = 136;
+
+    pub fn new(previous: BlockHash, representative: Public) -> Self {
+        Self {
+            previous,
+            representative,
+            work: None,
+            signature: None,
+        }
+    }
+}
+
+#[cfg(feature = "node")]
+impl Wire for ChangeBlock {
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.previous.as_bytes());
+        v.extend_from_slice(self.representative.as_bytes());
+        v.extend_from_slice(
+            self.signature
+                .as_ref()
+                .unwrap_or(&Signature::zero())
+                .as_bytes(),
+        );
+        v.extend_from_slice(&self.work.as_ref().unwrap_or(&Work::zero()).to_le_bytes());
+        v
+    }
+
+    fn deserialize(_: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut data = Bytes::new(data);
+        let previous = BlockHash::try_from(data.slice(BlockHash::LEN)?)?;
+        let representative = Public::try_from(data.slice(Public::LEN)?)?;
+        let signature = Some(Signature::try_from(data.slice(Signature::LEN)?)?);
+        let work = Some(Work::from_le_slice(data.slice(Work::LEN)?)?);
+
+        Ok(Self {
+            previous,
+            representative,
+            work,
+            signature,
+        })
+    }
+
+    fn len(header: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        debug_assert!(header.is_some());
+        let header = header.unwrap();
+        debug_assert_eq!(header.ext().block_type()?, BlockType::Change);
+
+        Ok(ChangeBlock::LEN)
+    }
 }
@@ -13,7 +13,7 @@ use crate::node::Wire;
 use crate::node::Header;
 
 use crate::encoding::blake2b;
-use crate::keys::public::to_address;
+use crate::keys::public::{from_address, to_address};
 use crate::network::Network;
 use crate::{Private, Public, Rai, Signature, Work};
 use anyhow::{anyhow, Context};
@@ -25,7 +25,7 @@ pub use receive_block::ReceiveBlock;
 pub use send_block::SendBlock;
 use serde;
 use serde::{Deserialize, Serialize};
-use state_block::Link;
+pub use state_block::Link;
 pub use state_block::StateBlock;
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -72,8 +72,74 @@ impl TryFrom<u8> for BlockType {
     }
 }
 
+/// What a block does to its account. Legacy blocks have one subtype each, while a state block can
+/// be any of them depending on how it changes the balance.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Subtype {
+    Send,
+    Receive,
+    Open,
+    Change,
+    Epoch,
+}
+
+/// The version of the ledger rules an account follows. Accounts start at `V0` and are upgraded
+/// by epoch blocks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Epoch {
+    V0,
+    V1,
+    V2,
+}
+
+impl Epoch {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Epoch::V0 => 0,
+            Epoch::V1 => 1,
+            Epoch::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Epoch {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Epoch::V0,
+            1 => Epoch::V1,
+            2 => Epoch::V2,
+            _ => return Err(anyhow!("Invalid epoch: {}", value)),
+        })
+    }
+}
+
+/// The block type is sent by itself before each block in bulk pull and bulk push streams.
+#[cfg(feature = "node")]
+impl Wire for BlockType {
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.as_u8()]
+    }
+
+    fn deserialize(_: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        crate::expect_len(data.len(), 1, "Block type")?;
+        BlockType::try_from(data[0])
+    }
+
+    fn len(_: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        Ok(1)
+    }
+}
+
 /// For "holding" deserialized blocks that we can't convert to `Block` yet.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BlockHolder {
     Send(SendBlock),
     Receive(ReceiveBlock),
@@ -82,10 +148,106 @@ pub enum BlockHolder {
     State(StateBlock),
 }
 
+impl BlockHolder {
+    pub fn block_type(&self) -> BlockType {
+        match self {
+            BlockHolder::Send(_) => BlockType::Send,
+            BlockHolder::Receive(_) => BlockType::Receive,
+            BlockHolder::Open(_) => BlockType::Open,
+            BlockHolder::Change(_) => BlockType::Change,
+            BlockHolder::State(_) => BlockType::State,
+        }
+    }
+
+    /// The root of the held block, like `Block::root`.
+    pub fn root(&self) -> BlockHash {
+        match self {
+            BlockHolder::Send(b) => b.previous.to_owned(),
+            BlockHolder::Receive(b) => b.previous.to_owned(),
+            BlockHolder::Open(b) => account_root(&b.account),
+            BlockHolder::Change(b) => b.previous.to_owned(),
+            BlockHolder::State(b) if b.previous == BlockHash::zero() => account_root(&b.account),
+            BlockHolder::State(b) => b.previous.to_owned(),
+        }
+    }
+
+    pub fn work(&self) -> Option<&Work> {
+        match self {
+            BlockHolder::Send(b) => b.work.as_ref(),
+            BlockHolder::Receive(b) => b.work.as_ref(),
+            BlockHolder::Open(b) => b.work.as_ref(),
+            BlockHolder::Change(b) => b.work.as_ref(),
+            BlockHolder::State(b) => b.work.as_ref(),
+        }
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        match self {
+            BlockHolder::Send(b) => b.signature.as_ref(),
+            BlockHolder::Receive(b) => b.signature.as_ref(),
+            BlockHolder::Open(b) => b.signature.as_ref(),
+            BlockHolder::Change(b) => b.signature.as_ref(),
+            BlockHolder::State(b) => b.signature.as_ref(),
+        }
+    }
+
+    /// The key that signs the held block, if the block tells without the ledger. Legacy blocks
+    /// other than open don't carry their account.
+    pub fn signer(&self, network: &Network) -> anyhow::Result<Option<Public>> {
+        Ok(match self {
+            BlockHolder::Open(b) => Some(b.account.to_owned()),
+            BlockHolder::State(b) => Some(match b.link.epoch() {
+                Some(epoch) => network.epoch_signer(epoch)?,
+                None => b.account.to_owned(),
+            }),
+            _ => None,
+        })
+    }
+
+    /// Calculate the hash of the held block.
+    pub fn hash(&self) -> anyhow::Result<BlockHash> {
+        match self {
+            BlockHolder::Send(b) => hash_block(&[
+                b.previous.as_bytes(),
+                b.destination.as_bytes(),
+                b.balance.to_vec().as_slice(),
+            ]),
+            BlockHolder::Receive(b) => hash_block(&[b.previous.as_bytes(), b.source.as_bytes()]),
+            BlockHolder::Open(b) => hash_block(&[
+                b.source.as_bytes(),
+                b.representative.as_bytes(),
+                b.account.as_bytes(),
+            ]),
+            BlockHolder::Change(b) => {
+                hash_block(&[b.previous.as_bytes(), b.representative.as_bytes()])
+            }
+            BlockHolder::State(b) => {
+                let mut preamble = [0u8; 32];
+                preamble[31] = BlockType::State as u8;
+
+                hash_block(&[
+                    &preamble,
+                    b.account.as_bytes(),
+                    b.previous.as_bytes(),
+                    b.representative.as_bytes(),
+                    b.balance.to_vec().as_slice(),
+                    b.link.as_bytes(),
+                ])
+            }
+        }
+    }
+}
+
 #[cfg(feature = "node")]
 impl Wire for BlockHolder {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        match self {
+            BlockHolder::State(b) => Wire::serialize(b),
+            BlockHolder::Send(b) => Wire::serialize(b),
+            BlockHolder::Receive(b) => Wire::serialize(b),
+            BlockHolder::Open(b) => Wire::serialize(b),
+            BlockHolder::Change(b) => Wire::serialize(b),
+        }
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -106,7 +268,16 @@ impl Wire for BlockHolder {
                 BlockHolder::State(Wire::deserialize(header, data).context(context)?)
             }
             BlockType::Send => BlockHolder::Send(Wire::deserialize(header, data).context(context)?),
-            _ => todo!(),
+            BlockType::Receive => {
+                BlockHolder::Receive(Wire::deserialize(header, data).context(context)?)
+            }
+            BlockType::Open => BlockHolder::Open(Wire::deserialize(header, data).context(context)?),
+            BlockType::Change => {
+                BlockHolder::Change(Wire::deserialize(header, data).context(context)?)
+            }
+            block_type => {
+                return Err(anyhow!("{:?} is not a block", block_type)).context(context);
+            }
         };
         Ok(holder)
     }
@@ -119,12 +290,15 @@ impl Wire for BlockHolder {
         match header.as_ref().unwrap().ext().block_type()? {
             BlockType::State => StateBlock::len(header),
             BlockType::Send => SendBlock::len(header),
-            _ => todo!(),
+            BlockType::Receive => ReceiveBlock::len(header),
+            BlockType::Open => OpenBlock::len(header),
+            BlockType::Change => ChangeBlock::len(header),
+            block_type => Err(anyhow!("{:?} is not a block", block_type)),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Previous {
     Block(BlockHash),
     Open,
@@ -145,7 +319,7 @@ impl Previous {
 ///
 /// When processing blocks from the network, this should be created after going through the
 /// controller since certain fields such as "amount" won't be available immediately.
-#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Block {
     #[serde(rename = "type")]
     block_type: BlockType,
@@ -180,7 +354,7 @@ pub struct Block {
     state: ValidationState,
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ValidationState {
     Published,
     PresumedValid,
@@ -247,6 +421,45 @@ impl Block {
         b
     }
 
+    /// A legacy receive block doesn't carry its account, representative or balance, so they need
+    /// to come from the previous block and the send block being received.
+    pub fn from_receive_block(
+        receive_block: &ReceiveBlock,
+        account: &Public,
+        representative: &Public,
+        balance: &Rai,
+    ) -> Self {
+        let mut b = Self::new(
+            BlockType::Receive,
+            account.to_owned(),
+            Previous::Block(receive_block.previous.to_owned()),
+            representative.to_owned(),
+            balance.to_owned(),
+            Link::Source(receive_block.source.to_owned()),
+            ValidationState::Valid,
+        );
+        b.signature = receive_block.signature.to_owned();
+        b.work = receive_block.work.to_owned();
+        b
+    }
+
+    /// A legacy change block doesn't carry its account or balance, so they need to come from the
+    /// previous block.
+    pub fn from_change_block(change_block: &ChangeBlock, account: &Public, balance: &Rai) -> Self {
+        let mut b = Self::new(
+            BlockType::Change,
+            account.to_owned(),
+            Previous::Block(change_block.previous.to_owned()),
+            change_block.representative.to_owned(),
+            balance.to_owned(),
+            Link::Nothing,
+            ValidationState::Valid,
+        );
+        b.signature = change_block.signature.to_owned();
+        b.work = change_block.work.to_owned();
+        b
+    }
+
     pub fn from_state_block(state_block: &StateBlock) -> Self {
         let mut b = Self::new(
             BlockType::State,
@@ -262,6 +475,68 @@ impl Block {
         b
     }
 
+    /// Convert back into the original block type, e.g. for sending over the network.
+    pub fn to_holder(&self) -> anyhow::Result<BlockHolder> {
+        let previous = match &self.previous {
+            Previous::Block(hash) => hash.to_owned(),
+            Previous::Open => BlockHash::zero(),
+        };
+
+        let holder = match self.block_type {
+            BlockType::State => {
+                let mut b = StateBlock::new(
+                    self.account.to_owned(),
+                    previous,
+                    self.representative.to_owned(),
+                    self.balance.to_owned(),
+                    self.link.to_owned(),
+                );
+                b.signature = self.signature.to_owned();
+                b.work = self.work.to_owned();
+                BlockHolder::State(b)
+            }
+            BlockType::Send => {
+                let mut b = SendBlock::new(
+                    previous,
+                    self.destination()?.to_owned(),
+                    self.balance.to_owned(),
+                );
+                b.signature = self.signature.to_owned();
+                b.work = self.work.to_owned();
+                BlockHolder::Send(b)
+            }
+            BlockType::Receive => {
+                let mut b = ReceiveBlock::new(previous, self.source()?.to_owned());
+                b.signature = self.signature.to_owned();
+                b.work = self.work.to_owned();
+                BlockHolder::Receive(b)
+            }
+            BlockType::Open => {
+                let mut b = OpenBlock::new(
+                    self.source()?.to_owned(),
+                    self.representative.to_owned(),
+                    self.account.to_owned(),
+                );
+                b.signature = self.signature.to_owned();
+                b.work = self.work.to_owned();
+                BlockHolder::Open(b)
+            }
+            BlockType::Change => {
+                let mut b = ChangeBlock::new(previous, self.representative.to_owned());
+                b.signature = self.signature.to_owned();
+                b.work = self.work.to_owned();
+                BlockHolder::Change(b)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Converting a {:?} block is not supported",
+                    self.block_type
+                ))
+            }
+        };
+        Ok(holder)
+    }
+
     pub fn hash(&self) -> anyhow::Result<&BlockHash> {
         self.hash.as_ref().ok_or(anyhow!("Hash not calculated yet"))
     }
@@ -285,6 +560,14 @@ impl Block {
                 self.destination().with_context(context)?.as_bytes(),
                 self.balance.to_vec().as_slice(),
             ]),
+            BlockType::Receive => hash_block(&[
+                self.previous.to_bytes().as_slice(),
+                self.source().with_context(context)?.as_bytes(),
+            ]),
+            BlockType::Change => hash_block(&[
+                self.previous.to_bytes().as_slice(),
+                self.representative.as_bytes(),
+            ]),
             BlockType::State => {
                 let mut preamble = [0u8; 32];
                 preamble[31] = BlockType::State as u8;
@@ -298,7 +581,7 @@ impl Block {
                     self.link.as_bytes(),
                 ])
             }
-            _ => todo!(),
+            block_type => Err(anyhow!("Can not hash a {:?} block", block_type)),
         };
 
         let hash = hash_result.with_context(context)?;
@@ -346,6 +629,19 @@ impl Block {
             .context("Verify block")?)
     }
 
+    /// The key that signs the block. Epoch blocks are signed by the epoch signer of the network
+    /// instead of the owner of the account. State blocks need their link resolved first.
+    pub fn signer(&self, network: &Network) -> anyhow::Result<Public> {
+        if self.subtype()? != Subtype::Epoch {
+            return Ok(self.account.to_owned());
+        }
+        let epoch = self
+            .link
+            .epoch()
+            .ok_or_else(|| anyhow!("Unknown epoch in {:?}", self))?;
+        network.epoch_signer(epoch)
+    }
+
     pub fn sign(&mut self, private: Private) -> anyhow::Result<()> {
         let hash = self.hash()?;
         let signature = private.sign(hash.as_bytes())?;
@@ -361,13 +657,59 @@ impl Block {
         &self.previous
     }
 
+    pub fn link(&self) -> &Link {
+        &self.link
+    }
+
+    /// The previous block hash, or the account for the first block of an account. Blocks that
+    /// share a root are forks of each other.
+    pub fn root(&self) -> BlockHash {
+        match &self.previous {
+            Previous::Block(hash) if hash != &BlockHash::zero() => hash.to_owned(),
+            _ => account_root(&self.account),
+        }
+    }
+
+    /// What the block does to its account. State blocks need their link resolved first.
+    pub fn subtype(&self) -> anyhow::Result<Subtype> {
+        Ok(match (&self.block_type, &self.link) {
+            (BlockType::Send, _) => Subtype::Send,
+            (BlockType::Receive, _) => Subtype::Receive,
+            (BlockType::Open, _) => Subtype::Open,
+            (BlockType::Change, _) => Subtype::Change,
+            (BlockType::State, Link::DestinationAccount(_)) => Subtype::Send,
+            (BlockType::State, Link::Source(_)) => match &self.previous {
+                Previous::Block(hash) if hash != &BlockHash::zero() => Subtype::Receive,
+                _ => Subtype::Open,
+            },
+            (BlockType::State, Link::Nothing) => Subtype::Change,
+            (BlockType::State, Link::Epoch(_)) => Subtype::Epoch,
+            (BlockType::State, Link::Unsure(_)) => {
+                return Err(anyhow!(
+                    "The link of state block {:?} is unresolved",
+                    self.hash
+                ))
+            }
+            (block_type, _) => return Err(anyhow!("{:?} blocks have no subtype", block_type)),
+        })
+    }
+
+    /// Classify a state block by replacing an `Unsure` link with what it refers to, given the
+    /// balance of the previous block. `previous_balance` is None for the first block of an
+    /// account.
+    pub fn resolve_link(&mut self, previous_balance: Option<&Rai>) -> anyhow::Result<()> {
+        self.link = self
+            .link
+            .resolve(previous_balance, &self.balance)
+            .with_context(|| format!("Resolving link of {:?}", self))?;
+        Ok(())
+    }
+
     /// For an open or recv block, get the sender's block hash, otherwise Err.
     pub fn source(&self) -> anyhow::Result<&BlockHash> {
-        if self.block_type != BlockType::Open {
-            return Err(anyhow!(
-                "Source requested for a {:?} block",
-                self.block_type
-            ));
+        let subtype = self.subtype()?;
+        if subtype != Subtype::Open && subtype != Subtype::Receive {
+            return Err(anyhow!("Source requested for a {:?} block", subtype));
         }
 
         if let Link::Source(hash) = &self.link {
@@ -382,10 +724,11 @@ impl Block {
 
     /// For a send block, the destination account being sent to.
     pub fn destination(&self) -> anyhow::Result<&Public> {
-        if self.block_type != BlockType::Send {
+        let subtype = self.subtype()?;
+        if subtype != Subtype::Send {
             return Err(anyhow!(
                 "Destination requested for a {:?} block: {:?}",
-                self.block_type,
+                subtype,
                 self
             ));
         }
@@ -401,6 +744,11 @@ impl Block {
     }
 }
 
+/// The root of the first block of an account is the account itself.
+fn account_root(account: &Public) -> BlockHash {
+    BlockHash::try_from(account.as_bytes()).expect("account is a hash length")
+}
+
 pub fn hash_block(parts: &[&[u8]]) -> anyhow::Result<BlockHash> {
     let mut v = Vec::new(); // TODO: with_capacity
     for b in parts {
@@ -411,7 +759,65 @@ pub fn hash_block(parts: &[&[u8]]) -> anyhow::Result<BlockHash> {
 
 #[cfg(test)]
 mod tests {
+    use super::{Block, BlockHash, BlockType, Link, Previous, Subtype, ValidationState};
     use crate::network::Network;
+    use crate::{Private, Rai, Work};
+
+    /// A signed legacy block with the fields `Block` needs, as if it came from the ledger.
+    fn legacy_block(block_type: BlockType, link: Link) -> Block {
+        let private = Private::random();
+        let account = private.to_public().unwrap();
+        let previous = Previous::Block(BlockHash::zero());
+        let mut block = Block::new(
+            block_type,
+            account.to_owned(),
+            previous,
+            account,
+            Rai::new(1u128),
+            link,
+            ValidationState::Valid,
+        );
+        block.set_work(Work::zero());
+        block.calc_hash().unwrap();
+        block.sign(private).unwrap();
+        block
+    }
+
+    #[cfg(feature = "node")]
+    #[test]
+    fn legacy_blocks_round_trip() {
+        use super::{BlockHolder, ChangeBlock, ReceiveBlock};
+        use crate::node::Wire;
+
+        let receive = legacy_block(BlockType::Receive, Link::Source(BlockHash::zero()));
+        let change = legacy_block(BlockType::Change, Link::Nothing);
+        assert_eq!(receive.subtype().unwrap(), Subtype::Receive);
+        assert_eq!(change.subtype().unwrap(), Subtype::Change);
+
+        for block in &[receive, change] {
+            let holder = block.to_holder().unwrap();
+            assert_eq!(&holder.hash().unwrap(), block.hash().unwrap());
+
+            let data = holder.serialize();
+            let decoded = match &holder {
+                BlockHolder::Receive(_) => {
+                    assert_eq!(data.len(), ReceiveBlock::LEN);
+                    BlockHolder::Receive(ReceiveBlock::deserialize(None, &data).unwrap())
+                }
+                BlockHolder::Change(_) => {
+                    assert_eq!(data.len(), ChangeBlock::LEN);
+                    BlockHolder::Change(ChangeBlock::deserialize(None, &data).unwrap())
+                }
+                _ => unreachable!(),
+            };
+            assert_eq!(decoded.serialize(), data);
+            assert_eq!(decoded.hash().unwrap(), holder.hash().unwrap());
+
+            let json = serde_json::to_string(&holder).unwrap();
+            let decoded: BlockHolder = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.serialize(), data);
+        }
+    }
 
     #[test]
     fn json() {
@@ -425,4 +831,12 @@ mod tests {
         assert!(a.contains(r#"work": "62F"#));
         assert!(a.contains(r#"signature": "9F"#));
     }
+
+    #[test]
+    fn json_round_trip() {
+        let genesis = Network::Live.genesis_block();
+        let json = serde_json::to_string(&genesis).unwrap();
+        let decoded: Block = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, genesis);
+    }
 }
@@ -1,7 +1,15 @@
-use crate::blocks::BlockHash;
+#[cfg(feature = "node")]
+use crate::node::Header;
+
+#[cfg(feature = "node")]
+use crate::node::Wire;
+
+use crate::blocks::{BlockHash, BlockType};
+use crate::bytes::Bytes;
 use crate::keys::public::{from_address, to_address};
 use crate::{Public, Signature, Work};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct OpenBlock {
@@ -19,6 +27,8 @@ pub struct OpenBlock {
 }
 
 impl OpenBlock {
+    pub const LEN: usize = 168;
+
     pub fn new(source: BlockHash, representative: Public, account: Public) -> Self {
         Self {
             source,
@@ -29,3 +39,52 @@ impl OpenBlock {
         }
     }
 }
+
+#[cfg(feature = "node")]
+impl Wire for OpenBlock {
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.source.as_bytes());
+        v.extend_from_slice(self.representative.as_bytes());
+        v.extend_from_slice(self.account.as_bytes());
+        v.extend_from_slice(
+            self.signature
+                .as_ref()
+                .unwrap_or(&Signature::zero())
+                .as_bytes(),
+        );
+        v.extend_from_slice(&self.work.as_ref().unwrap_or(&Work::zero()).to_le_bytes());
+        v
+    }
+
+    fn deserialize(_: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut data = Bytes::new(data);
+        let source = BlockHash::try_from(data.slice(BlockHash::LEN)?)?;
+        let representative = Public::try_from(data.slice(Public::LEN)?)?;
+        let account = Public::try_from(data.slice(Public::LEN)?)?;
+        let signature = Some(Signature::try_from(data.slice(Signature::LEN)?)?);
+        let work = Some(Work::from_le_slice(data.slice(Work::LEN)?)?);
+
+        Ok(Self {
+            source,
+            representative,
+            account,
+            work,
+            signature,
+        })
+    }
+
+    fn len(header: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        debug_assert!(header.is_some());
+        let header = header.unwrap();
+        debug_assert_eq!(header.ext().block_type()?, BlockType::Open);
+
+        Ok(OpenBlock::LEN)
+    }
+}
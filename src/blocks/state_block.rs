@@ -4,11 +4,13 @@ use crate::node::Header;
 #[cfg(feature = "node")]
 use crate::node::Wire;
 
-use crate::blocks::{BlockHash, BlockType};
+use crate::blocks::{BlockHash, BlockType, Epoch};
 use crate::bytes::Bytes;
 use crate::{expect_len, Public, Rai, Signature, Work};
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -47,7 +49,20 @@ impl StateBlock {
 #[cfg(feature = "node")]
 impl Wire for StateBlock {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.account.as_bytes());
+        v.extend_from_slice(self.previous.as_bytes());
+        v.extend_from_slice(self.representative.as_bytes());
+        v.extend_from_slice(&self.balance.to_vec());
+        v.extend_from_slice(self.link.as_bytes());
+        v.extend_from_slice(
+            self.signature
+                .as_ref()
+                .unwrap_or(&Signature::zero())
+                .as_bytes(),
+        );
+        v.extend_from_slice(self.work.as_ref().unwrap_or(&Work::zero()).as_bytes());
+        v
     }
 
     fn deserialize(_header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -91,8 +106,56 @@ mod tests {
     use super::Rai;
     use super::StateBlock;
     use crate::blocks::{Block, BlockHash};
+    use crate::Public;
     use std::str::FromStr;
 
+    #[test]
+    fn resolve_link() {
+        let bytes = [7u8; Link::LEN];
+        let unsure = Link::Unsure(bytes);
+        let resolve = |previous: Option<u128>, balance: u128| {
+            unsure.resolve(previous.map(Rai::new).as_ref(), &Rai::new(balance))
+        };
+
+        assert_eq!(
+            resolve(Some(10), 5).unwrap(),
+            Link::DestinationAccount(Public::from_str(&hex::encode(bytes)).unwrap())
+        );
+        assert_eq!(
+            resolve(Some(10), 15).unwrap(),
+            Link::Source(BlockHash::from_str(&hex::encode(bytes)).unwrap())
+        );
+        assert_eq!(
+            resolve(None, 15).unwrap(),
+            Link::Source(BlockHash::from_str(&hex::encode(bytes)).unwrap())
+        );
+        assert!(resolve(Some(10), 10).is_err());
+
+        let zero = Link::Unsure([0u8; Link::LEN]);
+        let balance = Rai::new(10u128);
+        assert_eq!(
+            zero.resolve(Some(&balance), &balance).unwrap(),
+            Link::Nothing
+        );
+
+        let mut epoch = [0u8; Link::LEN];
+        epoch[..14].copy_from_slice(b"epoch v2 block");
+        assert_eq!(
+            Link::Unsure(epoch)
+                .resolve(Some(&balance), &balance)
+                .unwrap(),
+            Link::Epoch(epoch)
+        );
+
+        // Known links are left alone.
+        assert_eq!(
+            Link::Nothing
+                .resolve(Some(&balance), &Rai::new(5u128))
+                .unwrap(),
+            Link::Nothing
+        );
+    }
+
     #[test]
     fn hash_a_real_state_block() {
         let account =
@@ -127,6 +190,39 @@ mod tests {
             .unwrap()
         )
     }
+
+    #[cfg(feature = "node")]
+    #[test]
+    fn serialize() {
+        use crate::node::Wire;
+
+        let account =
+            Address::from_str("nano_34prihdxwz3u4ps8qjnn14p7ujyewkoxkwyxm3u665it8rg5rdqw84qrypzk")
+                .unwrap()
+                .to_public();
+        let previous =
+            BlockHash::from_str("7837C80964CAD551DEABE162C7FC4BB58688A0C6EB6D9907C0D2A7C74A33C7EB")
+                .unwrap();
+        let link = [3u8; Link::LEN];
+        let mut block = StateBlock::new(
+            account.clone(),
+            previous,
+            account,
+            Rai::new(2711469892748129430069222848295u128),
+            Link::Unsure(link),
+        );
+        block.signature = Some(Signature::from_str("BCF9F123138355AE9E741912D319FF48E5FCCA39D9E5DD74411D32C69B1C7501A0BF001C45D4F68CB561B902A42711E6166B9018E76C50CC868EF2E32B78F200").unwrap());
+        block.work = Some(Work::from_str("d4757052401b9e08").unwrap());
+
+        let data = block.serialize();
+        assert_eq!(data.len(), StateBlock::LEN);
+        assert_eq!(&data[112..144], &link);
+        assert_eq!(
+            &data[StateBlock::LEN - Work::LEN..],
+            block.work.as_ref().unwrap().as_bytes()
+        );
+        assert_eq!(StateBlock::deserialize(None, &data).unwrap(), block);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -143,6 +239,9 @@ pub enum Link {
 
     /// Send to a destination account.
     DestinationAccount(Public),
+
+    /// Upgrade the account to a new epoch, without changing anything else.
+    Epoch([u8; Link::LEN]),
 }
 
 impl Link {
@@ -164,7 +263,59 @@ impl Link {
             Link::Nothing => &[0u8; Self::LEN],
             Link::Source(hash) => hash.as_bytes(),
             Link::DestinationAccount(key) => key.as_bytes(),
-            Link::Unsure(b) => b.as_ref(),
+            Link::Unsure(b) | Link::Epoch(b) => b.as_ref(),
+        }
+    }
+
+    /// Work out what an `Unsure` link is from how the block changes the account balance. A send
+    /// lowers it, a receive raises it, and a change or an epoch block leaves it as it was.
+    ///
+    /// `previous_balance` is None for the first block of an account. Links that are already known
+    /// are returned as they are.
+    pub fn resolve(&self, previous_balance: Option<&Rai>, balance: &Rai) -> anyhow::Result<Link> {
+        let bytes = match self {
+            Link::Unsure(bytes) => bytes,
+            link => return Ok(link.to_owned()),
+        };
+        let previous_balance = previous_balance.map(|b| b.to_u128()).unwrap_or(0);
+
+        Ok(match balance.to_u128().cmp(&previous_balance) {
+            Ordering::Less => Link::DestinationAccount(Public::try_from(bytes.as_ref())?),
+            Ordering::Greater => Link::Source(BlockHash::try_from(bytes.as_ref())?),
+            Ordering::Equal if bytes == &[0u8; Self::LEN] => Link::Nothing,
+            Ordering::Equal if Self::is_epoch(bytes) => Link::Epoch(*bytes),
+            Ordering::Equal => {
+                return Err(anyhow!(
+                    "Link {} doesn't match a block that keeps the balance",
+                    hex::encode_upper(bytes)
+                ))
+            }
+        })
+    }
+
+    /// The epoch an epoch link upgrades to. An unresolved link that reads like an epoch link is
+    /// taken as one, since no key or block hash starts with the message.
+    pub fn epoch(&self) -> Option<Epoch> {
+        match self {
+            Link::Epoch(bytes) | Link::Unsure(bytes) => Self::epoch_of(bytes),
+            _ => None,
         }
     }
+
+    fn is_epoch(bytes: &[u8; Self::LEN]) -> bool {
+        Self::epoch_of(bytes).is_some()
+    }
+
+    /// Epoch links are a message like "epoch v1 block", padded with zeros.
+    fn epoch_of(bytes: &[u8; Self::LEN]) -> Option<Epoch> {
+        [
+            (b"epoch v1 block", Epoch::V1),
+            (b"epoch v2 block", Epoch::V2),
+        ]
+        .iter()
+        .find(|(message, _)| {
+            bytes.starts_with(*message) && bytes[message.len()..].iter().all(|b| *b == 0)
+        })
+        .map(|(_, epoch)| *epoch)
+    }
 }
@@ -1,20 +1,28 @@
 use crate::cli::pcap::PcapDumpOpts;
+use crate::cli::publish::PublishOpts;
+use crate::cli::telemetry::TelemetryOpts;
 use crate::cli::unit::UnitOpts;
 use crate::cli::vanity::VanityOpts;
 use crate::cli::wallet::WalletOpts;
 use crate::debug::parse_pcap_log_file_to_csv;
-use crate::node::node_with_autodiscovery;
+use crate::node::{
+    format_node_id, node_id, node_with_autodiscovery, ControllerConfig, Representative, Version,
+    VersionRange,
+};
+use crate::units::Nano;
+use crate::{Private, DEFAULT_PORT};
 use address::AddressOpts;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use clap::Clap;
 use phrase::PhraseOpts;
 use private::PrivateOpts;
 use public::PublicOpts;
 use seed::SeedOpts;
-use std::io;
 use std::io::Read;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::{env, fs, io};
 use tracing::Level;
 use tracing_subscriber::EnvFilter;
 
@@ -23,7 +31,9 @@ mod pcap;
 mod phrase;
 mod private;
 mod public;
+mod publish;
 mod seed;
+mod telemetry;
 mod unit;
 mod vanity;
 mod wallet;
@@ -48,6 +58,12 @@ enum Command {
     /// Launches a node
     Node(NodeOpts),
 
+    /// Publish a signed and worked block to the network.
+    Publish(PublishOpts),
+
+    /// Summarize the telemetry of nodes on the network.
+    Telemetry(TelemetryOpts),
+
     /// Conversion between units, e.g. Rai to Nano
     Unit(UnitOpts),
 
@@ -81,9 +97,108 @@ enum Command {
 
 #[derive(Clap)]
 struct NodeOpts {
+    /// Run the node when left out.
+    #[clap(subcommand)]
+    command: Option<NodeCommand>,
+
     /// Comma separated list of IP:PORT pairs. Overrides default initial nodes.
     #[clap(short, long)]
     override_peers: Option<Vec<String>>,
+
+    /// Directory to store the ledger and peers in.
+    #[clap(short, long, default_value = "data")]
+    data_dir: PathBuf,
+
+    /// File with the private key of a representative to vote as, in hex. The key can also be
+    /// given in the FEELESS_REPRESENTATIVE environment variable instead, so that it doesn't show
+    /// up in the process list.
+    #[clap(long)]
+    representative_file: Option<PathBuf>,
+
+    /// Share of the online voting weight, in percent, that a block needs to be elected.
+    #[clap(long, default_value = "67")]
+    online_weight_quorum: u8,
+
+    /// The online voting weight used for quorum is at least this much, in Nano.
+    #[clap(long, default_value = "60000000")]
+    online_weight_minimum: Nano,
+
+    /// How many elections can be active at once. Blocks that would start another election are
+    /// ignored until one ends.
+    #[clap(long, default_value = "5000")]
+    max_active_elections: usize,
+
+    /// Oldest protocol version to talk to peers with. Peers that only support older versions are
+    /// disconnected.
+    #[clap(long, default_value = "18")]
+    min_version: u8,
+
+    /// Newest protocol version to talk to peers with.
+    #[clap(long, default_value = "19")]
+    max_version: u8,
+
+    /// Address to accept connections from peers on. Defaults to every IPv6 and IPv4 address, on
+    /// the default port.
+    #[clap(long)]
+    listen: Option<SocketAddr>,
+
+    /// Don't accept connections from peers.
+    #[clap(long)]
+    no_listen: bool,
+
+    /// Address to accept requests from local tools on, e.g. `feeless publish`.
+    #[clap(long, default_value = "127.0.0.1:7076")]
+    control: SocketAddr,
+
+    /// Don't accept requests from local tools.
+    #[clap(long)]
+    no_control: bool,
+
+    /// Maximum number of peers that can be connected to this node at the same time.
+    #[clap(long, default_value = "64")]
+    max_inbound: usize,
+
+    /// Maximum number of peers this node connects to.
+    #[clap(long, default_value = "16")]
+    max_outbound: usize,
+}
+
+impl NodeOpts {
+    /// The representative key from `representative_file`, or from the environment.
+    fn representative(&self) -> anyhow::Result<Option<Representative>> {
+        let hex = match &self.representative_file {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Reading representative key from {:?}", path))?,
+            None => match env::var("FEELESS_REPRESENTATIVE") {
+                Ok(hex) => hex,
+                Err(env::VarError::NotPresent) => return Ok(None),
+                Err(err) => return Err(err).context("Reading FEELESS_REPRESENTATIVE"),
+            },
+        };
+        let private = Private::from_str(hex.trim()).context("Invalid representative key")?;
+        Ok(Some(Representative::new(private)))
+    }
+
+    fn online_weight_quorum(&self) -> anyhow::Result<u8> {
+        if !(1..=100).contains(&self.online_weight_quorum) {
+            return Err(anyhow!(
+                "Online weight quorum {}% is not between 1% and 100%",
+                self.online_weight_quorum
+            ));
+        }
+        Ok(self.online_weight_quorum)
+    }
+
+    fn versions(&self) -> anyhow::Result<VersionRange> {
+        VersionRange::new(Version(self.min_version), Version(self.max_version))
+            .context("Invalid protocol versions")
+    }
+}
+
+#[derive(Clap)]
+enum NodeCommand {
+    /// Show the ID this node identifies itself with to peers, creating it if needed.
+    Id,
 }
 
 #[derive(Clap)]
@@ -118,10 +233,60 @@ pub async fn run() -> anyhow::Result<()> {
 
     match opts.command {
         #[cfg(feature = "node")]
-        Command::Node(o) => node_with_autodiscovery(o.override_peers).await,
+        Command::Node(NodeOpts {
+            command: Some(NodeCommand::Id),
+            data_dir,
+            ..
+        }) => {
+            println!("{}", format_node_id(&node_id(&data_dir).await?));
+            Ok(())
+        }
+        #[cfg(feature = "node")]
+        Command::Node(o) => {
+            let listen = if o.no_listen {
+                None
+            } else {
+                Some(
+                    o.listen
+                        .unwrap_or_else(|| SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_PORT))),
+                )
+            };
+            let control = if o.no_control {
+                None
+            } else {
+                Some(o.control)
+            };
+            let config = ControllerConfig {
+                representative: o.representative()?,
+                online_weight_quorum: o.online_weight_quorum()?,
+                online_weight_minimum: o.online_weight_minimum.to_rai()?,
+                versions: o.versions()?,
+                max_active_elections: o.max_active_elections,
+            };
+            node_with_autodiscovery(
+                o.override_peers,
+                &o.data_dir,
+                config,
+                listen,
+                control,
+                o.max_inbound,
+                o.max_outbound,
+            )
+            .await
+        }
         #[cfg(not(feature = "node"))]
         Command::Node(_) => panic!("Compile with the `node` feature to enable this."),
 
+        #[cfg(feature = "node")]
+        Command::Publish(o) => o.handle().await,
+        #[cfg(not(feature = "node"))]
+        Command::Publish(_) => panic!("Compile with the `node` feature to enable this."),
+
+        #[cfg(feature = "node")]
+        Command::Telemetry(o) => o.handle().await,
+        #[cfg(not(feature = "node"))]
+        Command::Telemetry(_) => panic!("Compile with the `node` feature to enable this."),
+
         #[cfg(feature = "pcap")]
         Command::Pcap(o) => o.handle().await,
         #[cfg(not(feature = "pcap"))]
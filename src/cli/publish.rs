@@ -0,0 +1,39 @@
+use crate::blocks::Block;
+use crate::cli::StringOrStdin;
+use crate::node::publish_block;
+use anyhow::{anyhow, Context};
+use clap::Clap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Publish a signed and worked block to the network, and wait for it to be confirmed.
+#[derive(Clap)]
+pub(crate) struct PublishOpts {
+    /// The block as JSON, or "-" for stdin.
+    block: StringOrStdin<String>,
+
+    /// Control address of the running node to publish through. The node validates the block
+    /// against its ledger, so it needs to be synced.
+    #[clap(short, long, default_value = "127.0.0.1:7076")]
+    node: SocketAddr,
+
+    /// Seconds to wait for the block to be confirmed.
+    #[clap(short, long, default_value = "30")]
+    timeout: u64,
+}
+
+impl PublishOpts {
+    pub async fn handle(&self) -> anyhow::Result<()> {
+        let json = self.block.to_owned().resolve()?;
+        let mut block: Block = serde_json::from_str(&json).context("Invalid block")?;
+        block.calc_hash()?;
+
+        let (hash, confirmed) =
+            publish_block(&block, self.node, Duration::from_secs(self.timeout)).await?;
+        if !confirmed {
+            return Err(anyhow!("Block {:?} was not confirmed in time", hash));
+        }
+        println!("{:?}", hash);
+        Ok(())
+    }
+}
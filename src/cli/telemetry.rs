@@ -0,0 +1,32 @@
+use crate::node::network_telemetry;
+use clap::Clap;
+use std::time::Duration;
+
+/// Summarize the telemetry of nodes on the network, e.g. their block counts and versions.
+#[derive(Clap)]
+pub(crate) struct TelemetryOpts {
+    /// Comma separated list of IP:PORT pairs. Overrides default initial nodes.
+    #[clap(short, long)]
+    override_peers: Option<Vec<String>>,
+
+    /// Maximum number of peers to ask for telemetry.
+    #[clap(long, default_value = "32")]
+    max_peers: usize,
+
+    /// Seconds to wait for peers to send their telemetry.
+    #[clap(short, long, default_value = "10")]
+    wait: u64,
+}
+
+impl TelemetryOpts {
+    pub async fn handle(&self) -> anyhow::Result<()> {
+        let summary = network_telemetry(
+            self.override_peers.to_owned(),
+            self.max_peers,
+            Duration::from_secs(self.wait),
+        )
+        .await?;
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        Ok(())
+    }
+}
@@ -16,7 +16,7 @@ use std::str::FromStr;
 use crate::FeelessError;
 
 /// 256 bit public key which can be converted into an [Address](crate::Address) or verify a [Signature](crate::Signature).
-#[derive(Clone, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct Public([u8; Public::LEN]);
 
 impl Public {
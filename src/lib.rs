@@ -54,6 +54,9 @@ pub use errors::FeelessError;
 #[cfg(feature = "node")]
 mod node;
 
+#[cfg(feature = "node")]
+pub use node::{format_node_id, network_telemetry, node_id, publish_block, TelemetrySummary};
+
 #[cfg(feature = "pcap")]
 mod pcap;
 
@@ -1,5 +1,6 @@
-use crate::blocks::{Block, BlockHash, OpenBlock, Previous};
-use crate::Rai;
+use crate::blocks::{Block, BlockHash, Epoch, OpenBlock, Previous, Subtype};
+use crate::pow::difficulty::Difficulty;
+use crate::{Address, Public, Rai};
 use anyhow::anyhow;
 use std::convert::TryFrom;
 use std::str::FromStr;
@@ -42,6 +43,53 @@ impl Network {
         block
     }
 
+    /// The lowest difficulty any block needs for its work, whatever its type or epoch.
+    pub fn work_threshold(&self) -> Difficulty {
+        match self {
+            Self::Live => Difficulty::new(0xfffffe0000000000),
+            Self::Beta => Difficulty::new(0xffffe00000000000),
+            Self::Test => Difficulty::new(0xf000000000000000),
+        }
+    }
+
+    /// The difficulty a block needs for its work. From epoch 2, blocks that receive or upgrade
+    /// need less work than blocks that send or change.
+    pub fn epoch_work_threshold(&self, epoch: Epoch, subtype: Subtype) -> Difficulty {
+        let receive = matches!(subtype, Subtype::Receive | Subtype::Open | Subtype::Epoch);
+        let difficulty = match (self, epoch, receive) {
+            (Self::Live, Epoch::V0, _) | (Self::Live, Epoch::V1, _) => 0xffffffc000000000,
+            (Self::Live, Epoch::V2, false) => 0xfffffff800000000,
+            (Self::Live, Epoch::V2, true) => 0xfffffe0000000000,
+            (Self::Beta, Epoch::V2, true) => 0xffffe00000000000,
+            (Self::Beta, _, _) => 0xfffff00000000000,
+            (Self::Test, Epoch::V0, _) | (Self::Test, Epoch::V1, _) => 0xfe00000000000000,
+            (Self::Test, Epoch::V2, false) => 0xffc0000000000000,
+            (Self::Test, Epoch::V2, true) => 0xf000000000000000,
+        };
+        Difficulty::new(difficulty)
+    }
+
+    /// The account that signs the epoch blocks upgrading accounts to `epoch`. Only the epoch
+    /// signers of the live network are known.
+    pub fn epoch_signer(&self, epoch: Epoch) -> anyhow::Result<Public> {
+        let address = match (self, epoch) {
+            (Self::Live, Epoch::V1) => {
+                "nano_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3"
+            }
+            (Self::Live, Epoch::V2) => {
+                "nano_3qb6o6i1tkzr6jwr5s7eehfxwg9x6eemitdinbpi7u8bjjwsgqfj4wzser3x"
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown epoch signer for {:?} on {:?}",
+                    epoch,
+                    self
+                ))
+            }
+        };
+        Ok(Address::from_str(address)?.to_public())
+    }
+
     pub fn genesis_hash(&self) -> BlockHash {
         match self {
             Self::Live => BlockHash::from_str(
@@ -70,6 +118,7 @@ impl TryFrom<u8> for Network {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pow::work::Subject;
 
     #[test]
     fn hash_live_genesis_block() {
@@ -79,4 +128,36 @@ mod tests {
         let hash = block.hash().unwrap();
         assert_eq!(hash, &net.genesis_hash());
     }
+
+    #[test]
+    fn live_epoch_signers() {
+        let net = Network::Live;
+        let genesis = net.genesis_block();
+        assert_eq!(&net.epoch_signer(Epoch::V1).unwrap(), genesis.account());
+        assert_ne!(&net.epoch_signer(Epoch::V2).unwrap(), genesis.account());
+        assert!(net.epoch_signer(Epoch::V0).is_err());
+        assert!(Network::Beta.epoch_signer(Epoch::V1).is_err());
+    }
+
+    #[test]
+    fn lowest_work_threshold() {
+        for net in &[Network::Live, Network::Beta, Network::Test] {
+            assert_eq!(
+                net.epoch_work_threshold(Epoch::V2, Subtype::Receive),
+                net.work_threshold()
+            );
+        }
+    }
+
+    #[test]
+    fn genesis_work() {
+        let net = Network::Live;
+        let block = net.genesis_block();
+        let subject = Subject::Public(block.account().to_owned());
+        assert!(block
+            .work()
+            .unwrap()
+            .verify(&subject, &net.work_threshold())
+            .unwrap());
+    }
 }
@@ -1,36 +1,195 @@
 use crate::network::Network;
-use crate::node::controller::{Controller, Packet};
+use crate::node::controller::{Controller, ControllerConfig, Packet};
 use crate::node::state::ArcState;
+use crate::Public;
+use anyhow::Context;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
 
+/// The outgoing side of every channel whose peer has proven its node ID, shared by the whole node so
+/// that messages such as published blocks can be flooded to other peers.
+#[derive(Clone, Default)]
+pub struct Channels {
+    outgoing: Arc<Mutex<HashMap<SocketAddr, Sender<Packet>>>>,
+
+    /// Representative -> the channel its votes were last received on.
+    representatives: Arc<Mutex<HashMap<Public, SocketAddr>>>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn add(&self, peer_addr: SocketAddr, outgoing: Sender<Packet>) {
+        self.outgoing.lock().await.insert(peer_addr, outgoing);
+    }
+
+    pub async fn remove(&self, peer_addr: &SocketAddr) {
+        self.outgoing.lock().await.remove(peer_addr);
+        self.representatives
+            .lock()
+            .await
+            .retain(|_, rep_addr| rep_addr != peer_addr);
+    }
+
+    pub async fn contains(&self, peer_addr: &SocketAddr) -> bool {
+        self.outgoing.lock().await.contains_key(peer_addr)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.outgoing.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Send a message to a random square root of the channels, like the reference node does, so
+    /// that it spreads through the network without every node sending it to every peer. Returns
+    /// the peers it was sent to.
+    pub async fn flood(&self, data: &[u8]) -> Vec<SocketAddr> {
+        let channels = self.outgoing().await;
+        let fanout = (channels.len() as f64).sqrt().ceil() as usize;
+        let chosen: Vec<(SocketAddr, Sender<Packet>)> = channels
+            .choose_multiple(&mut rand::thread_rng(), fanout)
+            .cloned()
+            .collect();
+        Self::send(&chosen, data).await
+    }
+
+    /// Send a message to every channel. Returns the peers it was sent to.
+    pub async fn broadcast(&self, data: &[u8]) -> Vec<SocketAddr> {
+        Self::send(&self.outgoing().await, data).await
+    }
+
+    /// Remember that votes from `representative` come in on the channel to `peer_addr`, so that it
+    /// can be asked for votes directly. Ignored if there's no such channel.
+    pub async fn set_representative(&self, representative: &Public, peer_addr: SocketAddr) {
+        if self.contains(&peer_addr).await {
+            self.representatives
+                .lock()
+                .await
+                .insert(representative.to_owned(), peer_addr);
+        }
+    }
+
+    /// The representatives that votes have come in from on any of the channels.
+    pub async fn representatives(&self) -> Vec<Public> {
+        self.representatives.lock().await.keys().cloned().collect()
+    }
+
+    /// Send a message to the channels the votes of `representatives` come in on. Returns the
+    /// peers it was sent to.
+    pub async fn send_to_representatives(
+        &self,
+        representatives: &[Public],
+        data: &[u8],
+    ) -> Vec<SocketAddr> {
+        let peer_addrs: Vec<SocketAddr> = {
+            let known = self.representatives.lock().await;
+            representatives
+                .iter()
+                .filter_map(|rep| known.get(rep).cloned())
+                .collect()
+        };
+        let channels: Vec<(SocketAddr, Sender<Packet>)> = self
+            .outgoing()
+            .await
+            .into_iter()
+            .filter(|(peer_addr, _)| peer_addrs.contains(peer_addr))
+            .collect();
+        Self::send(&channels, data).await
+    }
+
+    async fn outgoing(&self) -> Vec<(SocketAddr, Sender<Packet>)> {
+        self.outgoing
+            .lock()
+            .await
+            .iter()
+            .map(|(peer_addr, outgoing)| (peer_addr.to_owned(), outgoing.clone()))
+            .collect()
+    }
+
+    async fn send(channels: &[(SocketAddr, Sender<Packet>)], data: &[u8]) -> Vec<SocketAddr> {
+        let mut sent = vec![];
+        for (peer_addr, outgoing) in channels {
+            // The channel is removed when its controller stops, so this is only a race with that.
+            match outgoing.send(Packet::new(data.to_vec())).await {
+                Ok(()) => sent.push(peer_addr.to_owned()),
+                Err(err) => warn!("Could not send to {}: {:?}", peer_addr, err),
+            }
+        }
+        sent
+    }
+}
+
+/// Run a controller for a connected peer. When `bootstrap` is set, the connection is only used to
+/// bootstrap the ledger from the peer, otherwise it joins `channels` once the peer has proven its
+/// node ID.
+///
+/// With a representative in `config`, the controller votes for the blocks the peer asks about.
+///
+/// Returns once the controller stops, with its error if it had one. A bootstrap controller only
+/// stops without an error once the bootstrap is complete.
 pub async fn network_channel(
     network: Network,
     state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
     stream: TcpStream,
+    bootstrap: bool,
 ) -> anyhow::Result<()> {
-    // TODO: How would this fail?
-    let peer_addr = stream.peer_addr().unwrap();
+    // The peer can already be gone by now.
+    let peer_addr = stream
+        .peer_addr()
+        .context("Getting the address of the peer")?;
 
-    let (controller, tx, mut rx) = Controller::new_with_channels(network, state, peer_addr);
+    let (mut controller, tx, mut rx) = Controller::new_with_channels(network, state, peer_addr);
+    controller.set_config(config);
+    if bootstrap {
+        controller.enable_bootstrap();
+    } else {
+        controller.set_channels(channels);
+    }
 
-    // We don't `await` here since the controller will quit when the incoming channel drops.
-    tokio::spawn(controller.run());
+    // The controller will quit when the incoming channel drops, which also ends the writes below.
+    let controller = tokio::spawn(controller.run());
 
     let (mut in_stream, mut out_stream) = stream.into_split();
 
     // Handle reads in a separate task.
+    // Returning drops `tx`, which stops the controller.
     tokio::spawn(async move {
         let mut buffer: [u8; 10240] = [0; 10240];
         loop {
-            let bytes = in_stream
-                .read(&mut buffer)
-                .await
-                .expect("Could not read from peer");
+            let bytes = match in_stream.read(&mut buffer).await {
+                Ok(0) => {
+                    debug!("Peer {} closed the connection", peer_addr);
+                    return;
+                }
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    warn!("Could not read from peer {}: {:?}", peer_addr, err);
+                    return;
+                }
+            };
 
-            tx.send(Packet::new(Vec::from(&buffer[0..bytes])))
+            if tx
+                .send(Packet::new(Vec::from(&buffer[0..bytes])))
                 .await
-                .expect("Could not send to controller");
+                .is_err()
+            {
+                // The controller has stopped.
+                return;
+            }
         }
     });
 
@@ -38,7 +197,7 @@ pub async fn network_channel(
     loop {
         let to_send = match rx.recv().await {
             Some(bytes) => bytes,
-            None => return Ok(()),
+            None => return controller.await.context("Controller panicked")?,
         };
 
         out_stream.write_all(&to_send.data).await?;
@@ -0,0 +1,194 @@
+use crate::blocks::{Block, BlockHash};
+use crate::network::Network;
+use crate::node::channel::Channels;
+use crate::node::controller::{Controller, ControllerConfig};
+use crate::node::state::ArcState;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+/// How long to wait for the node to be connected to a peer before publishing.
+const PUBLISH_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent to the control port as a line of JSON, to publish a block through the running node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublishRequest {
+    pub block: Block,
+
+    /// How long to wait for the block to be confirmed before answering.
+    pub timeout: Duration,
+}
+
+/// The answer to a `PublishRequest`, as a line of JSON: the hash of the block and whether it was
+/// confirmed in time, or why it couldn't be published.
+pub type PublishResponse = Result<(BlockHash, bool), String>;
+
+/// Accept connections from local tools such as `feeless publish`, until the node stops. Each
+/// connection sends one request, which is handled with the node's ledger and channels.
+pub async fn listen_control(
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
+    listener: TcpListener,
+) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening for control requests on {}", addr),
+        Err(err) => warn!(
+            "Listening for control requests on an unknown address: {:?}",
+            err
+        ),
+    }
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Could not accept a control connection: {:?}", err);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let channels = channels.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_control(network, state, channels, config, stream, addr).await {
+                warn!("Error in control connection from {}: {:?}", addr, err);
+            }
+        });
+    }
+}
+
+async fn handle_control(
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .context("Reading the request")?
+        .ok_or_else(|| anyhow!("Closed before sending a request"))?;
+    let request: PublishRequest = serde_json::from_str(&line).context("Decoding the request")?;
+
+    // The controller isn't connected to a peer, it floods the block through the node's channels.
+    let (mut controller, _incoming, _outgoing) =
+        Controller::new_with_channels(network, state, addr);
+    controller.set_config(config);
+    controller.set_channels(channels.clone());
+    let response: PublishResponse = publish(&mut controller, &channels, request)
+        .await
+        .map_err(|err| format!("{:?}", err));
+
+    let mut json = serde_json::to_vec(&response).context("Encoding the response")?;
+    json.push(b'\n');
+    writer
+        .write_all(&json)
+        .await
+        .context("Sending the response")?;
+    Ok(())
+}
+
+async fn publish(
+    controller: &mut Controller,
+    channels: &Channels,
+    request: PublishRequest,
+) -> anyhow::Result<(BlockHash, bool)> {
+    let mut block = request.block;
+    block.calc_hash()?;
+
+    // The node may have only just started.
+    let deadline = Instant::now() + PUBLISH_CONNECT_TIMEOUT;
+    while channels.is_empty().await {
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "The node isn't connected to any peers to publish to"
+            ));
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let hash = controller.publish(&block).await?;
+    let confirmed = controller
+        .wait_for_confirmation(&hash, request.timeout)
+        .await?;
+    Ok((hash, confirmed))
+}
+
+/// Send a `PublishRequest` to the control port of a running node, and wait for its answer.
+pub async fn request_publish(
+    control_addr: SocketAddr,
+    request: &PublishRequest,
+) -> anyhow::Result<PublishResponse> {
+    let stream = TcpStream::connect(control_addr)
+        .await
+        .with_context(|| format!("Could not connect to a node on {}", control_addr))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut json = serde_json::to_vec(request).context("Encoding the request")?;
+    json.push(b'\n');
+    writer
+        .write_all(&json)
+        .await
+        .context("Sending the request")?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .context("Reading the response")?
+        .ok_or_else(|| anyhow!("The node closed the connection without answering"))?;
+    serde_json::from_str(&line).context("Decoding the response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::controller::Packet;
+    use crate::node::state::MemoryState;
+    use crate::DEFAULT_PORT;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    #[tokio::test]
+    async fn publish_through_control_port() {
+        let network = Network::Live;
+        let state: ArcState = Arc::new(Mutex::new(MemoryState::new(network)));
+        let (mut controller, _tx, _rx) =
+            Controller::new_with_channels(network, state.clone(), "127.0.0.1:1".parse().unwrap());
+        controller.init().await.unwrap();
+        let channels = Channels::new();
+        let (peer_tx, _peer_rx) = mpsc::channel::<Packet>(10);
+        let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), DEFAULT_PORT));
+        channels.add(peer_addr, peer_tx).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        tokio::spawn(listen_control(
+            network,
+            state,
+            channels,
+            ControllerConfig::default(),
+            listener,
+        ));
+
+        // The node validates the block against its own ledger, where genesis has no source.
+        let request = PublishRequest {
+            block: network.genesis_block(),
+            timeout: Duration::from_millis(10),
+        };
+        let err = request_publish(control_addr, &request)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(err.contains("depends on a missing block"), "{}", err);
+    }
+}
@@ -0,0 +1,302 @@
+use super::Controller;
+use crate::blocks::{Block, BlockHash, BlockHolder, Link, Previous};
+use crate::pow::work::Subject;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// How many blocks can wait on a dependency. Once full, the oldest are forgotten.
+pub const MAX_UNCHECKED: usize = 65536;
+
+/// Where a block came from, which decides how it gets into the ledger.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockOrigin {
+    /// Published, pushed or requested for confirmation. The block needs to win an election first.
+    Live,
+
+    /// Pulled while bootstrapping. The block is added to the ledger unless it forks.
+    Bootstrap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessResult {
+    /// Added to the ledger.
+    Progress,
+
+    /// Already in the ledger.
+    Old,
+
+    /// Waiting in the unchecked store until the previous block arrives.
+    GapPrevious(BlockHash),
+
+    /// Waiting in the unchecked store until the send block being received arrives.
+    GapSource(BlockHash),
+
+    /// Waiting for an election to decide between it and the block it forks with.
+    Fork,
+
+    /// Waiting for an election to confirm it.
+    Election,
+}
+
+impl ProcessResult {
+    /// The block that a block in the unchecked store is waiting on.
+    pub fn dependency(&self) -> Option<&BlockHash> {
+        match self {
+            ProcessResult::GapPrevious(hash) | ProcessResult::GapSource(hash) => Some(hash),
+            _ => None,
+        }
+    }
+}
+
+impl Controller {
+    /// Process a block from the network, then any unchecked blocks that can now be processed
+    /// because of it.
+    pub async fn process_block(
+        &mut self,
+        block: &BlockHolder,
+        origin: BlockOrigin,
+    ) -> anyhow::Result<ProcessResult> {
+        let context = || format!("Process block {:?}", block);
+        let hash = block.hash().with_context(context)?;
+        let result = self
+            .process_one(block, origin)
+            .await
+            .with_context(context)?;
+        self.process_unchecked(&hash).await.with_context(context)?;
+        Ok(result)
+    }
+
+    /// Process the unchecked blocks that were waiting on `hash`, if it's in the ledger, and in turn
+    /// the blocks that were waiting on them.
+    pub async fn process_unchecked(&mut self, hash: &BlockHash) -> anyhow::Result<()> {
+        let context = || format!("Process unchecked blocks of {:?}", hash);
+        let mut ready = vec![hash.to_owned()];
+        while let Some(dependency) = ready.pop() {
+            let mut state = self.state.lock().await;
+            if state
+                .get_block_by_hash(&dependency)
+                .await
+                .with_context(context)?
+                .is_none()
+            {
+                continue;
+            }
+            let unchecked = state
+                .take_unchecked(&dependency)
+                .await
+                .with_context(context)?;
+            drop(state);
+
+            for (block, origin) in unchecked {
+                // One bad block shouldn't stop the others from being processed.
+                match self.process_one(&block, origin).await {
+                    Ok(_) => ready.push(block.hash().with_context(context)?),
+                    Err(err) => warn!("Dropping unchecked block: {:?}", err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_one(
+        &mut self,
+        holder: &BlockHolder,
+        origin: BlockOrigin,
+    ) -> anyhow::Result<ProcessResult> {
+        let context = || format!("Process {:?} block {:?}", origin, holder);
+        let hash = holder.hash().with_context(context)?;
+        if self
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(&hash)
+            .await
+            .with_context(context)?
+            .is_some()
+        {
+            return Ok(ProcessResult::Old);
+        }
+        self.precheck_block(holder).with_context(context)?;
+
+        if let Some(gap) = self.gap(holder).await.with_context(context)? {
+            let dependency = gap.dependency().expect("gap has a dependency");
+            debug!("Block {:?} is waiting on {:?}", holder, dependency);
+            let mut state = self.state.lock().await;
+            if state.unchecked_count().await.with_context(context)? >= MAX_UNCHECKED {
+                state
+                    .remove_oldest_unchecked()
+                    .await
+                    .with_context(context)?;
+            }
+            state
+                .add_unchecked(dependency, holder, origin)
+                .await
+                .with_context(context)?;
+            return Ok(gap);
+        }
+
+        let block = self
+            .resolve_block(holder)
+            .await
+            .with_context(context)?
+            .ok_or_else(|| anyhow!("Source has already been received"))
+            .with_context(context)?;
+        self.verify_block(&block).await.with_context(context)?;
+
+        let fork = self.ledger_fork(&block).await.with_context(context)?;
+        if fork.is_some() || origin == BlockOrigin::Live {
+            let elected = self.start_election(&block).await.with_context(context)?;
+            return Ok(match elected {
+                Some(winner) if winner.hash().ok() == Some(&hash) => ProcessResult::Progress,
+                _ if fork.is_some() => ProcessResult::Fork,
+                _ => ProcessResult::Election,
+            });
+        }
+
+        // Without an election, only a block that continues its own account chain is added.
+        if !self.extends_head(&block).await.with_context(context)? {
+            return Err(anyhow!("Block doesn't extend the head of its account"))
+                .with_context(context);
+        }
+        self.add_elected_block(&block).await.with_context(context)?;
+        Ok(ProcessResult::Progress)
+    }
+
+    /// Whether a block is built on the head of its own account, or opens an account that hasn't
+    /// been opened yet.
+    async fn extends_head(&self, block: &Block) -> anyhow::Result<bool> {
+        let head = self
+            .state
+            .lock()
+            .await
+            .get_latest_block_hash_for_account(block.account())
+            .await?;
+        Ok(match (head, block.previous()) {
+            (Some(head), Previous::Block(previous)) => previous == &head,
+            (None, Previous::Block(previous)) => previous == &BlockHash::zero(),
+            (None, Previous::Open) => true,
+            (Some(_), Previous::Open) => false,
+        })
+    }
+
+    /// The gap of a block, if a block it depends on isn't in the ledger yet.
+    pub async fn gap(&self, holder: &BlockHolder) -> anyhow::Result<Option<ProcessResult>> {
+        let state = self.state.lock().await;
+        let (previous, source) = match holder {
+            BlockHolder::Send(b) => (Some(b.previous.to_owned()), None),
+            BlockHolder::Receive(b) => (Some(b.previous.to_owned()), Some(b.source.to_owned())),
+            BlockHolder::Open(b) => (None, Some(b.source.to_owned())),
+            BlockHolder::Change(b) => (Some(b.previous.to_owned()), None),
+            BlockHolder::State(b) => {
+                // Only a receive links to a source, which needs the previous balance to tell.
+                let previous_balance = if b.previous == BlockHash::zero() {
+                    None
+                } else {
+                    match state.get_block_by_hash(&b.previous).await? {
+                        Some(previous) => Some(previous.balance().to_owned()),
+                        None => return Ok(Some(ProcessResult::GapPrevious(b.previous.to_owned()))),
+                    }
+                };
+                match b.link.resolve(previous_balance.as_ref(), &b.balance)? {
+                    Link::Source(source) => (None, Some(source)),
+                    _ => (None, None),
+                }
+            }
+        };
+
+        if let Some(previous) = previous {
+            if state.get_block_by_hash(&previous).await?.is_none() {
+                return Ok(Some(ProcessResult::GapPrevious(previous)));
+            }
+        }
+        if let Some(source) = source {
+            if state.get_block_by_hash(&source).await?.is_none() {
+                return Ok(Some(ProcessResult::GapSource(source)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check what can be checked without the ledger, before a block is stored to wait on a
+    /// dependency: the work against the lowest threshold, and the signature if the block says who
+    /// signed it.
+    fn precheck_block(&self, holder: &BlockHolder) -> anyhow::Result<()> {
+        let work = holder
+            .work()
+            .ok_or_else(|| anyhow!("Work is missing from block"))?;
+        if !work.verify(
+            &Subject::Hash(holder.root()),
+            &self.network.work_threshold(),
+        )? {
+            return Err(anyhow!("Not enough work: {:?}", work));
+        }
+
+        if let Some(signer) = holder.signer(&self.network)? {
+            let signature = holder
+                .signature()
+                .ok_or_else(|| anyhow!("Signature missing"))?;
+            signer
+                .verify(holder.hash()?.as_bytes(), signature)
+                .context("Incorrect signature")?;
+        }
+        Ok(())
+    }
+
+    /// Check the signature and the work of a block, with the work threshold of its type and
+    /// epoch.
+    async fn verify_block(&self, block: &Block) -> anyhow::Result<()> {
+        block
+            .verify_signature(&block.signer(&self.network)?)
+            .context("Incorrect signature")?;
+
+        let epoch = self.state.lock().await.epoch_for_block(block).await?;
+        let threshold = self.network.epoch_work_threshold(epoch, block.subtype()?);
+        let work = block
+            .work()
+            .ok_or_else(|| anyhow!("Work is missing from block"))?;
+        if !work.verify(&Subject::Hash(block.root()), &threshold)? {
+            return Err(anyhow!("Not enough work for {:?}: {:?}", epoch, work));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::controller::tests::{empty_lattice, genesis_send};
+    use crate::node::state::StateBatch;
+
+    #[tokio::test]
+    async fn refuse_bootstrap_blocks_off_the_head() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let genesis = network.genesis_block();
+        let gen_send = genesis_send(network);
+
+        // Off the head of a whole account chain, a block forks with the block after its previous
+        // and goes to an election. With genesis stored but no longer the head of its account,
+        // nothing forks with the send built on it.
+        let mut batch = StateBatch::new();
+        batch.remove_latest_block_hash(genesis.account());
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        let err = controller
+            .process_block(&gen_send.to_holder().unwrap(), BlockOrigin::Bootstrap)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("doesn't extend the head of its account"),
+            "{:?}",
+            err
+        );
+        let state = controller.state.lock().await;
+        assert!(state
+            .get_block_by_hash(gen_send.hash().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,63 +1,18 @@
-use crate::blocks::{Block, BlockHash, BlockType, Previous};
+use crate::blocks::{Block, BlockHash, BlockHolder, Previous, Subtype};
+use crate::node::controller::rep_weights::stage_rep_weights;
 use crate::node::controller::Controller;
-use crate::node::messages::confirm_ack::{Confirm, ConfirmAck};
-use crate::{Public, Rai, Signature};
+use crate::node::messages::bulk_pull::BulkPull;
+use crate::node::state::{Receivable, StateBatch};
+use crate::{Public, Rai};
 use anyhow::{anyhow, Context};
-use tracing::{debug, instrument, warn};
-
-struct AccountDelta {
-    from: Public,
-    to: Public,
-    amount: Rai,
-}
+use tracing::debug;
 
 impl Controller {
-    #[instrument(skip(self))]
-    pub async fn add_vote(&mut self, confirm_ack: &ConfirmAck) -> anyhow::Result<()> {
-        let context = || format!("Adding vote {:?}", &confirm_ack);
-
-        let hashes = if let Confirm::VoteByHash(hashes) = &confirm_ack.confirm {
-            hashes
-        } else {
-            return Err(anyhow!("Confirm::Block not implemented")).with_context(context);
-        };
-
-        for hash in hashes {
-            self.validate_vote(hash, &confirm_ack.account, &confirm_ack.signature)
-                .await
-                .with_context(context)?;
-
-            self.state
-                .lock()
-                .await
-                .add_vote(hash, &confirm_ack.account)
-                .await
-                .with_context(context)?;
-
-            // self.check_votes(&confirm_ack.confirm)
-            //     .await
-            //     .with_context(context)?;
-        }
-
-        Ok(())
-    }
-
-    #[instrument(skip(self))]
-    pub async fn validate_vote(
-        &mut self,
-        hash: &BlockHash,
-        representative: &Public,
-        signature: &Signature,
-    ) -> anyhow::Result<()> {
-        warn!("TODO validate vote");
-        Ok(())
-    }
-
     /// Add a block that has been deemed valid by ORV.
     ///
     /// Before adding a block we need to make sure it:
     /// * Doesn't already exist.
-    /// * Verify the work.
+    /// * Has work. The block processor has checked it against the threshold already.
     /// * Verify the signature.
     /// * Handle the specific block type appropriately.
     ///
@@ -67,14 +22,15 @@ impl Controller {
         let context = || format!("Block {:?}", &block);
         let block_hash = block.hash().with_context(context)?;
 
+        // Hold the lock until the block is committed so nothing else can change the ledger in the
+        // meantime.
+        let mut state = self.state.lock().await;
+
         // Block already exists, we can ignore this.
         // In reality this shouldn't even happen so it should be a panic.
         // This function should only have the chance to be called once per block.
-        if self
-            .state
-            .lock()
-            .await
-            .get_block_by_hash(&block_hash)
+        if state
+            .get_block_by_hash(block_hash)
             .await
             .with_context(context)?
             .is_some()
@@ -83,8 +39,9 @@ impl Controller {
         }
 
         let context = || format!("Block {:?}", block);
+        let signer = block.signer(&self.network).with_context(context)?;
         block
-            .verify_signature(&block.account())
+            .verify_signature(&signer)
             .context("Incorrect signature")
             .with_context(context)?;
 
@@ -92,29 +49,61 @@ impl Controller {
         if work.is_none() {
             return Err(anyhow!("Work is missing from block")).with_context(context);
         }
-        // TODO: Verify work
-
-        // TODO: For now just assume this is a send block
-        match block.block_type() {
-            BlockType::Send => {
-                dbg!(block);
-
-                let previous_hash = match block.previous() {
-                    Previous::Block(h) => h,
-                    Previous::Open => {
-                        return Err(anyhow!("Send block has a blank previous block hash"))
-                            .with_context(context)
-                    }
-                };
 
-                let prev_block = self
-                    .state
-                    .lock()
-                    .await
-                    .get_block_by_hash(previous_hash)
+        let previous_block = match block.previous() {
+            Previous::Block(hash) if hash != &BlockHash::zero() => Some(
+                state
+                    .get_block_by_hash(hash)
                     .await
                     .context("Previous block")
                     .with_context(context)?
+                    .ok_or_else(|| anyhow!("Previous block {:?} is not in the ledger", hash))
+                    .with_context(context)?,
+            ),
+            _ => None,
+        };
+        // Otherwise the block would be resolved against, and move funds of, another account.
+        if let Some(previous) = &previous_block {
+            if previous.account() != block.account() {
+                return Err(anyhow!(
+                    "Previous block belongs to another account: {:?}",
+                    previous.account()
+                ))
+                .with_context(context);
+            }
+        }
+        let height = match &previous_block {
+            Some(previous) => {
+                let previous_hash = previous.hash().with_context(context)?;
+                state
+                    .block_height(previous_hash)
+                    .await
+                    .with_context(context)?
+                    .ok_or_else(|| anyhow!("Missing height of previous block"))
+                    .with_context(context)?
+                    + 1
+            }
+            None => 1,
+        };
+
+        let epoch = state.epoch_for_block(block).await.with_context(context)?;
+
+        // All the ledger changes for this block are committed together.
+        let mut batch = StateBatch::new();
+        batch
+            .add_block(block, height, epoch)
+            .with_context(context)?
+            .set_latest_block_hash(block.account(), block_hash);
+
+        match block.subtype().with_context(context)? {
+            Subtype::Send => {
+                if block.previous() == &Previous::Open {
+                    return Err(anyhow!("Send block has a blank previous block hash"))
+                        .with_context(context);
+                }
+
+                let prev_block = previous_block
+                    .as_ref()
                     .ok_or_else(|| anyhow!("Could not find previous block"))
                     .with_context(context)?;
                 let prev_balance = prev_block.balance();
@@ -127,8 +116,8 @@ impl Controller {
                     .with_context(context);
                 }
 
-                let _to_account = block.destination().with_context(context)?;
-                let _amount = prev_balance
+                let to_account = block.destination().with_context(context)?;
+                let amount = prev_balance
                     .checked_sub(block.balance())
                     .ok_or_else(|| {
                         anyhow!(
@@ -138,33 +127,181 @@ impl Controller {
                         )
                     })
                     .with_context(context)?;
+                batch.add_receivable(
+                    to_account,
+                    block_hash,
+                    &Receivable::new(block.account().to_owned(), amount),
+                );
             }
-            BlockType::Open => {
-                dbg!(block);
-
+            Subtype::Open | Subtype::Receive => {
                 // If the block is the genesis block, we basically just trust the balance.
                 if !block.is_genesis(&self.network)? {
-                    // TODO: Make sure the balance in the open block matches the amount in the
-                    // send block.
+                    let source = block.source().with_context(context)?;
+                    let receivable = state
+                        .receivables(block.account())
+                        .await
+                        .with_context(context)?
+                        .into_iter()
+                        .find(|(hash, _)| hash == source)
+                        .map(|(_, receivable)| receivable)
+                        .ok_or_else(|| anyhow!("Source {:?} is not receivable", source))
+                        .with_context(context)?;
+
+                    let prev_balance = previous_block
+                        .as_ref()
+                        .map(|b| b.balance().to_owned())
+                        .unwrap_or_else(Rai::zero);
+                    let amount = block
+                        .balance()
+                        .checked_sub(&prev_balance)
+                        .ok_or_else(|| anyhow!("Can not decrease balance in a receive block"))
+                        .with_context(context)?;
+                    if amount != receivable.amount {
+                        return Err(anyhow!(
+                            "Received {:?} but {:?} was sent",
+                            amount,
+                            receivable.amount
+                        ))
+                        .with_context(context);
+                    }
+                    batch.remove_receivable(block.account(), source);
+                }
+            }
+            Subtype::Change => {}
+            Subtype::Epoch => {
+                // An epoch block only upgrades the account, so it can't move funds or votes. The
+                // first block of an account has nothing to keep, so it starts without either.
+                let (balance, representative) = match &previous_block {
+                    Some(previous) => (
+                        previous.balance().to_owned(),
+                        previous.representative().as_bytes(),
+                    ),
+                    None => (Rai::zero(), [0u8; Public::LEN].as_ref()),
+                };
+                if block.balance() != &balance {
+                    return Err(anyhow!("Epoch block changes the balance")).with_context(context);
+                }
+                if block.representative().as_bytes() != representative {
+                    return Err(anyhow!("Epoch block changes the representative"))
+                        .with_context(context);
                 }
             }
-            _ => todo!(),
         }
 
-        self.state
-            .lock()
-            .await
-            .add_block(block)
-            .await
-            .with_context(context)?;
-
-        // self.balance_rep_weights(block)
-        //     .await
-        //     .with_context(context)?;
+        stage_rep_weights(&*state, &mut batch, previous_block.as_ref(), Some(block)).await?;
+        state.commit(batch).await.with_context(context)?;
 
         Ok(())
     }
 
+    /// Fill in what a block doesn't carry by itself from the ledger, e.g. the account of a legacy
+    /// send block, or what the link of a state block refers to. Returns None when a block it
+    /// depends on isn't in the ledger yet.
+    pub async fn resolve_block(&self, holder: &BlockHolder) -> anyhow::Result<Option<Block>> {
+        let context = || format!("Resolve block {:?}", holder);
+        let state = self.state.lock().await;
+
+        let mut block = match holder {
+            BlockHolder::State(b) => {
+                let previous_balance = if b.previous == BlockHash::zero() {
+                    None
+                } else {
+                    match state
+                        .get_block_by_hash(&b.previous)
+                        .await
+                        .with_context(context)?
+                    {
+                        Some(previous) if previous.account() != &b.account => {
+                            return Err(anyhow!(
+                                "Previous block belongs to another account: {:?}",
+                                previous.account()
+                            ))
+                            .with_context(context);
+                        }
+                        Some(previous) => Some(previous.balance().to_owned()),
+                        None => return Ok(None),
+                    }
+                };
+                let mut block = Block::from_state_block(b);
+                block
+                    .resolve_link(previous_balance.as_ref())
+                    .with_context(context)?;
+                block
+            }
+            BlockHolder::Send(b) => {
+                let previous = state
+                    .get_block_by_hash(&b.previous)
+                    .await
+                    .with_context(context)?;
+                match previous {
+                    Some(previous) => {
+                        Block::from_send_block(b, previous.account(), previous.representative())
+                    }
+                    None => return Ok(None),
+                }
+            }
+            BlockHolder::Open(b) => {
+                let receivable = state
+                    .receivables(&b.account)
+                    .await
+                    .with_context(context)?
+                    .into_iter()
+                    .find(|(hash, _)| hash == &b.source);
+                match receivable {
+                    Some((_, receivable)) => {
+                        Block::from_open_block(b, &Previous::Open, &receivable.amount)
+                    }
+                    None => return Ok(None),
+                }
+            }
+            BlockHolder::Receive(b) => {
+                let previous = match state
+                    .get_block_by_hash(&b.previous)
+                    .await
+                    .with_context(context)?
+                {
+                    Some(previous) => previous,
+                    None => return Ok(None),
+                };
+                let receivable = state
+                    .receivables(previous.account())
+                    .await
+                    .with_context(context)?
+                    .into_iter()
+                    .find(|(hash, _)| hash == &b.source);
+                let amount = match receivable {
+                    Some((_, receivable)) => receivable.amount,
+                    None => return Ok(None),
+                };
+                let balance = previous
+                    .balance()
+                    .checked_add(&amount)
+                    .ok_or_else(|| anyhow!("Balance overflow"))
+                    .with_context(context)?;
+                Block::from_receive_block(
+                    b,
+                    previous.account(),
+                    previous.representative(),
+                    &balance,
+                )
+            }
+            BlockHolder::Change(b) => {
+                let previous = state
+                    .get_block_by_hash(&b.previous)
+                    .await
+                    .with_context(context)?;
+                match previous {
+                    Some(previous) => {
+                        Block::from_change_block(b, previous.account(), previous.balance())
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+        block.calc_hash().with_context(context)?;
+        Ok(Some(block))
+    }
+
     pub async fn get_latest_block(&self, account: &Public) -> anyhow::Result<Option<Block>> {
         let block_hash = self
             .state
@@ -178,8 +315,7 @@ impl Controller {
             None => return Ok(None),
         };
 
-        Ok(self
-            .state
+        self.state
             .lock()
             .await
             .get_block_by_hash(&block_hash)
@@ -189,6 +325,141 @@ impl Controller {
                     "Could not get block for latest hash for account: {:?}",
                     account
                 )
-            })?)
+            })
+    }
+
+    /// The first block to respond to a bulk pull with, which is the frontier of the account (or
+    /// the start block).
+    pub async fn bulk_pull_start(&self, bulk_pull: &BulkPull) -> anyhow::Result<Option<BlockHash>> {
+        let context = || format!("Bulk pull start for {:?}", bulk_pull);
+        let state = self.state.lock().await;
+
+        let account = bulk_pull.start.to_account();
+        match state
+            .get_latest_block_hash_for_account(&account)
+            .await
+            .with_context(context)?
+        {
+            Some(hash) => Ok(Some(hash)),
+            None => {
+                let hash = bulk_pull.start.to_block_hash();
+                Ok(state
+                    .get_block_by_hash(&hash)
+                    .await
+                    .with_context(context)?
+                    .map(|_| hash))
+            }
+        }
+    }
+
+    /// Up to `count` blocks to respond to a bulk pull with, walking backwards from `start` until
+    /// `end` or the open block is reached. Also returns where the next batch starts, if anywhere.
+    pub async fn bulk_pull_blocks(
+        &self,
+        bulk_pull: &BulkPull,
+        start: BlockHash,
+        count: usize,
+    ) -> anyhow::Result<(Vec<Block>, Option<BlockHash>)> {
+        let context = || format!("Bulk pull blocks for {:?}", bulk_pull);
+        let state = self.state.lock().await;
+
+        let mut current = Some(start);
+        let mut blocks = vec![];
+        while let Some(hash) = current.clone() {
+            if hash == bulk_pull.end {
+                current = None;
+                break;
+            }
+            if blocks.len() >= count {
+                break;
+            }
+
+            let block = state
+                .get_block_by_hash(&hash)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing block {:?} in account chain", hash))
+                .with_context(context)?;
+            current = match block.previous() {
+                Previous::Block(previous) if previous != &BlockHash::zero() => {
+                    Some(previous.to_owned())
+                }
+                _ => None,
+            };
+            blocks.push(block);
+        }
+
+        Ok((blocks, current))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockType, Link};
+    use crate::network::Network;
+    use crate::node::controller::tests::{empty_lattice, open_account, signed_block};
+    use crate::Private;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn refuse_blocks_on_another_account() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let (a, b, c) = (Private::random(), Private::random(), Private::random());
+        let public = |private: &Private| private.to_public().unwrap();
+        open_account(&controller, &a).await;
+        let b_open = open_account(&controller, &b).await;
+        let b_previous = Previous::Block(b_open.hash().unwrap().to_owned());
+
+        // A signs a send of 40 out of B's balance.
+        let send = signed_block(
+            &a,
+            BlockType::Send,
+            b_previous.clone(),
+            60,
+            Link::DestinationAccount(public(&c)),
+        );
+        assert!(controller.add_elected_block(&send).await.is_err());
+
+        // Or the same as a state block, the way it arrives from the network.
+        let state_send = signed_block(
+            &a,
+            BlockType::State,
+            b_previous,
+            60,
+            Link::Unsure(<[u8; Link::LEN]>::try_from(public(&c).as_bytes()).unwrap()),
+        );
+        assert!(controller
+            .resolve_block(&state_send.to_holder().unwrap())
+            .await
+            .is_err());
+
+        // A previous block that isn't in the ledger doesn't make it an open block either.
+        let missing = signed_block(
+            &a,
+            BlockType::Send,
+            Previous::Block(BlockHash::try_from([1u8; BlockHash::LEN].as_ref()).unwrap()),
+            60,
+            Link::DestinationAccount(public(&c)),
+        );
+        assert!(controller.add_elected_block(&missing).await.is_err());
+
+        assert_eq!(
+            controller.account_balance(&public(&b)).await.unwrap(),
+            Rai::new(100u128)
+        );
+        assert_eq!(
+            controller.rep_weight(&public(&b)).await.unwrap(),
+            Rai::new(100u128)
+        );
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .receivables(&public(&c))
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
@@ -0,0 +1,333 @@
+use super::messages::{next_account, FRONTIER_BATCH_SIZE};
+use super::Controller;
+use crate::blocks::BlockHash;
+use crate::node::header::{Extensions, MessageType};
+use crate::node::messages::bulk_pull::{BulkPull, HashOrAccount};
+use crate::node::messages::frontier_req::FrontierReq;
+use crate::node::messages::frontier_resp::FrontierResp;
+use crate::Public;
+use anyhow::{anyhow, Context};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Tracks bootstrapping the ledger from a single peer.
+///
+/// The peer is asked for every account frontier, which are compared against our ledger. Accounts
+/// that we don't have or are behind on are then bulk pulled. Accounts that the peer doesn't have or
+/// is behind on are bulk pushed to it.
+#[derive(Debug)]
+pub struct Bootstrap {
+    started: Instant,
+    last_logged: Instant,
+
+    /// Has the peer finished sending frontiers?
+    frontiers_complete: bool,
+
+    /// Accounts that are behind, waiting to be pulled.
+    pending_pulls: VecDeque<BulkPull>,
+
+    /// The last account received from the peer. Frontiers are sent in account order, so any of our
+    /// accounts between this and the next frontier are missing from the peer.
+    last_frontier: Option<Public>,
+
+    /// Accounts that the peer is behind on, with the peer's frontier. Sent in a single bulk push
+    /// once all frontiers are received.
+    pending_pushes: Vec<(Public, BlockHash)>,
+
+    pub(super) progress: BootstrapProgress,
+}
+
+impl Bootstrap {
+    /// How many bulk pulls can be waiting on a response at once.
+    pub const MAX_PULLS_IN_FLIGHT: usize = 4;
+
+    /// How often progress is logged.
+    const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_logged: Instant::now(),
+            frontiers_complete: false,
+            pending_pulls: VecDeque::new(),
+            last_frontier: None,
+            pending_pushes: vec![],
+            progress: BootstrapProgress::default(),
+        }
+    }
+
+    pub fn progress(&self) -> BootstrapProgress {
+        let mut progress = self.progress.clone();
+        progress.elapsed = self.started.elapsed();
+        progress
+    }
+
+    fn log_progress(&mut self) {
+        if self.last_logged.elapsed() >= Self::LOG_INTERVAL {
+            self.last_logged = Instant::now();
+            info!("Bootstrap progress: {}", self.progress());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BootstrapProgress {
+    /// Frontiers received from the peer.
+    pub frontiers: usize,
+
+    /// Accounts that were already up to date with the peer.
+    pub accounts_up_to_date: usize,
+
+    /// Accounts where we have more blocks than the peer.
+    pub accounts_ahead: usize,
+
+    /// Bulk pulls scheduled for accounts that are missing or behind.
+    pub pulls_scheduled: usize,
+
+    pub pulls_completed: usize,
+    pub blocks_pulled: usize,
+
+    /// Accounts that are missing or behind on the peer.
+    pub pushes_scheduled: usize,
+
+    pub blocks_pushed: usize,
+    pub elapsed: Duration,
+}
+
+impl Display for BootstrapProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "frontiers: {} up to date: {} ahead: {} pulls: {}/{} blocks: {} pushes: {} blocks: {} \
+             elapsed: {:?}",
+            self.frontiers,
+            self.accounts_up_to_date,
+            self.accounts_ahead,
+            self.pulls_completed,
+            self.pulls_scheduled,
+            self.blocks_pulled,
+            self.pushes_scheduled,
+            self.blocks_pushed,
+            self.elapsed,
+        )
+    }
+}
+
+impl Controller {
+    /// Bootstrap from this peer when the controller is run, instead of handshaking.
+    pub fn enable_bootstrap(&mut self) {
+        self.bootstrap = Some(Bootstrap::new());
+    }
+
+    pub fn bootstrap_progress(&self) -> Option<BootstrapProgress> {
+        self.bootstrap.as_ref().map(|b| b.progress())
+    }
+
+    /// All frontiers have been received and every scheduled pull has completed.
+    pub fn is_bootstrap_complete(&self) -> bool {
+        match &self.bootstrap {
+            Some(bootstrap) => {
+                bootstrap.frontiers_complete
+                    && bootstrap.pending_pulls.is_empty()
+                    && self.bulk_pulls.is_empty()
+            }
+            None => false,
+        }
+    }
+
+    /// Request every frontier from the peer. The rest of the bootstrap is driven by the responses.
+    pub async fn start_bootstrap(&mut self) -> anyhow::Result<()> {
+        if self.bootstrap.is_none() {
+            return Err(anyhow!("Bootstrap is not enabled"));
+        }
+
+        info!("Starting bootstrap from {:?}", self.peer_addr);
+        self.send_header(MessageType::FrontierReq, Extensions::new())
+            .await?;
+        self.send(&FrontierReq::all()).await?;
+
+        // The rest of this connection will be a bunch of frontiers without any headers.
+        self.frontier_stream = true;
+        Ok(())
+    }
+
+    /// Compare a frontier from the peer with our ledger, and schedule a pull if we're behind.
+    pub async fn bootstrap_frontier(&mut self, frontier: &FrontierResp) -> anyhow::Result<()> {
+        let context = || format!("Bootstrap frontier {:?}", frontier);
+
+        self.bootstrap_missing_accounts(Some(&frontier.account))
+            .await
+            .with_context(context)?;
+
+        let (our_frontier, have_their_frontier) = {
+            let state = self.state.lock().await;
+            let ours = state
+                .get_latest_block_hash_for_account(&frontier.account)
+                .await
+                .with_context(context)?;
+            let have_theirs = state
+                .get_block_by_hash(&frontier.frontier_hash)
+                .await
+                .with_context(context)?
+                .is_some();
+            (ours, have_theirs)
+        };
+
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        bootstrap.progress.frontiers += 1;
+        bootstrap.last_frontier = Some(frontier.account.to_owned());
+
+        let start = HashOrAccount::from(&frontier.account);
+        match our_frontier {
+            Some(ours) if ours == frontier.frontier_hash => {
+                bootstrap.progress.accounts_up_to_date += 1;
+            }
+            Some(_) if have_their_frontier => {
+                // The peer is behind us for this account.
+                bootstrap.progress.accounts_ahead += 1;
+                bootstrap.progress.pushes_scheduled += 1;
+                bootstrap.pending_pushes.push((
+                    frontier.account.to_owned(),
+                    frontier.frontier_hash.to_owned(),
+                ));
+            }
+            Some(ours) => {
+                // Pull only the blocks after our frontier.
+                bootstrap
+                    .pending_pulls
+                    .push_back(BulkPull::new(start, ours, None));
+                bootstrap.progress.pulls_scheduled += 1;
+            }
+            None => {
+                bootstrap
+                    .pending_pulls
+                    .push_back(BulkPull::account(&frontier.account));
+                bootstrap.progress.pulls_scheduled += 1;
+            }
+        }
+
+        bootstrap.log_progress();
+        Ok(())
+    }
+
+    pub async fn bootstrap_frontiers_end(&mut self) -> anyhow::Result<()> {
+        self.bootstrap_missing_accounts(None).await?;
+
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        bootstrap.frontiers_complete = true;
+        info!("Bootstrap frontiers received: {}", bootstrap.progress());
+
+        self.send_bootstrap_pushes().await?;
+        self.send_bootstrap_pulls().await?;
+        self.log_bootstrap_complete();
+        Ok(())
+    }
+
+    pub async fn bootstrap_pull_end(&mut self, bulk_pull: &BulkPull) -> anyhow::Result<()> {
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        bootstrap.progress.pulls_completed += 1;
+        debug!("Bootstrap pull completed: {:?}", bulk_pull);
+        bootstrap.log_progress();
+
+        self.send_bootstrap_pulls().await?;
+        self.log_bootstrap_complete();
+        Ok(())
+    }
+
+    fn log_bootstrap_complete(&self) {
+        if self.is_bootstrap_complete() {
+            info!(
+                "Bootstrap complete: {}",
+                self.bootstrap_progress().expect("bootstrap is None")
+            );
+        }
+    }
+
+    /// Schedule pushes for our accounts that the peer skipped over, i.e. accounts after the last
+    /// received frontier and before `until`, or all remaining accounts when `until` is `None`.
+    async fn bootstrap_missing_accounts(&mut self, until: Option<&Public>) -> anyhow::Result<()> {
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        let mut start = match &bootstrap.last_frontier {
+            Some(account) => next_account(account),
+            None => Some(Public::try_from([0u8; Public::LEN].as_ref()).expect("zero public")),
+        };
+
+        let mut missing = vec![];
+        'batches: while let Some(batch_start) = start {
+            let frontiers = self
+                .state
+                .lock()
+                .await
+                .frontiers(&batch_start, None, FRONTIER_BATCH_SIZE)
+                .await
+                .context("Frontiers for missing accounts")?;
+            start = match frontiers.last() {
+                Some((account, _)) if frontiers.len() == FRONTIER_BATCH_SIZE => {
+                    next_account(account)
+                }
+                _ => None,
+            };
+
+            for (account, _) in frontiers {
+                if let Some(until) = until {
+                    if &account >= until {
+                        break 'batches;
+                    }
+                }
+                missing.push((account, BlockHash::zero()));
+            }
+        }
+
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        bootstrap.progress.pushes_scheduled += missing.len();
+        bootstrap.pending_pushes.extend(missing);
+        Ok(())
+    }
+
+    /// Push every block the peer is missing in one go, since there is no response to wait for.
+    /// Blocks are read from the ledger in batches, like for a bulk pull.
+    async fn send_bootstrap_pushes(&mut self) -> anyhow::Result<()> {
+        let bootstrap = self.bootstrap.as_mut().expect("bootstrap is None");
+        let pushes = std::mem::take(&mut bootstrap.pending_pushes);
+        if pushes.is_empty() {
+            return Ok(());
+        }
+
+        let mut pushed = 0;
+        self.start_bulk_push().await?;
+        for (account, end) in pushes {
+            let bulk_pull = BulkPull::new(HashOrAccount::from(&account), end, None);
+            pushed += self.send_bulk_pull_blocks(&bulk_pull).await?;
+        }
+        self.end_bulk_push().await?;
+
+        debug!("Bootstrap pushed {} blocks", pushed);
+        self.bootstrap
+            .as_mut()
+            .expect("bootstrap is None")
+            .progress
+            .blocks_pushed += pushed;
+        Ok(())
+    }
+
+    /// Send scheduled pulls, keeping at most `MAX_PULLS_IN_FLIGHT` waiting on the peer.
+    async fn send_bootstrap_pulls(&mut self) -> anyhow::Result<()> {
+        while self.bulk_pulls.len() < Bootstrap::MAX_PULLS_IN_FLIGHT {
+            let bulk_pull = match self
+                .bootstrap
+                .as_mut()
+                .expect("bootstrap is None")
+                .pending_pulls
+                .pop_front()
+            {
+                Some(b) => b,
+                None => break,
+            };
+            self.send_bulk_pull(bulk_pull).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use super::Controller;
+use crate::blocks::{BlockHash, Previous};
+use crate::node::state::{ConfirmationHeight, StateBatch};
+use crate::Public;
+use anyhow::{anyhow, Context};
+use tracing::debug;
+
+impl Controller {
+    /// Cement a block, the blocks before it in its account chain, and the send blocks they
+    /// received from in other accounts. Returns the newly cemented block hashes in the order they
+    /// were cemented.
+    pub async fn cement(&mut self, hash: &BlockHash) -> anyhow::Result<Vec<BlockHash>> {
+        let context = || format!("Cement {:?}", hash);
+        let mut cemented = vec![];
+
+        // Blocks to cement up to. A source that isn't cemented yet is pushed on top, so that it
+        // goes first.
+        let mut targets = vec![hash.to_owned()];
+        'targets: while let Some(target) = targets.last().cloned() {
+            let mut state = self.state.lock().await;
+            let account = state
+                .account_for_block_hash(&target)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing block {:?}", target))
+                .with_context(context)?;
+            let target_height = state
+                .block_height(&target)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing height of block {:?}", target))
+                .with_context(context)?;
+            let cemented_height = state
+                .confirmation_height(&account)
+                .await
+                .with_context(context)?
+                .map(|c| c.height)
+                .unwrap_or(0);
+            if target_height <= cemented_height {
+                targets.pop();
+                continue;
+            }
+
+            // Walk back to the cemented frontier, collecting the blocks that aren't cemented yet.
+            let mut uncemented = vec![];
+            let mut current = Some(target.to_owned());
+            for _ in cemented_height..target_height {
+                let hash = current
+                    .ok_or_else(|| anyhow!("Account chain of {:?} ends early", target))
+                    .with_context(context)?;
+                let block = state
+                    .get_block_by_hash(&hash)
+                    .await
+                    .with_context(context)?
+                    .ok_or_else(|| anyhow!("Missing block {:?} in account chain", hash))
+                    .with_context(context)?;
+                current = match block.previous() {
+                    Previous::Block(previous) if previous != &BlockHash::zero() => {
+                        Some(previous.to_owned())
+                    }
+                    _ => None,
+                };
+                uncemented.push((hash, block));
+            }
+            drop(state);
+
+            for (_, block) in &uncemented {
+                // Genesis didn't receive from a block.
+                if block.is_genesis(&self.network).with_context(context)? {
+                    continue;
+                }
+                if let Ok(source) = block.source() {
+                    if !self.is_cemented(source).await.with_context(context)? {
+                        targets.push(source.to_owned());
+                        continue 'targets;
+                    }
+                }
+            }
+
+            let mut batch = StateBatch::new();
+            batch
+                .set_confirmation_height(&account, &ConfirmationHeight::new(target_height, target));
+            self.state
+                .lock()
+                .await
+                .commit(batch)
+                .await
+                .with_context(context)?;
+            debug!("Cemented {:?} up to height {}", account, target_height);
+
+            cemented.extend(uncemented.into_iter().rev().map(|(hash, _)| hash));
+            targets.pop();
+        }
+
+        Ok(cemented)
+    }
+
+    /// Whether a block in the ledger has been cemented.
+    pub async fn is_cemented(&self, hash: &BlockHash) -> anyhow::Result<bool> {
+        let context = || format!("Is cemented {:?}", hash);
+        let mut state = self.state.lock().await;
+        let account = match state
+            .account_for_block_hash(hash)
+            .await
+            .with_context(context)?
+        {
+            Some(account) => account,
+            None => return Ok(false),
+        };
+        let height = match state.block_height(hash).await.with_context(context)? {
+            Some(height) => height,
+            None => return Ok(false),
+        };
+
+        // Cemented blocks are the ones from the cemented frontier back to the open block.
+        Ok(state
+            .confirmation_height(&account)
+            .await
+            .with_context(context)?
+            .map(|c| height <= c.height)
+            .unwrap_or(false))
+    }
+
+    /// How far an account chain has been cemented, if at all.
+    pub async fn confirmation_height(
+        &self,
+        account: &Public,
+    ) -> anyhow::Result<Option<ConfirmationHeight>> {
+        self.state
+            .lock()
+            .await
+            .confirmation_height(account)
+            .await
+            .with_context(|| format!("Confirmation height for {:?}", account))
+    }
+}
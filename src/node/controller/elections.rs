@@ -0,0 +1,249 @@
+use super::Controller;
+use crate::blocks::{Block, BlockHash};
+use crate::node::messages::confirm_ack::ConfirmAck;
+use crate::node::state::ForkEvent;
+use crate::Rai;
+use anyhow::{anyhow, Context};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+/// The default share of the online weight, in percent, that a block needs to win its election.
+pub const ONLINE_WEIGHT_QUORUM: u8 = 67;
+
+/// The default lower bound of the online weight used for quorum, in raw (60M Nano).
+pub const ONLINE_WEIGHT_MINIMUM: u128 = 60_000_000 * 10u128.pow(30);
+
+/// The default number of elections that can be active at once. Blocks that would start another
+/// election are ignored until there's room.
+pub const MAX_ACTIVE_ELECTIONS: usize = 5000;
+
+/// How long an election can go without being decided before it's stopped, dropping its votes.
+pub const ELECTION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+impl Controller {
+    /// Start an election for a block, or add it to the election of the blocks it forks with.
+    ///
+    /// If the block forks with a block already in the ledger, the ledger block becomes a
+    /// candidate too. No new election is started while `max_active_elections` are active. The
+    /// election may already have votes when the block joins it, so it's checked straight away.
+    /// Returns the winning block if that's enough to decide the election.
+    pub async fn start_election(&mut self, block: &Block) -> anyhow::Result<Option<Block>> {
+        let context = || format!("Start election for {:?}", block);
+        let hash = block.hash().with_context(context)?;
+        let root = block.root();
+        let signer = block.signer(&self.network).with_context(context)?;
+        block
+            .verify_signature(&signer)
+            .context("Incorrect signature")
+            .with_context(context)?;
+        if self
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(hash)
+            .await
+            .with_context(context)?
+            .is_some()
+        {
+            debug!("Block {:?} is already in the ledger", hash);
+            return Ok(None);
+        }
+        let ledger_fork = self.ledger_fork(block).await.with_context(context)?;
+        if let Some(ledger_block) = &ledger_fork {
+            let ledger_hash = ledger_block.hash().with_context(context)?;
+            if self.is_cemented(ledger_hash).await.with_context(context)? {
+                warn!(
+                    "Ignoring {:?}, it forks with cemented {:?}",
+                    hash, ledger_hash
+                );
+                return Ok(None);
+            }
+        }
+
+        let candidates = {
+            let mut state = self.state.lock().await;
+            if state
+                .election_candidates(&root)
+                .await
+                .with_context(context)?
+                .is_empty()
+                && state.active_elections().await.with_context(context)?.len()
+                    >= self.config.max_active_elections
+            {
+                debug!("Too many active elections to start one for {:?}", hash);
+                return Ok(None);
+            }
+            let now = SystemTime::now();
+            if let Some(ledger_block) = &ledger_fork {
+                state
+                    .add_election_candidate(&root, ledger_block, now)
+                    .await
+                    .with_context(context)?;
+            }
+            if !state
+                .add_election_candidate(&root, block, now)
+                .await
+                .with_context(context)?
+            {
+                return Ok(None);
+            }
+            state
+                .election_candidates(&root)
+                .await
+                .with_context(context)?
+        };
+
+        if candidates.len() > 1 {
+            let blocks = candidates
+                .iter()
+                .map(|block| block.hash().map(|hash| hash.to_owned()))
+                .collect::<anyhow::Result<Vec<BlockHash>>>()
+                .with_context(context)?;
+            self.add_fork_event(ForkEvent::Detected {
+                root: root.to_owned(),
+                blocks,
+            })
+            .await
+            .with_context(context)?;
+        }
+        self.update_election(&root).await.with_context(context)
+    }
+
+    /// Store a vote, then check the elections of the blocks it votes for.
+    pub async fn process_vote(&mut self, confirm_ack: &ConfirmAck) -> anyhow::Result<Vec<Block>> {
+        let context = || format!("Process vote {:?}", confirm_ack);
+        if !self.add_vote(confirm_ack).await.with_context(context)? {
+            return Ok(vec![]);
+        }
+
+        let mut roots: Vec<BlockHash> = vec![];
+        for hash in confirm_ack.hashes().with_context(context)? {
+            let root = self
+                .state
+                .lock()
+                .await
+                .election_root(&hash)
+                .await
+                .with_context(context)?;
+            if let Some(root) = root {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+        }
+
+        let mut elected = vec![];
+        for root in roots {
+            if let Some(block) = self.update_election(&root).await.with_context(context)? {
+                elected.push(block);
+            }
+        }
+        Ok(elected)
+    }
+
+    /// Tally the election of `root`. If a candidate has reached quorum the election is over, and
+    /// the winner replaces any block it forks with in the ledger, then is cemented.
+    ///
+    /// The election is only removed once the winner is cemented, so that it's decided again by the
+    /// next vote if anything fails on the way. If the block it forks with has been cemented in the
+    /// meantime, the winner is dropped.
+    pub async fn update_election(&mut self, root: &BlockHash) -> anyhow::Result<Option<Block>> {
+        let context = || format!("Update election {:?}", root);
+        let candidates = self
+            .state
+            .lock()
+            .await
+            .election_candidates(root)
+            .await
+            .with_context(context)?;
+        let hashes = candidates
+            .iter()
+            .map(|block| block.hash().map(|hash| hash.to_owned()))
+            .collect::<anyhow::Result<Vec<BlockHash>>>()
+            .with_context(context)?;
+
+        let tally = self.tally_votes(&hashes).await.with_context(context)?;
+        let (winner, weight) = match tally.first() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let quorum_delta = self.quorum_delta().await.with_context(context)?;
+        if weight == &Rai::zero() || weight < &quorum_delta {
+            return Ok(None);
+        }
+
+        let candidates_len = candidates.len();
+        let block = candidates
+            .into_iter()
+            .find(|block| block.hash().ok() == Some(winner))
+            .expect("winner is a candidate");
+        info!(
+            "Elected {:?} with {:?} of {:?}",
+            winner, weight, quorum_delta
+        );
+
+        let mut rolled_back = vec![];
+        if let Some(loser) = self.ledger_fork(&block).await.with_context(context)? {
+            let loser = loser.hash().with_context(context)?;
+            if self.is_cemented(loser).await.with_context(context)? {
+                warn!("Elected {:?} forks with cemented {:?}", winner, loser);
+                self.remove_election(root).await.with_context(context)?;
+                return Ok(None);
+            }
+            rolled_back = self.rollback(loser).await.with_context(context)?;
+        }
+        let in_ledger = self
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(winner)
+            .await
+            .with_context(context)?
+            .is_some();
+        if !in_ledger {
+            self.add_elected_block(&block).await.with_context(context)?;
+        }
+        self.cement(winner).await.with_context(context)?;
+        self.remove_election(root).await.with_context(context)?;
+
+        if candidates_len > 1 {
+            self.add_fork_event(ForkEvent::Resolved {
+                root: root.to_owned(),
+                winner: winner.to_owned(),
+                rolled_back,
+            })
+            .await
+            .with_context(context)?;
+        }
+        Ok(Some(block))
+    }
+
+    /// The weight a block needs to be voted for to win its election.
+    pub async fn quorum_delta(&self) -> anyhow::Result<Rai> {
+        let online_weight = self.online_weight().await?.to_u128();
+        let online_weight = online_weight.max(self.config.online_weight_minimum.to_u128());
+        let delta = (online_weight / 100)
+            .checked_mul(self.config.online_weight_quorum as u128)
+            .ok_or_else(|| anyhow!("Quorum delta overflow"))?;
+        Ok(Rai::new(delta))
+    }
+
+    async fn remove_election(&self, root: &BlockHash) -> anyhow::Result<()> {
+        self.state
+            .lock()
+            .await
+            .remove_election(root)
+            .await
+            .with_context(|| format!("Remove election {:?}", root))
+    }
+
+    /// The roots of the elections that haven't been decided yet.
+    pub async fn active_elections(&self) -> anyhow::Result<Vec<BlockHash>> {
+        self.state
+            .lock()
+            .await
+            .active_elections()
+            .await
+            .context("Active elections")
+    }
+}
@@ -0,0 +1,539 @@
+use super::Controller;
+use crate::blocks::{Block, BlockHash, Previous, Subtype};
+use crate::network::Network;
+use crate::node::controller::rep_weights::stage_rep_weights;
+use crate::node::state::{DynState, ForkEvent, Receivable, StateBatch};
+use crate::{Public, Rai};
+use anyhow::{anyhow, Context};
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+/// How many fork events are kept. Once full, the oldest are forgotten.
+pub const MAX_FORK_EVENTS: usize = 1000;
+
+impl Controller {
+    /// The block in the ledger with the same root as `block`, if it's a different block.
+    pub async fn ledger_fork(&self, block: &Block) -> anyhow::Result<Option<Block>> {
+        let context = || format!("Ledger fork of {:?}", block);
+        let root = block.root();
+        let hash = block.hash().with_context(context)?;
+        let state = self.state.lock().await;
+
+        let mut current = match state
+            .get_latest_block_hash_for_account(block.account())
+            .await
+            .with_context(context)?
+        {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+        // The block extends the account chain, which is by far the most common case.
+        if current == root {
+            return Ok(None);
+        }
+
+        // Walk back through the account chain for the block that was built on the same root.
+        loop {
+            let ledger_block = state
+                .get_block_by_hash(&current)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing block {:?} in account chain", current))
+                .with_context(context)?;
+            if ledger_block.root() == root {
+                if ledger_block.hash().with_context(context)? == hash {
+                    return Ok(None);
+                }
+                return Ok(Some(ledger_block));
+            }
+            current = match ledger_block.previous() {
+                Previous::Block(previous) if previous != &BlockHash::zero() => previous.to_owned(),
+                _ => return Ok(None),
+            };
+        }
+    }
+
+    /// Roll back a block, every block after it in its account chain, and any blocks in other
+    /// accounts that received from them. Returns the rolled back block hashes, newest first.
+    ///
+    /// Cemented blocks can't be rolled back. Blocks that received from an uncemented send can't be
+    /// cemented either, so nothing is rolled back if `hash` is cemented. Every change is committed
+    /// in one batch, so the ledger is left as it was if any block can't be rolled back.
+    pub async fn rollback(&mut self, hash: &BlockHash) -> anyhow::Result<Vec<BlockHash>> {
+        let context = || format!("Rollback {:?}", hash);
+        if self.is_cemented(hash).await.with_context(context)? {
+            return Err(anyhow!("Can not roll back a cemented block")).with_context(context);
+        }
+        let mut state = self.state.lock().await;
+        let mut staged = StagedRollback::default();
+
+        // Blocks to roll back up to. A receive of a send being rolled back is pushed on top, so
+        // that it goes first.
+        let mut targets = vec![hash.to_owned()];
+        while let Some(target) = targets.last().cloned() {
+            let account = if staged.removed.contains(&target) {
+                None
+            } else {
+                state
+                    .account_for_block_hash(&target)
+                    .await
+                    .with_context(context)?
+            };
+            let account = match account {
+                Some(account) => account,
+                None => {
+                    // Already rolled back.
+                    targets.pop();
+                    continue;
+                }
+            };
+            let latest_hash = staged
+                .head(&*state, &account)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing frontier of {:?}", account))
+                .with_context(context)?;
+            let latest = state
+                .get_block_by_hash(&latest_hash)
+                .await
+                .with_context(context)?
+                .ok_or_else(|| anyhow!("Missing frontier block {:?}", latest_hash))
+                .with_context(context)?;
+
+            if latest.subtype().with_context(context)? == Subtype::Send {
+                let destination = latest.destination().with_context(context)?;
+                if let Some(receive) = staged
+                    .find_receive(&*state, destination, &latest_hash)
+                    .await
+                    .with_context(context)?
+                {
+                    targets.push(receive);
+                    continue;
+                }
+            }
+
+            staged
+                .rollback_block(&mut *state, &self.network, &latest)
+                .await
+                .with_context(context)?;
+            if latest_hash == target {
+                targets.pop();
+            }
+        }
+
+        state.commit(staged.batch).await.with_context(context)?;
+        Ok(staged.rolled_back)
+    }
+
+    /// The latest `MAX_FORK_EVENTS` fork events seen by the node, oldest first.
+    pub async fn fork_events(&self) -> anyhow::Result<Vec<ForkEvent>> {
+        self.state
+            .lock()
+            .await
+            .fork_events()
+            .await
+            .context("Fork events")
+    }
+
+    pub(super) async fn add_fork_event(&self, event: ForkEvent) -> anyhow::Result<()> {
+        match &event {
+            ForkEvent::Detected { root, blocks } => {
+                warn!("Fork detected at {:?} between {:?}", root, blocks)
+            }
+            ForkEvent::Resolved {
+                root,
+                winner,
+                rolled_back,
+            } => info!(
+                "Fork at {:?} resolved to {:?}, rolled back {:?}",
+                root, winner, rolled_back
+            ),
+        }
+        let context = || format!("Add fork event {:?}", event);
+        let mut state = self.state.lock().await;
+        if state.fork_event_count().await.with_context(context)? >= MAX_FORK_EVENTS {
+            state
+                .remove_oldest_fork_event()
+                .await
+                .with_context(context)?;
+        }
+        state.add_fork_event(&event).await.with_context(context)
+    }
+}
+
+/// A rollback staged in a single batch, and the account heads as they will be once it's committed.
+/// Rolled back blocks stay in the ledger until then, so they're still read from it.
+#[derive(Default)]
+struct StagedRollback {
+    batch: StateBatch,
+
+    /// The head of every account that has rolled back blocks, or `None` if its open block was.
+    heads: HashMap<Public, Option<BlockHash>>,
+
+    removed: HashSet<BlockHash>,
+
+    /// Newest first.
+    rolled_back: Vec<BlockHash>,
+}
+
+impl StagedRollback {
+    async fn head(&self, state: &DynState, account: &Public) -> anyhow::Result<Option<BlockHash>> {
+        match self.heads.get(account) {
+            Some(head) => Ok(head.to_owned()),
+            None => state.get_latest_block_hash_for_account(account).await,
+        }
+    }
+
+    /// The block in `account` that received `send_hash`, if it has been received.
+    async fn find_receive(
+        &self,
+        state: &DynState,
+        account: &Public,
+        send_hash: &BlockHash,
+    ) -> anyhow::Result<Option<BlockHash>> {
+        let receivable = state
+            .receivables(account)
+            .await?
+            .into_iter()
+            .any(|(hash, _)| &hash == send_hash);
+        if receivable {
+            return Ok(None);
+        }
+
+        let mut current = self.head(state, account).await?;
+        while let Some(hash) = current {
+            let block = match state.get_block_by_hash(&hash).await? {
+                Some(block) => block,
+                None => break,
+            };
+            if block.source().ok() == Some(send_hash) {
+                return Ok(Some(hash));
+            }
+            current = match block.previous() {
+                Previous::Block(previous) if previous != &BlockHash::zero() => {
+                    Some(previous.to_owned())
+                }
+                _ => None,
+            };
+        }
+        Ok(None)
+    }
+
+    /// Stage undoing the ledger changes of the frontier block of an account. The frontier is only
+    /// cemented if it's the cemented frontier too.
+    async fn rollback_block(
+        &mut self,
+        state: &mut DynState,
+        network: &Network,
+        block: &Block,
+    ) -> anyhow::Result<()> {
+        let context = || format!("Rollback block {:?}", block);
+        let hash = block.hash().with_context(context)?;
+        if block.is_genesis(network)? {
+            return Err(anyhow!("Can not roll back the genesis block")).with_context(context);
+        }
+
+        let confirmation_height = state
+            .confirmation_height(block.account())
+            .await
+            .with_context(context)?;
+        if confirmation_height.map(|c| c.frontier).as_ref() == Some(hash) {
+            return Err(anyhow!("Can not roll back a cemented block")).with_context(context);
+        }
+
+        let previous = match block.previous() {
+            Previous::Block(previous) if previous != &BlockHash::zero() => state
+                .get_block_by_hash(previous)
+                .await
+                .with_context(context)?,
+            _ => None,
+        };
+
+        let batch = &mut self.batch;
+        batch.remove_block(block).with_context(context)?;
+        let head = match &previous {
+            Some(previous) => {
+                let previous_hash = previous.hash().with_context(context)?;
+                batch.set_latest_block_hash(block.account(), previous_hash);
+                Some(previous_hash.to_owned())
+            }
+            None => {
+                batch.remove_latest_block_hash(block.account());
+                None
+            }
+        };
+
+        match block.subtype().with_context(context)? {
+            Subtype::Send => {
+                batch.remove_receivable(block.destination().with_context(context)?, hash);
+            }
+            Subtype::Open | Subtype::Receive => {
+                // The funds can be received again.
+                let source = block.source().with_context(context)?;
+                let sender = state
+                    .account_for_block_hash(source)
+                    .await
+                    .with_context(context)?
+                    .ok_or_else(|| anyhow!("Missing source block {:?}", source))
+                    .with_context(context)?;
+                let previous_balance = previous
+                    .as_ref()
+                    .map(|b| b.balance().to_owned())
+                    .unwrap_or_else(Rai::zero);
+                let amount = block
+                    .balance()
+                    .checked_sub(&previous_balance)
+                    .ok_or_else(|| anyhow!("Receive block decreased the balance"))
+                    .with_context(context)?;
+                batch.add_receivable(block.account(), source, &Receivable::new(sender, amount));
+            }
+            Subtype::Change | Subtype::Epoch => {}
+        }
+
+        stage_rep_weights(&*state, batch, Some(block), previous.as_ref()).await?;
+        self.heads.insert(block.account().to_owned(), head);
+        self.removed.insert(hash.to_owned());
+        self.rolled_back.push(hash.to_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockType, Link};
+    use crate::node::controller::tests::{empty_lattice, open_account, signed_block, signed_vote};
+    use crate::node::state::ConfirmationHeight;
+    use crate::Private;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn resolve_fork_with_rollback() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let nano = 10u128.pow(30);
+        let (a, b, c, voter) = (
+            Private::random(),
+            Private::random(),
+            Private::random(),
+            Private::random(),
+        );
+        let public = |private: &Private| private.to_public().unwrap();
+
+        // Account A already has 100 raw, sent to it from somewhere and cemented.
+        let a_open = open_account(&controller, &a).await;
+        let mut batch = StateBatch::new();
+        batch
+            .set_rep_weight(&public(&voter), &Rai::new(100_000_000 * nano))
+            .set_confirmation_height(
+                &public(&a),
+                &ConfirmationHeight::new(1, a_open.hash().unwrap().to_owned()),
+            );
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        // A sends 40 to B, who receives it.
+        let a_previous = Previous::Block(a_open.hash().unwrap().to_owned());
+        let to_b = signed_block(
+            &a,
+            BlockType::Send,
+            a_previous.clone(),
+            60,
+            Link::DestinationAccount(public(&b)),
+        );
+        let to_b_hash = to_b.hash().unwrap().to_owned();
+        controller.add_elected_block(&to_b).await.unwrap();
+        let b_open = signed_block(
+            &b,
+            BlockType::Open,
+            Previous::Open,
+            40,
+            Link::Source(to_b_hash.clone()),
+        );
+        let b_open_hash = b_open.hash().unwrap().to_owned();
+        controller.add_elected_block(&b_open).await.unwrap();
+        assert_eq!(
+            controller.rep_weight(&public(&b)).await.unwrap(),
+            Rai::new(40u128)
+        );
+
+        // A double spends by sending 30 to C instead.
+        let to_c = signed_block(
+            &a,
+            BlockType::Send,
+            a_previous,
+            70,
+            Link::DestinationAccount(public(&c)),
+        );
+        let to_c_hash = to_c.hash().unwrap().to_owned();
+        let root = a_open.hash().unwrap().to_owned();
+        assert_eq!(
+            controller.ledger_fork(&to_c).await.unwrap(),
+            Some(to_b.clone())
+        );
+        assert_eq!(controller.start_election(&to_c).await.unwrap(), None);
+
+        let mut detected = vec![to_b_hash.clone(), to_c_hash.clone()];
+        detected.sort();
+        let mut events = controller.fork_events().await.unwrap();
+        if let Some(ForkEvent::Detected { blocks, .. }) = events.first_mut() {
+            blocks.sort();
+        }
+        assert_eq!(
+            events,
+            vec![ForkEvent::Detected {
+                root: root.clone(),
+                blocks: detected
+            }]
+        );
+
+        // The network votes for the send to C, so the send to B and B's open are rolled back.
+        let vote = signed_vote(&voter, 1, std::slice::from_ref(&to_c_hash));
+        assert_eq!(
+            controller.process_vote(&vote).await.unwrap(),
+            vec![to_c.clone()]
+        );
+        assert_eq!(
+            controller.fork_events().await.unwrap().last(),
+            Some(&ForkEvent::Resolved {
+                root: root.clone(),
+                winner: to_c_hash.clone(),
+                rolled_back: vec![b_open_hash.clone(), to_b_hash.clone()],
+            })
+        );
+        assert!(controller.is_cemented(&to_c_hash).await.unwrap());
+        assert_eq!(
+            controller.confirmation_height(&public(&a)).await.unwrap(),
+            Some(ConfirmationHeight::new(2, to_c_hash.clone()))
+        );
+
+        // The send to C is final, so another fork of it is ignored and it can't be rolled back.
+        let to_b_again = signed_block(
+            &a,
+            BlockType::Send,
+            Previous::Block(root.clone()),
+            50,
+            Link::DestinationAccount(public(&b)),
+        );
+        let fork_events = controller.fork_events().await.unwrap();
+        assert_eq!(controller.start_election(&to_b_again).await.unwrap(), None);
+        assert!(controller.active_elections().await.unwrap().is_empty());
+        assert_eq!(controller.fork_events().await.unwrap(), fork_events);
+        assert!(controller.rollback(&to_c_hash).await.is_err());
+
+        let state = controller.state.lock().await;
+        for hash in &[&to_b_hash, &b_open_hash] {
+            assert_eq!(state.get_block_by_hash(hash).await.unwrap(), None);
+        }
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(&public(&a))
+                .await
+                .unwrap(),
+            Some(to_c_hash.clone())
+        );
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(&public(&b))
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(state.receivables(&public(&b)).await.unwrap().is_empty());
+        assert_eq!(
+            state.receivables(&public(&c)).await.unwrap(),
+            vec![(to_c_hash, Receivable::new(public(&a), Rai::new(30u128)))]
+        );
+        assert_eq!(
+            state.rep_weight(&public(&a)).await.unwrap(),
+            Rai::new(70u128)
+        );
+        assert_eq!(state.rep_weight(&public(&b)).await.unwrap(), Rai::zero());
+    }
+
+    #[tokio::test]
+    async fn rollback_all_or_nothing() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let (a, b) = (Private::random(), Private::random());
+        let public = |private: &Private| private.to_public().unwrap();
+
+        let a_open = open_account(&controller, &a).await;
+
+        // A sends to B, then changes its representative. B receives and cements the send.
+        let to_b = signed_block(
+            &a,
+            BlockType::Send,
+            Previous::Block(a_open.hash().unwrap().to_owned()),
+            60,
+            Link::DestinationAccount(public(&b)),
+        );
+        let to_b_hash = to_b.hash().unwrap().to_owned();
+        controller.add_elected_block(&to_b).await.unwrap();
+        let change = signed_block(
+            &a,
+            BlockType::Change,
+            Previous::Block(to_b_hash.clone()),
+            60,
+            Link::Nothing,
+        );
+        controller.add_elected_block(&change).await.unwrap();
+        let b_open = signed_block(
+            &b,
+            BlockType::Open,
+            Previous::Open,
+            40,
+            Link::Source(to_b_hash.clone()),
+        );
+        controller.add_elected_block(&b_open).await.unwrap();
+        let mut batch = StateBatch::new();
+        batch.set_confirmation_height(
+            &public(&b),
+            &ConfirmationHeight::new(1, b_open.hash().unwrap().to_owned()),
+        );
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        // The change block could be rolled back, but B's open can't, so nothing is.
+        assert!(controller.rollback(&to_b_hash).await.is_err());
+        let state = controller.state.lock().await;
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(&public(&a))
+                .await
+                .unwrap()
+                .as_ref(),
+            Some(change.hash().unwrap())
+        );
+        assert_eq!(
+            state.get_block_by_hash(&to_b_hash).await.unwrap(),
+            Some(to_b)
+        );
+        assert_eq!(
+            state.rep_weight(&public(&a)).await.unwrap(),
+            Rai::new(60u128)
+        );
+        assert_eq!(
+            state.rep_weight(&public(&b)).await.unwrap(),
+            Rai::new(40u128)
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_latest_fork_events() {
+        let controller = empty_lattice(Network::Live).await;
+        let event = |n: usize| {
+            let mut root = [0u8; BlockHash::LEN];
+            root[..8].copy_from_slice(&(n as u64).to_be_bytes());
+            ForkEvent::Detected {
+                root: BlockHash::try_from(root.as_ref()).unwrap(),
+                blocks: vec![],
+            }
+        };
+        for n in 0..=MAX_FORK_EVENTS {
+            controller.add_fork_event(event(n)).await.unwrap();
+        }
+        let events = controller.fork_events().await.unwrap();
+        assert_eq!(events.len(), MAX_FORK_EVENTS);
+        assert_eq!(events.first(), Some(&event(1)));
+        assert_eq!(events.last(), Some(&event(MAX_FORK_EVENTS)));
+    }
+}
@@ -4,13 +4,30 @@ use anyhow::Context;
 use tracing::info;
 
 impl Controller {
+    /// Add and cement the genesis block, unless the ledger already has it.
     pub async fn ensure_genesis(&mut self) -> anyhow::Result<()> {
         info!("Ensuring genesis");
-        let mut block = self.network.genesis_block();
+        let block = self.network.genesis_block();
 
-        self.add_elected_block(&mut block)
+        // The ledger might have been loaded from disk.
+        if self
+            .state
+            .lock()
             .await
-            .context("Adding genesis block")?;
+            .get_block_by_hash(block.hash()?)
+            .await
+            .context("Checking for genesis block")?
+            .is_none()
+        {
+            self.add_elected_block(&block)
+                .await
+                .context("Adding genesis block")?;
+
+            // Genesis is confirmed by definition, there's no election to cement it.
+            self.cement(block.hash()?)
+                .await
+                .context("Cementing genesis block")?;
+        }
 
         Ok(())
     }
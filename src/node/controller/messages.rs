@@ -1,8 +1,14 @@
-use super::Controller;
+use super::{BlockOrigin, Controller};
+use crate::blocks::{Block, BlockHash, BlockHolder, BlockType};
 use crate::node::cookie::Cookie;
 use crate::node::header::{Extensions, Header, MessageType};
+use crate::node::messages::bulk_pull::BulkPull;
+use crate::node::messages::bulk_pull_account::{
+    BulkPullAccount, BulkPullAccountEntry, BulkPullAccountFrontier, BulkPullAccountResponse,
+};
+use crate::node::messages::bulk_push::BulkPush;
 use crate::node::messages::confirm_ack::ConfirmAck;
-use crate::node::messages::confirm_req::ConfirmReq;
+use crate::node::messages::confirm_req::{ConfirmReq, RootHashPair};
 use crate::node::messages::frontier_req::FrontierReq;
 use crate::node::messages::frontier_resp::FrontierResp;
 use crate::node::messages::handshake::{Handshake, HandshakeQuery, HandshakeResponse};
@@ -10,10 +16,27 @@ use crate::node::messages::keepalive::Keepalive;
 use crate::node::messages::publish::Publish;
 use crate::node::messages::telemetry_ack::TelemetryAck;
 use crate::node::messages::telemetry_req::TelemetryReq;
-use crate::{Public, Seed, Signature};
-use anyhow::Context;
+use crate::node::node_id::{format_node_id, load_node_key};
+use crate::node::telemetry::TELEMETRY_MAX_AGE;
+use crate::{Public, Rai, Signature};
+use anyhow::{anyhow, Context};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{debug, instrument, trace, warn};
 
+/// How many frontiers are read from the ledger at a time when responding to a frontier req.
+pub(super) const FRONTIER_BATCH_SIZE: usize = 1_000;
+
+/// How many blocks are read from the ledger at a time when responding to a bulk pull.
+pub(super) const BULK_PULL_BATCH_SIZE: usize = 1_000;
+
+/// How long a peer has to answer the cookie in our handshake query, like the reference node.
+pub const COOKIE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most peers that are kept. Peers from keepalives are ignored once there are this many.
+pub const MAX_PEERS: usize = 1_000;
+
 impl Controller {
     #[instrument(skip(self))]
     pub async fn send_handshake(&mut self) -> anyhow::Result<()> {
@@ -21,12 +44,11 @@ impl Controller {
         self.send_header(MessageType::Handshake, *Extensions::new().query())
             .await?;
 
-        // TODO: Track our own cookie?
         let cookie = Cookie::random();
         self.state
             .lock()
             .await
-            .set_cookie(self.peer_addr, cookie.clone())
+            .set_cookie(self.peer_addr, cookie.clone(), SystemTime::now())
             .await?;
         let handshake_query = HandshakeQuery::new(cookie);
         self.send(&handshake_query).await?;
@@ -44,15 +66,13 @@ impl Controller {
             Yes(Public, Signature),
         }
         let mut should_respond = ShouldRespond::No;
+        let mut proven = false;
 
         if header.ext().is_query() {
             // This would probably be a programming error if it panicked.
             let query = handshake.query.expect("query is None but is_query is True");
 
-            // XXX: Hacky code here just to see if it works!
-            // TODO: Move into state
-            let seed = Seed::random();
-            let private = seed.derive(0);
+            let private = self.node_key().await?;
             let public = private.to_public()?;
             let signature = private.sign(query.cookie().as_bytes())?;
             public
@@ -67,29 +87,11 @@ impl Controller {
             let response = handshake
                 .response
                 .expect("response is None but is_response is True");
-            let public = response.public;
-            let signature = response.signature;
-
-            // TODO: Move to controller
-            let cookie = &self
-                .state
-                .lock()
-                .await
-                .cookie_for_socket_addr(&self.peer_addr)
-                .await?;
-            if cookie.is_none() {
-                warn!(
-                    "Peer {:?} has no cookie. Can't verify handshake.",
-                    self.peer_addr
-                );
-                return Ok(());
-            }
-            let cookie = cookie.as_ref().unwrap();
 
             if self.validate_handshakes {
-                public
-                    .verify(&cookie.as_bytes(), &signature)
-                    .context("Invalid signature in handshake response")?;
+                self.verify_node_id(response.public, &response.signature)
+                    .await?;
+                proven = true;
             }
         }
 
@@ -102,86 +104,529 @@ impl Controller {
             self.send(&response).await?;
         }
 
+        if proven {
+            // Anything flooded to this channel is now queued after the handshake.
+            self.channels
+                .add(self.peer_addr, self.outgoing.clone())
+                .await;
+            self.send_telemetry_req().await?;
+        }
+
         Ok(())
     }
 
+    /// Check a handshake response against the cookie we sent the peer, then record its node ID.
+    ///
+    /// A cookie can only be answered once, within `COOKIE_TIMEOUT`. This node, or a node that is
+    /// already connected through another channel, is refused.
+    async fn verify_node_id(
+        &mut self,
+        node_id: Public,
+        signature: &Signature,
+    ) -> anyhow::Result<()> {
+        let peer_addr = self.peer_addr;
+        let context = || format!("Verifying node ID {:?} of {}", node_id, peer_addr);
+        let mut state = self.state.lock().await;
+
+        let (cookie, created) = state
+            .cookie_for_socket_addr(&peer_addr)
+            .await
+            .with_context(context)?
+            .ok_or_else(|| anyhow!("We haven't sent the peer a cookie"))
+            .with_context(context)?;
+        state
+            .remove_cookie(&peer_addr)
+            .await
+            .with_context(context)?;
+        let age = SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default();
+        if age > COOKIE_TIMEOUT {
+            return Err(anyhow!("Cookie expired, it was sent {:?} ago", age)).with_context(context);
+        }
+        node_id
+            .verify(cookie.as_bytes(), signature)
+            .context("Invalid signature in handshake response")
+            .with_context(context)?;
+
+        if load_node_key(&mut *state).await?.to_public()? == node_id {
+            return Err(anyhow!("Connected to ourselves")).with_context(context);
+        }
+        for other in state
+            .socket_addrs_for_node_id(&node_id)
+            .await
+            .with_context(context)?
+        {
+            if other != peer_addr && self.channels.contains(&other).await {
+                return Err(anyhow!("Already connected to the node through {}", other))
+                    .with_context(context);
+            }
+        }
+        state
+            .set_node_id(peer_addr, node_id.to_owned(), SystemTime::now())
+            .await
+            .with_context(context)?;
+        drop(state);
+
+        debug!(
+            "Peer {} has node ID {}",
+            peer_addr,
+            format_node_id(&node_id)
+        );
+        self.peer_node_id = Some(node_id);
+        Ok(())
+    }
+
+    /// Remember the peers in a keepalive, so the node can connect to them.
     pub async fn handle_keepalive(
         &mut self,
         _header: &Header,
         keepalive: Keepalive,
     ) -> anyhow::Result<()> {
-        // dbg!(keepalive);
         debug!("{:?}", keepalive);
-        Ok(())
+        let mut peers: Vec<SocketAddr> = keepalive
+            .peers()
+            .iter()
+            .map(|peer| peer.socket_addr())
+            .filter(|addr| !addr.ip().is_unspecified() && addr.port() != 0)
+            .collect();
+        let mut state = self.state.lock().await;
+        let room = MAX_PEERS.saturating_sub(state.peer_count().await.context("Peer count")?);
+        peers.truncate(room);
+        state
+            .add_peers(peers)
+            .await
+            .context("Adding peers from keepalive")
     }
 
+    /// Answer with the telemetry of this node.
     pub async fn handle_telemetry_req(
         &mut self,
         _header: &Header,
         _telemetry_req: TelemetryReq,
     ) -> anyhow::Result<()> {
-        // dbg!(telemetry_req);
+        if self.observe_only {
+            return Ok(());
+        }
+
+        let telemetry = self.telemetry().await?;
+        let ext = telemetry.extensions(self.header.version_using());
+        self.send_header(MessageType::TelemetryAck, ext).await?;
+        self.send(&telemetry).await?;
         Ok(())
     }
 
+    /// Store telemetry that is signed by the peer's node ID, is for our network and is recent.
     pub async fn handle_telemetry_ack(
         &mut self,
         _header: &Header,
-        _telemetry_ack: TelemetryAck,
+        telemetry_ack: TelemetryAck,
     ) -> anyhow::Result<()> {
-        // dbg!(telemetry_ack);
-        Ok(())
+        if let Err(err) = telemetry_ack.verify_signature() {
+            warn!("Invalid telemetry from {}: {:?}", self.peer_addr, err);
+            return Ok(());
+        }
+        if telemetry_ack.genesis_block != self.network.genesis_hash() {
+            warn!(
+                "Telemetry from {} has the genesis block {:?} of another network",
+                self.peer_addr, telemetry_ack.genesis_block
+            );
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        let created = UNIX_EPOCH + Duration::from_millis(telemetry_ack.timestamp);
+        if created < now - TELEMETRY_MAX_AGE || created > now + TELEMETRY_MAX_AGE {
+            warn!(
+                "Telemetry from {} was created at {:?}, which isn't recent",
+                self.peer_addr, created
+            );
+            return Ok(());
+        }
+        if let Some(node_id) = &self.peer_node_id {
+            if node_id != &telemetry_ack.node_id {
+                warn!(
+                    "Telemetry from {} is signed by {:?} instead of its node ID {:?}",
+                    self.peer_addr, telemetry_ack.node_id, node_id
+                );
+                return Ok(());
+            }
+        }
+        debug!("Telemetry from {}: {:?}", self.peer_addr, telemetry_ack);
+        self.state
+            .lock()
+            .await
+            .add_telemetry(&telemetry_ack, now)
+            .await
+            .context("Adding telemetry")
     }
 
     pub async fn handle_publish(
         &mut self,
         _header: &Header,
-        _publish: Publish,
+        publish: Publish,
     ) -> anyhow::Result<()> {
-        // dbg!(publish);
-
-        // self.state.lock().await.add_block(&publish.0).await?;
-        // todo!();
-
+        // A bad block is the peer's problem, so don't drop the connection over it.
+        if let Err(err) = self.process_block(&publish.0, BlockOrigin::Live).await {
+            warn!("Ignoring published block: {:?}", err);
+        }
         Ok(())
     }
 
     pub async fn handle_confirm_req(
         &mut self,
         _header: &Header,
-        _confirm_req: ConfirmReq,
+        confirm_req: ConfirmReq,
     ) -> anyhow::Result<()> {
-        // dbg!(confirm_req);
-        Ok(())
+        let pairs = match confirm_req {
+            ConfirmReq::ConfirmReqByHash(pairs) => pairs,
+            ConfirmReq::BlockSelector(block) => {
+                if let Err(err) = self.process_block(&block, BlockOrigin::Live).await {
+                    warn!("Ignoring block in confirm req: {:?}", err);
+                }
+                vec![RootHashPair::new(block.hash()?, block.root())]
+            }
+        };
+        self.vote_for_requested(&pairs).await
     }
 
     pub async fn handle_confirm_ack(
         &mut self,
         _header: &Header,
-        _confirm_ack: ConfirmAck,
+        confirm_ack: ConfirmAck,
     ) -> anyhow::Result<()> {
-        // dbg!(confirm_ack);
+        // Like blocks, a bad vote is the peer's problem.
+        let elected = match self.process_vote(&confirm_ack).await {
+            Ok(elected) => elected,
+            Err(err) => {
+                warn!("Ignoring vote: {:?}", err);
+                return Ok(());
+            }
+        };
+        for block in elected {
+            self.process_unchecked(block.hash()?).await?;
+        }
         Ok(())
     }
 
     pub async fn handle_frontier_req(
         &mut self,
         _header: &Header,
-        _frontier_req: FrontierReq,
+        frontier_req: FrontierReq,
     ) -> anyhow::Result<()> {
-        // The rest of this connection will be a bunch of frontiers without any headers.
-        self.frontier_stream = true;
+        if self.observe_only {
+            // The rest of this connection will be a bunch of frontiers without any headers.
+            self.frontier_stream = true;
+            return Ok(());
+        }
+
+        let modified_since = if frontier_req.age == u32::MAX {
+            None
+        } else {
+            SystemTime::now().checked_sub(Duration::from_secs(frontier_req.age as u64))
+        };
+
+        let mut remaining = frontier_req.count as usize;
+        let mut start = Some(frontier_req.start);
+        while let Some(batch_start) = start {
+            let batch_size = remaining.min(FRONTIER_BATCH_SIZE);
+            if batch_size == 0 {
+                break;
+            }
+
+            let frontiers = self
+                .state
+                .lock()
+                .await
+                .frontiers(&batch_start, modified_since, batch_size)
+                .await
+                .context("Frontiers for frontier req")?;
+            start = match frontiers.last() {
+                Some((account, _)) if frontiers.len() == batch_size => next_account(account),
+                _ => None,
+            };
+            remaining -= frontiers.len();
+
+            for (account, frontier_hash) in frontiers {
+                self.send(&FrontierResp::new(account, frontier_hash))
+                    .await?;
+            }
+        }
+        self.send(&FrontierResp::end()).await?;
 
         Ok(())
     }
 
     pub async fn handle_frontier_resp(
         &mut self,
-        _frontier_resp: FrontierResp,
+        frontier_resp: FrontierResp,
     ) -> anyhow::Result<()> {
-        // dbg!(frontier_resp);
-        // dbg!("----------------------------------------------------------------------");
+        if frontier_resp.is_end() {
+            self.frontier_stream = false;
+            if self.bootstrap.is_some() {
+                self.bootstrap_frontiers_end().await?;
+            }
+            return Ok(());
+        }
+
+        if self.bootstrap.is_some() {
+            self.bootstrap_frontier(&frontier_resp).await?;
+        }
 
         Ok(())
     }
+
+    /// Request blocks from the peer. The block stream is expected to be received after any
+    /// previously requested bulk pulls.
+    pub async fn send_bulk_pull(&mut self, bulk_pull: BulkPull) -> anyhow::Result<()> {
+        let mut ext = Extensions::new();
+        if bulk_pull.count.is_some() {
+            ext.count_present();
+        }
+        self.send_header(MessageType::BulkPull, ext).await?;
+        self.send(&bulk_pull).await?;
+        self.bulk_pulls.push_back(bulk_pull);
+        Ok(())
+    }
+
+    pub async fn handle_bulk_pull(
+        &mut self,
+        _header: &Header,
+        bulk_pull: BulkPull,
+    ) -> anyhow::Result<()> {
+        if self.observe_only {
+            // The block stream will follow from the other side.
+            self.bulk_pulls.push_back(bulk_pull);
+            return Ok(());
+        }
+
+        let sent = self.send_bulk_pull_blocks(&bulk_pull).await?;
+        debug!("Sent {} blocks for {:?}", sent, bulk_pull);
+        self.send(&BlockType::NotABlock).await?;
+
+        Ok(())
+    }
+
+    /// Send the blocks that answer `bulk_pull`, reading them `BULK_PULL_BATCH_SIZE` at a time so
+    /// the state isn't locked for a whole account chain. Returns how many blocks were sent.
+    pub(super) async fn send_bulk_pull_blocks(
+        &mut self,
+        bulk_pull: &BulkPull,
+    ) -> anyhow::Result<usize> {
+        let mut remaining = match bulk_pull.count {
+            Some(count) if count != 0 => count as usize,
+            _ => usize::MAX,
+        };
+        let mut sent = 0;
+        let mut start = self.bulk_pull_start(bulk_pull).await?;
+        while let Some(batch_start) = start {
+            let batch_size = remaining.min(BULK_PULL_BATCH_SIZE);
+            if batch_size == 0 {
+                break;
+            }
+
+            let (blocks, next) = self
+                .bulk_pull_blocks(bulk_pull, batch_start, batch_size)
+                .await?;
+            start = next;
+            remaining -= blocks.len();
+            sent += blocks.len();
+
+            for block in blocks {
+                self.send_stream_block(&block).await?;
+            }
+        }
+        Ok(sent)
+    }
+
+    pub async fn handle_bulk_pull_block(&mut self, block: BlockHolder) -> anyhow::Result<()> {
+        if let Some(bootstrap) = self.bootstrap.as_mut() {
+            bootstrap.progress.blocks_pulled += 1;
+        }
+        if let Err(err) = self.process_block(&block, BlockOrigin::Bootstrap).await {
+            warn!("Ignoring pulled block: {:?}", err);
+        }
+        self.pulled_blocks += 1;
+        Ok(())
+    }
+
+    pub async fn handle_bulk_pull_end(&mut self, bulk_pull: BulkPull) -> anyhow::Result<()> {
+        debug!(
+            "Bulk pull finished for {:?}. {} pulled blocks.",
+            bulk_pull, self.pulled_blocks
+        );
+
+        if self.bootstrap.is_some() {
+            self.bootstrap_pull_end(&bulk_pull).await?;
+        }
+        Ok(())
+    }
+
+    /// Request the frontier and receivable entries of an account from the peer.
+    pub async fn send_bulk_pull_account(
+        &mut self,
+        bulk_pull_account: BulkPullAccount,
+    ) -> anyhow::Result<()> {
+        self.send_header(MessageType::BulkPullAccount, Extensions::new())
+            .await?;
+        self.send(&bulk_pull_account).await?;
+        self.bulk_pull_accounts.push_back(bulk_pull_account);
+        Ok(())
+    }
+
+    pub async fn handle_bulk_pull_account(
+        &mut self,
+        _header: &Header,
+        bulk_pull_account: BulkPullAccount,
+    ) -> anyhow::Result<()> {
+        if self.observe_only {
+            // The response will follow from the other side.
+            self.bulk_pull_accounts.push_back(bulk_pull_account);
+            return Ok(());
+        }
+
+        let context = || format!("Bulk pull account {:?}", bulk_pull_account);
+        let account = &bulk_pull_account.account;
+        let (frontier, receivables) = {
+            let state = self.state.lock().await;
+            let frontier = match state
+                .get_latest_block_hash_for_account(account)
+                .await
+                .with_context(context)?
+            {
+                Some(hash) => {
+                    let balance = state
+                        .get_block_by_hash(&hash)
+                        .await
+                        .with_context(context)?
+                        .map(|block| block.balance().to_owned())
+                        .unwrap_or_else(Rai::zero);
+                    BulkPullAccountFrontier::new(hash, balance)
+                }
+                None => BulkPullAccountFrontier::new(BlockHash::zero(), Rai::zero()),
+            };
+            let receivables = state.receivables(account).await.with_context(context)?;
+            (frontier, receivables)
+        };
+
+        self.send(&frontier).await?;
+        let flags = bulk_pull_account.flags;
+        for (hash, receivable) in receivables {
+            if receivable.amount < bulk_pull_account.minimum_amount {
+                continue;
+            }
+            let entry = BulkPullAccountEntry::new(hash, receivable.amount, receivable.source);
+            self.send_bytes(entry.serialize(flags)).await?;
+        }
+        self.send_bytes(BulkPullAccountEntry::end().serialize(flags))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn handle_bulk_pull_account_frontier(
+        &mut self,
+        frontier: BulkPullAccountFrontier,
+    ) -> anyhow::Result<()> {
+        self.bulk_pull_account_response = Some(BulkPullAccountResponse::new(frontier));
+        Ok(())
+    }
+
+    pub async fn handle_bulk_pull_account_entry(
+        &mut self,
+        entry: BulkPullAccountEntry,
+    ) -> anyhow::Result<()> {
+        let response = self
+            .bulk_pull_account_response
+            .as_mut()
+            .expect("bulk pull account response is None");
+        if !entry.is_end() {
+            response.entries.push(entry);
+            return Ok(());
+        }
+
+        let bulk_pull_account = self
+            .bulk_pull_accounts
+            .pop_front()
+            .expect("bulk pull account is empty");
+        let response = self
+            .bulk_pull_account_response
+            .take()
+            .expect("bulk pull account response is None");
+        debug!(
+            "Bulk pull account finished for {:?}. {} receivable entries.",
+            bulk_pull_account,
+            response.entries.len()
+        );
+        self.pulled_accounts.push((bulk_pull_account, response));
+        Ok(())
+    }
+
+    /// Push blocks to the peer, e.g. when it is missing blocks that we have.
+    pub async fn send_bulk_push(&mut self, blocks: &[Block]) -> anyhow::Result<()> {
+        self.start_bulk_push().await?;
+        for block in blocks {
+            self.send_stream_block(block).await?;
+        }
+        self.end_bulk_push().await
+    }
+
+    /// Start a bulk push, which is followed by any number of `send_stream_block` and then
+    /// `end_bulk_push`.
+    pub(super) async fn start_bulk_push(&mut self) -> anyhow::Result<()> {
+        self.send_header(MessageType::BulkPush, Extensions::new())
+            .await?;
+        self.send(&BulkPush).await
+    }
+
+    pub(super) async fn end_bulk_push(&mut self) -> anyhow::Result<()> {
+        self.send(&BlockType::NotABlock).await
+    }
+
+    /// Send a block of a headerless block stream, prefixed by its block type.
+    pub(super) async fn send_stream_block(&mut self, block: &Block) -> anyhow::Result<()> {
+        let holder = block.to_holder()?;
+        self.send(&holder.block_type()).await?;
+        self.send(&holder).await
+    }
+
+    pub async fn handle_bulk_push(
+        &mut self,
+        _header: &Header,
+        _bulk_push: BulkPush,
+    ) -> anyhow::Result<()> {
+        // The rest of the request is a stream of blocks without any headers.
+        self.bulk_push_stream = true;
+        Ok(())
+    }
+
+    pub async fn handle_bulk_push_block(&mut self, block: BlockHolder) -> anyhow::Result<()> {
+        // Any peer can push blocks, even before a handshake, so they have to win an election
+        // like published blocks do.
+        if let Err(err) = self.process_block(&block, BlockOrigin::Live).await {
+            warn!("Ignoring pushed block: {:?}", err);
+        }
+        self.pushed_blocks += 1;
+        Ok(())
+    }
+
+    pub async fn handle_bulk_push_end(&mut self) -> anyhow::Result<()> {
+        self.bulk_push_stream = false;
+        debug!("Bulk push finished. {} pushed blocks.", self.pushed_blocks);
+        Ok(())
+    }
+}
+
+/// The account directly after this one, treating it as a big endian integer. `None` if this is the
+/// last possible account.
+pub(super) fn next_account(account: &Public) -> Option<Public> {
+    let mut bytes = <[u8; Public::LEN]>::try_from(account.as_bytes()).expect("public length");
+    for byte in bytes.iter_mut().rev() {
+        if *byte == u8::MAX {
+            *byte = 0;
+        } else {
+            *byte += 1;
+            return Some(Public::try_from(bytes.as_ref()).expect("public length"));
+        }
+    }
+    None
 }
@@ -1,20 +1,46 @@
+mod block_processor;
 mod blocks;
+mod bootstrap;
+mod cementing;
+mod elections;
+mod forks;
 mod genesis;
 mod messages;
+mod publish;
+mod rep_weights;
+mod representative;
+mod telemetry;
+mod votes;
 
-use crate::blocks::Block;
+use crate::blocks::{BlockHolder, BlockType};
 use crate::network::Network;
-use crate::node::header::{Extensions, Header, MessageType};
+use crate::node::channel::Channels;
+use crate::node::header::{Extensions, Header, MessageType, Version, VersionRange};
+use crate::node::messages::bulk_pull::BulkPull;
+use crate::node::messages::bulk_pull_account::{
+    BulkPullAccount, BulkPullAccountEntry, BulkPullAccountFrontier, BulkPullAccountResponse,
+};
 use crate::node::messages::frontier_resp::FrontierResp;
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::node::node_id::load_node_key;
 use crate::node::state::ArcState;
 use crate::node::wire::Wire;
-use crate::{to_hex, Public, Rai};
+use crate::{to_hex, Private, Public, Rai};
 use anyhow::{anyhow, Context};
+pub use block_processor::BlockOrigin;
+use bootstrap::Bootstrap;
+pub use elections::ELECTION_TIMEOUT;
+use elections::{MAX_ACTIVE_ELECTIONS, ONLINE_WEIGHT_MINIMUM, ONLINE_WEIGHT_QUORUM};
+pub use messages::COOKIE_TIMEOUT;
+pub use rep_weights::ONLINE_WEIGHT_PERIOD;
+pub use representative::Representative;
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::net::SocketAddr;
+pub use telemetry::start_uptime;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{debug, instrument, trace};
+use tracing::{debug, instrument, trace, warn};
 
 /// A message sent between channels that contains a peer's network data.
 #[derive(Debug)]
@@ -42,20 +68,92 @@ impl Packet {
     }
 }
 
+/// Settings shared by every controller in a node, which come from the node options.
+#[derive(Debug, Clone)]
+pub struct ControllerConfig {
+    /// The representative this node votes as, when it's running as one.
+    pub representative: Option<Representative>,
+
+    /// The share of the online weight, in percent, a block needs to be voted for to win its
+    /// election.
+    pub online_weight_quorum: u8,
+
+    /// The online weight used for quorum is at least this, so that a few representatives can't
+    /// elect blocks by themselves while the rest of the network isn't seen voting.
+    pub online_weight_minimum: Rai,
+
+    /// The protocol versions this node supports.
+    pub versions: VersionRange,
+
+    /// How many elections can be active at once.
+    pub max_active_elections: usize,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            representative: None,
+            online_weight_quorum: ONLINE_WEIGHT_QUORUM,
+            online_weight_minimum: Rai::new(ONLINE_WEIGHT_MINIMUM),
+            versions: VersionRange::default(),
+            max_active_elections: MAX_ACTIVE_ELECTIONS,
+        }
+    }
+}
+
 /// The controller handles the logic with handling and emitting messages, as well as time based
 /// actions, peer management, etc.
 pub struct Controller {
     /// Disable when used for pcap dump, where might have our own different cookie.
     pub validate_handshakes: bool,
 
+    /// Enable when used for pcap dump, where both sides of the conversation are fed into the
+    /// controller. Requests aren't responded to, instead we expect the response from the peer.
+    pub observe_only: bool,
+
+    pub config: ControllerConfig,
+
     network: Network,
     state: ArcState,
 
     peer_addr: SocketAddr,
 
+    /// The newest protocol version both sides support, once the peer has sent a header.
+    peer_version: Option<Version>,
+
+    /// The node ID the peer has proven in its handshake.
+    peer_node_id: Option<Public>,
+
+    /// Every channel in the node, which this one joins once the peer has proven its node ID.
+    channels: Channels,
+
     /// Are we doing a frontier req stream? (Bootstrap?)
     frontier_stream: bool,
 
+    /// Bulk pulls we've requested and are waiting on a block stream for, in order.
+    bulk_pulls: VecDeque<BulkPull>,
+
+    /// How many blocks have been received from bulk pulls. The blocks themselves go to the block
+    /// processor.
+    pulled_blocks: usize,
+
+    bulk_pull_accounts: VecDeque<BulkPullAccount>,
+
+    /// The response to the first of `bulk_pull_accounts`, while it is being received.
+    bulk_pull_account_response: Option<BulkPullAccountResponse>,
+
+    pulled_accounts: Vec<(BulkPullAccount, BulkPullAccountResponse)>,
+
+    /// The peer is sending a bulk push stream.
+    bulk_push_stream: bool,
+
+    /// How many blocks the peer has pushed to us. The blocks themselves go to the block
+    /// processor.
+    pushed_blocks: usize,
+
+    /// Set when this connection is used to bootstrap the ledger from the peer.
+    bootstrap: Option<Bootstrap>,
+
     /// Internal buffer for incoming data.
     incoming_buffer: Vec<u8>,
 
@@ -84,10 +182,23 @@ impl Controller {
 
         let s = Self {
             validate_handshakes: true,
+            observe_only: false,
+            config: ControllerConfig::default(),
             network,
             state,
             peer_addr,
+            peer_version: None,
+            peer_node_id: None,
+            channels: Channels::new(),
             frontier_stream: false,
+            bulk_pulls: VecDeque::new(),
+            pulled_blocks: 0,
+            bulk_pull_accounts: VecDeque::new(),
+            bulk_pull_account_response: None,
+            pulled_accounts: vec![],
+            bulk_push_stream: false,
+            pushed_blocks: 0,
+            bootstrap: None,
             incoming_buffer: Vec::with_capacity(10_000),
             incoming: incoming_rx,
             outgoing: outgoing_tx,
@@ -101,6 +212,47 @@ impl Controller {
     /// Run will loop forever and is expected to be spawned and will quit when the incoming channel
     /// is closed.
     pub async fn run(mut self) -> anyhow::Result<()> {
+        if self.bootstrap.is_some() {
+            // Bootstrap connections don't handshake, they go straight into requests.
+            self.start_bootstrap().await?;
+        } else {
+            trace!("Initial handshake");
+            self.send_handshake().await?;
+        }
+
+        let result = self.recv_messages().await;
+        self.channels.remove(&self.peer_addr).await;
+        if self.peer_node_id.is_some() {
+            if let Err(err) = self
+                .state
+                .lock()
+                .await
+                .remove_node_id(&self.peer_addr)
+                .await
+            {
+                warn!("Could not remove node ID of {}: {:?}", self.peer_addr, err);
+            }
+        }
+        result
+    }
+
+    async fn recv_messages(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.recv_message().await?;
+
+            if self.is_bootstrap_complete() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Share the node's channels with this controller, so it can flood messages to other peers.
+    pub fn set_channels(&mut self, channels: Channels) {
+        self.channels = channels;
+    }
+
+    /// Receive and handle the next message, or the next item of a stream we're expecting.
+    pub async fn recv_message(&mut self) -> anyhow::Result<()> {
         macro_rules! handle {
             ($self: ident, $fun:ident, $header:expr) => {{
                 let sh = Some(&$header);
@@ -118,38 +270,112 @@ impl Controller {
                     .$fun(&$header, payload)
                     .await
                     .with_context(|| format!("Handling payload for {:?}", $header))?;
-            };};
+            }};
         }
 
-        trace!("Initial handshake");
-        self.send_handshake().await?;
-        // trace!("Initial telemetry request");
-        // self.send_telemetry_req().await?;
-
-        loop {
-            if self.frontier_stream {
-                let payload = self.recv::<FrontierResp>(None).await?;
-                self.handle_frontier_resp(payload).await?;
+        if self.frontier_stream {
+            let payload = self.recv::<FrontierResp>(None).await?;
+            self.handle_frontier_resp(payload).await?;
+        } else if self.bulk_push_stream {
+            match self.recv_stream_block(MessageType::BulkPush).await? {
+                Some(block) => self.handle_bulk_push_block(block).await?,
+                None => self.handle_bulk_push_end().await?,
+            }
+        } else if !self.bulk_pulls.is_empty() {
+            match self.recv_stream_block(MessageType::BulkPull).await? {
+                Some(block) => self.handle_bulk_pull_block(block).await?,
+                None => {
+                    let bulk_pull = self.bulk_pulls.pop_front().expect("bulk pull is empty");
+                    self.handle_bulk_pull_end(bulk_pull).await?;
+                }
+            }
+        } else if let Some(flags) = self.bulk_pull_accounts.front().map(|b| b.flags) {
+            if self.bulk_pull_account_response.is_none() {
+                let frontier = self.recv::<BulkPullAccountFrontier>(None).await?;
+                self.handle_bulk_pull_account_frontier(frontier).await?;
             } else {
-                let header = self.recv::<Header>(None).await?;
-                header.validate(&self.network)?;
-
-                match header.message_type() {
-                    MessageType::Keepalive => handle!(self, handle_keepalive, header),
-                    MessageType::Publish => handle!(self, handle_publish, header),
-                    MessageType::ConfirmReq => handle!(self, handle_confirm_req, header),
-                    MessageType::ConfirmAck => handle!(self, handle_confirm_ack, header),
-                    MessageType::FrontierReq => handle!(self, handle_frontier_req, header),
-                    MessageType::Handshake => handle!(self, handle_handshake, header),
-                    MessageType::TelemetryReq => handle!(self, handle_telemetry_req, header),
-                    MessageType::TelemetryAck => handle!(self, handle_telemetry_ack, header),
-                    // MessageType::BulkPull => {}
-                    // MessageType::BulkPush => {}
-                    // MessageType::BulkPullAccount => {}
-                    _ => panic!("{:?}", header),
-                };
+                let data = self.recv_buf(flags.entry_len()).await?;
+                let entry = BulkPullAccountEntry::deserialize(flags, &data)?;
+                self.handle_bulk_pull_account_entry(entry).await?;
+            }
+        } else {
+            let header = self.recv::<Header>(None).await?;
+            header.validate(&self.network, &self.config.versions)?;
+            self.negotiate_version(&header);
+            if self.requires_node_id(header.message_type()) {
+                return Err(anyhow!(
+                    "Peer {} sent {:?} before proving its node ID",
+                    self.peer_addr,
+                    header.message_type()
+                ));
             }
+
+            match header.message_type() {
+                MessageType::Keepalive => handle!(self, handle_keepalive, header),
+                MessageType::Publish => handle!(self, handle_publish, header),
+                MessageType::ConfirmReq => handle!(self, handle_confirm_req, header),
+                MessageType::ConfirmAck => handle!(self, handle_confirm_ack, header),
+                MessageType::BulkPull => handle!(self, handle_bulk_pull, header),
+                MessageType::FrontierReq => handle!(self, handle_frontier_req, header),
+                MessageType::Handshake => handle!(self, handle_handshake, header),
+                MessageType::TelemetryReq => handle!(self, handle_telemetry_req, header),
+                MessageType::TelemetryAck if TelemetryAck::is_empty(&header) => {
+                    debug!("{} has no telemetry to share", self.peer_addr)
+                }
+                MessageType::TelemetryAck => handle!(self, handle_telemetry_ack, header),
+                MessageType::BulkPush => handle!(self, handle_bulk_push, header),
+                MessageType::BulkPullAccount => handle!(self, handle_bulk_pull_account, header),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Use the newest version both sides support for everything sent to the peer from now on.
+    fn negotiate_version(&mut self, header: &Header) {
+        let version = self.config.versions.negotiate(header);
+        if self.peer_version != Some(version) {
+            debug!(
+                "Using protocol version {:?} with {}",
+                version, self.peer_addr
+            );
+            self.peer_version = Some(version);
+            self.header.set_versions(&self.config.versions, version);
+        }
+    }
+
+    /// Whether a message can't be handled yet because the peer hasn't proven its node ID.
+    ///
+    /// Bootstrap requests are served without a handshake, like the reference node does.
+    fn requires_node_id(&self, message_type: MessageType) -> bool {
+        if self.peer_node_id.is_some() || !self.validate_handshakes || self.bootstrap.is_some() {
+            return false;
+        }
+        !matches!(
+            message_type,
+            MessageType::Handshake
+                | MessageType::BulkPull
+                | MessageType::BulkPullAccount
+                | MessageType::BulkPush
+                | MessageType::FrontierReq
+        )
+    }
+
+    /// Receive the next block of a headerless block stream, which is prefixed by its block type.
+    /// `None` is returned when the stream has ended.
+    async fn recv_stream_block(
+        &mut self,
+        message_type: MessageType,
+    ) -> anyhow::Result<Option<BlockHolder>> {
+        let block_type = self.recv::<BlockType>(None).await?;
+        if block_type == BlockType::NotABlock {
+            return Ok(None);
         }
+
+        // Blocks in the stream don't have a header, so make one up to decode the block.
+        let mut header = self.header;
+        header.reset(message_type, *Extensions::new().set_block_type(block_type));
+        Ok(Some(self.recv::<BlockHolder>(Some(&header)).await?))
     }
 
     #[instrument(skip(self, header))]
@@ -204,7 +430,14 @@ impl Controller {
         let data = message.serialize();
         trace!("HEX {}", to_hex(&data));
         debug!("OBJ {:?}", &message);
-        self.outgoing.send(Packet::new(Vec::from(data))).await?;
+        self.outgoing.send(Packet::new(data)).await?;
+        Ok(())
+    }
+
+    /// Send data that doesn't implement `Wire`.
+    async fn send_bytes(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        trace!("HEX {}", to_hex(&data));
+        self.outgoing.send(Packet::new(data)).await?;
         Ok(())
     }
 
@@ -215,7 +448,7 @@ impl Controller {
     ) -> anyhow::Result<()> {
         let mut header = self.header;
         header.reset(message_type, ext);
-        Ok(self.send(&header).await?)
+        self.send(&header).await
     }
 
     /// Set up the genesis block if it hasn't already.
@@ -224,11 +457,7 @@ impl Controller {
         Ok(())
     }
 
-    /// Update the representative weights based on this block being added to the network.
-    pub async fn balance_rep_weights(&mut self, _full_block: &Block) -> anyhow::Result<()> {
-        todo!()
-    }
-
+    /// The balance of an account at its latest block, or zero if it hasn't been opened.
     pub async fn account_balance(&self, account: &Public) -> anyhow::Result<Rai> {
         let context = || anyhow!("Account balance for {:?}", account);
         let block = self.get_latest_block(account).await.with_context(context)?;
@@ -239,6 +468,11 @@ impl Controller {
         }
     }
 
+    /// The key of this node's ID, see `load_node_key`.
+    pub async fn node_key(&self) -> anyhow::Result<Private> {
+        load_node_key(&mut *self.state.lock().await).await
+    }
+
     pub fn network(&self) -> &Network {
         &self.network
     }
@@ -246,28 +480,211 @@ impl Controller {
     pub fn peer_addr(&self) -> &SocketAddr {
         &self.peer_addr
     }
+
+    /// Use the node wide settings, including the protocol versions in `config`.
+    pub fn set_config(&mut self, config: ControllerConfig) {
+        let versions = config.versions;
+        self.config = config;
+        self.set_versions(versions);
+    }
+
+    /// Change the protocol versions this node supports. Peers that don't support any of them are
+    /// disconnected when they send a message.
+    pub fn set_versions(&mut self, versions: VersionRange) {
+        self.config.versions = versions;
+        self.peer_version = None;
+        self.header.set_versions(&versions, versions.max);
+    }
+
+    pub fn versions(&self) -> &VersionRange {
+        &self.config.versions
+    }
+
+    /// The protocol version used with the peer, once it has sent a message.
+    pub fn peer_version(&self) -> Option<Version> {
+        self.peer_version
+    }
+
+    /// The node ID of the peer, once it has proven it in its handshake.
+    pub fn peer_node_id(&self) -> Option<&Public> {
+        self.peer_node_id.as_ref()
+    }
+
+    pub fn pulled_blocks(&self) -> usize {
+        self.pulled_blocks
+    }
+
+    pub fn pushed_blocks(&self) -> usize {
+        self.pushed_blocks
+    }
+
+    pub fn pulled_accounts(&self) -> &[(BulkPullAccount, BulkPullAccountResponse)] {
+        &self.pulled_accounts
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::blocks::{Block, BlockHash, OpenBlock, Previous, SendBlock};
-    use crate::node::state::MemoryState;
-    use crate::{Address, DEFAULT_PORT};
+    use crate::blocks::{
+        Block, BlockHash, Epoch, Link, OpenBlock, Previous, SendBlock, Subtype, ValidationState,
+    };
+    use crate::node::controller::block_processor::ProcessResult;
+    use crate::node::controller::telemetry::TELEMETRY_MAKER;
+    use crate::node::cookie::Cookie;
+    use crate::node::messages::bulk_pull::{BulkPull, HashOrAccount};
+    use crate::node::messages::bulk_pull_account::BulkPullAccountFlags;
+    use crate::node::messages::confirm_ack::{Confirm, ConfirmAck};
+    use crate::node::messages::confirm_req::{ConfirmReq, RootHashPair};
+    use crate::node::messages::frontier_req::FrontierReq;
+    use crate::node::messages::handshake::{Handshake, HandshakeQuery, HandshakeResponse};
+    use crate::node::messages::keepalive::Keepalive;
+    use crate::node::messages::publish::Publish;
+    use crate::node::messages::telemetry_req::TelemetryReq;
+    use crate::node::peer::Peer;
+    use crate::node::state::{ConfirmationHeight, MemoryState, Receivable, StateBatch};
+    use crate::node::telemetry::TELEMETRY_MAX_AGE;
+    use crate::node::timestamp::Timestamp;
+    use crate::{Address, Signature, Work, DEFAULT_PORT};
+    use std::convert::TryFrom;
     use std::net::{Ipv4Addr, SocketAddrV4};
     use std::str::FromStr;
     use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
     use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    pub(super) async fn empty_lattice(network: Network) -> Controller {
+        let (controller, _tx, _rx) = empty_lattice_with_channels(network).await;
+        controller
+    }
 
-    async fn empty_lattice(network: Network) -> Controller {
+    /// Also returns the channels so that packets can be sent to and received from the controller.
+    pub(super) async fn empty_lattice_with_channels(
+        network: Network,
+    ) -> (Controller, Sender<Packet>, Receiver<Packet>) {
         let state = Arc::new(Mutex::new(MemoryState::new(network)));
-        let (mut controller, _rx, _tx) = Controller::new_with_channels(
+        let (mut controller, tx, rx) = Controller::new_with_channels(
             network,
             state,
             SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
         );
         controller.init().await.unwrap();
-        controller
+        (controller, tx, rx)
+    }
+
+    /// Everything the controller has sent so far.
+    async fn sent_data(rx: &mut Receiver<Packet>) -> Vec<u8> {
+        let mut data = vec![];
+        while let Ok(Some(packet)) = timeout(Duration::from_millis(10), rx.recv()).await {
+            data.extend(packet.data);
+        }
+        data
+    }
+
+    /// The first send from the genesis account on the live network.
+    pub(super) fn genesis_send(network: Network) -> Block {
+        let genesis = network.genesis_block();
+        let gen_send: SendBlock = serde_json::from_str(
+            r#"{
+                "type": "send",
+                "previous": "991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948",
+                "destination": "nano_13ezf4od79h1tgj9aiu4djzcmmguendtjfuhwfukhuucboua8cpoihmh8byo",
+                "balance": "FD89D89D89D89D89D89D89D89D89D89D",
+                "work": "3c82cc724905ee95",
+                "signature": "5B11B17DB9C8FE0CC58CAC6A6EECEF9CB122DA8A81C6D3DB1B5EE3AB065AA8F8CB1D6765C8EB91B58530C5FF5987AD95E6D34BB57F44257E20795EE412E61600"
+            }"#,
+        )
+        .unwrap();
+
+        let mut block: Block =
+            Block::from_send_block(&gen_send, genesis.account(), genesis.representative());
+        block.calc_hash().unwrap();
+        block
+    }
+
+    /// A real open block to the "Landing" account, receiving `genesis_send`.
+    fn landing_open() -> Block {
+        // `type` is ignored here, but just left it in as it's part of the RPC response and
+        // might be checked in the future.
+        let land_open: OpenBlock = serde_json::from_str(
+            r#"{
+                "type": "open",
+                "source": "A170D51B94E00371ACE76E35AC81DC9405D5D04D4CEBC399AEACE07AE05DD293",
+                "representative": "nano_1awsn43we17c1oshdru4azeqjz9wii41dy8npubm4rg11so7dx3jtqgoeahy",
+                "account": "nano_13ezf4od79h1tgj9aiu4djzcmmguendtjfuhwfukhuucboua8cpoihmh8byo",
+                "work": "e997c097a452a1b1",
+                "signature": "E950FFDF0C9C4DAF43C27AE3993378E4D8AD6FA591C24497C53E07A3BC80468539B0A467992A916F0DDA6F267AD764A3C1A5BDBD8F489DFAE8175EEE0E337402"
+            }"#,
+        ).unwrap();
+        let given = Rai::from(3271945835778254456378601994536232802u128);
+        let mut land_open = Block::from_open_block(&land_open, &Previous::Open, &given);
+        land_open.calc_hash().unwrap();
+        land_open
+    }
+
+    /// The first send from the "Landing" account, after `landing_open`.
+    fn landing_send() -> Block {
+        let land_send: SendBlock = serde_json::from_str(
+            r#"{
+    "type": "send",
+    "previous": "90D0C16AC92DD35814E84BFBCC739A039615D0A42A76EF44ADAEF1D99E9F8A35",
+    "destination": "nano_35jjmmmh81kydepzeuf9oec8hzkay7msr6yxagzxpcht7thwa5bus5tomgz9",
+    "balance": "02761762762762762762762762762762",
+    "work": "6d6d59ca60cab77d",
+    "signature": "434CF7E7B2C2CAA3E3910CC711B29498870636C1247EA8C72BD5C0A7BB15A7BACFEC9CF289B92E4BD56F56E68277B45B3A3FF9339D2547038B87DE38C851B70B"
+  }"#).unwrap();
+
+        let land_open = landing_open();
+        let mut land_send =
+            Block::from_send_block(&land_send, land_open.account(), land_open.representative());
+        land_send.calc_hash().unwrap();
+        land_send
+    }
+
+    /// A signed block of our own, since the only real blocks we have are signed by others.
+    pub(super) fn signed_block(
+        private: &Private,
+        block_type: BlockType,
+        previous: Previous,
+        balance: u128,
+        link: Link,
+    ) -> Block {
+        let account = private.to_public().unwrap();
+        let mut block = Block::new(
+            block_type,
+            account.to_owned(),
+            previous,
+            account,
+            Rai::new(balance),
+            link,
+            ValidationState::Valid,
+        );
+        block.set_work(Work::zero());
+        block.calc_hash().unwrap();
+        block.sign(private.to_owned()).unwrap();
+        block
+    }
+
+    /// Open an account with 100 raw, as if it had been sent from somewhere, straight into the
+    /// ledger. Returns the open block.
+    pub(super) async fn open_account(controller: &Controller, private: &Private) -> Block {
+        let account = private.to_public().unwrap();
+        let open = signed_block(
+            private,
+            BlockType::Open,
+            Previous::Open,
+            100,
+            Link::Source(BlockHash::zero()),
+        );
+        let mut batch = StateBatch::new();
+        batch
+            .add_block(&open, 1, Epoch::V0)
+            .unwrap()
+            .set_latest_block_hash(&account, open.hash().unwrap())
+            .set_rep_weight(&account, &Rai::new(100u128));
+        controller.state.lock().await.commit(batch).await.unwrap();
+        open
     }
 
     #[tokio::test]
@@ -301,24 +718,7 @@ mod tests {
 
         let mut controller = empty_lattice(network).await;
 
-        let gen_send: SendBlock = serde_json::from_str(
-            r#"{
-                "type": "send",
-                "previous": "991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948",
-                "destination": "nano_13ezf4od79h1tgj9aiu4djzcmmguendtjfuhwfukhuucboua8cpoihmh8byo",
-                "balance": "FD89D89D89D89D89D89D89D89D89D89D",
-                "work": "3c82cc724905ee95",
-                "signature": "5B11B17DB9C8FE0CC58CAC6A6EECEF9CB122DA8A81C6D3DB1B5EE3AB065AA8F8CB1D6765C8EB91B58530C5FF5987AD95E6D34BB57F44257E20795EE412E61600"
-            }"#,
-        )
-        .unwrap();
-
-        // TODO: This should be done somewhere (the controller?
-        // e.g. controller.validate_send_block() or controller.fill_send_block()
-        let mut block: Block =
-            Block::from_send_block(&gen_send, genesis.account(), genesis.representative());
-        block.calc_hash().unwrap();
-
+        let block = genesis_send(network);
         controller.add_elected_block(&block).await.unwrap();
 
         let given = Rai::from(3271945835778254456378601994536232802u128);
@@ -334,29 +734,38 @@ mod tests {
             genesis_balance
         );
 
+        // The sent amount isn't delegated to anyone until it's received.
+        assert_eq!(
+            controller
+                .rep_weight(genesis.representative())
+                .await
+                .unwrap(),
+            genesis_balance
+        );
+
         // Account isn't opened yet so it's empty.
         assert_eq!(
             controller.account_balance(&landing_account).await.unwrap(),
             Rai::zero()
         );
 
-        // TODO: Check pending balance of landing account.
+        // The send is receivable by the landing account.
+        let receivables = controller
+            .state
+            .lock()
+            .await
+            .receivables(&landing_account)
+            .await
+            .unwrap();
+        assert_eq!(
+            receivables,
+            vec![(
+                block.hash().unwrap().to_owned(),
+                Receivable::new(genesis.account().to_owned(), given.to_owned())
+            )]
+        );
 
-        // A real open block to the "Landing" account.
-        // `type` is ignored here, but just left it in as it's part of the RPC response and
-        // might be checked in the future.
-        let land_open: OpenBlock = serde_json::from_str(
-            r#"{
-                "type": "open",
-                "source": "A170D51B94E00371ACE76E35AC81DC9405D5D04D4CEBC399AEACE07AE05DD293",
-                "representative": "nano_1awsn43we17c1oshdru4azeqjz9wii41dy8npubm4rg11so7dx3jtqgoeahy",
-                "account": "nano_13ezf4od79h1tgj9aiu4djzcmmguendtjfuhwfukhuucboua8cpoihmh8byo",
-                "work": "e997c097a452a1b1",
-                "signature": "E950FFDF0C9C4DAF43C27AE3993378E4D8AD6FA591C24497C53E07A3BC80468539B0A467992A916F0DDA6F267AD764A3C1A5BDBD8F489DFAE8175EEE0E337402"
-            }"#,
-        ).unwrap();
-        let mut land_open = Block::from_open_block(&land_open, &Previous::Open, &given);
-        land_open.calc_hash().unwrap();
+        let land_open = landing_open();
         assert_eq!(
             land_open.hash().unwrap(),
             &BlockHash::from_str(
@@ -371,28 +780,1772 @@ mod tests {
             controller.account_balance(&landing_account).await.unwrap(),
             given
         );
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .receivables(&landing_account)
+            .await
+            .unwrap()
+            .is_empty());
 
-        let land_send: SendBlock = serde_json::from_str(
-            r#"{
-    "type": "send",
-    "previous": "90D0C16AC92DD35814E84BFBCC739A039615D0A42A76EF44ADAEF1D99E9F8A35",
-    "destination": "nano_35jjmmmh81kydepzeuf9oec8hzkay7msr6yxagzxpcht7thwa5bus5tomgz9",
-    "balance": "02761762762762762762762762762762",
-    "work": "6d6d59ca60cab77d",
-    "signature": "434CF7E7B2C2CAA3E3910CC711B29498870636C1247EA8C72BD5C0A7BB15A7BACFEC9CF289B92E4BD56F56E68277B45B3A3FF9339D2547038B87DE38C851B70B"
-  }"#).unwrap();
-
-        let mut land_send =
-            Block::from_send_block(&land_send, &landing_account, &land_open.representative());
-        land_send.calc_hash().unwrap();
-
+        let land_send = landing_send();
         controller.add_elected_block(&land_send).await.unwrap();
 
+        let land_balance = given
+            .checked_sub(&Rai::from(324518553658426726783156020576256))
+            .unwrap();
         assert_eq!(
             controller.account_balance(&landing_account).await.unwrap(),
-            given
-                .checked_sub(&Rai::from(324518553658426726783156020576256))
-                .unwrap()
+            land_balance
+        );
+
+        let land_rep = land_open.representative();
+        assert_eq!(controller.rep_weight(land_rep).await.unwrap(), land_balance);
+        assert_eq!(
+            controller.top_reps(10).await.unwrap(),
+            vec![
+                (genesis.representative().to_owned(), genesis_balance),
+                (land_rep.to_owned(), land_balance.to_owned()),
+            ]
+        );
+        assert_eq!(controller.top_reps(1).await.unwrap().len(), 1);
+
+        // Only reps that have voted recently count towards the online weight.
+        assert_eq!(controller.online_weight().await.unwrap(), Rai::zero());
+        controller
+            .state
+            .lock()
+            .await
+            .add_online_rep(land_rep, SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(controller.online_weight().await.unwrap(), land_balance);
+    }
+
+    #[tokio::test]
+    async fn bulk_pull_genesis() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        let bulk_pull = BulkPull::account(genesis.account());
+        let header = Header::new(network, MessageType::BulkPull, Extensions::new());
+        server
+            .handle_bulk_pull(&header, bulk_pull.clone())
+            .await
+            .unwrap();
+
+        let data = sent_data(&mut server_rx).await;
+        assert_eq!(data.len(), 1 + OpenBlock::LEN + 1);
+        assert_eq!(data[0], BlockType::Open.as_u8());
+        assert_eq!(data[data.len() - 1], BlockType::NotABlock.as_u8());
+
+        // Feed the stream into a client that requested the same bulk pull.
+        let (mut client, client_tx, mut client_rx) = empty_lattice_with_channels(network).await;
+        client.send_bulk_pull(bulk_pull).await.unwrap();
+        assert_eq!(
+            sent_data(&mut client_rx).await.len(),
+            Header::LEN + BulkPull::LEN
+        );
+
+        client_tx.send(Packet::new(data)).await.unwrap();
+        client.recv_message().await.unwrap();
+        client.recv_message().await.unwrap();
+        assert!(client.bulk_pulls.is_empty());
+
+        assert_eq!(client.pulled_blocks(), 1);
+    }
+
+    #[tokio::test]
+    async fn bulk_pull_unknown_account() {
+        let network = Network::Live;
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        let account =
+            Public::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        let header = Header::new(network, MessageType::BulkPull, Extensions::new());
+        server
+            .handle_bulk_pull(&header, BulkPull::account(&account))
+            .await
+            .unwrap();
+        assert_eq!(
+            sent_data(&mut server_rx).await,
+            vec![BlockType::NotABlock.as_u8()]
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_pull_in_batches() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+        let send = genesis_send(network);
+        let mut server = empty_lattice(network).await;
+        server.add_elected_block(&send).await.unwrap();
+
+        // Each batch continues where the last one stopped.
+        let bulk_pull = BulkPull::account(genesis.account());
+        let start = server.bulk_pull_start(&bulk_pull).await.unwrap().unwrap();
+        let (blocks, next) = server.bulk_pull_blocks(&bulk_pull, start, 1).await.unwrap();
+        assert_eq!(blocks, vec![send.clone()]);
+        let next = next.unwrap();
+        let (blocks, next) = server.bulk_pull_blocks(&bulk_pull, next, 1).await.unwrap();
+        assert_eq!(blocks, vec![genesis.clone()]);
+        assert_eq!(next, None);
+
+        // The count limits the blocks across batches.
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        server.add_elected_block(&send).await.unwrap();
+        let bulk_pull = BulkPull::new(
+            HashOrAccount::from(genesis.account()),
+            BlockHash::zero(),
+            Some(1),
+        );
+        assert_eq!(server.send_bulk_pull_blocks(&bulk_pull).await.unwrap(), 1);
+        assert_eq!(sent_data(&mut server_rx).await[0], BlockType::Send.as_u8());
+    }
+
+    #[tokio::test]
+    async fn bootstrap_schedules_pulls() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+
+        let (mut client, client_tx, mut client_rx) = empty_lattice_with_channels(network).await;
+        client.enable_bootstrap();
+        client.start_bootstrap().await.unwrap();
+        assert_eq!(
+            sent_data(&mut client_rx).await.len(),
+            Header::LEN + FrontierReq::LEN
         );
+
+        let missing =
+            Public::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        // Frontiers are sent in account order.
+        let frontiers = vec![
+            FrontierResp::new(missing.clone(), BlockHash::zero()),
+            FrontierResp::new(genesis.account().to_owned(), network.genesis_hash()),
+            FrontierResp::end(),
+        ];
+        for frontier in &frontiers {
+            client_tx
+                .send(Packet::new(frontier.serialize()))
+                .await
+                .unwrap();
+            client.recv_message().await.unwrap();
+        }
+        assert!(!client.frontier_stream);
+
+        let progress = client.bootstrap_progress().unwrap();
+        assert_eq!(progress.frontiers, 2);
+        assert_eq!(progress.accounts_up_to_date, 1);
+        assert_eq!(progress.pulls_scheduled, 1);
+        assert!(!client.is_bootstrap_complete());
+
+        // Only the missing account is pulled.
+        let expected = BulkPull::account(&missing);
+        let mut data = vec![];
+        data.extend(Header::new(network, MessageType::BulkPull, Extensions::new()).serialize());
+        data.extend(expected.serialize());
+        assert_eq!(sent_data(&mut client_rx).await, data);
+
+        // The peer doesn't have any blocks for it.
+        client_tx
+            .send(Packet::new(BlockType::NotABlock.serialize()))
+            .await
+            .unwrap();
+        client.recv_message().await.unwrap();
+        assert_eq!(client.bootstrap_progress().unwrap().pulls_completed, 1);
+        assert!(client.is_bootstrap_complete());
+    }
+
+    #[tokio::test]
+    async fn bootstrap_pushes_missing_accounts() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+
+        let (mut client, client_tx, mut client_rx) = empty_lattice_with_channels(network).await;
+        client.enable_bootstrap();
+        client.start_bootstrap().await.unwrap();
+        sent_data(&mut client_rx).await;
+
+        // The peer has no accounts at all.
+        client_tx
+            .send(Packet::new(FrontierResp::end().serialize()))
+            .await
+            .unwrap();
+        client.recv_message().await.unwrap();
+        let progress = client.bootstrap_progress().unwrap();
+        assert_eq!(progress.pushes_scheduled, 1);
+        assert_eq!(progress.blocks_pushed, 1);
+        assert!(client.is_bootstrap_complete());
+
+        let holder = genesis.to_holder().unwrap();
+        let mut expected = vec![];
+        expected.extend(Header::new(network, MessageType::BulkPush, Extensions::new()).serialize());
+        expected.extend(holder.block_type().serialize());
+        expected.extend(Wire::serialize(&holder));
+        expected.extend(BlockType::NotABlock.serialize());
+        let pushed = sent_data(&mut client_rx).await;
+        assert_eq!(pushed, expected);
+
+        // The peer stages the pushed blocks, then carries on with regular messages.
+        let (mut server, server_tx, _server_rx) = empty_lattice_with_channels(network).await;
+        server_tx.send(Packet::new(pushed)).await.unwrap();
+        server_tx
+            .send(Packet::new(
+                Header::new(network, MessageType::TelemetryReq, Extensions::new()).serialize(),
+            ))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            server.recv_message().await.unwrap();
+        }
+        assert!(!server.bulk_push_stream);
+        assert_eq!(server.pushed_blocks(), 1);
+
+        // A pushed block that isn't in the ledger yet needs to be elected first.
+        let send = genesis_send(network);
+        let holder = send.to_holder().unwrap();
+        let mut pushed = Header::new(network, MessageType::BulkPush, Extensions::new()).serialize();
+        pushed.extend(holder.block_type().serialize());
+        pushed.extend(Wire::serialize(&holder));
+        pushed.extend(BlockType::NotABlock.serialize());
+        let (mut server, server_tx, _server_rx) = empty_lattice_with_channels(network).await;
+        server_tx.send(Packet::new(pushed)).await.unwrap();
+        for _ in 0..3 {
+            server.recv_message().await.unwrap();
+        }
+        assert_eq!(server.pushed_blocks(), 1);
+        assert!(server
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(send.hash().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(server.active_elections().await.unwrap(), vec![send.root()]);
+    }
+
+    #[tokio::test]
+    async fn bulk_pull_account_receivables() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+        let account = genesis.account().to_owned();
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        let (mut client, client_tx, mut client_rx) = empty_lattice_with_channels(network).await;
+
+        let small =
+            BlockHash::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        let large =
+            BlockHash::from_str("0000000000000000000000000000000000000000000000000000000000000002")
+                .unwrap();
+        for (hash, amount) in &[(&small, 1u128), (&large, 1_000u128)] {
+            let receivable = Receivable::new(account.to_owned(), Rai::new(*amount));
+            server
+                .state
+                .lock()
+                .await
+                .add_receivable(&account, hash, &receivable)
+                .await
+                .unwrap();
+        }
+
+        let request = BulkPullAccount::new(
+            account.to_owned(),
+            Rai::new(100u128),
+            BulkPullAccountFlags::HashAndAmount,
+        );
+        client
+            .send_bulk_pull_account(request.clone())
+            .await
+            .unwrap();
+        let data = sent_data(&mut client_rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::BulkPullAccount);
+        let decoded = BulkPullAccount::deserialize(Some(&header), &data[Header::LEN..]).unwrap();
+        assert_eq!(decoded, request);
+
+        server
+            .handle_bulk_pull_account(&header, decoded)
+            .await
+            .unwrap();
+        let response = sent_data(&mut server_rx).await;
+        assert_eq!(response.len(), BulkPullAccountFrontier::LEN + 48 * 2);
+
+        client_tx.send(Packet::new(response)).await.unwrap();
+        for _ in 0..3 {
+            client.recv_message().await.unwrap();
+        }
+        assert!(client.bulk_pull_accounts.is_empty());
+
+        let expected = BulkPullAccountResponse {
+            frontier: BulkPullAccountFrontier::new(network.genesis_hash(), Rai::max()),
+            entries: vec![BulkPullAccountEntry::new(
+                large,
+                Rai::new(1_000u128),
+                BulkPullAccountEntry::end().source,
+            )],
+        };
+        assert_eq!(client.pulled_accounts(), &[(request, expected)]);
+    }
+
+    #[tokio::test]
+    async fn serve_frontier_req() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        let header = Header::new(network, MessageType::FrontierReq, Extensions::new());
+
+        server
+            .handle_frontier_req(&header, FrontierReq::all())
+            .await
+            .unwrap();
+        let mut expected = vec![];
+        expected.extend(
+            FrontierResp::new(genesis.account().to_owned(), network.genesis_hash()).serialize(),
+        );
+        expected.extend(FrontierResp::end().serialize());
+        assert_eq!(sent_data(&mut server_rx).await, expected);
+
+        // Starting after the genesis account.
+        let req = FrontierReq::new(next_genesis_account(), u32::MAX, u32::MAX);
+        server.handle_frontier_req(&header, req).await.unwrap();
+        assert_eq!(
+            sent_data(&mut server_rx).await,
+            FrontierResp::end().serialize()
+        );
+
+        // Zero count.
+        let req = FrontierReq::new(genesis.account().to_owned(), u32::MAX, 0);
+        server.handle_frontier_req(&header, req).await.unwrap();
+        assert_eq!(
+            sent_data(&mut server_rx).await,
+            FrontierResp::end().serialize()
+        );
+
+        // Recently modified.
+        let req = FrontierReq::new(genesis.account().to_owned(), 60, u32::MAX);
+        server.handle_frontier_req(&header, req).await.unwrap();
+        assert_eq!(sent_data(&mut server_rx).await, expected);
+    }
+
+    fn next_genesis_account() -> Public {
+        let mut bytes = Network::Live.genesis_block().account().as_bytes().to_vec();
+        *bytes.last_mut().unwrap() += 1;
+        Public::try_from(bytes.as_slice()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bootstrap_from_up_to_date_peer() {
+        let network = Network::Live;
+        let (mut server, _server_tx, mut server_rx) = empty_lattice_with_channels(network).await;
+        let (mut client, client_tx, mut client_rx) = empty_lattice_with_channels(network).await;
+        client.enable_bootstrap();
+        client.start_bootstrap().await.unwrap();
+
+        // Pass the frontier req through to the server.
+        handle_sent_frontier_req(&mut server, sent_data(&mut client_rx).await).await;
+
+        client_tx
+            .send(Packet::new(sent_data(&mut server_rx).await))
+            .await
+            .unwrap();
+        while !client.is_bootstrap_complete() {
+            client.recv_message().await.unwrap();
+        }
+        let progress = client.bootstrap_progress().unwrap();
+        assert_eq!(progress.frontiers, 1);
+        assert_eq!(progress.accounts_up_to_date, 1);
+        assert_eq!(progress.pulls_scheduled, 0);
+    }
+
+    /// A vote for `hashes`, signed by the representative.
+    pub(super) fn signed_vote(
+        private: &Private,
+        timestamp: u64,
+        hashes: &[BlockHash],
+    ) -> ConfirmAck {
+        let mut confirm_ack = ConfirmAck::new(
+            private.to_public().unwrap(),
+            private.sign(&[]).unwrap(),
+            Timestamp::from_u64(timestamp),
+            Confirm::VoteByHash(hashes.to_vec()),
+        );
+        confirm_ack.signature = private.sign(&confirm_ack.inner_hash().unwrap()).unwrap();
+        confirm_ack
+    }
+
+    #[tokio::test]
+    async fn tally_votes() {
+        let (mut controller, _, _) = empty_lattice_with_channels(Network::Live).await;
+        let big = Private::random();
+        let small = Private::random();
+        let genesis = Network::Live.genesis_block();
+        let send = genesis_send(Network::Live);
+        let a = genesis.hash().unwrap().to_owned();
+        let b = send.hash().unwrap().to_owned();
+
+        let mut batch = StateBatch::new();
+        batch
+            .set_rep_weight(&big.to_public().unwrap(), &Rai::new(100u128))
+            .set_rep_weight(&small.to_public().unwrap(), &Rai::new(10u128));
+        {
+            let mut state = controller.state.lock().await;
+            state.commit(batch).await.unwrap();
+            // Votes are only kept for blocks in elections.
+            for block in &[&genesis, &send] {
+                state
+                    .add_election_candidate(&block.root(), block, SystemTime::now())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        controller
+            .add_vote(&signed_vote(&big, 1, std::slice::from_ref(&a)))
+            .await
+            .unwrap();
+        controller
+            .add_vote(&signed_vote(&small, 1, std::slice::from_ref(&b)))
+            .await
+            .unwrap();
+        assert_eq!(
+            controller
+                .tally_votes(&[a.clone(), b.clone()])
+                .await
+                .unwrap(),
+            vec![
+                (a.clone(), Rai::new(100u128)),
+                (b.clone(), Rai::new(10u128))
+            ]
+        );
+
+        // A newer vote moves the representative's weight over to the other block.
+        controller
+            .add_vote(&signed_vote(&big, 2, std::slice::from_ref(&b)))
+            .await
+            .unwrap();
+        assert_eq!(
+            controller
+                .tally_votes(&[a.clone(), b.clone()])
+                .await
+                .unwrap(),
+            vec![(b.clone(), Rai::new(110u128)), (a.clone(), Rai::zero())]
+        );
+
+        // An older vote doesn't replace a newer one.
+        controller
+            .add_vote(&signed_vote(&small, 0, std::slice::from_ref(&a)))
+            .await
+            .unwrap();
+        assert_eq!(
+            controller
+                .tally_votes(&[a.clone(), b.clone()])
+                .await
+                .unwrap(),
+            vec![(b.clone(), Rai::new(110u128)), (a.clone(), Rai::zero())]
+        );
+
+        // A vote carrying the block counts towards the block's hash.
+        let mut block_vote = ConfirmAck::new(
+            small.to_public().unwrap(),
+            small.sign(&[]).unwrap(),
+            Timestamp::from_u64(3),
+            Confirm::Block(genesis.to_holder().unwrap()),
+        );
+        block_vote.signature = small.sign(&block_vote.inner_hash().unwrap()).unwrap();
+        controller.add_vote(&block_vote).await.unwrap();
+        assert_eq!(
+            controller
+                .tally_votes(&[a.clone(), b.clone()])
+                .await
+                .unwrap(),
+            vec![
+                (b.clone(), Rai::new(100u128)),
+                (a.clone(), Rai::new(10u128))
+            ]
+        );
+        assert_eq!(controller.online_weight().await.unwrap(), Rai::new(110u128));
+
+        // A vote signed by someone else is rejected.
+        let mut forged = signed_vote(&small, 4, std::slice::from_ref(&b));
+        forged.signature = big.sign(&forged.inner_hash().unwrap()).unwrap();
+        assert!(controller.add_vote(&forged).await.is_err());
+        assert_eq!(
+            controller
+                .tally_votes(&[a.clone(), b.clone()])
+                .await
+                .unwrap(),
+            vec![
+                (b.clone(), Rai::new(100u128)),
+                (a.clone(), Rai::new(10u128))
+            ]
+        );
+
+        // A representative without any weight isn't stored, nor counted as online.
+        let nobody = Private::random();
+        assert!(!controller
+            .add_vote(&signed_vote(&nobody, 5, std::slice::from_ref(&a)))
+            .await
+            .unwrap());
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .votes(&a)
+            .await
+            .unwrap()
+            .iter()
+            .all(|(rep, _)| rep != &nobody.to_public().unwrap()));
+        assert!(!controller
+            .state
+            .lock()
+            .await
+            .online_reps(SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap()
+            .contains(&nobody.to_public().unwrap()));
+
+        // A vote for a block that isn't in an election isn't stored either.
+        let other = BlockHash::zero();
+        assert!(controller
+            .add_vote(&signed_vote(&big, 6, std::slice::from_ref(&other)))
+            .await
+            .unwrap());
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .votes(&other)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn elect_block_at_quorum() {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+        let mut controller = empty_lattice(network).await;
+        let nano = 10u128.pow(30);
+        let big = Private::random();
+        let small = Private::random();
+
+        let mut batch = StateBatch::new();
+        batch
+            .set_rep_weight(&big.to_public().unwrap(), &Rai::new(100_000_000 * nano))
+            .set_rep_weight(&small.to_public().unwrap(), &Rai::new(10_000_000 * nano));
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        let block = genesis_send(network);
+        let hash = block.hash().unwrap().to_owned();
+        assert_eq!(controller.start_election(&block).await.unwrap(), None);
+        assert_eq!(
+            controller.active_elections().await.unwrap(),
+            vec![genesis.hash().unwrap().to_owned()]
+        );
+
+        // Not enough is online, so the minimum online weight is used for quorum.
+        let vote = signed_vote(&small, 1, std::slice::from_ref(&hash));
+        assert!(controller.process_vote(&vote).await.unwrap().is_empty());
+        assert_eq!(
+            controller.quorum_delta().await.unwrap(),
+            Rai::new(40_200_000 * nano)
+        );
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(&hash)
+            .await
+            .unwrap()
+            .is_none());
+
+        let vote = signed_vote(&big, 1, std::slice::from_ref(&hash));
+        assert_eq!(
+            controller.process_vote(&vote).await.unwrap(),
+            vec![block.clone()]
+        );
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .get_block_by_hash(&hash)
+                .await
+                .unwrap(),
+            Some(block.clone())
+        );
+        assert!(controller.is_cemented(&hash).await.unwrap());
+        assert!(controller.active_elections().await.unwrap().is_empty());
+
+        // The block is in the ledger, so there's nothing left to elect.
+        assert_eq!(controller.start_election(&block).await.unwrap(), None);
+        assert!(controller.active_elections().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_election_when_adding_fails() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let voter = Private::random();
+        let mut batch = StateBatch::new();
+        batch.set_rep_weight(
+            &voter.to_public().unwrap(),
+            &Rai::new(100_000_000 * 10u128.pow(30)),
+        );
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        // Nothing was sent to this account, so the block can't be added to the ledger.
+        let block = signed_block(
+            &Private::random(),
+            BlockType::State,
+            Previous::Open,
+            1,
+            Link::Source(BlockHash::zero()),
+        );
+        let root = block.root();
+        controller.start_election(&block).await.unwrap();
+        let vote = signed_vote(&voter, 1, &[block.hash().unwrap().to_owned()]);
+        assert!(controller.process_vote(&vote).await.is_err());
+        assert_eq!(controller.active_elections().await.unwrap(), vec![root]);
+    }
+
+    #[tokio::test]
+    async fn limit_active_elections() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        controller.config.max_active_elections = 1;
+        let open = |private: &Private, balance: u128| {
+            signed_block(
+                private,
+                BlockType::State,
+                Previous::Open,
+                balance,
+                Link::Source(BlockHash::zero()),
+            )
+        };
+        let first = Private::random();
+        let block = open(&first, 1);
+        controller.start_election(&block).await.unwrap();
+
+        // There's no room for another election, but forks can still join the active one.
+        controller
+            .start_election(&open(&Private::random(), 1))
+            .await
+            .unwrap();
+        let fork = open(&first, 2);
+        controller.start_election(&fork).await.unwrap();
+        assert_eq!(
+            controller.active_elections().await.unwrap(),
+            vec![block.root()]
+        );
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .election_candidates(&block.root())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn epoch_block_election() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let owner = Private::random();
+        let mut epoch_link = [0u8; Link::LEN];
+        epoch_link[..14].copy_from_slice(b"epoch v1 block");
+
+        // Epoch blocks are checked against the epoch signer, so the owner of the account can't
+        // sign one.
+        let mut epoch = signed_block(
+            &owner,
+            BlockType::State,
+            Previous::Open,
+            0,
+            Link::Unsure(epoch_link),
+        );
+        epoch.resolve_link(Some(&Rai::zero())).unwrap();
+        assert_eq!(epoch.subtype().unwrap(), Subtype::Epoch);
+        let err = controller.start_election(&epoch).await.unwrap_err();
+        assert!(format!("{:?}", err).contains("Incorrect signature"));
+        assert!(controller.active_elections().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolve_state_blocks() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let (a, b) = (Private::random(), Private::random());
+        let public = |private: &Private| private.to_public().unwrap();
+        let unsure = |bytes: &[u8]| Link::Unsure(<[u8; Link::LEN]>::try_from(bytes).unwrap());
+
+        // Account A already has 100 raw, sent to it from somewhere.
+        let a_open = open_account(&controller, &a).await;
+        let a_open_hash = a_open.hash().unwrap().to_owned();
+
+        // Adds a state block the way it arrives from the network, with an unsure link.
+        async fn add(controller: &mut Controller, block: &Block) -> anyhow::Result<Block> {
+            let holder = block.to_holder()?;
+            let block = controller
+                .resolve_block(&holder)
+                .await?
+                .ok_or_else(|| anyhow!("Missing dependency"))?;
+            controller.add_elected_block(&block).await?;
+            Ok(block)
+        }
+
+        // A sends 40 to B.
+        let send = signed_block(
+            &a,
+            BlockType::State,
+            Previous::Block(a_open_hash.clone()),
+            60,
+            unsure(public(&b).as_bytes()),
+        );
+        let send_hash = send.hash().unwrap().to_owned();
+        let send = add(&mut controller, &send).await.unwrap();
+        assert_eq!(send.subtype().unwrap(), Subtype::Send);
+        assert_eq!(send.destination().unwrap(), &public(&b));
+
+        // B receives it in its first block. Receiving the wrong amount doesn't work.
+        let open = |balance: u128| {
+            signed_block(
+                &b,
+                BlockType::State,
+                Previous::Block(BlockHash::zero()),
+                balance,
+                unsure(send_hash.as_bytes()),
+            )
+        };
+        assert!(add(&mut controller, &open(50)).await.is_err());
+        let b_open = add(&mut controller, &open(40)).await.unwrap();
+        assert_eq!(b_open.subtype().unwrap(), Subtype::Open);
+        assert_eq!(b_open.source().unwrap(), &send_hash);
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .receivables(&public(&b))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // B keeps the balance, so a zero link is a change, and anything else is invalid.
+        let b_previous = Previous::Block(b_open.hash().unwrap().to_owned());
+        let bad = signed_block(
+            &b,
+            BlockType::State,
+            b_previous.clone(),
+            40,
+            unsure(a_open_hash.as_bytes()),
+        );
+        assert!(add(&mut controller, &bad).await.is_err());
+        let change = signed_block(
+            &b,
+            BlockType::State,
+            b_previous,
+            40,
+            Link::Unsure([0u8; Link::LEN]),
+        );
+        let change = add(&mut controller, &change).await.unwrap();
+        assert_eq!(change.subtype().unwrap(), Subtype::Change);
+
+        // Only the epoch signer can upgrade an account, not the account itself.
+        let mut epoch_link = [0u8; Link::LEN];
+        epoch_link[..14].copy_from_slice(b"epoch v1 block");
+        let epoch = signed_block(
+            &b,
+            BlockType::State,
+            Previous::Block(change.hash().unwrap().to_owned()),
+            40,
+            Link::Unsure(epoch_link),
+        );
+        assert!(add(&mut controller, &epoch).await.is_err());
+        assert_eq!(
+            controller.account_balance(&public(&b)).await.unwrap(),
+            Rai::new(40u128)
+        );
+
+        // Rolling back B's blocks makes the send receivable again.
+        let rolled_back = controller.rollback(b_open.hash().unwrap()).await.unwrap();
+        assert_eq!(rolled_back.len(), 2);
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .receivables(&public(&b))
+                .await
+                .unwrap(),
+            vec![(send_hash, Receivable::new(public(&a), Rai::new(40u128)))]
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_receive_and_change() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let (a, b) = (Private::random(), Private::random());
+        let public = |private: &Private| private.to_public().unwrap();
+
+        // Account A already has 100 raw, sent to it from somewhere.
+        let a_open = open_account(&controller, &a).await;
+
+        // A sends 40 then 30 to B, who opens with the first send.
+        let first = signed_block(
+            &a,
+            BlockType::Send,
+            Previous::Block(a_open.hash().unwrap().to_owned()),
+            60,
+            Link::DestinationAccount(public(&b)),
+        );
+        let second = signed_block(
+            &a,
+            BlockType::Send,
+            Previous::Block(first.hash().unwrap().to_owned()),
+            30,
+            Link::DestinationAccount(public(&b)),
+        );
+        let b_open = signed_block(
+            &b,
+            BlockType::Open,
+            Previous::Open,
+            40,
+            Link::Source(first.hash().unwrap().to_owned()),
+        );
+        for block in &[&first, &second, &b_open] {
+            controller.add_elected_block(block).await.unwrap();
+        }
+
+        // B receives the second send. The balance and representative come from the ledger.
+        let receive = signed_block(
+            &b,
+            BlockType::Receive,
+            Previous::Block(b_open.hash().unwrap().to_owned()),
+            0,
+            Link::Source(second.hash().unwrap().to_owned()),
+        );
+        let holder = receive.to_holder().unwrap();
+        let receive = controller.resolve_block(&holder).await.unwrap().unwrap();
+        assert_eq!(receive.balance(), &Rai::new(70u128));
+        assert_eq!(receive.representative(), &public(&b));
+        controller.add_elected_block(&receive).await.unwrap();
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .receivables(&public(&b))
+            .await
+            .unwrap()
+            .is_empty());
+        // It can't be received twice.
+        assert_eq!(controller.resolve_block(&holder).await.unwrap(), None);
+
+        // B delegates to A.
+        let mut change = Block::new(
+            BlockType::Change,
+            public(&b),
+            Previous::Block(receive.hash().unwrap().to_owned()),
+            public(&a),
+            Rai::zero(),
+            Link::Nothing,
+            ValidationState::Valid,
+        );
+        change.set_work(Work::zero());
+        change.calc_hash().unwrap();
+        change.sign(b.to_owned()).unwrap();
+        let change = controller
+            .resolve_block(&change.to_holder().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.balance(), &Rai::new(70u128));
+        controller.add_elected_block(&change).await.unwrap();
+        assert_eq!(
+            controller.rep_weight(&public(&a)).await.unwrap(),
+            Rai::new(100u128)
+        );
+        assert_eq!(
+            controller.rep_weight(&public(&b)).await.unwrap(),
+            Rai::zero()
+        );
+
+        // Rolling back the receive undoes the change first, and the second send is receivable
+        // again.
+        let rolled_back = controller.rollback(receive.hash().unwrap()).await.unwrap();
+        assert_eq!(
+            rolled_back,
+            vec![
+                change.hash().unwrap().to_owned(),
+                receive.hash().unwrap().to_owned()
+            ]
+        );
+        assert_eq!(
+            controller.rep_weight(&public(&b)).await.unwrap(),
+            Rai::new(40u128)
+        );
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .receivables(&public(&b))
+                .await
+                .unwrap(),
+            vec![(
+                second.hash().unwrap().to_owned(),
+                Receivable::new(public(&a), Rai::new(30u128))
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn cement_with_sources() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let genesis = network.genesis_block();
+        let genesis_hash = genesis.hash().unwrap().to_owned();
+        let gen_send = genesis_send(network);
+        let gen_send_hash = gen_send.hash().unwrap().to_owned();
+        let land_open = landing_open();
+        let land_open_hash = land_open.hash().unwrap().to_owned();
+        controller.add_elected_block(&gen_send).await.unwrap();
+        controller.add_elected_block(&land_open).await.unwrap();
+
+        // Only genesis is cemented to begin with.
+        assert!(controller.is_cemented(&genesis_hash).await.unwrap());
+        assert!(!controller.is_cemented(&gen_send_hash).await.unwrap());
+        assert_eq!(
+            controller
+                .confirmation_height(genesis.account())
+                .await
+                .unwrap(),
+            Some(ConfirmationHeight::new(1, genesis_hash.clone()))
+        );
+        assert_eq!(
+            controller
+                .confirmation_height(land_open.account())
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Cementing the open block cements the send it received from first.
+        assert_eq!(
+            controller.cement(&land_open_hash).await.unwrap(),
+            vec![gen_send_hash.clone(), land_open_hash.clone()]
+        );
+        assert!(controller.is_cemented(&gen_send_hash).await.unwrap());
+        assert!(controller.is_cemented(&land_open_hash).await.unwrap());
+        assert_eq!(
+            controller
+                .confirmation_height(genesis.account())
+                .await
+                .unwrap(),
+            Some(ConfirmationHeight::new(2, gen_send_hash.clone()))
+        );
+        assert_eq!(
+            controller
+                .confirmation_height(land_open.account())
+                .await
+                .unwrap(),
+            Some(ConfirmationHeight::new(1, land_open_hash.clone()))
+        );
+        assert!(controller.cement(&gen_send_hash).await.unwrap().is_empty());
+        assert!(controller.cement(&genesis_hash).await.unwrap().is_empty());
+
+        // Cemented blocks stay in the ledger.
+        assert!(controller.rollback(&gen_send_hash).await.is_err());
+        assert!(controller.rollback(&land_open_hash).await.is_err());
+        assert_eq!(
+            controller
+                .get_latest_block(land_open.account())
+                .await
+                .unwrap(),
+            Some(land_open)
+        );
+    }
+
+    #[tokio::test]
+    async fn process_blocks_out_of_order() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let gen_send = genesis_send(network);
+        let land_open = landing_open();
+        let land_send = landing_send();
+        let hash = |block: &Block| block.hash().unwrap().to_owned();
+        let holder = |block: &Block| block.to_holder().unwrap();
+
+        // A tampered block doesn't match its signature.
+        let mut tampered = holder(&gen_send);
+        if let BlockHolder::Send(send) = &mut tampered {
+            send.balance = Rai::zero();
+        }
+        assert!(controller
+            .process_block(&tampered, BlockOrigin::Bootstrap)
+            .await
+            .is_err());
+
+        // Blocks without enough work, or signed by someone else, aren't kept waiting.
+        let mut no_work = holder(&land_send);
+        if let BlockHolder::Send(send) = &mut no_work {
+            send.work = Some(Work::zero());
+        }
+        let mut forged = holder(&land_open);
+        if let BlockHolder::Open(open) = &mut forged {
+            open.signature = Some(Signature::zero());
+        }
+        for block in &[no_work, forged] {
+            assert!(controller
+                .process_block(block, BlockOrigin::Bootstrap)
+                .await
+                .is_err());
+        }
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .unchecked_count()
+                .await
+                .unwrap(),
+            0
+        );
+
+        // Pulled blocks arrive newest first, so they wait for the blocks they depend on.
+        assert_eq!(
+            controller
+                .process_block(&holder(&land_send), BlockOrigin::Bootstrap)
+                .await
+                .unwrap(),
+            ProcessResult::GapPrevious(hash(&land_open))
+        );
+        assert_eq!(
+            controller
+                .process_block(&holder(&land_open), BlockOrigin::Bootstrap)
+                .await
+                .unwrap(),
+            ProcessResult::GapSource(hash(&gen_send))
+        );
+        let state = controller.state.clone();
+        let unchecked_count = || async { state.lock().await.unchecked_count().await.unwrap() };
+        assert_eq!(unchecked_count().await, 2);
+
+        assert_eq!(
+            controller
+                .process_block(&holder(&gen_send), BlockOrigin::Bootstrap)
+                .await
+                .unwrap(),
+            ProcessResult::Progress
+        );
+        assert_eq!(unchecked_count().await, 0);
+        assert_eq!(
+            controller
+                .get_latest_block(land_open.account())
+                .await
+                .unwrap(),
+            Some(land_send.clone())
+        );
+        assert_eq!(
+            controller
+                .process_block(&holder(&gen_send), BlockOrigin::Bootstrap)
+                .await
+                .unwrap(),
+            ProcessResult::Old
+        );
+
+        // Live blocks need to be elected, and blocks waiting on them are processed once they are.
+        let mut controller = empty_lattice(network).await;
+        let voter = Private::random();
+        let mut batch = StateBatch::new();
+        batch.set_rep_weight(&voter.to_public().unwrap(), &Rai::max());
+        controller.state.lock().await.commit(batch).await.unwrap();
+
+        assert_eq!(
+            controller
+                .process_block(&holder(&land_open), BlockOrigin::Live)
+                .await
+                .unwrap(),
+            ProcessResult::GapSource(hash(&gen_send))
+        );
+        assert_eq!(
+            controller
+                .process_block(&holder(&gen_send), BlockOrigin::Live)
+                .await
+                .unwrap(),
+            ProcessResult::Election
+        );
+        let header = Header::new(network, MessageType::ConfirmAck, Extensions::new());
+        let vote = signed_vote(&voter, 1, &[hash(&gen_send)]);
+        controller.handle_confirm_ack(&header, vote).await.unwrap();
+        assert_eq!(
+            controller
+                .get_latest_block(gen_send.account())
+                .await
+                .unwrap(),
+            Some(gen_send.clone())
+        );
+        assert_eq!(
+            controller.active_elections().await.unwrap(),
+            vec![land_open.root()]
+        );
+    }
+
+    /// Decode a message sent to the server and handle it.
+    async fn handle_sent_frontier_req(server: &mut Controller, data: Vec<u8>) {
+        let header = Header::deserialize(None, &data[0..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::FrontierReq);
+        let req = FrontierReq::deserialize(Some(&header), &data[Header::LEN..]).unwrap();
+        server.handle_frontier_req(&header, req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_floods_to_channels() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let channels = Channels::new();
+        let (peer_tx, mut peer_rx) = mpsc::channel::<Packet>(10);
+        let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), DEFAULT_PORT));
+        channels.add(peer_addr, peer_tx).await;
+        let (rep_tx, mut rep_rx) = mpsc::channel::<Packet>(10);
+        let rep_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 5), DEFAULT_PORT));
+        channels.add(rep_addr, rep_tx).await;
+        let rep = Private::random().to_public().unwrap();
+        channels.set_representative(&rep, rep_addr).await;
+        controller.set_channels(channels);
+
+        // The open block can't be published before the send it receives from.
+        assert!(controller.publish(&landing_open()).await.is_err());
+        assert!(sent_data(&mut peer_rx).await.is_empty());
+        assert_eq!(
+            controller
+                .state
+                .lock()
+                .await
+                .unchecked_count()
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(controller.publish(&network.genesis_block()).await.is_err());
+
+        let gen_send = genesis_send(network);
+        let hash = controller.publish(&gen_send).await.unwrap();
+        assert_eq!(&hash, gen_send.hash().unwrap());
+        assert_eq!(
+            controller.active_elections().await.unwrap(),
+            vec![gen_send.root()]
+        );
+
+        let data = sent_data(&mut peer_rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::Publish);
+        let publish = Publish::deserialize(Some(&header), &data[Header::LEN..]).unwrap();
+        assert_eq!(&publish.0.hash().unwrap(), gen_send.hash().unwrap());
+
+        // With two channels both get the flood, and the representative is asked to vote too.
+        let rep_data = sent_data(&mut rep_rx).await;
+        assert_eq!(&rep_data[..data.len()], data.as_slice());
+        let rep_data = &rep_data[data.len()..];
+        let header = Header::deserialize(None, &rep_data[..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::ConfirmReq);
+        match ConfirmReq::deserialize(Some(&header), &rep_data[Header::LEN..]).unwrap() {
+            ConfirmReq::BlockSelector(block) => {
+                assert_eq!(&block.hash().unwrap(), gen_send.hash().unwrap())
+            }
+            confirm_req => panic!("Unexpected {:?}", confirm_req),
+        }
+
+        // Nobody voted for it.
+        assert!(!controller
+            .wait_for_confirmation(&hash, Duration::from_millis(10))
+            .await
+            .unwrap());
+        assert!(controller
+            .wait_for_confirmation(
+                network.genesis_block().hash().unwrap(),
+                Duration::from_millis(10)
+            )
+            .await
+            .unwrap());
+    }
+
+    /// Decode the votes the controller has sent so far.
+    async fn sent_votes(rx: &mut Receiver<Packet>) -> Vec<ConfirmAck> {
+        let data = sent_data(rx).await;
+        let mut votes = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let header = Header::deserialize(None, &data[offset..offset + Header::LEN]).unwrap();
+            assert_eq!(header.message_type(), MessageType::ConfirmAck);
+            offset += Header::LEN;
+            let len = ConfirmAck::len(Some(&header)).unwrap();
+            votes
+                .push(ConfirmAck::deserialize(Some(&header), &data[offset..offset + len]).unwrap());
+            offset += len;
+        }
+        votes
+    }
+
+    #[tokio::test]
+    async fn vote_for_confirm_req() {
+        let network = Network::Live;
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        let genesis = network.genesis_block();
+        let gen_send = genesis_send(network);
+        controller.add_elected_block(&gen_send).await.unwrap();
+        let hash = |n: u8| BlockHash::try_from([n; BlockHash::LEN].as_ref()).unwrap();
+        let genesis_account = BlockHash::try_from(genesis.account().as_bytes()).unwrap();
+
+        let confirm_req = || {
+            ConfirmReq::ConfirmReqByHash(vec![
+                RootHashPair::new(
+                    gen_send.hash().unwrap().to_owned(),
+                    genesis.hash().unwrap().to_owned(),
+                ),
+                // A fork of `gen_send`, which lost to it.
+                RootHashPair::new(hash(1), genesis.hash().unwrap().to_owned()),
+                // A fork of the genesis block, by its account as the root.
+                RootHashPair::new(hash(2), genesis_account.to_owned()),
+                // Nothing is known about this one.
+                RootHashPair::new(hash(3), hash(4)),
+            ])
+        };
+        let header = Header::new(network, MessageType::ConfirmReq, confirm_req().extensions());
+
+        // Only representatives vote.
+        controller
+            .handle_confirm_req(&header, ConfirmReq::ConfirmReqByHash(vec![]))
+            .await
+            .unwrap();
+        assert!(sent_votes(&mut rx).await.is_empty());
+
+        let private = Private::random();
+        let representative = Representative::new(private.clone());
+        controller.config.representative = Some(representative.clone());
+        controller
+            .handle_confirm_req(&header, confirm_req())
+            .await
+            .unwrap();
+        let votes = sent_votes(&mut rx).await;
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].account, private.to_public().unwrap());
+        assert!(votes[0].verify_signature().is_ok());
+        assert_eq!(
+            votes[0].hashes().unwrap(),
+            vec![
+                gen_send.hash().unwrap().to_owned(),
+                genesis.hash().unwrap().to_owned()
+            ]
+        );
+
+        // Asking again, through any channel, gets the same vote instead of a new one.
+        controller
+            .handle_confirm_req(&header, confirm_req())
+            .await
+            .unwrap();
+        let again = sent_votes(&mut rx).await;
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].signature, votes[0].signature);
+
+        // Another block on a root that was just voted for isn't voted for yet.
+        let fork = (genesis.hash().unwrap().to_owned(), hash(5));
+        assert!(representative.votes(&[fork]).await.unwrap().is_empty());
+
+        // A new block is only a candidate of its election, which it leads.
+        let land_open = landing_open();
+        let block_selector = ConfirmReq::BlockSelector(land_open.to_holder().unwrap());
+        let header = Header::new(
+            network,
+            MessageType::ConfirmReq,
+            block_selector.extensions(),
+        );
+        controller
+            .handle_confirm_req(&header, block_selector)
+            .await
+            .unwrap();
+        assert!(controller
+            .state
+            .lock()
+            .await
+            .get_block_by_hash(land_open.hash().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+        let votes = sent_votes(&mut rx).await;
+        assert_eq!(votes.len(), 1);
+        assert_eq!(
+            votes[0].hashes().unwrap(),
+            vec![land_open.hash().unwrap().to_owned()]
+        );
+
+        // Votes are batched.
+        let winners: Vec<(BlockHash, BlockHash)> = (10..23).map(|i| (hash(i), hash(i))).collect();
+        let votes = representative.votes(&winners).await.unwrap();
+        assert_eq!(votes.len(), 2);
+        let hashes: Vec<BlockHash> = winners.into_iter().map(|(_, hash)| hash).collect();
+        assert_eq!(votes[0].hashes().unwrap(), hashes[..12].to_vec());
+        assert_eq!(votes[1].hashes().unwrap(), hashes[12..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn learn_peers_from_keepalive() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let peer = SocketAddr::from_str("1.2.3.4:7075").unwrap();
+        let keepalive = Keepalive::new(vec![
+            Peer::from(peer),
+            // Can't be connected to.
+            Peer::from(SocketAddr::from_str("[::]:7075").unwrap()),
+            Peer::from(SocketAddr::from_str("5.6.7.8:0").unwrap()),
+        ]);
+        let header = Header::new(network, MessageType::Keepalive, Extensions::new());
+        controller
+            .handle_keepalive(&header, keepalive)
+            .await
+            .unwrap();
+
+        let peers = controller.state.lock().await.peers().await.unwrap();
+        assert_eq!(peers.into_iter().collect::<Vec<_>>(), vec![peer]);
+    }
+
+    #[tokio::test]
+    async fn cap_peers_from_keepalive() {
+        let network = Network::Live;
+        let mut controller = empty_lattice(network).await;
+        let known: Vec<SocketAddr> = (0..messages::MAX_PEERS - 1)
+            .map(|n| SocketAddr::from(([10, 0, (n / 256) as u8, (n % 256) as u8], 7075)))
+            .collect();
+        controller
+            .state
+            .lock()
+            .await
+            .add_peers(known)
+            .await
+            .unwrap();
+
+        let keepalive = Keepalive::new(vec![
+            Peer::from(SocketAddr::from_str("1.2.3.4:7075").unwrap()),
+            Peer::from(SocketAddr::from_str("5.6.7.8:7075").unwrap()),
+        ]);
+        let header = Header::new(network, MessageType::Keepalive, Extensions::new());
+        controller
+            .handle_keepalive(&header, keepalive)
+            .await
+            .unwrap();
+
+        let state = controller.state.lock().await;
+        assert_eq!(state.peer_count().await.unwrap(), messages::MAX_PEERS);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_node_id() {
+        let network = Network::Live;
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        let node_id = controller.node_key().await.unwrap().to_public().unwrap();
+        let header = Header::new(network, MessageType::Handshake, *Extensions::new().query());
+
+        // Every connection is answered with the same node ID.
+        for _ in 0..2 {
+            let cookie = Cookie::random();
+            let handshake = Handshake {
+                query: Some(HandshakeQuery::new(cookie.clone())),
+                response: None,
+            };
+            controller
+                .handle_handshake(&header, handshake)
+                .await
+                .unwrap();
+
+            let data = sent_data(&mut rx).await;
+            let response_header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+            assert!(response_header.ext().is_response());
+            let response =
+                HandshakeResponse::deserialize(Some(&response_header), &data[Header::LEN..])
+                    .unwrap();
+            assert_eq!(response.public, node_id);
+            assert!(node_id
+                .verify(cookie.as_bytes(), &response.signature)
+                .is_ok());
+        }
+    }
+
+    /// Answer the handshake query the controller sends with the node ID of `private`.
+    async fn prove_node_id(
+        controller: &mut Controller,
+        rx: &mut Receiver<Packet>,
+        private: &Private,
+    ) -> anyhow::Result<()> {
+        controller.send_handshake().await?;
+        let data = sent_data(rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN])?;
+        let query = HandshakeQuery::deserialize(Some(&header), &data[Header::LEN..])?;
+
+        let signature = private.sign(query.cookie().as_bytes())?;
+        let handshake = Handshake {
+            query: None,
+            response: Some(HandshakeResponse::new(private.to_public()?, signature)),
+        };
+        let header = Header::new(
+            controller.network,
+            MessageType::Handshake,
+            *Extensions::new().response(),
+        );
+        controller.handle_handshake(&header, handshake).await
+    }
+
+    #[tokio::test]
+    async fn refuse_messages_before_node_id() {
+        let network = Network::Live;
+        let header = Header::new(network, MessageType::Keepalive, Extensions::new());
+        let mut keepalive = header.serialize();
+        keepalive.extend(Keepalive::new(vec![]).serialize());
+
+        let (mut controller, tx, _rx) = empty_lattice_with_channels(network).await;
+        tx.send(Packet::new(keepalive.clone())).await.unwrap();
+        assert!(controller.recv_message().await.is_err());
+
+        let (mut controller, tx, mut rx) = empty_lattice_with_channels(network).await;
+        let channels = Channels::new();
+        controller.set_channels(channels.clone());
+        let peer = Private::random();
+        prove_node_id(&mut controller, &mut rx, &peer)
+            .await
+            .unwrap();
+        assert_eq!(controller.peer_node_id(), Some(&peer.to_public().unwrap()));
+        assert!(channels.contains(controller.peer_addr()).await);
+
+        tx.send(Packet::new(keepalive)).await.unwrap();
+        controller.recv_message().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_node_ids() {
+        let network = Network::Live;
+        let peer = Private::random();
+        let response_header = Header::new(
+            network,
+            MessageType::Handshake,
+            *Extensions::new().response(),
+        );
+        let response = |cookie: &Cookie| Handshake {
+            query: None,
+            response: Some(HandshakeResponse::new(
+                peer.to_public().unwrap(),
+                peer.sign(cookie.as_bytes()).unwrap(),
+            )),
+        };
+
+        // Signed with a cookie we didn't send.
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        controller.send_handshake().await.unwrap();
+        sent_data(&mut rx).await;
+        assert!(controller
+            .handle_handshake(&response_header, response(&Cookie::random()))
+            .await
+            .is_err());
+
+        // Answered too late.
+        let cookie = Cookie::random();
+        controller
+            .state
+            .lock()
+            .await
+            .set_cookie(
+                controller.peer_addr,
+                cookie.clone(),
+                SystemTime::now() - COOKIE_TIMEOUT * 2,
+            )
+            .await
+            .unwrap();
+        assert!(controller
+            .handle_handshake(&response_header, response(&cookie))
+            .await
+            .is_err());
+
+        // Cookies can't be answered twice.
+        let cookie = Cookie::random();
+        controller
+            .state
+            .lock()
+            .await
+            .set_cookie(controller.peer_addr, cookie.clone(), SystemTime::now())
+            .await
+            .unwrap();
+        controller
+            .handle_handshake(&response_header, response(&cookie))
+            .await
+            .unwrap();
+        assert!(controller
+            .handle_handshake(&response_header, response(&cookie))
+            .await
+            .is_err());
+
+        // Connected to ourselves.
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        let node_key = controller.node_key().await.unwrap();
+        assert!(prove_node_id(&mut controller, &mut rx, &node_key)
+            .await
+            .is_err());
+        assert!(controller.peer_node_id().is_none());
+    }
+
+    #[tokio::test]
+    async fn close_duplicate_node_ids() {
+        let network = Network::Live;
+        let state: ArcState = Arc::new(Mutex::new(MemoryState::new(network)));
+        let channels = Channels::new();
+        let peer = Private::random();
+        let connect = |n| {
+            let peer_addr =
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, n), DEFAULT_PORT));
+            let (mut controller, _tx, rx) =
+                Controller::new_with_channels(network, state.clone(), peer_addr);
+            controller.set_channels(channels.clone());
+            (controller, rx)
+        };
+
+        let (mut first, mut first_rx) = connect(1);
+        prove_node_id(&mut first, &mut first_rx, &peer)
+            .await
+            .unwrap();
+        let (mut second, mut second_rx) = connect(2);
+        assert!(prove_node_id(&mut second, &mut second_rx, &peer)
+            .await
+            .is_err());
+        assert!(!channels.contains(second.peer_addr()).await);
+
+        // Once the first channel is gone, the node can connect again.
+        channels.remove(first.peer_addr()).await;
+        let (mut third, mut third_rx) = connect(3);
+        prove_node_id(&mut third, &mut third_rx, &peer)
+            .await
+            .unwrap();
+        let mut socket_addrs = state
+            .lock()
+            .await
+            .socket_addrs_for_node_id(&peer.to_public().unwrap())
+            .await
+            .unwrap();
+        socket_addrs.sort();
+        assert_eq!(socket_addrs, vec![*first.peer_addr(), *third.peer_addr()]);
+    }
+
+    #[tokio::test]
+    async fn answer_telemetry_req() {
+        let network = Network::Live;
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        let header = Header::new(network, MessageType::TelemetryReq, Extensions::new());
+        controller
+            .handle_telemetry_req(&header, TelemetryReq)
+            .await
+            .unwrap();
+
+        let data = sent_data(&mut rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::TelemetryAck);
+        assert_eq!(
+            TelemetryAck::len(Some(&header)).unwrap(),
+            data.len() - Header::LEN
+        );
+        let telemetry = TelemetryAck::deserialize(Some(&header), &data[Header::LEN..]).unwrap();
+        telemetry.verify_signature().unwrap();
+        let node_id = controller.node_key().await.unwrap().to_public().unwrap();
+        assert_eq!(telemetry.node_id, node_id);
+        assert_eq!(telemetry.block_count, 1);
+        assert_eq!(telemetry.cemented_count, 1);
+        assert_eq!(telemetry.account_count, 1);
+        assert_eq!(telemetry.peer_count, 0);
+        assert_eq!(telemetry.genesis_block, network.genesis_hash());
+        assert_eq!(telemetry.maker, TELEMETRY_MAKER);
+    }
+
+    #[tokio::test]
+    async fn store_verified_telemetry() {
+        let network = Network::Live;
+        let (mut controller, _tx, mut rx) = empty_lattice_with_channels(network).await;
+        let peer = Private::random();
+        prove_node_id(&mut controller, &mut rx, &peer)
+            .await
+            .unwrap();
+
+        // Telemetry is requested once the peer has proven its node ID.
+        let data = sent_data(&mut rx).await;
+        let header = Header::deserialize(None, &data).unwrap();
+        assert_eq!(header.message_type(), MessageType::TelemetryReq);
+
+        async fn stored(controller: &Controller) -> Vec<(TelemetryAck, SystemTime)> {
+            let state = controller.state.lock().await;
+            state.telemetry(SystemTime::UNIX_EPOCH).await.unwrap()
+        }
+
+        // Signed by our node ID instead of the peer's.
+        let mut telemetry = controller.telemetry().await.unwrap();
+        let ext = telemetry.extensions(controller.header.version_using());
+        let header = Header::new(network, MessageType::TelemetryAck, ext);
+        controller
+            .handle_telemetry_ack(&header, telemetry.clone())
+            .await
+            .unwrap();
+        assert!(stored(&controller).await.is_empty());
+
+        // Changed after it was signed.
+        telemetry.sign(&peer).unwrap();
+        telemetry.block_count += 1;
+        controller
+            .handle_telemetry_ack(&header, telemetry.clone())
+            .await
+            .unwrap();
+        assert!(stored(&controller).await.is_empty());
+
+        // From another network.
+        let mut other_network = telemetry.clone();
+        other_network.genesis_block = BlockHash::zero();
+        other_network.sign(&peer).unwrap();
+        controller
+            .handle_telemetry_ack(&header, other_network)
+            .await
+            .unwrap();
+        assert!(stored(&controller).await.is_empty());
+
+        // Created too long ago.
+        let mut old = telemetry.clone();
+        old.timestamp -= TELEMETRY_MAX_AGE.as_millis() as u64 * 2;
+        old.sign(&peer).unwrap();
+        controller.handle_telemetry_ack(&header, old).await.unwrap();
+        assert!(stored(&controller).await.is_empty());
+
+        telemetry.sign(&peer).unwrap();
+        controller
+            .handle_telemetry_ack(&header, telemetry.clone())
+            .await
+            .unwrap();
+        let stored = stored(&controller).await;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0.node_id, peer.to_public().unwrap());
+        assert_eq!(stored[0].0.block_count, telemetry.block_count);
+    }
+
+    #[tokio::test]
+    async fn empty_telemetry() {
+        let network = Network::Live;
+        let (mut controller, tx, mut rx) = empty_lattice_with_channels(network).await;
+        let peer = Private::random();
+        prove_node_id(&mut controller, &mut rx, &peer)
+            .await
+            .unwrap();
+
+        // No payload follows, so the next message is read as usual.
+        let header = Header::new(network, MessageType::TelemetryAck, Extensions::new());
+        let mut data = header.serialize();
+        let header = Header::new(network, MessageType::Keepalive, Extensions::new());
+        data.extend(header.serialize());
+        data.extend(Keepalive::new(vec![]).serialize());
+        tx.send(Packet::new(data)).await.unwrap();
+        controller.recv_message().await.unwrap();
+        controller.recv_message().await.unwrap();
+        let state = controller.state.lock().await;
+        assert!(state
+            .telemetry(SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap()
+            .is_empty());
+        drop(state);
+
+        let header = Header::new(
+            network,
+            MessageType::TelemetryAck,
+            *Extensions::new().set_telemetry_size(TelemetryAck::LEN - 1),
+        );
+        tx.send(Packet::new(header.serialize())).await.unwrap();
+        assert!(controller.recv_message().await.is_err());
+    }
+
+    /// A handshake query from a peer that supports `versions`.
+    fn handshake_query(network: Network, versions: &VersionRange) -> Vec<u8> {
+        let mut header = Header::new(network, MessageType::Handshake, *Extensions::new().query());
+        header.set_versions(versions, versions.max);
+        let mut data = header.serialize();
+        data.extend(HandshakeQuery::new(Cookie::random()).serialize());
+        data
+    }
+
+    #[tokio::test]
+    async fn negotiate_version() {
+        let network = Network::Live;
+        let (mut controller, tx, mut rx) = empty_lattice_with_channels(network).await;
+        assert_eq!(controller.peer_version(), None);
+
+        // A newer peer talks to us in our newest version.
+        let newer = VersionRange::new(Version::V18, Version(21)).unwrap();
+        tx.send(Packet::new(handshake_query(network, &newer)))
+            .await
+            .unwrap();
+        controller.recv_message().await.unwrap();
+        let ours = *controller.versions();
+        assert_eq!(controller.peer_version(), Some(ours.max));
+        let data = sent_data(&mut rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.version_using(), ours.max);
+        assert_eq!(header.version_min(), ours.min);
+
+        // Too old for us.
+        let (mut controller, tx, _rx) = empty_lattice_with_channels(network).await;
+        let older = VersionRange::new(Version::V17, Version::V17).unwrap();
+        tx.send(Packet::new(handshake_query(network, &older)))
+            .await
+            .unwrap();
+        assert!(controller.recv_message().await.is_err());
+
+        // Unless the node is configured to support it.
+        let (mut controller, tx, _rx) = empty_lattice_with_channels(network).await;
+        controller.set_config(ControllerConfig {
+            versions: VersionRange::new(Version::V17, Version::V19).unwrap(),
+            ..ControllerConfig::default()
+        });
+        tx.send(Packet::new(handshake_query(network, &older)))
+            .await
+            .unwrap();
+        controller.recv_message().await.unwrap();
+        assert_eq!(controller.peer_version(), Some(Version::V17));
+    }
+
+    #[tokio::test]
+    async fn telemetry_for_old_version() {
+        let network = Network::Live;
+        let (mut controller, tx, mut rx) = empty_lattice_with_channels(network).await;
+        controller.set_versions(VersionRange::new(Version::V17, Version::V19).unwrap());
+        let older = VersionRange::new(Version::V17, Version::V17).unwrap();
+        tx.send(Packet::new(handshake_query(network, &older)))
+            .await
+            .unwrap();
+        controller.recv_message().await.unwrap();
+        assert_eq!(controller.peer_version(), Some(Version::V17));
+        sent_data(&mut rx).await;
+
+        // Telemetry had a fixed length before V18, which isn't in the header.
+        let header = Header::new(network, MessageType::TelemetryReq, Extensions::new());
+        controller
+            .handle_telemetry_req(&header, TelemetryReq)
+            .await
+            .unwrap();
+        let data = sent_data(&mut rx).await;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.version_using(), Version::V17);
+        assert_eq!(header.ext().telemetry_size(), 0);
+        assert_eq!(data.len(), Header::LEN + TelemetryAck::LEN);
+        TelemetryAck::deserialize(Some(&header), &data[Header::LEN..])
+            .unwrap()
+            .verify_signature()
+            .unwrap();
     }
 }
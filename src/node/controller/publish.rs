@@ -0,0 +1,107 @@
+use super::{BlockOrigin, Controller};
+use crate::blocks::{Block, BlockHash};
+use crate::node::controller::block_processor::ProcessResult;
+use crate::node::header::MessageType;
+use crate::node::messages::confirm_req::ConfirmReq;
+use crate::node::messages::publish::Publish;
+use crate::node::wire::Wire;
+use crate::Public;
+use anyhow::{anyhow, Context};
+use std::cmp::Reverse;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tracing::info;
+
+/// How often the ledger is checked while waiting for a published block to be confirmed.
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many representatives are asked to vote for a published block straight away.
+const CONFIRM_REQ_REPRESENTATIVES: usize = 10;
+
+impl Controller {
+    /// Publish a signed and worked block that was created locally, e.g. by a wallet.
+    ///
+    /// The block is processed like a live block from the network, so it has to fit on the ledger
+    /// and an election is started for it. Only then it's flooded to the other peers, and the
+    /// representatives with the most weight are asked to vote for it.
+    pub async fn publish(&mut self, block: &Block) -> anyhow::Result<BlockHash> {
+        let context = || format!("Publish {:?}", block);
+        let holder = block.to_holder().with_context(context)?;
+        let hash = holder.hash().with_context(context)?;
+
+        // Unlike blocks from peers, a gap is an error instead of waiting in the unchecked store.
+        if let Some(gap) = self.gap(&holder).await.with_context(context)? {
+            return Err(anyhow!("Block depends on a missing block: {:?}", gap))
+                .with_context(context);
+        }
+        match self
+            .process_block(&holder, BlockOrigin::Live)
+            .await
+            .with_context(context)?
+        {
+            ProcessResult::Progress | ProcessResult::Election => {}
+            ProcessResult::Old => {
+                return Err(anyhow!("Block is already in the ledger")).with_context(context)
+            }
+            result => {
+                return Err(anyhow!("Block was not accepted: {:?}", result)).with_context(context)
+            }
+        }
+
+        let publish = Publish::new(holder.clone());
+        let mut header = self.header;
+        header.reset(MessageType::Publish, publish.extensions());
+        let mut data = header.serialize();
+        data.extend(publish.serialize());
+        let peers = self.channels.flood(&data).await;
+        info!("Published {:?} to {:?}", hash, peers);
+
+        // The block comes with the request, in case the flood hasn't reached the representative.
+        let confirm_req = ConfirmReq::BlockSelector(holder);
+        header.reset(MessageType::ConfirmReq, confirm_req.extensions());
+        let mut data = header.serialize();
+        data.extend(confirm_req.serialize());
+        let reps = self.top_representatives().await.with_context(context)?;
+        let peers = self.channels.send_to_representatives(&reps, &data).await;
+        info!("Requested votes for {:?} from {:?}", hash, peers);
+
+        Ok(hash)
+    }
+
+    /// The representatives with the most weight that votes have come in from on the channels.
+    async fn top_representatives(&self) -> anyhow::Result<Vec<Public>> {
+        let mut reps = vec![];
+        for rep in self.channels.representatives().await {
+            let weight = self.rep_weight(&rep).await?;
+            reps.push((rep, weight));
+        }
+        reps.sort_by_key(|(_, weight)| Reverse(weight.to_u128()));
+        Ok(reps
+            .into_iter()
+            .take(CONFIRM_REQ_REPRESENTATIVES)
+            .map(|(rep, _)| rep)
+            .collect())
+    }
+
+    /// Wait until a block has been cemented, returning false if it hasn't been by `timeout`.
+    pub async fn wait_for_confirmation(
+        &self,
+        hash: &BlockHash,
+        timeout: Duration,
+    ) -> anyhow::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self
+                .is_cemented(hash)
+                .await
+                .with_context(|| format!("Wait for confirmation of {:?}", hash))?
+            {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            sleep(CONFIRMATION_POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use super::Controller;
+use crate::blocks::Block;
+use crate::node::state::{DynState, StateBatch};
+use crate::{Public, Rai};
+use anyhow::{anyhow, Context};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// How recently a representative needs to have voted to count towards the online weight.
+pub const ONLINE_WEIGHT_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+impl Controller {
+    /// The voting weight delegated to a representative.
+    pub async fn rep_weight(&self, representative: &Public) -> anyhow::Result<Rai> {
+        self.state
+            .lock()
+            .await
+            .rep_weight(representative)
+            .await
+            .with_context(|| format!("Rep weight for {:?}", representative))
+    }
+
+    /// The total weight of representatives that have voted within the `ONLINE_WEIGHT_PERIOD`.
+    pub async fn online_weight(&self) -> anyhow::Result<Rai> {
+        let context = || "Online weight";
+        let state = self.state.lock().await;
+        let since = SystemTime::now()
+            .checked_sub(ONLINE_WEIGHT_PERIOD)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        let mut total = Rai::zero();
+        for rep in state.online_reps(since).await.with_context(context)? {
+            let weight = state.rep_weight(&rep).await.with_context(context)?;
+            total = total
+                .checked_add(&weight)
+                .ok_or_else(|| anyhow!("Online weight overflow"))?;
+        }
+        Ok(total)
+    }
+
+    /// The `count` representatives with the most weight, heaviest first.
+    pub async fn top_reps(&self, count: usize) -> anyhow::Result<Vec<(Public, Rai)>> {
+        let mut weights = self
+            .state
+            .lock()
+            .await
+            .rep_weights()
+            .await
+            .context("Top reps")?;
+        weights.sort_by(|(a_rep, a), (b_rep, b)| {
+            b.to_u128().cmp(&a.to_u128()).then_with(|| a_rep.cmp(b_rep))
+        });
+        weights.truncate(count);
+        Ok(weights)
+    }
+}
+
+/// Stage the representative weight changes caused by the head of an account changing from `from`
+/// to `to`, e.g. when adding a block after `from`, or rolling back `from` to `to`.
+///
+/// The balance of `from` is taken away from its representative, and the balance of `to` is given
+/// to its representative. This covers every block type, since a `Block` always has the
+/// representative and balance of the account after the block. Weights already staged in `batch`
+/// are built on.
+pub(super) async fn stage_rep_weights(
+    state: &DynState,
+    batch: &mut StateBatch,
+    from: Option<&Block>,
+    to: Option<&Block>,
+) -> anyhow::Result<()> {
+    let context = || format!("Rep weights from {:?} to {:?}", from, to);
+    let mut weights: HashMap<Public, Rai> = HashMap::new();
+
+    if let Some(from) = from {
+        let rep = from.representative();
+        let weight = staged_rep_weight(state, batch, rep)
+            .await
+            .with_context(context)?;
+        let weight = weight
+            .checked_sub(from.balance())
+            .ok_or_else(|| anyhow!("Rep weight underflow for {:?}", rep))
+            .with_context(context)?;
+        weights.insert(rep.to_owned(), weight);
+    }
+
+    if let Some(to) = to {
+        let rep = to.representative();
+        let weight = match weights.get(rep) {
+            Some(weight) => weight.to_owned(),
+            None => staged_rep_weight(state, batch, rep)
+                .await
+                .with_context(context)?,
+        };
+        let weight = weight
+            .checked_add(to.balance())
+            .ok_or_else(|| anyhow!("Rep weight overflow for {:?}", rep))
+            .with_context(context)?;
+        weights.insert(rep.to_owned(), weight);
+    }
+
+    for (rep, weight) in weights {
+        batch.set_rep_weight(&rep, &weight);
+    }
+    Ok(())
+}
+
+async fn staged_rep_weight(
+    state: &DynState,
+    batch: &StateBatch,
+    representative: &Public,
+) -> anyhow::Result<Rai> {
+    match batch.rep_weight(representative) {
+        Some(weight) => Ok(weight.to_owned()),
+        None => state.rep_weight(representative).await,
+    }
+}
@@ -0,0 +1,166 @@
+use super::Controller;
+use crate::blocks::BlockHash;
+use crate::node::header::MessageType;
+use crate::node::messages::confirm_ack::{Confirm, ConfirmAck};
+use crate::node::messages::confirm_req::RootHashPair;
+use crate::node::timestamp::Timestamp;
+use crate::Private;
+use anyhow::Context;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// The most hashes the reference node puts in a single vote.
+pub const MAX_VOTE_HASHES: usize = 12;
+
+/// How long a vote for a root is sent again instead of signing a new one. A different block on the
+/// same root isn't voted for until then either, so the representative doesn't flip between forks.
+pub const VOTE_SPACING: Duration = Duration::from_secs(15);
+
+/// The representative a node votes as. Clones share the votes that have been cast, so every channel
+/// answers requests for the same roots with the same votes.
+#[derive(Debug, Clone)]
+pub struct Representative {
+    private: Private,
+    votes: Arc<Mutex<VoteCache>>,
+}
+
+/// Votes cast within `VOTE_SPACING`.
+#[derive(Debug, Default)]
+struct VoteCache {
+    /// Root -> the block voted for and the vote.
+    by_root: HashMap<BlockHash, (BlockHash, Arc<ConfirmAck>)>,
+
+    /// When each root was voted for, oldest first.
+    cast: VecDeque<(Instant, BlockHash)>,
+}
+
+impl Representative {
+    pub fn new(private: Private) -> Self {
+        Self {
+            private,
+            votes: Arc::new(Mutex::new(VoteCache::default())),
+        }
+    }
+
+    /// Votes for `winners`, as pairs of a root and the block that won it. A root that was voted
+    /// for recently gets the same vote again, or nothing if the vote was for another block. The
+    /// other winners are signed in new votes, with up to `MAX_VOTE_HASHES` hashes in each.
+    pub async fn votes(
+        &self,
+        winners: &[(BlockHash, BlockHash)],
+    ) -> anyhow::Result<Vec<Arc<ConfirmAck>>> {
+        let now = Instant::now();
+        let mut cache = self.votes.lock().await;
+        cache.expire(now);
+
+        let mut votes: Vec<Arc<ConfirmAck>> = vec![];
+        let mut unvoted = vec![];
+        for (root, hash) in winners {
+            match cache.by_root.get(root) {
+                Some((voted, vote)) if voted == hash => {
+                    if !votes.iter().any(|v| Arc::ptr_eq(v, vote)) {
+                        votes.push(vote.clone());
+                    }
+                }
+                Some((voted, _)) => {
+                    debug!(
+                        "Not voting for {:?} yet, voted for {:?} instead",
+                        hash, voted
+                    )
+                }
+                None => unvoted.push((root, hash)),
+            }
+        }
+
+        for batch in unvoted.chunks(MAX_VOTE_HASHES) {
+            let hashes = batch.iter().map(|(_, hash)| (*hash).to_owned()).collect();
+            let vote = Arc::new(ConfirmAck::sign(
+                &self.private,
+                Timestamp::now(),
+                Confirm::VoteByHash(hashes),
+            )?);
+            for (root, hash) in batch {
+                cache
+                    .by_root
+                    .insert((*root).to_owned(), ((*hash).to_owned(), vote.clone()));
+                cache.cast.push_back((now, (*root).to_owned()));
+            }
+            votes.push(vote);
+        }
+        Ok(votes)
+    }
+}
+
+impl VoteCache {
+    fn expire(&mut self, now: Instant) {
+        while let Some((cast, root)) = self.cast.front() {
+            if now.duration_since(*cast) < VOTE_SPACING {
+                break;
+            }
+            self.by_root.remove(root);
+            self.cast.pop_front();
+        }
+    }
+}
+
+impl Controller {
+    /// Vote for the winners of the requested roots, if the node is running as a representative.
+    pub async fn vote_for_requested(&mut self, pairs: &[RootHashPair]) -> anyhow::Result<()> {
+        let representative = match &self.config.representative {
+            Some(representative) => representative.to_owned(),
+            None => return Ok(()),
+        };
+
+        let context = || format!("Vote for requested {:?}", pairs);
+        let mut winners = vec![];
+        for pair in pairs {
+            // The winner is the block in the ledger built on the root. That's the requested
+            // block if it's in the ledger, otherwise the block it forks with, if any. Without
+            // one, it's whichever candidate of the root's election is leading.
+            let successor = self
+                .state
+                .lock()
+                .await
+                .successor(&pair.root)
+                .await
+                .with_context(context)?;
+            let winner = match successor {
+                Some(successor) => Some(successor),
+                None => self
+                    .leading_candidate(&pair.root)
+                    .await
+                    .with_context(context)?,
+            };
+            if let Some(winner) = winner {
+                let winner = (pair.root.to_owned(), winner);
+                if !winners.contains(&winner) {
+                    winners.push(winner);
+                }
+            }
+        }
+
+        for vote in representative.votes(&winners).await.with_context(context)? {
+            debug!("Voting for {:?}", vote.confirm);
+            self.send_header(MessageType::ConfirmAck, vote.extensions())
+                .await
+                .with_context(context)?;
+            self.send(&*vote).await.with_context(context)?;
+        }
+        Ok(())
+    }
+
+    /// The candidate with the most votes in the active election of `root`, if there is one.
+    async fn leading_candidate(&self, root: &BlockHash) -> anyhow::Result<Option<BlockHash>> {
+        let candidates = self.state.lock().await.election_candidates(root).await?;
+        let hashes = candidates
+            .iter()
+            .map(|block| block.hash().map(|hash| hash.to_owned()))
+            .collect::<anyhow::Result<Vec<BlockHash>>>()?;
+        let tally = self.tally_votes(&hashes).await?;
+        Ok(tally.into_iter().next().map(|(hash, _)| hash))
+    }
+}
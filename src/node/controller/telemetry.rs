@@ -0,0 +1,81 @@
+use super::Controller;
+use crate::node::header::{Extensions, MessageType};
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::Signature;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::trace;
+
+/// Identifies feeless in the `maker` field of telemetry. The reference node uses 0 and 1.
+pub const TELEMETRY_MAKER: u8 = 0xfe;
+
+/// When the node started, for the uptime in telemetry.
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Start counting the uptime of the node, otherwise it starts at the first telemetry request.
+pub fn start_uptime() {
+    Lazy::force(&STARTED);
+}
+
+impl Controller {
+    /// Ask the peer for its telemetry. The request has no payload.
+    pub async fn send_telemetry_req(&mut self) -> anyhow::Result<()> {
+        trace!("Sending telemetry req");
+        self.send_header(MessageType::TelemetryReq, Extensions::new())
+            .await
+    }
+
+    /// The telemetry of this node, signed with its node key.
+    pub async fn telemetry(&self) -> anyhow::Result<TelemetryAck> {
+        let context = || "Creating telemetry";
+        let private = self.node_key().await.with_context(context)?;
+        let (block_count, cemented_count, unchecked_count, account_count) = {
+            let state = self.state.lock().await;
+            (
+                state.block_count().await.with_context(context)?,
+                state.cemented_count().await.with_context(context)?,
+                state.unchecked_count().await.with_context(context)?,
+                state.account_count().await.with_context(context)?,
+            )
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut telemetry = TelemetryAck {
+            signature: Signature::zero(),
+            node_id: private.to_public().with_context(context)?,
+            block_count: block_count as u64,
+            cemented_count,
+            unchecked_count: unchecked_count as u64,
+            account_count: account_count as u64,
+            bandwidth_cap: 0,
+            uptime: STARTED.elapsed().as_secs(),
+            peer_count: self.channels.len().await as u32,
+            protocol_version: self.header.version_using().0,
+            genesis_block: self.network.genesis_hash(),
+            major_version: version_number(env!("CARGO_PKG_VERSION_MAJOR")),
+            minor_version: version_number(env!("CARGO_PKG_VERSION_MINOR")),
+            patch_version: version_number(env!("CARGO_PKG_VERSION_PATCH")),
+            prerelease_version: version_number(
+                env!("CARGO_PKG_VERSION_PRE")
+                    .rsplit('.')
+                    .next()
+                    .unwrap_or(""),
+            ),
+            maker: TELEMETRY_MAKER,
+            timestamp,
+            active_difficulty: self.network.work_threshold().as_u64(),
+            unknown_data: vec![],
+        };
+        telemetry.sign(&private).with_context(context)?;
+        Ok(telemetry)
+    }
+}
+
+/// A part of the crate version, or zero if it isn't a number, e.g. for "alpha".
+fn version_number(s: &str) -> u8 {
+    s.parse().unwrap_or(0)
+}
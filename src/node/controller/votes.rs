@@ -0,0 +1,109 @@
+use super::Controller;
+use crate::blocks::BlockHash;
+use crate::node::messages::confirm_ack::ConfirmAck;
+use crate::node::timestamp::Timestamp;
+use crate::{Public, Rai};
+use anyhow::{anyhow, Context};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tracing::{debug, instrument};
+
+impl Controller {
+    /// Validate and store a vote from a representative. Returns false if the vote was ignored,
+    /// because the representative has no weight for it to count with.
+    ///
+    /// Only votes for candidates of active elections are stored, until the election is removed.
+    /// A newer vote from the same representative replaces an older one for the same block.
+    #[instrument(skip(self))]
+    pub async fn add_vote(&mut self, confirm_ack: &ConfirmAck) -> anyhow::Result<bool> {
+        let context = || format!("Adding vote {:?}", &confirm_ack);
+        self.validate_vote(confirm_ack).with_context(context)?;
+
+        let mut state = self.state.lock().await;
+        let weight = state
+            .rep_weight(&confirm_ack.account)
+            .await
+            .with_context(context)?;
+        if weight == Rai::zero() {
+            debug!(
+                "Ignoring vote from {:?} without weight",
+                confirm_ack.account
+            );
+            return Ok(false);
+        }
+
+        for hash in confirm_ack.hashes().with_context(context)? {
+            if state
+                .election_root(&hash)
+                .await
+                .with_context(context)?
+                .is_none()
+            {
+                continue;
+            }
+            state
+                .add_vote(&hash, &confirm_ack.account, &confirm_ack.timestamp)
+                .await
+                .with_context(context)?;
+        }
+        state
+            .add_online_rep(&confirm_ack.account, SystemTime::now())
+            .await
+            .with_context(context)?;
+        drop(state);
+        self.channels
+            .set_representative(&confirm_ack.account, self.peer_addr)
+            .await;
+
+        Ok(true)
+    }
+
+    pub fn validate_vote(&self, confirm_ack: &ConfirmAck) -> anyhow::Result<()> {
+        confirm_ack
+            .verify_signature()
+            .with_context(|| format!("Invalid vote from {:?}", confirm_ack.account))
+    }
+
+    /// Tally the votes for competing blocks, heaviest first.
+    ///
+    /// Each representative only counts towards the candidate of its latest vote, weighted by the
+    /// balance delegated to it. Candidates without any votes are included with a zero weight.
+    pub async fn tally_votes(
+        &self,
+        candidates: &[BlockHash],
+    ) -> anyhow::Result<Vec<(BlockHash, Rai)>> {
+        let context = || format!("Tally votes for {:?}", candidates);
+        let state = self.state.lock().await;
+
+        let mut latest: HashMap<Public, (Timestamp, &BlockHash)> = HashMap::new();
+        for hash in candidates {
+            for (rep, timestamp) in state.votes(hash).await.with_context(context)? {
+                match latest.get(&rep) {
+                    Some((existing, _)) if existing >= &timestamp => {}
+                    _ => {
+                        latest.insert(rep, (timestamp, hash));
+                    }
+                }
+            }
+        }
+
+        let mut tally: Vec<(BlockHash, Rai)> = candidates
+            .iter()
+            .map(|hash| (hash.to_owned(), Rai::zero()))
+            .collect();
+        for (rep, (_, hash)) in latest {
+            let weight = state.rep_weight(&rep).await.with_context(context)?;
+            let (_, total) = tally
+                .iter_mut()
+                .find(|(candidate, _)| candidate == hash)
+                .expect("candidate is in tally");
+            *total = total
+                .checked_add(&weight)
+                .ok_or_else(|| anyhow!("Tally overflow"))
+                .with_context(context)?;
+        }
+        tally.sort_by_key(|(_, weight)| Reverse(weight.to_u128()));
+        Ok(tally)
+    }
+}
@@ -33,7 +33,8 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn validate(&self, network: &Network) -> anyhow::Result<()> {
+    /// Check that the peer is on our network, and that it supports a protocol version we do.
+    pub fn validate(&self, network: &Network, versions: &VersionRange) -> anyhow::Result<()> {
         if &self.network != network {
             return Err(anyhow!(
                 "network mismatch: They're on {:?}. We're on {:?}",
@@ -42,7 +43,20 @@ impl Header {
             ));
         }
 
-        // TODO: Check versions.
+        if self.version_max < versions.min {
+            return Err(anyhow!(
+                "version too old: Their maximum is {:?}. Our minimum is {:?}",
+                self.version_max,
+                versions.min,
+            ));
+        }
+        if self.version_min > versions.max {
+            return Err(anyhow!(
+                "version too new: Their minimum is {:?}. Our maximum is {:?}",
+                self.version_min,
+                versions.max,
+            ));
+        }
 
         Ok(())
     }
@@ -64,18 +78,28 @@ impl Header {
     const MESSAGE_TYPE: usize = 5;
     const EXTENSIONS: usize = 6;
 
+    /// A header that advertises the default version range, using the newest version.
     pub fn new(network: Network, message_type: MessageType, ext: Extensions) -> Self {
+        let versions = VersionRange::default();
         Self {
             magic_number: MagicNumber::new(),
             network,
-            version_max: Version::V18,
-            version_using: Version::V18,
-            version_min: Version::V18,
+            version_max: versions.max,
+            version_using: versions.max,
+            version_min: versions.min,
             message_type,
             ext,
         }
     }
 
+    /// Advertise `versions`, while using `using` for the messages this header is sent with.
+    pub fn set_versions(&mut self, versions: &VersionRange, using: Version) -> &mut Self {
+        self.version_max = versions.max;
+        self.version_using = using;
+        self.version_min = versions.min;
+        self
+    }
+
     pub fn reset(&mut self, message_type: MessageType, ext: Extensions) -> &mut Self {
         self.message_type = message_type;
         self.ext = ext;
@@ -86,6 +110,18 @@ impl Header {
         self.message_type
     }
 
+    pub fn version_max(&self) -> Version {
+        self.version_max
+    }
+
+    pub fn version_using(&self) -> Version {
+        self.version_using
+    }
+
+    pub fn version_min(&self) -> Version {
+        self.version_min
+    }
+
     pub fn ext(&self) -> Extensions {
         self.ext
     }
@@ -96,9 +132,9 @@ impl Wire for Header {
         vec![
             self.magic_number.0,
             self.network as u8,
-            self.version_max as u8,
-            self.version_using as u8,
-            self.version_min as u8,
+            self.version_max.0,
+            self.version_using.0,
+            self.version_min.0,
             self.message_type as u8,
             self.ext.0[0],
             self.ext.0[1],
@@ -118,7 +154,11 @@ impl Wire for Header {
         let ext =
             Extensions::try_from(&data[Self::EXTENSIONS..Self::EXTENSIONS + Extensions::LEN])?;
 
-        Ok(Header::new(network, message_type, ext))
+        let mut header = Header::new(network, message_type, ext);
+        header.version_max = Version(data[Self::VERSION_MAX]);
+        header.version_using = Version(data[Self::VERSION_USING]);
+        header.version_min = Version(data[Self::VERSION_MIN]);
+        Ok(header)
     }
 
     fn len(_: Option<&Header>) -> anyhow::Result<usize> {
@@ -155,11 +195,50 @@ impl TryFrom<u8> for MagicNumber {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(u8)]
-pub enum Version {
-    V18 = 18,
-    V19 = 19,
+/// A protocol version. Unknown versions are kept as is, since newer peers can still talk to us
+/// in a version we support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u8);
+
+impl Version {
+    /// The first version with telemetry.
+    pub const V17: Self = Self(17);
+
+    /// Telemetry acks have their length in the header, so that fields can be added.
+    pub const V18: Self = Self(18);
+
+    pub const V19: Self = Self(19);
+}
+
+/// The protocol versions a node supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: Version,
+    pub max: Version,
+}
+
+impl VersionRange {
+    pub fn new(min: Version, max: Version) -> anyhow::Result<Self> {
+        if min > max {
+            return Err(anyhow!("Minimum version {:?} is above maximum {:?}", min, max));
+        }
+        Ok(Self { min, max })
+    }
+
+    /// The version to use with a peer, which is the newest one we both support. The peer's
+    /// header has to be validated against this range first.
+    pub fn negotiate(&self, header: &Header) -> Version {
+        self.max.min(header.version_max)
+    }
+}
+
+impl Default for VersionRange {
+    fn default() -> Self {
+        Self {
+            min: Version::V18,
+            max: Version::V19,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -213,10 +292,13 @@ impl Extensions {
     // Bit offsets and lengths
     const QUERY: usize = 0;
     const RESPONSE: usize = 1;
+    const COUNT_PRESENT: usize = 0;
     const ITEM_COUNT: usize = 12;
     const ITEM_COUNT_BITS: usize = 4;
     const BLOCK_TYPE: usize = 8;
     const BLOCK_TYPE_BITS: usize = 4;
+    const TELEMETRY_SIZE: usize = 0;
+    const TELEMETRY_SIZE_BITS: usize = 10;
 
     pub fn new() -> Self {
         Self([0, 0])
@@ -240,16 +322,53 @@ impl Extensions {
         self.bits()[Self::RESPONSE]
     }
 
+    /// Used by bulk pull to indicate that there is an extended count field in the payload.
+    pub fn count_present(&mut self) -> &mut Self {
+        self.mut_bits().set(Self::COUNT_PRESENT, true);
+        self
+    }
+
+    pub fn is_count_present(&self) -> bool {
+        self.bits()[Self::COUNT_PRESENT]
+    }
+
     pub fn item_count(&self) -> usize {
         self.bits()[Self::ITEM_COUNT..Self::ITEM_COUNT + Self::ITEM_COUNT_BITS].load_be()
     }
 
+    /// The amount of hashes in a confirm req or confirm ack, which can be at most 15.
+    pub fn set_item_count(&mut self, count: usize) -> &mut Self {
+        debug_assert!(count < 1 << Self::ITEM_COUNT_BITS);
+        self.mut_bits()[Self::ITEM_COUNT..Self::ITEM_COUNT + Self::ITEM_COUNT_BITS]
+            .store_be(count as u8);
+        self
+    }
+
     pub fn block_type(&self) -> anyhow::Result<BlockType> {
         self.bits()[Self::BLOCK_TYPE..Self::BLOCK_TYPE + Self::BLOCK_TYPE_BITS]
             .load_be::<u8>()
             .try_into()
     }
 
+    pub fn set_block_type(&mut self, block_type: BlockType) -> &mut Self {
+        self.mut_bits()[Self::BLOCK_TYPE..Self::BLOCK_TYPE + Self::BLOCK_TYPE_BITS]
+            .store_be(block_type.as_u8());
+        self
+    }
+
+    /// The length of a telemetry ack payload, which is zero when the peer has nothing to send.
+    pub fn telemetry_size(&self) -> usize {
+        self.bits()[Self::TELEMETRY_SIZE..Self::TELEMETRY_SIZE + Self::TELEMETRY_SIZE_BITS]
+            .load_le::<u16>() as usize
+    }
+
+    pub fn set_telemetry_size(&mut self, size: usize) -> &mut Self {
+        debug_assert!(size < 1 << Self::TELEMETRY_SIZE_BITS);
+        self.mut_bits()[Self::TELEMETRY_SIZE..Self::TELEMETRY_SIZE + Self::TELEMETRY_SIZE_BITS]
+            .store_le(size as u16);
+        self
+    }
+
     fn bits(&self) -> &BitSlice<Lsb0, u8> {
         self.0.view_bits()
     }
@@ -302,7 +421,7 @@ mod tests {
         let h1 = Header::new(network, MessageType::Keepalive, ext);
         let s = h1.serialize();
         assert_eq!(s.len(), Header::LEN);
-        assert_eq!(s, vec![0x52, 0x43, 18, 18, 18, 2, 3, 0]);
+        assert_eq!(s, vec![0x52, 0x43, 19, 19, 18, 2, 3, 0]);
 
         let h2 = Header::deserialize(None, &s).unwrap();
         assert_eq!(h1, h2);
@@ -338,7 +457,7 @@ mod tests {
     fn bad_network() {
         let s = vec![0x52, 0x43, 18, 18, 18, 2, 3, 0];
         let header = Header::deserialize(None, &s).unwrap();
-        let result = header.validate(&Network::Test);
+        let result = header.validate(&Network::Test, &VersionRange::default());
         assert_contains_err(result, "network mismatch");
     }
 
@@ -364,4 +483,58 @@ mod tests {
             assert_eq!(ext.item_count() as u8, *expected);
         }
     }
+
+    #[test]
+    fn block_type() {
+        for block_type in &[BlockType::NotABlock, BlockType::Send, BlockType::State] {
+            let ext = *Extensions::new().set_block_type(block_type.to_owned());
+            assert_eq!(&ext.block_type().unwrap(), block_type);
+        }
+
+        // Matches the block type mask of 0x0f00 used by the nano node.
+        let ext = *Extensions::new().set_block_type(BlockType::State);
+        assert_eq!(ext.0, [0x00, 0x06]);
+    }
+
+    #[test]
+    fn telemetry_size() {
+        let ext = *Extensions::new().set_telemetry_size(202);
+        assert_eq!(ext.telemetry_size(), 202);
+
+        // Matches the telemetry size mask of 0x03ff used by the nano node.
+        assert_eq!(ext.0, [0xca, 0x00]);
+        let ext = Extensions::try_from([0xff, 0xff].as_ref()).unwrap();
+        assert_eq!(ext.telemetry_size(), 0x3ff);
+    }
+
+    #[test]
+    fn versions() {
+        let versions = VersionRange::default();
+        let mut header = Header::new(Network::Live, MessageType::Keepalive, Extensions::new());
+        let decoded = Header::deserialize(None, &header.serialize()).unwrap();
+        assert_eq!(decoded.version_max(), versions.max);
+        assert_eq!(decoded.version_using(), versions.max);
+        assert_eq!(decoded.version_min(), versions.min);
+
+        // A newer peer that still supports our versions.
+        let newer = VersionRange::new(Version::V18, Version(21)).unwrap();
+        header.set_versions(&newer, Version(21));
+        let decoded = Header::deserialize(None, &header.serialize()).unwrap();
+        assert_eq!(decoded.version_using(), Version(21));
+        decoded.validate(&Network::Live, &versions).unwrap();
+        assert_eq!(versions.negotiate(&decoded), versions.max);
+
+        let older = VersionRange::new(Version::V17, Version::V17).unwrap();
+        header.set_versions(&older, Version::V17);
+        assert_contains_err(header.validate(&Network::Live, &versions), "version too old");
+        let relaxed = VersionRange::new(Version::V17, Version::V19).unwrap();
+        header.validate(&Network::Live, &relaxed).unwrap();
+        assert_eq!(relaxed.negotiate(&header), Version::V17);
+
+        let only_newer = VersionRange::new(Version(20), Version(21)).unwrap();
+        header.set_versions(&only_newer, Version(21));
+        assert_contains_err(header.validate(&Network::Live, &versions), "version too new");
+
+        assert!(VersionRange::new(Version::V19, Version::V18).is_err());
+    }
 }
@@ -0,0 +1,112 @@
+use crate::network::Network;
+use crate::node::channel::{network_channel, Channels};
+use crate::node::controller::ControllerConfig;
+use crate::node::state::ArcState;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+/// Accept connections from peers and run a channel for each, until the node stops. While
+/// `max_inbound` peers are connected, any other connection is closed straight away.
+pub async fn listen(
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
+    listener: TcpListener,
+    max_inbound: usize,
+) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening for peers on {}", addr),
+        Err(err) => warn!("Listening for peers on an unknown address: {:?}", err),
+    }
+    let inbound = Arc::new(AtomicUsize::new(0));
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Could not accept a connection: {:?}", err);
+                continue;
+            }
+        };
+        // Only this loop adds connections, so the count can't go over between here and the add.
+        if inbound.load(Ordering::SeqCst) >= max_inbound {
+            debug!(
+                "Refusing {}, there are already {} inbound connections",
+                peer_addr, max_inbound
+            );
+            continue;
+        }
+        inbound.fetch_add(1, Ordering::SeqCst);
+
+        info!("Accepted a channel from {}", peer_addr);
+        let state = state.clone();
+        let channels = channels.clone();
+        let config = config.clone();
+        let inbound = inbound.clone();
+        tokio::spawn(async move {
+            // A peer going away is normal, so don't take the node down with it.
+            if let Err(err) = network_channel(network, state, channels, config, stream, false).await
+            {
+                warn!("Error in channel from {}: {:?}", peer_addr, err);
+            }
+            inbound.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::header::Header;
+    use crate::node::state::MemoryState;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn max_inbound() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listen(
+            network,
+            state,
+            Channels::new(),
+            ControllerConfig::default(),
+            listener,
+            1,
+        ));
+
+        // The node handshakes with a peer that connects to it.
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut header = [0u8; Header::LEN];
+        first.read_exact(&mut header).await.unwrap();
+
+        // A second peer is over the limit.
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0u8; Header::LEN];
+        let read = timeout(Duration::from_secs(1), second.read(&mut buffer))
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap(), 0);
+
+        // Once the first peer leaves, there is room again.
+        drop(first);
+        let mut third = None;
+        for _ in 0..100 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            if let Ok(Ok(Header::LEN)) =
+                timeout(Duration::from_millis(100), stream.read(&mut buffer)).await
+            {
+                third = Some(stream);
+                break;
+            }
+        }
+        assert!(third.is_some());
+    }
+}
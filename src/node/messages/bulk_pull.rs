@@ -0,0 +1,202 @@
+use crate::blocks::BlockHash;
+use crate::bytes::Bytes;
+use crate::node::header::Header;
+use crate::node::wire::Wire;
+use crate::{expect_len, hex_formatter, Public};
+use anyhow::{anyhow, Context};
+use std::convert::TryFrom;
+
+/// Requests the blocks of an account chain, starting from the account's frontier (or a specific
+/// block) and going backwards until `end` is reached.
+///
+/// The response is a stream of blocks without any headers, each prefixed by its block type, and
+/// terminated with a `BlockType::NotABlock`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkPull {
+    /// Either an account or a block hash.
+    pub start: HashOrAccount,
+
+    /// The block to stop at, which is not sent. When zero, the whole chain is requested.
+    pub end: BlockHash,
+
+    /// Maximum amount of blocks to send back. Only sent when the count present flag is set in
+    /// the header.
+    pub count: Option<u32>,
+}
+
+impl BulkPull {
+    pub const LEN: usize = HashOrAccount::LEN + BlockHash::LEN;
+
+    /// The length of the extended parameters when the count present flag is set.
+    pub const EXTENDED_LEN: usize = 8;
+
+    pub fn new(start: HashOrAccount, end: BlockHash, count: Option<u32>) -> Self {
+        Self { start, end, count }
+    }
+
+    /// Pull a whole account chain.
+    pub fn account(account: &Public) -> Self {
+        Self::new(HashOrAccount::from(account), BlockHash::zero(), None)
+    }
+}
+
+impl Wire for BulkPull {
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::LEN + Self::EXTENDED_LEN);
+        v.extend_from_slice(self.start.as_bytes());
+        v.extend_from_slice(self.end.as_bytes());
+        if let Some(count) = self.count {
+            // The first byte of the extended parameters is always zero.
+            let mut extended = [0u8; Self::EXTENDED_LEN];
+            extended[1..5].copy_from_slice(&count.to_le_bytes());
+            v.extend_from_slice(&extended);
+        }
+        v
+    }
+
+    fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        debug_assert!(header.is_some());
+        let header = header.unwrap();
+        let context = || "Deserialize bulk pull".to_string();
+
+        let mut bytes = Bytes::new(data);
+        let start =
+            HashOrAccount::try_from(bytes.slice(HashOrAccount::LEN)?).with_context(context)?;
+        let end = BlockHash::try_from(bytes.slice(BlockHash::LEN)?).with_context(context)?;
+
+        let count = if header.ext().is_count_present() {
+            let extended = bytes.slice(Self::EXTENDED_LEN).with_context(context)?;
+            if extended[0] != 0 {
+                return Err(anyhow!(
+                    "Invalid extended parameters in bulk pull: {:?}",
+                    extended
+                ));
+            }
+            let mut s32 = [0u8; 4];
+            s32.copy_from_slice(&extended[1..5]);
+            Some(u32::from_le_bytes(s32))
+        } else {
+            None
+        };
+
+        Ok(Self { start, end, count })
+    }
+
+    fn len(header: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        debug_assert!(header.is_some());
+        if header.unwrap().ext().is_count_present() {
+            Ok(Self::LEN + Self::EXTENDED_LEN)
+        } else {
+            Ok(Self::LEN)
+        }
+    }
+}
+
+/// Either an account or a block hash. We can only tell which by looking it up in the ledger.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HashOrAccount([u8; HashOrAccount::LEN]);
+
+impl HashOrAccount {
+    pub const LEN: usize = 32;
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_account(&self) -> Public {
+        Public::try_from(self.as_bytes()).expect("HashOrAccount to account")
+    }
+
+    pub fn to_block_hash(&self) -> BlockHash {
+        BlockHash::try_from(self.as_bytes()).expect("HashOrAccount to block hash")
+    }
+}
+
+impl From<&Public> for HashOrAccount {
+    fn from(account: &Public) -> Self {
+        Self::try_from(account.as_bytes()).expect("Public to HashOrAccount")
+    }
+}
+
+impl From<&BlockHash> for HashOrAccount {
+    fn from(hash: &BlockHash) -> Self {
+        Self::try_from(hash.as_bytes()).expect("BlockHash to HashOrAccount")
+    }
+}
+
+impl TryFrom<&[u8]> for HashOrAccount {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        expect_len(value.len(), Self::LEN, "Hash or account")?;
+        let mut s = Self([0u8; Self::LEN]);
+        s.0.copy_from_slice(value);
+        Ok(s)
+    }
+}
+
+impl std::fmt::Debug for HashOrAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HashOrAccount(")?;
+        hex_formatter(f, &self.0)?;
+        write!(f, ")")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::header::{Extensions, MessageType};
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let network = Network::Live;
+        let account =
+            Public::from_str("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA")
+                .unwrap();
+        let end =
+            BlockHash::from_str("991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948")
+                .unwrap();
+
+        let header = Header::new(network, MessageType::BulkPull, Extensions::new());
+        let bulk_pull = BulkPull::new(HashOrAccount::from(&account), end.clone(), None);
+        let data = bulk_pull.serialize();
+        assert_eq!(data.len(), BulkPull::len(Some(&header)).unwrap());
+        let decoded = BulkPull::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(decoded, bulk_pull);
+        assert_eq!(decoded.start.to_account(), account);
+
+        let header = Header::new(
+            network,
+            MessageType::BulkPull,
+            *Extensions::new().count_present(),
+        );
+        let bulk_pull = BulkPull::new(HashOrAccount::from(&account), end, Some(0x01020304));
+        let data = bulk_pull.serialize();
+        assert_eq!(data.len(), BulkPull::len(Some(&header)).unwrap());
+        assert_eq!(&data[64..], &[0, 4, 3, 2, 1, 0, 0, 0]);
+        let decoded = BulkPull::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(decoded, bulk_pull);
+    }
+
+    #[test]
+    fn bad_extended_parameters() {
+        let header = Header::new(
+            Network::Live,
+            MessageType::BulkPull,
+            *Extensions::new().count_present(),
+        );
+        let mut data = vec![0u8; BulkPull::LEN + BulkPull::EXTENDED_LEN];
+        data[BulkPull::LEN] = 1;
+        assert!(BulkPull::deserialize(Some(&header), &data).is_err());
+    }
+}
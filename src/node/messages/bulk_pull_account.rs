@@ -0,0 +1,296 @@
+use crate::blocks::BlockHash;
+use crate::bytes::Bytes;
+use crate::node::header::Header;
+use crate::node::wire::Wire;
+use crate::{Public, Rai};
+use anyhow::{anyhow, Context};
+use std::convert::TryFrom;
+
+/// Requests the frontier of an account, and the sends to it that haven't been received yet.
+///
+/// The response is a [BulkPullAccountFrontier] followed by a stream of [BulkPullAccountEntry],
+/// without any headers, terminated by a zeroed entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkPullAccount {
+    pub account: Public,
+
+    /// Receivable entries below this amount are not sent.
+    pub minimum_amount: Rai,
+
+    pub flags: BulkPullAccountFlags,
+}
+
+impl BulkPullAccount {
+    pub const LEN: usize = Public::LEN + Rai::LEN + 1;
+
+    pub fn new(account: Public, minimum_amount: Rai, flags: BulkPullAccountFlags) -> Self {
+        Self {
+            account,
+            minimum_amount,
+            flags,
+        }
+    }
+}
+
+impl Wire for BulkPullAccount {
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.account.as_bytes());
+        v.extend_from_slice(&self.minimum_amount.to_vec());
+        v.push(self.flags as u8);
+        v
+    }
+
+    fn deserialize(_: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let context = || "Deserialize bulk pull account".to_string();
+        let mut bytes = Bytes::new(data);
+
+        let account = Public::try_from(bytes.slice(Public::LEN)?).with_context(context)?;
+        let minimum_amount = Rai::try_from(bytes.slice(Rai::LEN)?).with_context(context)?;
+        let flags = BulkPullAccountFlags::try_from(bytes.slice(1)?[0]).with_context(context)?;
+
+        Ok(Self::new(account, minimum_amount, flags))
+    }
+
+    fn len(_: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        Ok(Self::LEN)
+    }
+}
+
+/// Which fields are sent for each receivable entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BulkPullAccountFlags {
+    HashAndAmount = 0,
+    AddressOnly = 1,
+    HashAmountAndAddress = 2,
+}
+
+impl BulkPullAccountFlags {
+    pub fn has_hash_and_amount(&self) -> bool {
+        self != &Self::AddressOnly
+    }
+
+    pub fn has_address(&self) -> bool {
+        self != &Self::HashAndAmount
+    }
+
+    /// The length of a single [BulkPullAccountEntry] with these flags.
+    pub fn entry_len(&self) -> usize {
+        let mut len = 0;
+        if self.has_hash_and_amount() {
+            len += BlockHash::LEN + Rai::LEN;
+        }
+        if self.has_address() {
+            len += Public::LEN;
+        }
+        len
+    }
+}
+
+impl TryFrom<u8> for BulkPullAccountFlags {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use BulkPullAccountFlags::*;
+        Ok(match value {
+            0 => HashAndAmount,
+            1 => AddressOnly,
+            2 => HashAmountAndAddress,
+            v => return Err(anyhow!("Unknown bulk pull account flags: {}", v)),
+        })
+    }
+}
+
+/// The first part of the response to a [BulkPullAccount]. Zeroed if the account doesn't exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkPullAccountFrontier {
+    pub frontier_hash: BlockHash,
+    pub balance: Rai,
+}
+
+impl BulkPullAccountFrontier {
+    pub const LEN: usize = BlockHash::LEN + Rai::LEN;
+
+    pub fn new(frontier_hash: BlockHash, balance: Rai) -> Self {
+        Self {
+            frontier_hash,
+            balance,
+        }
+    }
+}
+
+impl Wire for BulkPullAccountFrontier {
+    fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.frontier_hash.as_bytes());
+        v.extend_from_slice(&self.balance.to_vec());
+        v
+    }
+
+    fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        debug_assert!(header.is_none());
+        let context = || "Deserialize bulk pull account frontier".to_string();
+        let mut bytes = Bytes::new(data);
+
+        let frontier_hash =
+            BlockHash::try_from(bytes.slice(BlockHash::LEN)?).with_context(context)?;
+        let balance = Rai::try_from(bytes.slice(Rai::LEN)?).with_context(context)?;
+
+        Ok(Self::new(frontier_hash, balance))
+    }
+
+    fn len(_: Option<&Header>) -> anyhow::Result<usize>
+    where
+        Self: Sized,
+    {
+        Ok(Self::LEN)
+    }
+}
+
+/// A single receivable send in the response to a [BulkPullAccount].
+///
+/// The encoding depends on the [BulkPullAccountFlags] of the request, so this doesn't implement
+/// `Wire`. Fields that aren't sent are zeroed when decoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkPullAccountEntry {
+    /// The hash of the send block.
+    pub hash: BlockHash,
+
+    pub amount: Rai,
+
+    /// The account that sent the funds.
+    pub source: Public,
+}
+
+impl BulkPullAccountEntry {
+    pub fn new(hash: BlockHash, amount: Rai, source: Public) -> Self {
+        Self {
+            hash,
+            amount,
+            source,
+        }
+    }
+
+    /// The zeroed entry marking the end of the stream.
+    pub fn end() -> Self {
+        let source = Public::try_from([0u8; Public::LEN].as_ref()).expect("zero public");
+        Self::new(BlockHash::zero(), Rai::zero(), source)
+    }
+
+    pub fn is_end(&self) -> bool {
+        self == &Self::end()
+    }
+
+    pub fn serialize(&self, flags: BulkPullAccountFlags) -> Vec<u8> {
+        let mut v = Vec::with_capacity(flags.entry_len());
+        if flags.has_hash_and_amount() {
+            v.extend_from_slice(self.hash.as_bytes());
+            v.extend_from_slice(&self.amount.to_vec());
+        }
+        if flags.has_address() {
+            v.extend_from_slice(self.source.as_bytes());
+        }
+        v
+    }
+
+    pub fn deserialize(flags: BulkPullAccountFlags, data: &[u8]) -> anyhow::Result<Self> {
+        let context = || format!("Deserialize bulk pull account entry {:?}", flags);
+        let mut bytes = Bytes::new(data);
+
+        let mut entry = Self::end();
+        if flags.has_hash_and_amount() {
+            entry.hash = BlockHash::try_from(bytes.slice(BlockHash::LEN)?).with_context(context)?;
+            entry.amount = Rai::try_from(bytes.slice(Rai::LEN)?).with_context(context)?;
+        }
+        if flags.has_address() {
+            entry.source = Public::try_from(bytes.slice(Public::LEN)?).with_context(context)?;
+        }
+        Ok(entry)
+    }
+}
+
+/// A complete response to a [BulkPullAccount].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkPullAccountResponse {
+    pub frontier: BulkPullAccountFrontier,
+    pub entries: Vec<BulkPullAccountEntry>,
+}
+
+impl BulkPullAccountResponse {
+    pub fn new(frontier: BulkPullAccountFrontier) -> Self {
+        Self {
+            frontier,
+            entries: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let account =
+            Public::from_str("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA")
+                .unwrap();
+        let hash =
+            BlockHash::from_str("991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948")
+                .unwrap();
+
+        let req = BulkPullAccount::new(
+            account.clone(),
+            Rai::new(1234u128),
+            BulkPullAccountFlags::HashAmountAndAddress,
+        );
+        let data = req.serialize();
+        assert_eq!(data.len(), BulkPullAccount::LEN);
+        assert_eq!(data[BulkPullAccount::LEN - 1], 2);
+        assert_eq!(BulkPullAccount::deserialize(None, &data).unwrap(), req);
+
+        let frontier = BulkPullAccountFrontier::new(hash.clone(), Rai::max());
+        let data = frontier.serialize();
+        assert_eq!(
+            BulkPullAccountFrontier::deserialize(None, &data).unwrap(),
+            frontier
+        );
+
+        let entry = BulkPullAccountEntry::new(hash.clone(), Rai::new(5u128), account.clone());
+        for (flags, len) in &[
+            (BulkPullAccountFlags::HashAndAmount, 48),
+            (BulkPullAccountFlags::AddressOnly, 32),
+            (BulkPullAccountFlags::HashAmountAndAddress, 80),
+        ] {
+            let data = entry.serialize(*flags);
+            assert_eq!(data.len(), *len);
+            assert_eq!(data.len(), flags.entry_len());
+
+            let decoded = BulkPullAccountEntry::deserialize(*flags, &data).unwrap();
+            assert!(!decoded.is_end());
+            assert_eq!(flags.has_hash_and_amount(), decoded.hash == hash);
+            assert_eq!(flags.has_address(), decoded.source == account);
+
+            let end = BulkPullAccountEntry::end().serialize(*flags);
+            assert!(BulkPullAccountEntry::deserialize(*flags, &end)
+                .unwrap()
+                .is_end());
+        }
+    }
+
+    #[test]
+    fn bad_flags() {
+        let mut data = vec![0u8; BulkPullAccount::LEN];
+        data[BulkPullAccount::LEN - 1] = 3;
+        assert!(BulkPullAccount::deserialize(None, &data).is_err());
+    }
+}
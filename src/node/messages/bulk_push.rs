@@ -0,0 +1,26 @@
+use crate::node::header::Header;
+use crate::node::wire::Wire;
+
+/// Pushes blocks to a peer that it is missing.
+///
+/// There is no payload. The header is followed by a stream of blocks without any headers, each
+/// prefixed by its block type, and terminated with a `BlockType::NotABlock`. There is no response.
+#[derive(Debug)]
+pub struct BulkPush;
+
+impl Wire for BulkPush {
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn deserialize(_: Option<&Header>, _data: &[u8]) -> Result<Self, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self {})
+    }
+
+    fn len(_: Option<&Header>) -> Result<usize, anyhow::Error> {
+        Ok(0)
+    }
+}
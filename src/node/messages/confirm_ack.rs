@@ -1,10 +1,10 @@
-use crate::blocks::{Block, BlockHash, BlockType};
+use crate::blocks::{BlockHash, BlockHolder, BlockType};
 use crate::bytes::Bytes;
 use crate::encoding::blake2b;
-use crate::node::header::Header;
+use crate::node::header::{Extensions, Header};
 use crate::node::timestamp::Timestamp;
 use crate::node::wire::Wire;
-use crate::{Public, Signature};
+use crate::{Private, Public, Signature};
 use anyhow::Context;
 use std::convert::TryFrom;
 
@@ -23,8 +23,9 @@ pub struct ConfirmAck {
 pub enum Confirm {
     VoteByHash(Vec<BlockHash>),
 
-    // TODO: This looks like it isn't used on the live network.
-    Block(Block),
+    /// A vote for a single block, carrying the whole block. This looks like it isn't used on the
+    /// live network anymore.
+    Block(BlockHolder),
 }
 
 impl ConfirmAck {
@@ -44,35 +45,78 @@ impl ConfirmAck {
         }
     }
 
+    /// Create a vote signed by a representative.
+    pub fn sign(private: &Private, timestamp: Timestamp, confirm: Confirm) -> anyhow::Result<Self> {
+        let context = || "Sign ConfirmAck";
+        let account = private.to_public().with_context(context)?;
+        let mut confirm_ack = Self::new(account, Signature::zero(), timestamp, confirm);
+        confirm_ack.signature = private
+            .sign(&confirm_ack.inner_hash().with_context(context)?)
+            .with_context(context)?;
+        Ok(confirm_ack)
+    }
+
     pub fn verify_signature(&self) -> anyhow::Result<()> {
+        let context = || "Verify signature on ConfirmAck";
         self.account
-            .verify(&self.inner_hash(), &self.signature)
-            .context("Verify signature on ConfirmAck")
+            .verify(&self.inner_hash().with_context(context)?, &self.signature)
+            .with_context(context)
+    }
+
+    /// The hashes of the blocks being voted for.
+    pub fn hashes(&self) -> anyhow::Result<Vec<BlockHash>> {
+        Ok(match &self.confirm {
+            Confirm::VoteByHash(hashes) => hashes.to_owned(),
+            Confirm::Block(block) => vec![block.hash().context("Hash of voted block")?],
+        })
+    }
+
+    /// The header extensions that describe the payload, either the block type or the amount of
+    /// hashes voted for.
+    pub fn extensions(&self) -> Extensions {
+        let mut ext = Extensions::new();
+        match &self.confirm {
+            Confirm::VoteByHash(hashes) => ext
+                .set_block_type(BlockType::NotABlock)
+                .set_item_count(hashes.len()),
+            Confirm::Block(block) => ext.set_block_type(block.block_type()),
+        };
+        ext
     }
 
     // nano::block_hash nano::vote::hash () const
-    pub fn inner_hash(&self) -> Vec<u8> {
+    pub fn inner_hash(&self) -> anyhow::Result<Vec<u8>> {
         let mut v = Vec::new();
 
-        // TODO: Only add this prefix if there's data. See nano::vote::hash()
-        v.extend_from_slice("vote ".as_bytes());
+        // The prefix is only added when voting by hash. See nano::vote::hash()
+        if let Confirm::VoteByHash(_) = &self.confirm {
+            v.extend_from_slice("vote ".as_bytes());
+        }
 
-        if let Confirm::VoteByHash(hashes) = &self.confirm {
-            for hash in hashes {
-                v.extend_from_slice(hash.as_bytes())
-            }
-            v.extend_from_slice(&self.timestamp.to_bytes())
-        } else {
-            todo!("handle block hash");
+        for hash in self.hashes()? {
+            v.extend_from_slice(hash.as_bytes())
         }
+        v.extend_from_slice(&self.timestamp.to_bytes());
 
-        blake2b(BlockHash::LEN, &v).to_vec()
+        Ok(blake2b(BlockHash::LEN, &v).to_vec())
     }
 }
 
 impl Wire for ConfirmAck {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut v = Vec::with_capacity(Self::VOTE_COMMON_LEN);
+        v.extend_from_slice(self.account.as_bytes());
+        v.extend_from_slice(self.signature.as_bytes());
+        v.extend_from_slice(&self.timestamp.to_bytes());
+        match &self.confirm {
+            Confirm::VoteByHash(hashes) => {
+                for hash in hashes {
+                    v.extend_from_slice(hash.as_bytes());
+                }
+            }
+            Confirm::Block(block) => v.extend_from_slice(&block.serialize()),
+        }
+        v
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -95,9 +139,10 @@ impl Wire for ConfirmAck {
             }
             Confirm::VoteByHash(block_hashes)
         } else {
-            // let block = Block;
-            dbg!("block!!!!!!!", header.ext().block_type().unwrap());
-            todo!()
+            let block_data = data.slice(BlockHolder::len(Some(header))?)?;
+            let block = BlockHolder::deserialize(Some(header), block_data)
+                .context("Deserialize block in ConfirmAck")?;
+            Confirm::Block(block)
         };
 
         Ok(Self::new(account, signature, timestamp, confirm))
@@ -110,8 +155,7 @@ impl Wire for ConfirmAck {
         if header.ext().block_type()? == BlockType::NotABlock {
             Ok(Self::VOTE_COMMON_LEN + header.ext().item_count() * BlockHash::LEN)
         } else {
-            todo!("got a block in confirm ack {:#?}", header);
-            // Ok(Self::VOTE_COMMON_LEN + FullBlock::LEN)
+            Ok(Self::VOTE_COMMON_LEN + BlockHolder::len(Some(header))?)
         }
     }
 }
@@ -119,8 +163,71 @@ impl Wire for ConfirmAck {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::Network;
+    use crate::node::header::MessageType;
     use std::str::FromStr;
 
+    #[test]
+    fn serialize() {
+        let account =
+            Public::from_str("2994D330022A052DF83E10FCE1B3E140496CDCD7E0C0F2FF6DE2670291B88011")
+                .unwrap();
+        let signature = Signature::from_str("721C6CAFD61C2D7ED27643C556F77AE900308BD5AAF458E74310E42773BB45494A138EE0291B6868C360EB983AB5CE8FF2EFF6A66044CBA2B128047ACDBD4402").unwrap();
+        let hash1 =
+            BlockHash::from_str("C3A3FE56D584CB997199E3B09EC454F62DED3B7EF875D9D7E8E5011AC34C77A5")
+                .unwrap();
+        let hash2 =
+            BlockHash::from_str("139E1064D7CCC26495EFB4030015C02CE78556EBE3547192843B0E71C91599FC")
+                .unwrap();
+        let confirm_ack = ConfirmAck::new(
+            account.clone(),
+            signature,
+            Timestamp::from_u64(2019626603),
+            Confirm::VoteByHash(vec![hash1.clone(), hash2.clone()]),
+        );
+        let header = Header::new(
+            Network::Live,
+            MessageType::ConfirmAck,
+            confirm_ack.extensions(),
+        );
+        assert_eq!(header.ext().item_count(), 2);
+
+        let data = confirm_ack.serialize();
+        assert_eq!(data.len(), ConfirmAck::len(Some(&header)).unwrap());
+        assert_eq!(&data[..Public::LEN], account.as_bytes());
+        assert_eq!(
+            &data[Public::LEN + Signature::LEN..ConfirmAck::VOTE_COMMON_LEN],
+            &2019626603u64.to_le_bytes()
+        );
+        assert_eq!(
+            &data[ConfirmAck::VOTE_COMMON_LEN..],
+            [hash1.as_bytes(), hash2.as_bytes()].concat().as_slice()
+        );
+
+        let decoded = ConfirmAck::deserialize(Some(&header), &data).unwrap();
+        assert!(decoded.verify_signature().is_ok());
+        assert_eq!(decoded.serialize(), data);
+
+        // A vote carrying the whole block.
+        let block = Network::Live.genesis_block().to_holder().unwrap();
+        let confirm_ack = ConfirmAck::new(
+            account,
+            decoded.signature,
+            decoded.timestamp,
+            Confirm::Block(block),
+        );
+        let header = Header::new(
+            Network::Live,
+            MessageType::ConfirmAck,
+            confirm_ack.extensions(),
+        );
+        let data = confirm_ack.serialize();
+        assert_eq!(data.len(), ConfirmAck::len(Some(&header)).unwrap());
+        let decoded = ConfirmAck::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(decoded.hashes().unwrap(), vec![Network::Live.genesis_hash()]);
+        assert_eq!(decoded.serialize(), data);
+    }
+
     #[test]
     fn verify_sig() {
         let account =
@@ -142,4 +249,44 @@ mod tests {
         );
         assert!(confirm_ack.verify_signature().is_ok());
     }
+
+    #[test]
+    fn verify_block_vote_sig() {
+        let private = Private::random();
+        let block = Network::Live.genesis_block().to_holder().unwrap();
+        let mut confirm_ack = ConfirmAck::new(
+            private.to_public().unwrap(),
+            private.sign(b"something else").unwrap(),
+            Timestamp::from_u64(1234),
+            Confirm::Block(block.clone()),
+        );
+        assert!(confirm_ack.verify_signature().is_err());
+
+        confirm_ack.signature = private.sign(&confirm_ack.inner_hash().unwrap()).unwrap();
+        assert!(confirm_ack.verify_signature().is_ok());
+        assert_eq!(confirm_ack.hashes().unwrap(), vec![block.hash().unwrap()]);
+
+        // Block votes don't have the "vote " prefix, so signing a hash vote for the same block
+        // doesn't verify.
+        let vote_by_hash = ConfirmAck::new(
+            confirm_ack.account.clone(),
+            confirm_ack.signature.clone(),
+            Timestamp::from_u64(1234),
+            Confirm::VoteByHash(vec![block.hash().unwrap()]),
+        );
+        assert!(vote_by_hash.verify_signature().is_err());
+    }
+
+    #[test]
+    fn sign() {
+        let private = Private::random();
+        let confirm_ack = ConfirmAck::sign(
+            &private,
+            Timestamp::from_u64(2019626603),
+            Confirm::VoteByHash(vec![Network::Live.genesis_hash()]),
+        )
+        .unwrap();
+        assert_eq!(confirm_ack.account, private.to_public().unwrap());
+        assert!(confirm_ack.verify_signature().is_ok());
+    }
 }
@@ -1,7 +1,7 @@
 use crate::blocks::{BlockHash, BlockHolder, BlockType};
 use crate::bytes::Bytes;
 use crate::expect_len;
-use crate::node::header::Header;
+use crate::node::header::{Extensions, Header};
 use crate::node::wire::Wire;
 use anyhow::Context;
 use std::convert::TryFrom;
@@ -24,11 +24,34 @@ pub enum ConfirmReq {
 
 impl ConfirmReq {
     pub const CONFIRM_REQ_BY_HASH_LEN: usize = BlockHash::LEN * 2;
+
+    /// The header extensions that describe the payload, either the block type or the amount of
+    /// root hash pairs.
+    pub fn extensions(&self) -> Extensions {
+        let mut ext = Extensions::new();
+        match self {
+            Self::ConfirmReqByHash(pairs) => ext
+                .set_block_type(BlockType::NotABlock)
+                .set_item_count(pairs.len()),
+            Self::BlockSelector(block) => ext.set_block_type(block.block_type()),
+        };
+        ext
+    }
 }
 
 impl Wire for ConfirmReq {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        match self {
+            Self::ConfirmReqByHash(pairs) => {
+                let mut v = Vec::with_capacity(RootHashPair::LEN * pairs.len());
+                for pair in pairs {
+                    v.extend_from_slice(pair.hash.as_bytes());
+                    v.extend_from_slice(pair.root.as_bytes());
+                }
+                v
+            }
+            Self::BlockSelector(block) => block.serialize(),
+        }
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -90,7 +113,7 @@ impl Wire for ConfirmReq {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RootHashPair {
     pub hash: BlockHash,
     pub root: BlockHash,
@@ -98,6 +121,10 @@ pub struct RootHashPair {
 
 impl RootHashPair {
     const LEN: usize = BlockHash::LEN * 2;
+
+    pub fn new(hash: BlockHash, root: BlockHash) -> Self {
+        Self { hash, root }
+    }
 }
 
 impl TryFrom<&[u8]> for RootHashPair {
@@ -111,3 +138,57 @@ impl TryFrom<&[u8]> for RootHashPair {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::header::MessageType;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize_by_hash() {
+        let hash =
+            BlockHash::from_str("991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948")
+                .unwrap();
+        let root =
+            BlockHash::from_str("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA")
+                .unwrap();
+        let pairs = vec![
+            RootHashPair::new(hash.clone(), root.clone()),
+            RootHashPair::new(root.clone(), hash.clone()),
+        ];
+        let confirm_req = ConfirmReq::ConfirmReqByHash(pairs.clone());
+        let header = Header::new(
+            Network::Live,
+            MessageType::ConfirmReq,
+            confirm_req.extensions(),
+        );
+        assert_eq!(header.ext().item_count(), 2);
+
+        let data = confirm_req.serialize();
+        assert_eq!(data.len(), ConfirmReq::len(Some(&header)).unwrap());
+        assert_eq!(&data[..BlockHash::LEN], hash.as_bytes());
+        assert_eq!(&data[BlockHash::LEN..RootHashPair::LEN], root.as_bytes());
+        match ConfirmReq::deserialize(Some(&header), &data).unwrap() {
+            ConfirmReq::ConfirmReqByHash(decoded) => assert_eq!(decoded, pairs),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serialize_block() {
+        let network = Network::Live;
+        let confirm_req = ConfirmReq::BlockSelector(network.genesis_block().to_holder().unwrap());
+        let header = Header::new(network, MessageType::ConfirmReq, confirm_req.extensions());
+
+        let data = confirm_req.serialize();
+        assert_eq!(data.len(), ConfirmReq::len(Some(&header)).unwrap());
+        match ConfirmReq::deserialize(Some(&header), &data).unwrap() {
+            ConfirmReq::BlockSelector(block) => {
+                assert_eq!(block.hash().unwrap(), network.genesis_hash())
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
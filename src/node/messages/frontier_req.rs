@@ -4,20 +4,43 @@ use crate::node::wire::Wire;
 use crate::Public;
 use std::convert::TryFrom;
 
-#[derive(Debug)]
+/// Requests a stream of account frontiers (the latest block hash of each account), in account
+/// order, beginning at `start`.
+///
+/// The response is a stream of [FrontierResp](super::frontier_resp::FrontierResp) without any
+/// headers, terminated with a zeroed entry.
+#[derive(Debug, Clone, PartialEq)]
 pub struct FrontierReq {
-    start: Public,
-    age: u32,
-    count: u32,
+    pub start: Public,
+
+    /// Only include accounts modified within this many seconds. `u32::MAX` for every account.
+    pub age: u32,
+
+    /// Maximum amount of frontiers to send back. `u32::MAX` for no limit.
+    pub count: u32,
 }
 
 impl FrontierReq {
     pub const LEN: usize = 40;
+
+    pub fn new(start: Public, age: u32, count: u32) -> Self {
+        Self { start, age, count }
+    }
+
+    /// Request every frontier in the ledger.
+    pub fn all() -> Self {
+        let start = Public::try_from([0u8; Public::LEN].as_ref()).expect("zero public");
+        Self::new(start, u32::MAX, u32::MAX)
+    }
 }
 
 impl Wire for FrontierReq {
     fn serialize(&self) -> Vec<u8> {
-        vec![]
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.start.as_bytes());
+        v.extend_from_slice(&self.age.to_le_bytes());
+        v.extend_from_slice(&self.count.to_le_bytes());
+        v
     }
 
     fn deserialize(_: Option<&Header>, data: &[u8]) -> Result<Self, anyhow::Error>
@@ -41,3 +64,21 @@ impl Wire for FrontierReq {
         Ok(Self::LEN)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let start =
+            Public::from_str("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA")
+                .unwrap();
+        let frontier_req = FrontierReq::new(start, 1000, 5);
+        let data = frontier_req.serialize();
+        assert_eq!(data.len(), FrontierReq::LEN);
+        assert_eq!(&data[32..], &[0xe8, 0x03, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(FrontierReq::deserialize(None, &data).unwrap(), frontier_req);
+    }
+}
@@ -6,19 +6,41 @@ use crate::Public;
 use anyhow::Context;
 use std::convert::TryFrom;
 
-#[derive(Debug)]
+/// A single account and its frontier. Sent in a stream in response to a
+/// [FrontierReq](super::frontier_req::FrontierReq).
+#[derive(Debug, Clone, PartialEq)]
 pub struct FrontierResp {
-    account: Public,
-    frontier_hash: BlockHash,
+    pub account: Public,
+    pub frontier_hash: BlockHash,
 }
 
 impl FrontierResp {
     pub const LEN: usize = Public::LEN + BlockHash::LEN;
+
+    pub fn new(account: Public, frontier_hash: BlockHash) -> Self {
+        Self {
+            account,
+            frontier_hash,
+        }
+    }
+
+    /// The zeroed entry marking the end of the frontier stream.
+    pub fn end() -> Self {
+        let account = Public::try_from([0u8; Public::LEN].as_ref()).expect("zero public");
+        Self::new(account, BlockHash::zero())
+    }
+
+    pub fn is_end(&self) -> bool {
+        self == &Self::end()
+    }
 }
 
 impl Wire for FrontierResp {
     fn serialize(&self) -> Vec<u8> {
-        todo!()
+        let mut v = Vec::with_capacity(Self::LEN);
+        v.extend_from_slice(self.account.as_bytes());
+        v.extend_from_slice(self.frontier_hash.as_bytes());
+        v
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> Result<Self, anyhow::Error>
@@ -45,3 +67,29 @@ impl Wire for FrontierResp {
         Ok(Self::LEN)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let account =
+            Public::from_str("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA")
+                .unwrap();
+        let hash =
+            BlockHash::from_str("991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948")
+                .unwrap();
+        let frontier_resp = FrontierResp::new(account, hash);
+        assert!(!frontier_resp.is_end());
+        let data = frontier_resp.serialize();
+        assert_eq!(
+            FrontierResp::deserialize(None, &data).unwrap(),
+            frontier_resp
+        );
+
+        let end = FrontierResp::deserialize(None, &[0u8; FrontierResp::LEN]).unwrap();
+        assert!(end.is_end());
+    }
+}
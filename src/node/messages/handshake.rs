@@ -13,7 +13,14 @@ pub struct Handshake {
 
 impl Wire for Handshake {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut v = Vec::with_capacity(HandshakeQuery::LEN + HandshakeResponse::LEN);
+        if let Some(query) = &self.query {
+            v.extend_from_slice(&query.serialize());
+        }
+        if let Some(response) = &self.response {
+            v.extend_from_slice(&response.serialize());
+        }
+        v
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> Result<Self, anyhow::Error>
@@ -126,3 +133,39 @@ impl Wire for HandshakeResponse {
         Ok(Self::LEN)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::header::{Extensions, MessageType};
+    use crate::Private;
+
+    #[test]
+    fn serialize() {
+        let private = Private::random();
+        let cookie = Cookie::random();
+        let handshake = Handshake {
+            query: Some(HandshakeQuery::new(cookie.clone())),
+            response: Some(HandshakeResponse::new(
+                private.to_public().unwrap(),
+                private.sign(cookie.as_bytes()).unwrap(),
+            )),
+        };
+        let header = Header::new(
+            Network::Live,
+            MessageType::Handshake,
+            *Extensions::new().query().response(),
+        );
+
+        let data = handshake.serialize();
+        assert_eq!(data.len(), Handshake::len(Some(&header)).unwrap());
+        assert_eq!(&data[..Cookie::LEN], cookie.as_bytes());
+        let decoded = Handshake::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(
+            decoded.query.as_ref().unwrap().cookie().as_bytes(),
+            cookie.as_bytes()
+        );
+        assert_eq!(decoded.serialize(), data);
+    }
+}
@@ -8,11 +8,27 @@ pub struct Keepalive(Vec<Peer>);
 
 impl Keepalive {
     pub const PEERS: usize = 8;
+
+    /// Share up to `PEERS` peers. Any more than that are left out.
+    pub fn new(mut peers: Vec<Peer>) -> Self {
+        peers.truncate(Self::PEERS);
+        Self(peers)
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.0
+    }
 }
 
 impl Wire for Keepalive {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut v = Vec::with_capacity(Peer::LEN * Self::PEERS);
+        for peer in self.0.iter().take(Self::PEERS) {
+            v.extend_from_slice(&peer.serialize());
+        }
+        // Unused slots are zeroed.
+        v.resize(Peer::LEN * Self::PEERS, 0);
+        v
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -36,3 +52,28 @@ impl Wire for Keepalive {
         Ok(Peer::LEN * Keepalive::PEERS)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn serialize() {
+        let addrs = ["[::ffff:1.2.3.4]:7075", "[2001:db8::1]:54000"];
+        let peers = addrs.iter().map(|a| Peer::from_str(a).unwrap()).collect();
+        let keepalive = Keepalive::new(peers);
+
+        let data = keepalive.serialize();
+        assert_eq!(data.len(), Keepalive::len(None).unwrap());
+        assert_eq!(&data[Peer::LEN * 2..], &[0u8; Peer::LEN * 6][..]);
+
+        let decoded = Keepalive::deserialize(None, &data).unwrap();
+        let decoded: Vec<String> = decoded
+            .peers()
+            .iter()
+            .map(|p| p.socket_addr_v6().to_string())
+            .collect();
+        assert_eq!(decoded, addrs);
+    }
+}
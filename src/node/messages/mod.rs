@@ -1,3 +1,6 @@
+pub mod bulk_pull;
+pub mod bulk_pull_account;
+pub mod bulk_push;
 pub mod confirm_ack;
 pub mod confirm_req;
 pub mod empty;
@@ -1,13 +1,24 @@
 use crate::blocks::BlockHolder;
-use crate::node::header::Header;
+use crate::node::header::{Extensions, Header};
 use crate::node::wire::Wire;
 
 #[derive(Debug)]
 pub struct Publish(pub(crate) BlockHolder);
 
+impl Publish {
+    pub fn new(block: BlockHolder) -> Self {
+        Self(block)
+    }
+
+    /// The header extensions that describe the block in the payload.
+    pub fn extensions(&self) -> Extensions {
+        *Extensions::new().set_block_type(self.0.block_type())
+    }
+}
+
 impl Wire for Publish {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        self.0.serialize()
     }
 
     fn deserialize(header: Option<&Header>, data: &[u8]) -> anyhow::Result<Self>
@@ -21,3 +32,23 @@ impl Wire for Publish {
         BlockHolder::len(header)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::header::MessageType;
+
+    #[test]
+    fn serialize() {
+        let network = Network::Live;
+        let publish = Publish::new(network.genesis_block().to_holder().unwrap());
+        let header = Header::new(network, MessageType::Publish, publish.extensions());
+
+        let data = publish.serialize();
+        assert_eq!(data.len(), Publish::len(Some(&header)).unwrap());
+        let decoded = Publish::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(decoded.0.hash().unwrap(), network.genesis_hash());
+        assert_eq!(decoded.serialize(), data);
+    }
+}
@@ -1,110 +1,285 @@
 use crate::blocks::BlockHash;
 use crate::bytes::Bytes;
-use crate::node::header::Header;
+use crate::node::header::{Extensions, Header, Version};
 use crate::node::wire::Wire;
-use crate::{Public, Signature};
-use anyhow::Context;
+use crate::{Private, Public, Signature};
+use anyhow::{anyhow, Context};
 use std::convert::TryFrom;
-use tracing::warn;
 
-#[derive(Debug)]
+/// Statistics a node shares about itself, signed with its node ID.
+#[derive(Debug, Clone)]
 pub struct TelemetryAck {
-    signature: Signature,
-    node_id: Public,
-    block_count: u64,
-    cemented_count: u64,
-    unchecked_count: u64,
-    account_count: u64,
-    bandwidth_cap: u64,
-    uptime: u64,
-    peer_count: u32,
-    protocol_version: u8,
-    genesis_block: BlockHash,
-    major_version: u8,
-    minor_version: u8,
-    patch_version: u8,
-    prerelease_version: u8,
-    maker: u8,
-    timestamp: [u8; 8],
-    active_difficulty: [u8; 8],
+    pub signature: Signature,
+    pub node_id: Public,
+    pub block_count: u64,
+    pub cemented_count: u64,
+    pub unchecked_count: u64,
+    pub account_count: u64,
+    pub bandwidth_cap: u64,
+
+    /// Seconds since the node started.
+    pub uptime: u64,
+
+    pub peer_count: u32,
+    pub protocol_version: u8,
+    pub genesis_block: BlockHash,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub patch_version: u8,
+    pub prerelease_version: u8,
+    pub maker: u8,
+
+    /// Milliseconds since the UNIX epoch when the telemetry was created.
+    pub timestamp: u64,
+
+    pub active_difficulty: u64,
+
+    /// Fields added by newer versions of the protocol, which are covered by the signature.
+    pub unknown_data: Vec<u8>,
 }
 
 impl TelemetryAck {
     pub const LEN: usize = 202;
+
+    /// Set the node ID and sign the rest of the telemetry with its key.
+    pub fn sign(&mut self, private: &Private) -> anyhow::Result<()> {
+        let context = || "Sign TelemetryAck";
+        self.node_id = private.to_public().with_context(context)?;
+        self.signature = private.sign(&self.signed_data()).with_context(context)?;
+        Ok(())
+    }
+
+    pub fn verify_signature(&self) -> anyhow::Result<()> {
+        self.node_id
+            .verify(&self.signed_data(), &self.signature)
+            .context("Verify signature on TelemetryAck")
+    }
+
+    /// The header extensions that describe the payload when it's sent with `version`. Before V18
+    /// telemetry has a fixed length, otherwise the length is in the extensions.
+    pub fn extensions(&self, version: Version) -> Extensions {
+        let mut ext = Extensions::new();
+        if version >= Version::V18 {
+            ext.set_telemetry_size(Self::LEN + self.unknown_data.len());
+        }
+        ext
+    }
+
+    /// Since V18 a peer without telemetry to share answers with a size of zero and no payload.
+    pub fn is_empty(header: &Header) -> bool {
+        header.version_using() >= Version::V18 && header.ext().telemetry_size() == 0
+    }
+
+    /// Everything after the signature.
+    fn signed_data(&self) -> Vec<u8> {
+        self.serialize().split_off(Signature::LEN)
+    }
 }
 
 impl Wire for TelemetryAck {
     fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut v = Vec::with_capacity(Self::LEN + self.unknown_data.len());
+        v.extend_from_slice(self.signature.as_bytes());
+        v.extend_from_slice(self.node_id.as_bytes());
+        v.extend_from_slice(&self.block_count.to_be_bytes());
+        v.extend_from_slice(&self.cemented_count.to_be_bytes());
+        v.extend_from_slice(&self.unchecked_count.to_be_bytes());
+        v.extend_from_slice(&self.account_count.to_be_bytes());
+        v.extend_from_slice(&self.bandwidth_cap.to_be_bytes());
+        v.extend_from_slice(&self.uptime.to_be_bytes());
+        v.extend_from_slice(&self.peer_count.to_be_bytes());
+        v.push(self.protocol_version);
+        v.extend_from_slice(self.genesis_block.as_bytes());
+        v.push(self.major_version);
+        v.push(self.minor_version);
+        v.push(self.patch_version);
+        v.push(self.prerelease_version);
+        v.push(self.maker);
+        v.extend_from_slice(&self.timestamp.to_be_bytes());
+        v.extend_from_slice(&self.active_difficulty.to_be_bytes());
+        v.extend_from_slice(&self.unknown_data);
+        v
     }
 
     fn deserialize(_header: Option<&Header>, data: &[u8]) -> Result<Self, anyhow::Error>
     where
         Self: Sized,
     {
+        if data.len() < Self::LEN {
+            return Err(anyhow!(
+                "Telemetry ack is {} bytes, expecting at least {}",
+                data.len(),
+                Self::LEN
+            ));
+        }
         let mut bytes = Bytes::new(data);
 
-        let mut s = Self {
+        Ok(Self {
             signature: Signature::try_from(bytes.slice(Signature::LEN)?)
                 .context("Telemetry ack decoding signature")?,
             node_id: Public::try_from(bytes.slice(Public::LEN)?)
                 .context("Telemetry ack decoding node_id")?,
-            block_count: 0,
-            cemented_count: 0,
-            unchecked_count: 0,
-            account_count: 0,
-            bandwidth_cap: 0,
-            uptime: 0,
-            peer_count: 0,
-            protocol_version: 0,
-            genesis_block: BlockHash::zero(),
-            major_version: 0,
-            minor_version: 0,
-            patch_version: 0,
-            prerelease_version: 0,
-            maker: 0,
-            timestamp: [0u8; 8],
-            active_difficulty: [0u8; 8],
-        };
-
-        let mut s32 = [0u8; 4];
-        let mut s64 = [0u8; 8];
-
-        // TODO s.block_count = bytes.read_be_u64() ?
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.block_count = u64::from_be_bytes(s64);
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.cemented_count = u64::from_be_bytes(s64);
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.unchecked_count = u64::from_be_bytes(s64);
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.account_count = u64::from_be_bytes(s64);
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.bandwidth_cap = u64::from_be_bytes(s64);
-        s64.copy_from_slice(bytes.slice(8)?);
-        s.uptime = u64::from_be_bytes(s64);
-        s32.copy_from_slice(bytes.slice(4)?);
-        s.peer_count = u32::from_be_bytes(s32);
-        s.protocol_version = bytes.u8()?;
-        s.genesis_block = BlockHash::try_from(bytes.slice(BlockHash::LEN)?)
-            .context("Telemetry ack decoding genesis block")?;
-
-        s.major_version = bytes.u8()?;
-        s.minor_version = bytes.u8()?;
-        s.patch_version = bytes.u8()?;
-        s.prerelease_version = bytes.u8()?;
-        s.maker = bytes.u8()?;
-
-        warn!("TODO: telemetry ack timestamp");
-        warn!("TODO: telemetry ack active_difficulty");
-
-        Ok(s)
+            block_count: be_u64(&mut bytes)?,
+            cemented_count: be_u64(&mut bytes)?,
+            unchecked_count: be_u64(&mut bytes)?,
+            account_count: be_u64(&mut bytes)?,
+            bandwidth_cap: be_u64(&mut bytes)?,
+            uptime: be_u64(&mut bytes)?,
+            peer_count: u32::from_be_bytes(<[u8; 4]>::try_from(bytes.slice(4)?)?),
+            protocol_version: bytes.u8()?,
+            genesis_block: BlockHash::try_from(bytes.slice(BlockHash::LEN)?)
+                .context("Telemetry ack decoding genesis block")?,
+            major_version: bytes.u8()?,
+            minor_version: bytes.u8()?,
+            patch_version: bytes.u8()?,
+            prerelease_version: bytes.u8()?,
+            maker: bytes.u8()?,
+            timestamp: be_u64(&mut bytes)?,
+            active_difficulty: be_u64(&mut bytes)?,
+            unknown_data: bytes.slice(bytes.remain())?.to_vec(),
+        })
     }
 
-    fn len(_header: Option<&Header>) -> Result<usize, anyhow::Error>
+    /// Since V18 telemetry can be extended by newer versions, so the length is given by the
+    /// header. A length of zero means there's no payload, see `TelemetryAck::is_empty`.
+    fn len(header: Option<&Header>) -> Result<usize, anyhow::Error>
     where
         Self: Sized,
     {
-        Ok(TelemetryAck::LEN)
+        match header {
+            Some(header) if header.version_using() >= Version::V18 => {
+                let size = header.ext().telemetry_size();
+                if size != 0 && size < Self::LEN {
+                    return Err(anyhow!(
+                        "Telemetry ack size is {}, expecting at least {}",
+                        size,
+                        Self::LEN
+                    ));
+                }
+                Ok(size)
+            }
+            _ => Ok(TelemetryAck::LEN),
+        }
+    }
+}
+
+fn be_u64(bytes: &mut Bytes) -> anyhow::Result<u64> {
+    Ok(u64::from_be_bytes(<[u8; 8]>::try_from(bytes.slice(8)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::header::{MessageType, VersionRange};
+
+    fn telemetry_ack(private: &Private) -> TelemetryAck {
+        TelemetryAck {
+            signature: private.sign(b"telemetry").unwrap(),
+            node_id: private.to_public().unwrap(),
+            block_count: 1,
+            cemented_count: 2,
+            unchecked_count: 3,
+            account_count: 4,
+            bandwidth_cap: 5,
+            uptime: 6,
+            peer_count: 7,
+            protocol_version: 18,
+            genesis_block: Network::Live.genesis_hash(),
+            major_version: 22,
+            minor_version: 1,
+            patch_version: 0,
+            prerelease_version: 0,
+            maker: 0,
+            timestamp: 1614200740266,
+            active_difficulty: 0xfffffff800000000,
+            unknown_data: vec![],
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let private = Private::random();
+        let telemetry_ack = telemetry_ack(&private);
+
+        let data = telemetry_ack.serialize();
+        assert_eq!(data.len(), TelemetryAck::LEN);
+        assert_eq!(
+            &data[Signature::LEN..Signature::LEN + Public::LEN],
+            private.to_public().unwrap().as_bytes()
+        );
+        assert_eq!(&data[96..104], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&data[144..148], &[0, 0, 0, 7]);
+        assert_eq!(&data[186..194], &1614200740266u64.to_be_bytes());
+
+        let decoded = TelemetryAck::deserialize(None, &data).unwrap();
+        assert_eq!(decoded.block_count, 1);
+        assert_eq!(decoded.genesis_block, Network::Live.genesis_hash());
+        assert_eq!(decoded.timestamp, 1614200740266);
+        assert_eq!(decoded.active_difficulty, 0xfffffff800000000);
+        assert_eq!(decoded.serialize(), data);
+
+        assert!(TelemetryAck::deserialize(None, &data[..TelemetryAck::LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn sign() {
+        let private = Private::random();
+        let mut telemetry_ack = telemetry_ack(&Private::random());
+        assert!(telemetry_ack.verify_signature().is_err());
+
+        telemetry_ack.sign(&private).unwrap();
+        assert_eq!(telemetry_ack.node_id, private.to_public().unwrap());
+        telemetry_ack.verify_signature().unwrap();
+
+        // Data from newer versions is signed too.
+        telemetry_ack.unknown_data = vec![1, 2, 3];
+        assert!(telemetry_ack.verify_signature().is_err());
+        telemetry_ack.sign(&private).unwrap();
+        let data = telemetry_ack.serialize();
+        let header = Header::new(
+            Network::Live,
+            MessageType::TelemetryAck,
+            telemetry_ack.extensions(Version::V18),
+        );
+        assert_eq!(TelemetryAck::len(Some(&header)).unwrap(), data.len());
+        let decoded = TelemetryAck::deserialize(Some(&header), &data).unwrap();
+        assert_eq!(decoded.unknown_data, vec![1, 2, 3]);
+        decoded.verify_signature().unwrap();
+    }
+
+    #[test]
+    fn fixed_length_before_v18() {
+        let telemetry_ack = telemetry_ack(&Private::random());
+        let versions = VersionRange::new(Version::V17, Version::V17).unwrap();
+        let mut header = Header::new(
+            Network::Live,
+            MessageType::TelemetryAck,
+            telemetry_ack.extensions(Version::V17),
+        );
+        header.set_versions(&versions, Version::V17);
+        assert_eq!(header.ext().telemetry_size(), 0);
+        assert!(!TelemetryAck::is_empty(&header));
+        assert_eq!(TelemetryAck::len(Some(&header)).unwrap(), TelemetryAck::LEN);
+    }
+
+    #[test]
+    fn sizes_since_v18() {
+        let mut ext = Extensions::new();
+        let header = Header::new(Network::Live, MessageType::TelemetryAck, ext);
+        assert!(TelemetryAck::is_empty(&header));
+        assert_eq!(TelemetryAck::len(Some(&header)).unwrap(), 0);
+
+        ext.set_telemetry_size(TelemetryAck::LEN - 1);
+        let header = Header::new(Network::Live, MessageType::TelemetryAck, ext);
+        assert!(!TelemetryAck::is_empty(&header));
+        assert!(TelemetryAck::len(Some(&header)).is_err());
+
+        ext.set_telemetry_size(TelemetryAck::LEN + 3);
+        let header = Header::new(Network::Live, MessageType::TelemetryAck, ext);
+        assert_eq!(
+            TelemetryAck::len(Some(&header)).unwrap(),
+            TelemetryAck::LEN + 3
+        );
     }
 }
@@ -1,62 +1,121 @@
 mod channel;
+mod control;
 mod controller;
 mod cookie;
 mod header;
+mod listener;
 mod messages;
+mod node_id;
 mod peer;
+mod peer_manager;
 mod state;
+mod telemetry;
 mod timestamp;
 mod wire;
 
+use crate::blocks::{Block, BlockHash};
 use crate::network::Network;
-use channel::network_channel;
-pub use controller::{Controller, Packet};
-pub use header::Header;
+use crate::Public;
+use channel::{network_channel, Channels};
+use control::{listen_control, request_publish, PublishRequest};
+use controller::start_uptime;
+pub use controller::{Controller, ControllerConfig, Packet, Representative};
+pub use header::{Header, Version, VersionRange};
+use listener::listen;
+pub use node_id::format_node_id;
+use node_id::load_node_key;
+use peer_manager::PeerManager;
+pub use telemetry::TelemetrySummary;
+use telemetry::{TelemetryCollector, TELEMETRY_MAX_AGE};
 
-use crate::node::state::State;
-use anyhow::Context;
+use crate::node::state::{ArcState, State};
+use anyhow::{anyhow, Context};
 pub use state::{MemoryState, SledDiskState};
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use tracing::info;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{info, warn};
 pub use wire::Wire;
 
+/// How long to wait before trying every known peer again, after bootstrapping failed with all of
+/// them.
+const BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Run a node. With a representative in `config`, the node votes as that representative.
+///
+/// Peers can connect to the node on `listen_addr`, up to `max_inbound` of them at a time. The node
+/// connects to up to `max_outbound` peers itself. Local tools, e.g. `publish_block`, use the node
+/// through its control port on `control_addr`.
 pub async fn node_with_autodiscovery(
     addresses_override: Option<Vec<String>>,
+    data_dir: &Path,
+    config: ControllerConfig,
+    listen_addr: Option<SocketAddr>,
+    control_addr: Option<SocketAddr>,
+    max_inbound: usize,
+    max_outbound: usize,
 ) -> anyhow::Result<()> {
     let network = Network::Live;
-    // let state = SledDiskState::new(Network::Live);
-    let state = MemoryState::new(network);
+    start_uptime();
+    info!("Using data directory {:?}", data_dir);
+    let state = SledDiskState::new(network, data_dir)?;
 
     let state = Arc::new(Mutex::new(state));
-    let configured_peers = if addresses_override.is_some() {
-        parse_socket_list(addresses_override.unwrap())?
-    } else {
-        // TODO: Make this different depending on network, e.g. `network.peering_host()`
-        tokio::net::lookup_host("peering.nano.org:7075")
-            .await
-            .context("Error while trying to lookup default peers")?
-            .collect::<Vec<SocketAddr>>()
-    };
+    let configured_peers = configured_peers(addresses_override).await?;
     state.lock().await.add_peers(configured_peers).await?;
 
     let mut handles = vec![];
-    let initial_peers = state.lock().await.peers().await?;
-    for socket_addr in initial_peers {
-        info!("Spawning a channel to {}", socket_addr);
+    let channels = Channels::new();
+
+    if let Some(listen_addr) = listen_addr {
+        let listener = bind_listener(listen_addr).await?;
         let state = state.clone();
-        let handle = tokio::spawn(async move {
-            let stream = TcpStream::connect(socket_addr).await.unwrap();
-            network_channel(network, state, stream)
-                .await
-                .expect("Error in network_channel")
-        });
-        handles.push(handle)
+        let channels = channels.clone();
+        let config = config.clone();
+        handles.push(tokio::spawn(listen(
+            network,
+            state,
+            channels,
+            config,
+            listener,
+            max_inbound,
+        )));
+    }
+
+    if let Some(control_addr) = control_addr {
+        let listener = TcpListener::bind(control_addr).await.with_context(|| {
+            format!("Could not listen for control requests on {}", control_addr)
+        })?;
+        handles.push(tokio::spawn(listen_control(
+            network,
+            state.clone(),
+            channels.clone(),
+            config.clone(),
+            listener,
+        )));
     }
 
+    handles.push(tokio::spawn(bootstrap(
+        network,
+        state.clone(),
+        config.clone(),
+        BOOTSTRAP_RETRY_DELAY,
+    )));
+
+    let telemetry_collector =
+        TelemetryCollector::new(network, state.clone(), channels.clone(), config.versions);
+    handles.push(tokio::spawn(telemetry_collector.run()));
+
+    let peer_manager = PeerManager::new(network, state, channels, config, max_outbound);
+    handles.push(tokio::spawn(peer_manager.run()));
+
     for handle in handles {
         handle.await?
     }
@@ -64,6 +123,167 @@ pub async fn node_with_autodiscovery(
     Ok(())
 }
 
+/// Bootstrap the ledger over a separate connection to one known peer at a time, moving on to the
+/// next peer when a bootstrap fails. Once every peer has failed, try them all again after
+/// `retry_delay`. Returns when a bootstrap completes.
+async fn bootstrap(
+    network: Network,
+    state: ArcState,
+    config: ControllerConfig,
+    retry_delay: Duration,
+) {
+    loop {
+        let peers = match state.lock().await.peers().await {
+            Ok(peers) => peers,
+            Err(err) => {
+                warn!("Could not get peers to bootstrap from: {:?}", err);
+                HashSet::new()
+            }
+        };
+        for socket_addr in peers {
+            info!("Bootstrapping from {}", socket_addr);
+            let result = match TcpStream::connect(socket_addr).await {
+                Ok(stream) => {
+                    network_channel(
+                        network,
+                        state.clone(),
+                        Channels::new(),
+                        config.clone(),
+                        stream,
+                        true,
+                    )
+                    .await
+                }
+                Err(err) => Err(err).context("Connecting"),
+            };
+            match result {
+                Ok(()) => return,
+                Err(err) => warn!("Could not bootstrap from {}: {:?}", socket_addr, err),
+            }
+        }
+        info!("Bootstrapping again in {:?}", retry_delay);
+        sleep(retry_delay).await;
+    }
+}
+
+/// Listen on `listen_addr`. The unspecified IPv6 address also accepts IPv4 connections where the
+/// system allows it, and falls back to the unspecified IPv4 address on systems without IPv6.
+async fn bind_listener(listen_addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let err = match TcpListener::bind(listen_addr).await {
+        Ok(listener) => return Ok(listener),
+        Err(err) => err,
+    };
+    if listen_addr.ip() == Ipv6Addr::UNSPECIFIED {
+        let ipv4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, listen_addr.port()));
+        warn!(
+            "Could not listen on {}, trying {}: {:?}",
+            listen_addr, ipv4_addr, err
+        );
+        return TcpListener::bind(ipv4_addr)
+            .await
+            .with_context(|| format!("Could not listen on {}", ipv4_addr));
+    }
+    Err(err).with_context(|| format!("Could not listen on {}", listen_addr))
+}
+
+/// Publish a block that was created locally to the network through the node running with its
+/// control port on `control_addr`, then wait up to `timeout` for it to be confirmed. Returns the
+/// hash of the block and whether it was confirmed in time.
+///
+/// The block has to be signed and have enough work, and the node validates it against its ledger
+/// before it's sent to any peers. That ledger needs to be synced up to the blocks the new block
+/// depends on.
+pub async fn publish_block(
+    block: &Block,
+    control_addr: SocketAddr,
+    timeout: Duration,
+) -> anyhow::Result<(BlockHash, bool)> {
+    let request = PublishRequest {
+        block: block.to_owned(),
+        timeout,
+    };
+    request_publish(control_addr, &request)
+        .await?
+        .map_err(|err| anyhow!("The node could not publish the block: {}", err))
+}
+
+/// Connect to up to `max_peers` peers, and summarize the telemetry they send within `wait`.
+///
+/// Nothing is stored, so this can run next to a node without touching its data directory.
+pub async fn network_telemetry(
+    addresses_override: Option<Vec<String>>,
+    max_peers: usize,
+    wait: Duration,
+) -> anyhow::Result<TelemetrySummary> {
+    let network = Network::Live;
+    let state: ArcState = Arc::new(Mutex::new(MemoryState::new(network)));
+
+    let peers = configured_peers(addresses_override).await?;
+    let channels = Channels::new();
+    for socket_addr in peers.into_iter().take(max_peers) {
+        info!("Spawning a channel to {}", socket_addr);
+        spawn_channel(
+            network,
+            state.clone(),
+            channels.clone(),
+            ControllerConfig::default(),
+            socket_addr,
+        );
+    }
+
+    // Channels ask for telemetry as soon as the peer has proven its node ID.
+    sleep(wait).await;
+    let telemetry: Vec<_> = state
+        .lock()
+        .await
+        .telemetry(SystemTime::now() - TELEMETRY_MAX_AGE)
+        .await?
+        .into_iter()
+        .map(|(telemetry, _)| telemetry)
+        .collect();
+    Ok(TelemetrySummary::new(&telemetry))
+}
+
+/// The ID this node identifies itself with to peers, which is created on first use.
+pub async fn node_id(data_dir: &Path) -> anyhow::Result<Public> {
+    let mut state = SledDiskState::new(Network::Live, data_dir)?;
+    Ok(load_node_key(&mut state).await?.to_public()?)
+}
+
+async fn configured_peers(
+    addresses_override: Option<Vec<String>>,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    match addresses_override {
+        Some(addresses) => parse_socket_list(addresses),
+        // TODO: Make this different depending on network, e.g. `network.peering_host()`
+        None => Ok(tokio::net::lookup_host("peering.nano.org:7075")
+            .await
+            .context("Error while trying to lookup default peers")?
+            .collect::<Vec<SocketAddr>>()),
+    }
+}
+
+fn spawn_channel(
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
+    socket_addr: SocketAddr,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let stream = match TcpStream::connect(socket_addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Could not connect to {}: {:?}", socket_addr, err);
+                return;
+            }
+        };
+        if let Err(err) = network_channel(network, state, channels, config, stream, false).await {
+            warn!("Error in channel to {}: {:?}", socket_addr, err);
+        }
+    })
+}
+
 fn parse_socket_list(socket_list: Vec<String>) -> Result<Vec<SocketAddr>, anyhow::Error> {
     let mut retval: Vec<SocketAddr> = Vec::new();
     for socket in socket_list {
@@ -77,6 +297,59 @@ fn parse_socket_list(socket_list: Vec<String>) -> Result<Vec<SocketAddr>, anyhow
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::node::messages::frontier_resp::FrontierResp;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn bootstrap_from_another_peer() {
+        let network = Network::Live;
+        let state: ArcState = Arc::new(Mutex::new(MemoryState::new(network)));
+        let (mut controller, _tx, _rx) = Controller::new_with_channels(
+            network,
+            state.clone(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+        );
+        controller.init().await.unwrap();
+
+        // Nothing listens on the first peer any more.
+        let gone = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gone_addr = gone.local_addr().unwrap();
+        drop(gone);
+
+        // The second peer has no accounts, which completes the bootstrap straight away.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frontier_req = [0u8; 64];
+            let _ = stream.read(&mut frontier_req).await.unwrap();
+            stream
+                .write_all(&FrontierResp::end().serialize())
+                .await
+                .unwrap();
+            // Keep the connection open while the node pushes its own accounts.
+            let _ = stream.read(&mut frontier_req).await;
+        });
+
+        state
+            .lock()
+            .await
+            .add_peers(vec![gone_addr, peer_addr])
+            .await
+            .unwrap();
+        timeout(
+            Duration::from_secs(5),
+            bootstrap(
+                network,
+                state,
+                ControllerConfig::default(),
+                Duration::from_millis(10),
+            ),
+        )
+        .await
+        .unwrap();
+    }
 
     #[test]
     fn parse_socket_list_test() -> Result<(), anyhow::Error> {
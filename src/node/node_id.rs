@@ -0,0 +1,58 @@
+use crate::node::state::DynState;
+use crate::{Private, Public};
+use anyhow::Context;
+use tracing::info;
+
+/// The private key of this node's ID, which peers recognise the node by across connections. It's
+/// created the first time it's needed, then kept in the state.
+pub async fn load_node_key(state: &mut DynState) -> anyhow::Result<Private> {
+    if let Some(private) = state.node_key().await.context("Loading node key")? {
+        return Ok(private);
+    }
+
+    let private = Private::random();
+    state
+        .set_node_key(&private)
+        .await
+        .context("Storing node key")?;
+    info!("Created node ID {}", format_node_id(&private.to_public()?));
+    Ok(private)
+}
+
+/// Node IDs are shown like addresses, but with a `node_` prefix instead of `nano_`.
+pub fn format_node_id(public: &Public) -> String {
+    public
+        .to_address()
+        .to_string()
+        .replacen("nano_", "node_", 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::node::state::MemoryState;
+    use crate::Address;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn created_once() {
+        let mut state = MemoryState::new(Network::Live);
+        let first = load_node_key(&mut state)
+            .await
+            .unwrap()
+            .to_public()
+            .unwrap();
+        let second = load_node_key(&mut state)
+            .await
+            .unwrap()
+            .to_public()
+            .unwrap();
+        assert_eq!(first, second);
+
+        let node_id = format_node_id(&first);
+        assert!(node_id.starts_with("node_"));
+        let address = Address::from_str(&node_id.replacen("node_", "nano_", 1)).unwrap();
+        assert_eq!(address.to_public(), first);
+    }
+}
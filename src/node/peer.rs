@@ -1,7 +1,7 @@
 use crate::expect_len;
 use crate::node::header::Header;
 use crate::node::wire::Wire;
-use std::net::{Ipv6Addr, SocketAddrV6};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::str::FromStr;
 
 pub struct Peer(SocketAddrV6);
@@ -13,6 +13,25 @@ impl Peer {
     pub fn socket_addr_v6(&self) -> SocketAddrV6 {
         self.0
     }
+
+    /// The address to connect to, which is IPv4 if it's mapped into IPv6.
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.0.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), self.0.port()),
+            None => SocketAddr::V6(self.0),
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(socket_addr: SocketAddr) -> Self {
+        match socket_addr {
+            SocketAddr::V4(v4) => {
+                Peer(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+            }
+            SocketAddr::V6(v6) => Peer(v6),
+        }
+    }
 }
 
 impl FromStr for Peer {
@@ -69,4 +88,15 @@ mod tests {
         let addr2 = peer2.socket_addr_v6().to_string();
         assert_eq!(addr, addr2);
     }
+
+    #[test]
+    fn socket_addr() {
+        let v4 = SocketAddr::from_str("1.2.3.4:7075").unwrap();
+        let peer = Peer::from(v4);
+        assert_eq!(peer.socket_addr_v6().to_string(), "[::ffff:1.2.3.4]:7075");
+        assert_eq!(peer.socket_addr(), v4);
+
+        let v6 = SocketAddr::from_str("[2001:db8::1]:7075").unwrap();
+        assert_eq!(Peer::from(v6).socket_addr(), v6);
+    }
 }
@@ -0,0 +1,598 @@
+use crate::network::Network;
+use crate::node::channel::{network_channel, Channels};
+use crate::node::controller::{
+    ControllerConfig, COOKIE_TIMEOUT, ELECTION_TIMEOUT, ONLINE_WEIGHT_PERIOD,
+};
+use crate::node::header::{Extensions, Header, MessageType};
+use crate::node::messages::keepalive::Keepalive;
+use crate::node::peer::Peer;
+use crate::node::state::ArcState;
+use crate::node::wire::Wire;
+use anyhow::Context;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{interval, Instant};
+use tracing::{debug, info, warn};
+
+/// How often keepalives are sent, which is also when new connections are made if there's room,
+/// and stale handshake cookies, node IDs of peers that are gone, representatives that stopped
+/// voting and elections that went undecided for too long are removed.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait before connecting to a peer again. This doubles for every attempt in a row
+/// that couldn't connect, up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Attempts in a row that couldn't connect before a peer is forgotten.
+const MAX_CONNECT_FAILURES: u32 = 6;
+
+/// When a peer can be connected to again.
+#[derive(Debug)]
+struct Reconnect {
+    /// Attempts in a row that couldn't connect.
+    failures: u32,
+
+    at: Instant,
+}
+
+/// Keeps the node connected to its peers.
+///
+/// Every `KEEPALIVE_INTERVAL` it connects to known peers until there are `max_outbound`
+/// connections, and sends a keepalive with some known peers to every channel. Controllers learn
+/// new peers from the keepalives they receive.
+pub struct PeerManager {
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+    config: ControllerConfig,
+    max_outbound: usize,
+
+    /// Peers that we have connected to, or are trying to.
+    outbound: HashSet<SocketAddr>,
+
+    /// Peers that have disconnected or couldn't be connected to.
+    reconnects: HashMap<SocketAddr, Reconnect>,
+
+    /// Sent when an outbound connection ends, with whether it had connected.
+    ended_tx: Sender<(SocketAddr, bool)>,
+    ended_rx: Receiver<(SocketAddr, bool)>,
+}
+
+impl PeerManager {
+    pub fn new(
+        network: Network,
+        state: ArcState,
+        channels: Channels,
+        config: ControllerConfig,
+        max_outbound: usize,
+    ) -> Self {
+        let (ended_tx, ended_rx) = mpsc::channel(100);
+        Self {
+            network,
+            state,
+            channels,
+            config,
+            max_outbound,
+            outbound: HashSet::new(),
+            reconnects: HashMap::new(),
+            ended_tx,
+            ended_rx,
+        }
+    }
+
+    /// Runs until the node stops. Errors are logged, and the next tick tries again.
+    pub async fn run(mut self) {
+        let mut keepalive = interval(KEEPALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = keepalive.tick() => {
+                    if let Err(err) = self.connect().await {
+                        warn!("Could not connect to peers: {:?}", err);
+                    }
+                    if let Err(err) = self.send_keepalives().await {
+                        warn!("Could not send keepalives: {:?}", err);
+                    }
+                    if let Err(err) = self.remove_stale_cookies().await {
+                        warn!("Could not remove stale cookies: {:?}", err);
+                    }
+                    if let Err(err) = self.remove_stale_node_ids().await {
+                        warn!("Could not remove stale node IDs: {:?}", err);
+                    }
+                    if let Err(err) = self.remove_stale_online_reps().await {
+                        warn!("Could not remove stale online reps: {:?}", err);
+                    }
+                    if let Err(err) = self.remove_stale_elections().await {
+                        warn!("Could not remove stale elections: {:?}", err);
+                    }
+                }
+                Some((peer_addr, connected)) = self.ended_rx.recv() => {
+                    if let Err(err) = self.ended(peer_addr, connected).await {
+                        warn!("Could not handle the end of the channel to {}: {:?}", peer_addr, err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connect to known peers that aren't connected, until there are `max_outbound` connections.
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let room = self.max_outbound.saturating_sub(self.outbound.len());
+        if room == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let peers = self.state.lock().await.peers().await.context("Peers")?;
+        self.reconnects
+            .retain(|peer_addr, _| peers.contains(peer_addr));
+        let mut candidates: Vec<SocketAddr> = peers
+            .into_iter()
+            .filter(|peer_addr| !self.outbound.contains(peer_addr))
+            .filter(|peer_addr| match self.reconnects.get(peer_addr) {
+                Some(reconnect) => reconnect.at <= now,
+                None => true,
+            })
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+
+        for peer_addr in candidates.into_iter().take(room) {
+            info!("Spawning a channel to {}", peer_addr);
+            self.outbound.insert(peer_addr);
+
+            let network = self.network;
+            let state = self.state.clone();
+            let channels = self.channels.clone();
+            let config = self.config.clone();
+            let ended_tx = self.ended_tx.clone();
+            tokio::spawn(async move {
+                let connected = match TcpStream::connect(peer_addr).await {
+                    Ok(stream) => {
+                        if let Err(err) =
+                            network_channel(network, state, channels, config, stream, false).await
+                        {
+                            warn!("Error in channel to {}: {:?}", peer_addr, err);
+                        }
+                        true
+                    }
+                    Err(err) => {
+                        debug!("Could not connect to {}: {:?}", peer_addr, err);
+                        false
+                    }
+                };
+                // Only fails if the peer manager has stopped.
+                let _ = ended_tx.send((peer_addr, connected)).await;
+            });
+        }
+        Ok(())
+    }
+
+    /// An outbound connection has ended, so wait a while before connecting to it again. A peer
+    /// that couldn't be connected to `MAX_CONNECT_FAILURES` times in a row is forgotten.
+    async fn ended(&mut self, peer_addr: SocketAddr, connected: bool) -> anyhow::Result<()> {
+        self.outbound.remove(&peer_addr);
+        let failures = match (connected, self.reconnects.get(&peer_addr)) {
+            (true, _) => 0,
+            (false, Some(reconnect)) => reconnect.failures + 1,
+            (false, None) => 1,
+        };
+        if failures >= MAX_CONNECT_FAILURES {
+            debug!("Forgetting {} after {} failures", peer_addr, failures);
+            self.reconnects.remove(&peer_addr);
+            return self
+                .state
+                .lock()
+                .await
+                .remove_peer(&peer_addr)
+                .await
+                .with_context(|| format!("Removing peer {}", peer_addr));
+        }
+
+        let delay = reconnect_delay(failures);
+        debug!("Reconnecting to {} in {:?}", peer_addr, delay);
+        self.reconnects.insert(
+            peer_addr,
+            Reconnect {
+                failures,
+                at: Instant::now() + delay,
+            },
+        );
+        Ok(())
+    }
+
+    /// Send up to 8 random known peers to every channel.
+    async fn send_keepalives(&self) -> anyhow::Result<()> {
+        let peers: Vec<SocketAddr> = self
+            .state
+            .lock()
+            .await
+            .peers()
+            .await
+            .context("Peers")?
+            .into_iter()
+            .collect();
+        let keepalive = Keepalive::new(
+            peers
+                .choose_multiple(&mut rand::thread_rng(), Keepalive::PEERS)
+                .map(|peer_addr| Peer::from(peer_addr.to_owned()))
+                .collect(),
+        );
+
+        let versions = self.config.versions;
+        let mut header = Header::new(self.network, MessageType::Keepalive, Extensions::new());
+        header.set_versions(&versions, versions.max);
+        let mut data = header.serialize();
+        data.extend(keepalive.serialize());
+        self.channels.broadcast(&data).await;
+        Ok(())
+    }
+
+    /// Cookies of handshakes that weren't answered in time can't be used anymore.
+    async fn remove_stale_cookies(&self) -> anyhow::Result<()> {
+        let removed = self
+            .state
+            .lock()
+            .await
+            .remove_stale_cookies(SystemTime::now() - COOKIE_TIMEOUT)
+            .await
+            .context("Removing stale cookies")?;
+        if removed > 0 {
+            debug!("Removed {} stale cookies", removed);
+        }
+        Ok(())
+    }
+
+    /// A node ID is removed when its channel closes, but not if the node stopped before that. Node
+    /// IDs without a channel are removed once they're older than a handshake can take, so that a
+    /// handshake that's about to add its channel keeps its node ID.
+    async fn remove_stale_node_ids(&self) -> anyhow::Result<()> {
+        let before = SystemTime::now() - COOKIE_TIMEOUT;
+        let mut state = self.state.lock().await;
+        let mut removed = 0;
+        for (peer_addr, proven) in state.node_ids().await.context("Node IDs")? {
+            if proven < before && !self.channels.contains(&peer_addr).await {
+                state
+                    .remove_node_id(&peer_addr)
+                    .await
+                    .context("Removing stale node ID")?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            debug!("Removed {} node IDs of disconnected peers", removed);
+        }
+        Ok(())
+    }
+
+    /// Representatives that haven't voted within `ONLINE_WEIGHT_PERIOD` don't count as online.
+    async fn remove_stale_online_reps(&self) -> anyhow::Result<()> {
+        let removed = self
+            .state
+            .lock()
+            .await
+            .remove_stale_online_reps(SystemTime::now() - ONLINE_WEIGHT_PERIOD)
+            .await
+            .context("Removing stale online reps")?;
+        if removed > 0 {
+            debug!("Removed {} offline representatives", removed);
+        }
+        Ok(())
+    }
+
+    /// Elections that haven't been decided within `ELECTION_TIMEOUT` are stopped.
+    async fn remove_stale_elections(&self) -> anyhow::Result<()> {
+        let removed = self
+            .state
+            .lock()
+            .await
+            .remove_stale_elections(SystemTime::now() - ELECTION_TIMEOUT)
+            .await
+            .context("Removing stale elections")?;
+        if removed > 0 {
+            debug!("Removed {} undecided elections", removed);
+        }
+        Ok(())
+    }
+}
+
+fn reconnect_delay(failures: u32) -> Duration {
+    // Past 2^10 the delay is way over the maximum anyway.
+    let delay = RECONNECT_DELAY * 2u32.pow(failures.min(10));
+    delay.min(MAX_RECONNECT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::controller::Packet;
+    use crate::node::cookie::Cookie;
+    use crate::node::header::{Version, VersionRange};
+    use crate::node::state::{MemoryState, State};
+    use crate::{Private, DEFAULT_PORT};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    #[test]
+    fn delay() {
+        assert_eq!(reconnect_delay(0), RECONNECT_DELAY);
+        assert_eq!(reconnect_delay(1), RECONNECT_DELAY * 2);
+        assert_eq!(reconnect_delay(3), RECONNECT_DELAY * 8);
+        assert_eq!(reconnect_delay(100), MAX_RECONNECT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn reconnect_with_backoff() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+
+        // Nothing is listening on this address anymore.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        drop(listener);
+        state.lock().await.add_peers(vec![peer_addr]).await.unwrap();
+
+        let mut manager = PeerManager::new(
+            network,
+            state,
+            Channels::new(),
+            ControllerConfig::default(),
+            8,
+        );
+        for failures in 1..=2 {
+            manager.connect().await.unwrap();
+            assert!(manager.outbound.contains(&peer_addr));
+
+            let (ended, connected) = manager.ended_rx.recv().await.unwrap();
+            assert_eq!((ended, connected), (peer_addr, false));
+            manager.ended(ended, connected).await.unwrap();
+            assert!(manager.outbound.is_empty());
+            assert_eq!(manager.reconnects[&peer_addr].failures, failures);
+
+            // Not until the delay is over.
+            manager.connect().await.unwrap();
+            assert!(manager.outbound.is_empty());
+            manager.reconnects.get_mut(&peer_addr).unwrap().at = Instant::now();
+        }
+    }
+
+    #[tokio::test]
+    async fn forget_failing_peers() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1));
+        state.lock().await.add_peers(vec![peer_addr]).await.unwrap();
+
+        let mut manager = PeerManager::new(
+            network,
+            state.clone(),
+            Channels::new(),
+            ControllerConfig::default(),
+            8,
+        );
+        for _ in 1..MAX_CONNECT_FAILURES {
+            manager.ended(peer_addr, false).await.unwrap();
+        }
+        assert!(state
+            .lock()
+            .await
+            .peers()
+            .await
+            .unwrap()
+            .contains(&peer_addr));
+
+        manager.ended(peer_addr, false).await.unwrap();
+        assert!(manager.reconnects.is_empty());
+        assert_eq!(state.lock().await.peer_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn max_outbound() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let peers: Vec<SocketAddr> = (1..=5)
+            .map(|n| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, n), 1)))
+            .collect();
+        state.lock().await.add_peers(peers).await.unwrap();
+
+        let mut manager = PeerManager::new(
+            network,
+            state,
+            Channels::new(),
+            ControllerConfig::default(),
+            3,
+        );
+        manager.connect().await.unwrap();
+        assert_eq!(manager.outbound.len(), 3);
+        manager.connect().await.unwrap();
+        assert_eq!(manager.outbound.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn keepalives() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let peers: Vec<SocketAddr> = (1..=10)
+            .map(|n| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), DEFAULT_PORT)))
+            .collect();
+        state.lock().await.add_peers(peers.clone()).await.unwrap();
+
+        let channels = Channels::new();
+        let (tx, mut rx) = mpsc::channel::<Packet>(10);
+        channels.add(peers[0], tx).await;
+        let config = ControllerConfig {
+            versions: VersionRange::new(Version::V17, Version::V18).unwrap(),
+            ..Default::default()
+        };
+        let manager = PeerManager::new(network, state, channels, config, 8);
+        manager.send_keepalives().await.unwrap();
+
+        let data = rx.recv().await.unwrap().data;
+        let header = Header::deserialize(None, &data[..Header::LEN]).unwrap();
+        assert_eq!(header.message_type(), MessageType::Keepalive);
+        assert_eq!(header.version_min(), Version::V17);
+        assert_eq!(header.version_max(), Version::V18);
+        assert_eq!(header.version_using(), Version::V18);
+        let keepalive = Keepalive::deserialize(Some(&header), &data[Header::LEN..]).unwrap();
+        assert_eq!(keepalive.peers().len(), Keepalive::PEERS);
+        for peer in keepalive.peers() {
+            assert!(peers.contains(&peer.socket_addr()));
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_cookies() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let stale = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), DEFAULT_PORT));
+        let fresh = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), DEFAULT_PORT));
+        let now = SystemTime::now();
+        {
+            let mut state = state.lock().await;
+            state
+                .set_cookie(stale, Cookie::random(), now - COOKIE_TIMEOUT * 2)
+                .await
+                .unwrap();
+            state
+                .set_cookie(fresh, Cookie::random(), now)
+                .await
+                .unwrap();
+        }
+
+        let manager = PeerManager::new(
+            network,
+            state.clone(),
+            Channels::new(),
+            ControllerConfig::default(),
+            8,
+        );
+        manager.remove_stale_cookies().await.unwrap();
+        let state = state.lock().await;
+        assert!(state
+            .cookie_for_socket_addr(&stale)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(state
+            .cookie_for_socket_addr(&fresh)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_node_ids() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let addr =
+            |n: u8| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), DEFAULT_PORT));
+        let (gone, connected, handshaking) = (addr(1), addr(2), addr(3));
+        let now = SystemTime::now();
+        {
+            let mut state = state.lock().await;
+            for (peer_addr, proven) in &[
+                (gone, now - COOKIE_TIMEOUT * 2),
+                (connected, now - COOKIE_TIMEOUT * 2),
+                (handshaking, now),
+            ] {
+                let node_id = Private::random().to_public().unwrap();
+                state
+                    .set_node_id(*peer_addr, node_id, *proven)
+                    .await
+                    .unwrap();
+            }
+        }
+        let channels = Channels::new();
+        let (tx, _rx) = mpsc::channel(10);
+        channels.add(connected, tx).await;
+
+        let manager = PeerManager::new(
+            network,
+            state.clone(),
+            channels,
+            ControllerConfig::default(),
+            8,
+        );
+        manager.remove_stale_node_ids().await.unwrap();
+        let mut node_ids: Vec<SocketAddr> = state
+            .lock()
+            .await
+            .node_ids()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(peer_addr, _)| peer_addr)
+            .collect();
+        node_ids.sort();
+        assert_eq!(node_ids, vec![connected, handshaking]);
+    }
+
+    #[tokio::test]
+    async fn stale_online_reps() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let rep = Private::random().to_public().unwrap();
+        state
+            .lock()
+            .await
+            .add_online_rep(&rep, SystemTime::now() - ONLINE_WEIGHT_PERIOD * 2)
+            .await
+            .unwrap();
+
+        let manager = PeerManager::new(
+            network,
+            state.clone(),
+            Channels::new(),
+            ControllerConfig::default(),
+            8,
+        );
+        manager.remove_stale_online_reps().await.unwrap();
+        assert!(state
+            .lock()
+            .await
+            .online_reps(SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_elections() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let block = network.genesis_block();
+        state
+            .lock()
+            .await
+            .add_election_candidate(
+                &block.root(),
+                &block,
+                SystemTime::now() - ELECTION_TIMEOUT * 2,
+            )
+            .await
+            .unwrap();
+
+        let manager = PeerManager::new(
+            network,
+            state.clone(),
+            Channels::new(),
+            ControllerConfig::default(),
+            8,
+        );
+        manager.remove_stale_elections().await.unwrap();
+        assert!(state
+            .lock()
+            .await
+            .active_elections()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
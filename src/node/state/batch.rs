@@ -0,0 +1,183 @@
+use crate::blocks::{Block, BlockHash, Epoch};
+use crate::node::state::{ConfirmationHeight, Receivable};
+use crate::{Public, Rai};
+use anyhow::Context;
+
+/// Writes to the ledger that are staged together, then applied all at once with
+/// [State::commit](super::State::commit), or not at all.
+#[derive(Debug, Default)]
+pub struct StateBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    /// Store a block, indexed by its hash, with its height in the account chain, counting from 1
+    /// at the open block, and the epoch the account is in as of the block.
+    AddBlock {
+        hash: BlockHash,
+        block: Block,
+        height: u64,
+        epoch: Epoch,
+    },
+
+    /// Remove a block, e.g. when rolling it back.
+    RemoveBlock {
+        hash: BlockHash,
+        root: BlockHash,
+    },
+
+    /// Set the head of an account chain.
+    SetLatestBlockHash {
+        account: Public,
+        hash: BlockHash,
+    },
+
+    /// Forget an account chain, after its open block has been removed.
+    RemoveLatestBlockHash {
+        account: Public,
+    },
+
+    AddReceivable {
+        account: Public,
+        send_hash: BlockHash,
+        receivable: Receivable,
+    },
+
+    RemoveReceivable {
+        account: Public,
+        send_hash: BlockHash,
+    },
+
+    SetRepWeight {
+        representative: Public,
+        weight: Rai,
+    },
+
+    /// Cement an account chain up to and including `frontier`.
+    SetConfirmationHeight {
+        account: Public,
+        confirmation_height: ConfirmationHeight,
+    },
+}
+
+impl StateBatch {
+    /// Begin an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stage a block at `height` in its account chain. The block's hash needs to be calculated.
+    pub fn add_block(
+        &mut self,
+        block: &Block,
+        height: u64,
+        epoch: Epoch,
+    ) -> anyhow::Result<&mut Self> {
+        let hash = block
+            .hash()
+            .context("Staging a block without a hash")?
+            .to_owned();
+        self.ops.push(BatchOp::AddBlock {
+            hash,
+            block: block.to_owned(),
+            height,
+            epoch,
+        });
+        Ok(self)
+    }
+
+    /// Stage the removal of a block. The block's hash needs to be calculated.
+    pub fn remove_block(&mut self, block: &Block) -> anyhow::Result<&mut Self> {
+        let hash = block
+            .hash()
+            .context("Removing a block without a hash")?
+            .to_owned();
+        self.ops.push(BatchOp::RemoveBlock {
+            hash,
+            root: block.root(),
+        });
+        Ok(self)
+    }
+
+    pub fn set_latest_block_hash(&mut self, account: &Public, hash: &BlockHash) -> &mut Self {
+        self.ops.push(BatchOp::SetLatestBlockHash {
+            account: account.to_owned(),
+            hash: hash.to_owned(),
+        });
+        self
+    }
+
+    pub fn remove_latest_block_hash(&mut self, account: &Public) -> &mut Self {
+        self.ops.push(BatchOp::RemoveLatestBlockHash {
+            account: account.to_owned(),
+        });
+        self
+    }
+
+    pub fn add_receivable(
+        &mut self,
+        account: &Public,
+        send_hash: &BlockHash,
+        receivable: &Receivable,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::AddReceivable {
+            account: account.to_owned(),
+            send_hash: send_hash.to_owned(),
+            receivable: receivable.to_owned(),
+        });
+        self
+    }
+
+    pub fn remove_receivable(&mut self, account: &Public, send_hash: &BlockHash) -> &mut Self {
+        self.ops.push(BatchOp::RemoveReceivable {
+            account: account.to_owned(),
+            send_hash: send_hash.to_owned(),
+        });
+        self
+    }
+
+    pub fn set_rep_weight(&mut self, representative: &Public, weight: &Rai) -> &mut Self {
+        self.ops.push(BatchOp::SetRepWeight {
+            representative: representative.to_owned(),
+            weight: weight.to_owned(),
+        });
+        self
+    }
+
+    pub fn set_confirmation_height(
+        &mut self,
+        account: &Public,
+        confirmation_height: &ConfirmationHeight,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::SetConfirmationHeight {
+            account: account.to_owned(),
+            confirmation_height: confirmation_height.to_owned(),
+        });
+        self
+    }
+
+    /// The weight staged for a representative, if any, so that blocks staged later in the same
+    /// batch build on it.
+    pub fn rep_weight(&self, representative: &Public) -> Option<&Rai> {
+        self.ops.iter().rev().find_map(|op| match op {
+            BatchOp::SetRepWeight {
+                representative: staged,
+                weight,
+            } if staged == representative => Some(weight),
+            _ => None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
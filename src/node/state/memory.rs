@@ -1,49 +1,156 @@
-use crate::blocks::{Block, BlockHash};
+use crate::blocks::{Block, BlockHash, BlockHolder, Epoch};
 use crate::network::Network;
+use crate::node::controller::BlockOrigin;
 use crate::node::cookie::Cookie;
-use crate::node::state::State;
-use crate::Public;
-use anyhow::Context;
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::node::state::{BatchOp, ConfirmationHeight, ForkEvent, Receivable, State, StateBatch};
+use crate::node::timestamp::Timestamp;
+use crate::{Private, Public, Rai};
 use async_trait::async_trait;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
+use std::time::SystemTime;
 
 #[derive(Debug)]
 pub struct MemoryState {
     network: Network,
-    cookies: HashMap<SocketAddr, Cookie>,
+    node_key: Option<Private>,
+    cookies: HashMap<SocketAddr, (Cookie, SystemTime)>,
+    node_ids: HashMap<SocketAddr, (Public, SystemTime)>,
     blocks: HashMap<BlockHash, Block>,
     block_hash_to_account: HashMap<BlockHash, Public>,
-    latest_block_hash: HashMap<Public, BlockHash>,
-    votes: HashMap<BlockHash, HashSet<Public>>,
+    block_heights: HashMap<BlockHash, u64>,
+    block_epochs: HashMap<BlockHash, Epoch>,
+    successors: HashMap<BlockHash, BlockHash>,
+    latest_block_hash: BTreeMap<Public, BlockHash>,
+    account_modified: HashMap<Public, SystemTime>,
+    receivables: HashMap<Public, BTreeMap<BlockHash, Receivable>>,
+    rep_weights: HashMap<Public, Rai>,
+    online_reps: HashMap<Public, SystemTime>,
+    confirmation_heights: HashMap<Public, ConfirmationHeight>,
+    /// The sum of `confirmation_heights`.
+    cemented_count: u64,
+    votes: HashMap<BlockHash, HashMap<Public, Timestamp>>,
+    elections: HashMap<BlockHash, Vec<Block>>,
+    election_roots: HashMap<BlockHash, BlockHash>,
+    /// Election root -> when the election started.
+    election_starts: HashMap<BlockHash, SystemTime>,
+    fork_events: VecDeque<ForkEvent>,
+    /// Dependency -> block hash -> block, origin and when it was added in `unchecked_order`.
+    unchecked: HashMap<BlockHash, HashMap<BlockHash, (BlockHolder, BlockOrigin, u64)>>,
+    /// Sequential ID -> dependency and block hash, so the oldest unchecked block comes first.
+    unchecked_order: BTreeMap<u64, (BlockHash, BlockHash)>,
+    next_unchecked_id: u64,
     peers: HashSet<SocketAddr>,
+    telemetry: HashMap<Public, (TelemetryAck, SystemTime)>,
 }
 
 impl MemoryState {
     pub fn new(network: Network) -> Self {
         Self {
             network,
+            node_key: None,
             cookies: HashMap::new(),
+            node_ids: HashMap::new(),
             blocks: HashMap::new(),
             block_hash_to_account: HashMap::new(),
-            latest_block_hash: HashMap::new(),
+            block_heights: HashMap::new(),
+            block_epochs: HashMap::new(),
+            successors: HashMap::new(),
+            latest_block_hash: BTreeMap::new(),
+            account_modified: HashMap::new(),
+            receivables: HashMap::new(),
+            rep_weights: HashMap::new(),
+            online_reps: HashMap::new(),
+            confirmation_heights: HashMap::new(),
+            cemented_count: 0,
             votes: HashMap::new(),
+            elections: HashMap::new(),
+            election_roots: HashMap::new(),
+            election_starts: HashMap::new(),
+            fork_events: VecDeque::new(),
+            unchecked: HashMap::new(),
+            unchecked_order: BTreeMap::new(),
+            next_unchecked_id: 0,
             peers: HashSet::new(),
+            telemetry: HashMap::new(),
         }
     }
 }
 
 #[async_trait]
 impl State for MemoryState {
-    async fn add_block(&mut self, block: &Block) -> anyhow::Result<()> {
-        self.blocks.insert(
-            block.hash().context("Add block")?.to_owned(),
-            block.to_owned(),
-        );
-        self.block_hash_to_account
-            .insert(block.hash()?.to_owned(), block.account().to_owned());
-        self.latest_block_hash
-            .insert(block.account().to_owned(), block.hash()?.to_owned());
+    async fn commit(&mut self, batch: StateBatch) -> anyhow::Result<()> {
+        // Nothing below can fail, so the batch is applied atomically.
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::AddBlock {
+                    hash,
+                    block,
+                    height,
+                    epoch,
+                } => {
+                    self.block_hash_to_account
+                        .insert(hash.to_owned(), block.account().to_owned());
+                    self.block_heights.insert(hash.to_owned(), height);
+                    self.block_epochs.insert(hash.to_owned(), epoch);
+                    self.successors.insert(block.root(), hash.to_owned());
+                    self.blocks.insert(hash, block);
+                }
+                BatchOp::RemoveBlock { hash, root } => {
+                    self.successors.remove(&root);
+                    self.block_hash_to_account.remove(&hash);
+                    self.block_heights.remove(&hash);
+                    self.block_epochs.remove(&hash);
+                    self.blocks.remove(&hash);
+                }
+                BatchOp::SetLatestBlockHash { account, hash } => {
+                    self.account_modified
+                        .insert(account.to_owned(), SystemTime::now());
+                    self.latest_block_hash.insert(account, hash);
+                }
+                BatchOp::RemoveLatestBlockHash { account } => {
+                    self.account_modified.remove(&account);
+                    self.latest_block_hash.remove(&account);
+                }
+                BatchOp::AddReceivable {
+                    account,
+                    send_hash,
+                    receivable,
+                } => {
+                    self.receivables
+                        .entry(account)
+                        .or_default()
+                        .insert(send_hash, receivable);
+                }
+                BatchOp::RemoveReceivable { account, send_hash } => {
+                    if let Some(receivables) = self.receivables.get_mut(&account) {
+                        receivables.remove(&send_hash);
+                        if receivables.is_empty() {
+                            self.receivables.remove(&account);
+                        }
+                    }
+                }
+                BatchOp::SetRepWeight {
+                    representative,
+                    weight,
+                } => {
+                    self.rep_weights.insert(representative, weight);
+                }
+                BatchOp::SetConfirmationHeight {
+                    account,
+                    confirmation_height,
+                } => {
+                    self.cemented_count += confirmation_height.height;
+                    if let Some(old) = self
+                        .confirmation_heights
+                        .insert(account, confirmation_height)
+                    {
+                        self.cemented_count -= old.height;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
@@ -51,6 +158,18 @@ impl State for MemoryState {
         Ok(self.blocks.get(hash).map(|b| b.to_owned()))
     }
 
+    async fn block_height(&self, hash: &BlockHash) -> anyhow::Result<Option<u64>> {
+        Ok(self.block_heights.get(hash).cloned())
+    }
+
+    async fn successor(&self, root: &BlockHash) -> anyhow::Result<Option<BlockHash>> {
+        Ok(self.successors.get(root).cloned())
+    }
+
+    async fn block_epoch(&self, hash: &BlockHash) -> anyhow::Result<Option<Epoch>> {
+        Ok(self.block_epochs.get(hash).cloned())
+    }
+
     async fn get_latest_block_hash_for_account(
         &self,
         account: &Public,
@@ -58,6 +177,81 @@ impl State for MemoryState {
         Ok(self.latest_block_hash.get(account).map(|b| b.to_owned()))
     }
 
+    async fn frontiers(
+        &self,
+        start: &Public,
+        modified_since: Option<SystemTime>,
+        count: usize,
+    ) -> anyhow::Result<Vec<(Public, BlockHash)>> {
+        Ok(self
+            .latest_block_hash
+            .range(start.to_owned()..)
+            .filter(|(account, _)| match modified_since {
+                Some(since) => self
+                    .account_modified
+                    .get(account)
+                    .map(|modified| modified >= &since)
+                    .unwrap_or(false),
+                None => true,
+            })
+            .take(count)
+            .map(|(account, hash)| (account.to_owned(), hash.to_owned()))
+            .collect())
+    }
+
+    async fn receivables(&self, account: &Public) -> anyhow::Result<Vec<(BlockHash, Receivable)>> {
+        Ok(self
+            .receivables
+            .get(account)
+            .map(|r| {
+                r.iter()
+                    .map(|(hash, receivable)| (hash.to_owned(), receivable.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn rep_weight(&self, representative: &Public) -> anyhow::Result<Rai> {
+        Ok(self
+            .rep_weights
+            .get(representative)
+            .cloned()
+            .unwrap_or_else(Rai::zero))
+    }
+
+    async fn rep_weights(&self) -> anyhow::Result<Vec<(Public, Rai)>> {
+        Ok(self
+            .rep_weights
+            .iter()
+            .filter(|(_, weight)| **weight != Rai::zero())
+            .map(|(rep, weight)| (rep.to_owned(), weight.to_owned()))
+            .collect())
+    }
+
+    async fn add_online_rep(
+        &mut self,
+        representative: &Public,
+        seen: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.online_reps.insert(representative.to_owned(), seen);
+        Ok(())
+    }
+
+    async fn online_reps(&self, since: SystemTime) -> anyhow::Result<Vec<Public>> {
+        Ok(self
+            .online_reps
+            .iter()
+            .filter(|(_, seen)| **seen >= since)
+            .map(|(rep, _)| rep.to_owned())
+            .collect())
+    }
+
+    async fn remove_stale_online_reps(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let len = self.online_reps.len();
+        self.online_reps.retain(|_, seen| *seen >= before);
+        Ok(len - self.online_reps.len())
+    }
+
     async fn account_for_block_hash(
         &mut self,
         block_hash: &BlockHash,
@@ -68,23 +262,186 @@ impl State for MemoryState {
             .map(|a| a.to_owned()))
     }
 
-    async fn add_vote(&mut self, hash: &BlockHash, representative: &Public) -> anyhow::Result<()> {
-        let entry = self
+    async fn confirmation_height(
+        &self,
+        account: &Public,
+    ) -> anyhow::Result<Option<ConfirmationHeight>> {
+        Ok(self.confirmation_heights.get(account).cloned())
+    }
+
+    async fn block_count(&self) -> anyhow::Result<usize> {
+        Ok(self.blocks.len())
+    }
+
+    async fn cemented_count(&self) -> anyhow::Result<u64> {
+        Ok(self.cemented_count)
+    }
+
+    async fn account_count(&self) -> anyhow::Result<usize> {
+        Ok(self.latest_block_hash.len())
+    }
+
+    async fn add_vote(
+        &mut self,
+        hash: &BlockHash,
+        representative: &Public,
+        timestamp: &Timestamp,
+    ) -> anyhow::Result<bool> {
+        let votes = self.votes.entry(hash.to_owned()).or_default();
+        if let Some(existing) = votes.get(representative) {
+            if existing >= timestamp {
+                return Ok(false);
+            }
+        }
+        votes.insert(representative.to_owned(), timestamp.to_owned());
+        Ok(true)
+    }
+
+    async fn votes(&self, hash: &BlockHash) -> anyhow::Result<Vec<(Public, Timestamp)>> {
+        Ok(self
             .votes
-            .entry(hash.to_owned())
-            .or_insert_with(|| HashSet::new());
-        entry.insert(representative.to_owned());
+            .get(hash)
+            .map(|votes| {
+                votes
+                    .iter()
+                    .map(|(rep, timestamp)| (rep.to_owned(), timestamp.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn add_election_candidate(
+        &mut self,
+        root: &BlockHash,
+        block: &Block,
+        now: SystemTime,
+    ) -> anyhow::Result<bool> {
+        let hash = block.hash()?;
+        if self.election_roots.contains_key(hash) {
+            return Ok(false);
+        }
+        self.election_roots.insert(hash.to_owned(), root.to_owned());
+        self.election_starts.entry(root.to_owned()).or_insert(now);
+        self.elections
+            .entry(root.to_owned())
+            .or_default()
+            .push(block.to_owned());
+        Ok(true)
+    }
+
+    async fn election_candidates(&self, root: &BlockHash) -> anyhow::Result<Vec<Block>> {
+        Ok(self.elections.get(root).cloned().unwrap_or_default())
+    }
+
+    async fn election_root(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockHash>> {
+        Ok(self.election_roots.get(hash).cloned())
+    }
+
+    async fn active_elections(&self) -> anyhow::Result<Vec<BlockHash>> {
+        Ok(self.elections.keys().cloned().collect())
+    }
+
+    async fn remove_election(&mut self, root: &BlockHash) -> anyhow::Result<()> {
+        for block in self.elections.remove(root).unwrap_or_default() {
+            let hash = block.hash()?;
+            self.election_roots.remove(hash);
+            self.votes.remove(hash);
+        }
+        self.election_starts.remove(root);
+        Ok(())
+    }
+
+    async fn remove_stale_elections(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let stale: Vec<BlockHash> = self
+            .election_starts
+            .iter()
+            .filter(|(_, started)| **started < before)
+            .map(|(root, _)| root.to_owned())
+            .collect();
+        for root in &stale {
+            self.remove_election(root).await?;
+        }
+        Ok(stale.len())
+    }
+
+    async fn add_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+        block: &BlockHolder,
+        origin: BlockOrigin,
+    ) -> anyhow::Result<()> {
+        let hash = block.hash()?;
+        let id = self.next_unchecked_id;
+        self.next_unchecked_id += 1;
+        self.unchecked_order
+            .insert(id, (dependency.to_owned(), hash.to_owned()));
+        if let Some((_, _, replaced)) = self
+            .unchecked
+            .entry(dependency.to_owned())
+            .or_default()
+            .insert(hash, (block.to_owned(), origin, id))
+        {
+            self.unchecked_order.remove(&replaced);
+        }
+        Ok(())
+    }
 
-        // dbg!(&self
-        //     .votes
-        //     .iter()
-        //     .map(|v| format!("{} {}", v.0, v.1.len()))
-        //     .collect::<Vec<_>>());
+    async fn take_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+    ) -> anyhow::Result<Vec<(BlockHolder, BlockOrigin)>> {
+        let blocks = self.unchecked.remove(dependency).unwrap_or_default();
+        Ok(blocks
+            .into_values()
+            .map(|(block, origin, id)| {
+                self.unchecked_order.remove(&id);
+                (block, origin)
+            })
+            .collect())
+    }
 
-        // dbg!(&self.votes);
+    async fn unchecked_count(&self) -> anyhow::Result<usize> {
+        Ok(self.unchecked_order.len())
+    }
 
-        // println!("XXXXXX {:?} {:?}", hash, representative);
+    async fn remove_oldest_unchecked(&mut self) -> anyhow::Result<bool> {
+        let (id, (dependency, hash)) = match self.unchecked_order.iter().next() {
+            Some((id, entry)) => (*id, entry.to_owned()),
+            None => return Ok(false),
+        };
+        self.unchecked_order.remove(&id);
+        if let Some(blocks) = self.unchecked.get_mut(&dependency) {
+            blocks.remove(&hash);
+            if blocks.is_empty() {
+                self.unchecked.remove(&dependency);
+            }
+        }
+        Ok(true)
+    }
 
+    async fn add_fork_event(&mut self, event: &ForkEvent) -> anyhow::Result<()> {
+        self.fork_events.push_back(event.to_owned());
+        Ok(())
+    }
+
+    async fn fork_event_count(&self) -> anyhow::Result<usize> {
+        Ok(self.fork_events.len())
+    }
+
+    async fn remove_oldest_fork_event(&mut self) -> anyhow::Result<bool> {
+        Ok(self.fork_events.pop_front().is_some())
+    }
+
+    async fn fork_events(&self) -> anyhow::Result<Vec<ForkEvent>> {
+        Ok(self.fork_events.iter().cloned().collect())
+    }
+
+    async fn node_key(&self) -> anyhow::Result<Option<Private>> {
+        Ok(self.node_key.to_owned())
+    }
+
+    async fn set_node_key(&mut self, private: &Private) -> anyhow::Result<()> {
+        self.node_key = Some(private.to_owned());
         Ok(())
     }
 
@@ -92,18 +449,103 @@ impl State for MemoryState {
         &mut self,
         socket_addr: SocketAddr,
         cookie: Cookie,
+        created: SystemTime,
     ) -> Result<(), anyhow::Error> {
-        self.cookies.insert(socket_addr, cookie);
+        self.cookies.insert(socket_addr, (cookie, created));
         Ok(())
     }
 
     async fn cookie_for_socket_addr(
         &self,
         socket_addr: &SocketAddr,
-    ) -> Result<Option<Cookie>, anyhow::Error> {
+    ) -> Result<Option<(Cookie, SystemTime)>, anyhow::Error> {
         Ok(self.cookies.get(&socket_addr).map(|c| c.to_owned()))
     }
 
+    async fn remove_cookie(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()> {
+        self.cookies.remove(socket_addr);
+        Ok(())
+    }
+
+    async fn remove_stale_cookies(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let len = self.cookies.len();
+        self.cookies.retain(|_, (_, created)| *created >= before);
+        Ok(len - self.cookies.len())
+    }
+
+    async fn set_node_id(
+        &mut self,
+        socket_addr: SocketAddr,
+        node_id: Public,
+        proven: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.node_ids.insert(socket_addr, (node_id, proven));
+        Ok(())
+    }
+
+    async fn node_id_for_socket_addr(
+        &self,
+        socket_addr: &SocketAddr,
+    ) -> anyhow::Result<Option<Public>> {
+        Ok(self
+            .node_ids
+            .get(socket_addr)
+            .map(|(node_id, _)| node_id.to_owned()))
+    }
+
+    async fn socket_addrs_for_node_id(&self, node_id: &Public) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self
+            .node_ids
+            .iter()
+            .filter(|(_, (n, _))| n == node_id)
+            .map(|(socket_addr, _)| socket_addr.to_owned())
+            .collect())
+    }
+
+    async fn node_ids(&self) -> anyhow::Result<Vec<(SocketAddr, SystemTime)>> {
+        Ok(self
+            .node_ids
+            .iter()
+            .map(|(socket_addr, (_, proven))| (socket_addr.to_owned(), proven.to_owned()))
+            .collect())
+    }
+
+    async fn remove_node_id(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()> {
+        self.node_ids.remove(socket_addr);
+        Ok(())
+    }
+
+    async fn add_telemetry(
+        &mut self,
+        telemetry: &TelemetryAck,
+        received: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.telemetry.insert(
+            telemetry.node_id.to_owned(),
+            (telemetry.to_owned(), received),
+        );
+        Ok(())
+    }
+
+    async fn telemetry(
+        &self,
+        since: SystemTime,
+    ) -> anyhow::Result<Vec<(TelemetryAck, SystemTime)>> {
+        Ok(self
+            .telemetry
+            .values()
+            .filter(|(_, received)| *received >= since)
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_stale_telemetry(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let len = self.telemetry.len();
+        self.telemetry
+            .retain(|_, (_, received)| *received >= before);
+        Ok(len - self.telemetry.len())
+    }
+
     async fn add_peers(&mut self, addresses: Vec<SocketAddr>) -> Result<(), anyhow::Error> {
         for address in addresses {
             self.peers.insert(address);
@@ -114,4 +556,13 @@ impl State for MemoryState {
     async fn peers(&self) -> Result<HashSet<SocketAddr>, anyhow::Error> {
         Ok(self.peers.clone())
     }
+
+    async fn peer_count(&self) -> anyhow::Result<usize> {
+        Ok(self.peers.len())
+    }
+
+    async fn remove_peer(&mut self, address: &SocketAddr) -> anyhow::Result<()> {
+        self.peers.remove(address);
+        Ok(())
+    }
 }
@@ -1,16 +1,24 @@
-use crate::blocks::{Block, BlockHash};
+use crate::blocks::{Block, BlockHash, BlockHolder, Epoch, Previous};
 
+use crate::node::controller::BlockOrigin;
 use crate::node::cookie::Cookie;
-use crate::Public;
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::node::timestamp::Timestamp;
+use crate::{Private, Public, Rai};
+use anyhow::anyhow;
 use async_trait::async_trait;
+pub use batch::{BatchOp, StateBatch};
 pub use memory::MemoryState;
+use serde::{Deserialize, Serialize};
 pub use sled_disk::SledDiskState;
 use std::collections::HashSet;
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::Mutex;
 
+mod batch;
 mod memory;
 mod sled_disk;
 
@@ -21,30 +29,776 @@ pub type ArcState = Arc<Mutex<DynState>>;
 /// it also contains ephemeral information like peers.
 #[async_trait]
 pub trait State: Debug + Sync + Send + 'static {
-    async fn add_block(&mut self, block: &Block) -> anyhow::Result<()>;
+    /// Apply every write in the batch atomically. If an error is returned, none of the writes
+    /// have been applied.
+    async fn commit(&mut self, batch: StateBatch) -> anyhow::Result<()>;
+
+    /// Add a block and make it the head of its account.
+    async fn add_block(&mut self, block: &Block) -> anyhow::Result<()> {
+        let height = match block.previous() {
+            Previous::Block(previous) if previous != &BlockHash::zero() => {
+                self.block_height(previous)
+                    .await?
+                    .ok_or_else(|| anyhow!("Missing previous block {:?}", previous))?
+                    + 1
+            }
+            _ => 1,
+        };
+        let epoch = self.epoch_for_block(block).await?;
+        let mut batch = StateBatch::new();
+        batch
+            .add_block(block, height, epoch)?
+            .set_latest_block_hash(block.account(), block.hash()?);
+        self.commit(batch).await
+    }
 
     async fn get_block_by_hash(&self, hash: &BlockHash) -> anyhow::Result<Option<Block>>;
 
+    /// The height of a block in its account chain, counting from 1 at the open block.
+    async fn block_height(&self, hash: &BlockHash) -> anyhow::Result<Option<u64>>;
+
+    /// The block in the ledger whose root is `root`, i.e. the block after `root` in its account
+    /// chain, or the open block if `root` is an account.
+    async fn successor(&self, root: &BlockHash) -> anyhow::Result<Option<BlockHash>>;
+
+    /// The epoch an account is in as of a block.
+    async fn block_epoch(&self, hash: &BlockHash) -> anyhow::Result<Option<Epoch>>;
+
+    /// The epoch a block puts its account in: the epoch of the block before it, raised by an epoch
+    /// block or by receiving from an account in a later epoch. State blocks need their link
+    /// resolved first.
+    async fn epoch_for_block(&self, block: &Block) -> anyhow::Result<Epoch> {
+        if let Some(epoch) = block.link().epoch() {
+            return Ok(epoch);
+        }
+        let mut epoch = match block.previous() {
+            Previous::Block(previous) if previous != &BlockHash::zero() => self
+                .block_epoch(previous)
+                .await?
+                .ok_or_else(|| anyhow!("Missing previous block {:?}", previous))?,
+            _ => Epoch::V0,
+        };
+        if let Ok(source) = block.source() {
+            // Genesis received from nothing in the ledger.
+            if let Some(source_epoch) = self.block_epoch(source).await? {
+                epoch = epoch.max(source_epoch);
+            }
+        }
+        Ok(epoch)
+    }
+
     async fn get_latest_block_hash_for_account(
         &self,
         account: &Public,
     ) -> anyhow::Result<Option<BlockHash>>;
 
+    /// Account frontiers in account order, starting at `start`. Accounts that haven't been
+    /// modified since `modified_since` are skipped.
+    async fn frontiers(
+        &self,
+        start: &Public,
+        modified_since: Option<SystemTime>,
+        count: usize,
+    ) -> anyhow::Result<Vec<(Public, BlockHash)>>;
+
+    /// Sends to `account` that haven't been received yet, ordered by the send block hash.
+    async fn receivables(&self, account: &Public) -> anyhow::Result<Vec<(BlockHash, Receivable)>>;
+
+    async fn add_receivable(
+        &mut self,
+        account: &Public,
+        send_hash: &BlockHash,
+        receivable: &Receivable,
+    ) -> anyhow::Result<()> {
+        let mut batch = StateBatch::new();
+        batch.add_receivable(account, send_hash, receivable);
+        self.commit(batch).await
+    }
+
+    async fn remove_receivable(
+        &mut self,
+        account: &Public,
+        send_hash: &BlockHash,
+    ) -> anyhow::Result<()> {
+        let mut batch = StateBatch::new();
+        batch.remove_receivable(account, send_hash);
+        self.commit(batch).await
+    }
+
+    /// The voting weight delegated to a representative.
+    async fn rep_weight(&self, representative: &Public) -> anyhow::Result<Rai>;
+
+    /// Every representative with any weight delegated to it.
+    async fn rep_weights(&self) -> anyhow::Result<Vec<(Public, Rai)>>;
+
+    /// Record that a representative was seen voting.
+    async fn add_online_rep(
+        &mut self,
+        representative: &Public,
+        seen: SystemTime,
+    ) -> anyhow::Result<()>;
+
+    /// Representatives that have been seen voting since `since`.
+    async fn online_reps(&self, since: SystemTime) -> anyhow::Result<Vec<Public>>;
+
+    /// Forget the representatives last seen voting before `before`. Returns how many were removed.
+    async fn remove_stale_online_reps(&mut self, before: SystemTime) -> anyhow::Result<usize>;
+
     async fn account_for_block_hash(
         &mut self,
         block_hash: &BlockHash,
     ) -> anyhow::Result<Option<Public>>;
 
-    async fn add_vote(&mut self, hash: &BlockHash, representative: &Public) -> anyhow::Result<()>;
+    /// How far an account chain has been cemented, if at all.
+    async fn confirmation_height(
+        &self,
+        account: &Public,
+    ) -> anyhow::Result<Option<ConfirmationHeight>>;
+
+    /// How many blocks are in the ledger.
+    async fn block_count(&self) -> anyhow::Result<usize>;
+
+    /// How many blocks are cemented, which is the sum of every confirmation height.
+    async fn cemented_count(&self) -> anyhow::Result<u64>;
+
+    /// How many accounts have been opened.
+    async fn account_count(&self) -> anyhow::Result<usize>;
+
+    /// Store a vote for a block. A vote replaces any older vote from the same representative, and
+    /// is ignored if the stored vote is at least as new. Returns true if the vote was stored.
+    async fn add_vote(
+        &mut self,
+        hash: &BlockHash,
+        representative: &Public,
+        timestamp: &Timestamp,
+    ) -> anyhow::Result<bool>;
+
+    /// The latest vote of each representative for a block.
+    async fn votes(&self, hash: &BlockHash) -> anyhow::Result<Vec<(Public, Timestamp)>>;
+
+    /// Add a block to the election of `root`, starting the election at `now` if needed. Returns
+    /// false if the block is already a candidate.
+    async fn add_election_candidate(
+        &mut self,
+        root: &BlockHash,
+        block: &Block,
+        now: SystemTime,
+    ) -> anyhow::Result<bool>;
+
+    /// The competing blocks of an active election.
+    async fn election_candidates(&self, root: &BlockHash) -> anyhow::Result<Vec<Block>>;
+
+    /// The root of the active election a block is a candidate in.
+    async fn election_root(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockHash>>;
+
+    /// The roots of all active elections.
+    async fn active_elections(&self) -> anyhow::Result<Vec<BlockHash>>;
+
+    /// Stop an election, forgetting its candidates and their votes.
+    async fn remove_election(&mut self, root: &BlockHash) -> anyhow::Result<()>;
+
+    /// Stop the elections that started before `before`, like `remove_election`. Returns how many
+    /// were removed.
+    async fn remove_stale_elections(&mut self, before: SystemTime) -> anyhow::Result<usize>;
+
+    /// Store a block that can't be processed until `dependency` is in the ledger.
+    async fn add_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+        block: &BlockHolder,
+        origin: BlockOrigin,
+    ) -> anyhow::Result<()>;
+
+    /// Remove and return the blocks that were waiting on `dependency`.
+    async fn take_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+    ) -> anyhow::Result<Vec<(BlockHolder, BlockOrigin)>>;
+
+    /// How many blocks are waiting on a dependency.
+    async fn unchecked_count(&self) -> anyhow::Result<usize>;
+
+    /// Forget the block that has been waiting the longest, to make room. Returns false if there
+    /// are no unchecked blocks.
+    async fn remove_oldest_unchecked(&mut self) -> anyhow::Result<bool>;
+
+    async fn add_fork_event(&mut self, event: &ForkEvent) -> anyhow::Result<()>;
+
+    async fn fork_event_count(&self) -> anyhow::Result<usize>;
+
+    /// Forget the oldest fork event, to make room. Returns false if there are no fork events.
+    async fn remove_oldest_fork_event(&mut self) -> anyhow::Result<bool>;
+
+    /// Every fork event, oldest first.
+    async fn fork_events(&self) -> anyhow::Result<Vec<ForkEvent>>;
 
-    async fn set_cookie(&mut self, socket_addr: SocketAddr, cookie: Cookie) -> anyhow::Result<()>;
+    /// The private key of this node's ID, once it has been created.
+    async fn node_key(&self) -> anyhow::Result<Option<Private>>;
+
+    async fn set_node_key(&mut self, private: &Private) -> anyhow::Result<()>;
+
+    /// Remember the cookie sent to a peer in a handshake query, and when it was created.
+    async fn set_cookie(
+        &mut self,
+        socket_addr: SocketAddr,
+        cookie: Cookie,
+        created: SystemTime,
+    ) -> anyhow::Result<()>;
 
     async fn cookie_for_socket_addr(
         &self,
         socket_addr: &SocketAddr,
-    ) -> anyhow::Result<Option<Cookie>>;
+    ) -> anyhow::Result<Option<(Cookie, SystemTime)>>;
+
+    async fn remove_cookie(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()>;
+
+    /// Remove the cookies created before `before`. Returns how many were removed.
+    async fn remove_stale_cookies(&mut self, before: SystemTime) -> anyhow::Result<usize>;
+
+    /// Record the node ID a peer has proven in its handshake, and when it was proven.
+    async fn set_node_id(
+        &mut self,
+        socket_addr: SocketAddr,
+        node_id: Public,
+        proven: SystemTime,
+    ) -> anyhow::Result<()>;
+
+    async fn node_id_for_socket_addr(
+        &self,
+        socket_addr: &SocketAddr,
+    ) -> anyhow::Result<Option<Public>>;
+
+    /// The peers that have proven `node_id`, which is more than one if a node is connected
+    /// more than once.
+    async fn socket_addrs_for_node_id(&self, node_id: &Public) -> anyhow::Result<Vec<SocketAddr>>;
+
+    /// Every peer with a recorded node ID, and when the node ID was proven.
+    async fn node_ids(&self) -> anyhow::Result<Vec<(SocketAddr, SystemTime)>>;
+
+    async fn remove_node_id(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()>;
+
+    /// Store verified telemetry, replacing any older telemetry from the same node ID.
+    async fn add_telemetry(
+        &mut self,
+        telemetry: &TelemetryAck,
+        received: SystemTime,
+    ) -> anyhow::Result<()>;
+
+    /// The latest telemetry of every node, if it was received since `since`.
+    async fn telemetry(&self, since: SystemTime)
+        -> anyhow::Result<Vec<(TelemetryAck, SystemTime)>>;
+
+    /// Remove the telemetry received before `before`. Returns how many were removed.
+    async fn remove_stale_telemetry(&mut self, before: SystemTime) -> anyhow::Result<usize>;
 
     async fn add_peers(&mut self, addresses: Vec<SocketAddr>) -> anyhow::Result<()>;
 
     async fn peers(&self) -> anyhow::Result<HashSet<SocketAddr>>;
+
+    async fn peer_count(&self) -> anyhow::Result<usize>;
+
+    async fn remove_peer(&mut self, address: &SocketAddr) -> anyhow::Result<()>;
+}
+
+/// Funds sent to an account that haven't been received yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receivable {
+    /// The account that sent the funds.
+    pub source: Public,
+
+    pub amount: Rai,
+}
+
+impl Receivable {
+    pub fn new(source: Public, amount: Rai) -> Self {
+        Self { source, amount }
+    }
+}
+
+/// How many blocks of an account chain are cemented, counting from the open block, and the
+/// newest of them. Cemented blocks are final and can't be rolled back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmationHeight {
+    pub height: u64,
+    pub frontier: BlockHash,
+}
+
+impl ConfirmationHeight {
+    pub fn new(height: u64, frontier: BlockHash) -> Self {
+        Self { height, frontier }
+    }
+}
+
+/// Something that happened to a fork, kept for monitoring.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ForkEvent {
+    /// Blocks with the same root were seen competing with each other.
+    Detected {
+        root: BlockHash,
+        blocks: Vec<BlockHash>,
+    },
+
+    /// The election of a fork was won by `winner`. The blocks that lost, and the blocks that
+    /// depended on them, were rolled back newest first.
+    Resolved {
+        root: BlockHash,
+        winner: BlockHash,
+        rolled_back: Vec<BlockHash>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockType, Link, Previous, ValidationState};
+    use crate::network::Network;
+    use crate::Signature;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    async fn commit_batch(state: &mut DynState) {
+        let network = Network::Live;
+        let genesis = network.genesis_block();
+        let account = genesis.account();
+        let hash = genesis.hash().unwrap();
+        let other =
+            BlockHash::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        let receivable = Receivable::new(account.to_owned(), Rai::new(1u128));
+        let confirmation_height = ConfirmationHeight::new(1, hash.to_owned());
+
+        // Nothing is staged when the block is missing its hash.
+        let mut batch = StateBatch::new();
+        let unhashed = match genesis.to_holder().unwrap() {
+            BlockHolder::Open(open) => {
+                Block::from_open_block(&open, genesis.previous(), genesis.balance())
+            }
+            _ => unreachable!(),
+        };
+        assert!(batch.add_block(&unhashed, 1, Epoch::V0).is_err());
+        assert!(batch.is_empty());
+
+        batch
+            .add_block(&genesis, 1, Epoch::V0)
+            .unwrap()
+            .set_latest_block_hash(account, hash)
+            .add_receivable(account, &other, &receivable)
+            .set_rep_weight(account, &Rai::max())
+            .set_confirmation_height(account, &confirmation_height);
+        assert_eq!(batch.ops().len(), 5);
+        state.commit(batch).await.unwrap();
+
+        assert_eq!(
+            state.get_block_by_hash(hash).await.unwrap().as_ref(),
+            Some(&genesis)
+        );
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(account)
+                .await
+                .unwrap(),
+            Some(hash.to_owned())
+        );
+        assert_eq!(
+            state.receivables(account).await.unwrap(),
+            vec![(other.to_owned(), receivable)]
+        );
+        assert_eq!(state.rep_weight(account).await.unwrap(), Rai::max());
+        assert_eq!(
+            state.confirmation_height(account).await.unwrap(),
+            Some(confirmation_height.clone())
+        );
+        assert_eq!(state.block_count().await.unwrap(), 1);
+        assert_eq!(state.cemented_count().await.unwrap(), 1);
+        assert_eq!(state.account_count().await.unwrap(), 1);
+
+        // Writing the same things again doesn't change the counts.
+        let mut batch = StateBatch::new();
+        batch
+            .add_block(&genesis, 1, Epoch::V0)
+            .unwrap()
+            .set_latest_block_hash(account, hash)
+            .set_confirmation_height(account, &confirmation_height);
+        state.commit(batch).await.unwrap();
+        assert_eq!(state.block_count().await.unwrap(), 1);
+        assert_eq!(state.cemented_count().await.unwrap(), 1);
+        assert_eq!(state.account_count().await.unwrap(), 1);
+        assert_eq!(state.block_height(hash).await.unwrap(), Some(1));
+        assert_eq!(state.block_epoch(hash).await.unwrap(), Some(Epoch::V0));
+        assert_eq!(
+            state.successor(&genesis.root()).await.unwrap().as_ref(),
+            Some(hash)
+        );
+
+        let mut batch = StateBatch::new();
+        batch.remove_receivable(account, &other);
+        state.commit(batch).await.unwrap();
+        assert!(state.receivables(account).await.unwrap().is_empty());
+
+        let mut batch = StateBatch::new();
+        batch
+            .remove_block(&genesis)
+            .unwrap()
+            .remove_latest_block_hash(account);
+        state.commit(batch).await.unwrap();
+        assert_eq!(state.get_block_by_hash(hash).await.unwrap(), None);
+        assert_eq!(state.block_count().await.unwrap(), 0);
+        assert_eq!(state.account_count().await.unwrap(), 0);
+        assert_eq!(state.account_for_block_hash(hash).await.unwrap(), None);
+        assert_eq!(state.block_height(hash).await.unwrap(), None);
+        assert_eq!(state.block_epoch(hash).await.unwrap(), None);
+        assert_eq!(state.successor(&genesis.root()).await.unwrap(), None);
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(account)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    async fn unchecked(state: &mut DynState) {
+        let network = Network::Live;
+        let genesis = network.genesis_block().to_holder().unwrap();
+        let other = match genesis.clone() {
+            BlockHolder::Open(mut open) => {
+                open.source = BlockHash::zero();
+                BlockHolder::Open(open)
+            }
+            _ => unreachable!(),
+        };
+        let dependency = BlockHash::zero();
+
+        state
+            .add_unchecked(&dependency, &genesis, BlockOrigin::Live)
+            .await
+            .unwrap();
+        state
+            .add_unchecked(&dependency, &other, BlockOrigin::Bootstrap)
+            .await
+            .unwrap();
+        // Adding a block again doesn't count it twice.
+        state
+            .add_unchecked(&dependency, &other, BlockOrigin::Bootstrap)
+            .await
+            .unwrap();
+        assert_eq!(state.unchecked_count().await.unwrap(), 2);
+
+        // The oldest block goes first.
+        assert!(state.remove_oldest_unchecked().await.unwrap());
+        assert_eq!(state.unchecked_count().await.unwrap(), 1);
+        let taken: Vec<_> = state
+            .take_unchecked(&dependency)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(block, origin)| (block.hash().unwrap(), origin))
+            .collect();
+        assert_eq!(taken, vec![(other.hash().unwrap(), BlockOrigin::Bootstrap)]);
+        assert_eq!(state.unchecked_count().await.unwrap(), 0);
+        assert!(!state.remove_oldest_unchecked().await.unwrap());
+    }
+
+    async fn online_reps(state: &mut DynState) {
+        let stale = Private::random().to_public().unwrap();
+        let fresh = Private::random().to_public().unwrap();
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(60);
+        state.add_online_rep(&stale, old).await.unwrap();
+        state.add_online_rep(&fresh, now).await.unwrap();
+
+        let before = now - Duration::from_secs(30);
+        assert_eq!(
+            state.online_reps(before).await.unwrap(),
+            vec![fresh.clone()]
+        );
+        assert_eq!(state.remove_stale_online_reps(before).await.unwrap(), 1);
+        assert_eq!(
+            state.online_reps(SystemTime::UNIX_EPOCH).await.unwrap(),
+            vec![fresh]
+        );
+    }
+
+    async fn elections(state: &mut DynState) {
+        let genesis = Network::Live.genesis_block();
+        let account = genesis.account();
+        let fork = |balance: u128| {
+            let mut block = Block::new(
+                BlockType::State,
+                account.to_owned(),
+                Previous::Block(BlockHash::zero()),
+                account.to_owned(),
+                Rai::new(balance),
+                Link::Nothing,
+                ValidationState::Published,
+            );
+            block.calc_hash().unwrap();
+            block
+        };
+        let (a, b) = (fork(1), fork(2));
+        let root = a.root();
+        assert_eq!(root, b.root());
+
+        let now = SystemTime::now();
+        assert!(state.add_election_candidate(&root, &a, now).await.unwrap());
+        assert!(state.add_election_candidate(&root, &b, now).await.unwrap());
+        assert!(!state.add_election_candidate(&root, &a, now).await.unwrap());
+
+        let mut candidates = state.election_candidates(&root).await.unwrap();
+        candidates.sort_by_key(|block| block.balance().to_u128());
+        assert_eq!(candidates, vec![a.clone(), b.clone()]);
+        assert_eq!(
+            state.election_root(b.hash().unwrap()).await.unwrap(),
+            Some(root.to_owned())
+        );
+        assert_eq!(state.active_elections().await.unwrap(), vec![root.clone()]);
+
+        let rep = Private::random().to_public().unwrap();
+        assert!(state
+            .add_vote(a.hash().unwrap(), &rep, &Timestamp::from_u64(1))
+            .await
+            .unwrap());
+
+        state.remove_election(&root).await.unwrap();
+        assert!(state.votes(a.hash().unwrap()).await.unwrap().is_empty());
+        assert!(state.election_candidates(&root).await.unwrap().is_empty());
+        assert_eq!(state.election_root(a.hash().unwrap()).await.unwrap(), None);
+        assert!(state.active_elections().await.unwrap().is_empty());
+
+        // Joining an election doesn't restart it, so it's stale from when it started.
+        let old = now - Duration::from_secs(60);
+        let mut next = Block::new(
+            BlockType::State,
+            account.to_owned(),
+            Previous::Block(a.hash().unwrap().to_owned()),
+            account.to_owned(),
+            Rai::zero(),
+            Link::Nothing,
+            ValidationState::Published,
+        );
+        next.calc_hash().unwrap();
+        state.add_election_candidate(&root, &a, old).await.unwrap();
+        state.add_election_candidate(&root, &b, now).await.unwrap();
+        state
+            .add_election_candidate(&next.root(), &next, now)
+            .await
+            .unwrap();
+        state
+            .add_vote(b.hash().unwrap(), &rep, &Timestamp::from_u64(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            state
+                .remove_stale_elections(now - Duration::from_secs(30))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(state.votes(b.hash().unwrap()).await.unwrap().is_empty());
+        assert_eq!(state.election_root(b.hash().unwrap()).await.unwrap(), None);
+        assert_eq!(state.active_elections().await.unwrap(), vec![next.root()]);
+        state.remove_election(&next.root()).await.unwrap();
+        assert_eq!(state.remove_stale_elections(now).await.unwrap(), 0);
+
+        let event = ForkEvent::Detected {
+            root: root.to_owned(),
+            blocks: vec![a.hash().unwrap().to_owned(), b.hash().unwrap().to_owned()],
+        };
+        let resolved = ForkEvent::Resolved {
+            root: root.to_owned(),
+            winner: a.hash().unwrap().to_owned(),
+            rolled_back: vec![b.hash().unwrap().to_owned()],
+        };
+        state.add_fork_event(&event).await.unwrap();
+        state.add_fork_event(&resolved).await.unwrap();
+        assert_eq!(
+            state.fork_events().await.unwrap(),
+            vec![event, resolved.clone()]
+        );
+        assert_eq!(state.fork_event_count().await.unwrap(), 2);
+
+        assert!(state.remove_oldest_fork_event().await.unwrap());
+        assert_eq!(state.fork_events().await.unwrap(), vec![resolved]);
+        assert!(state.remove_oldest_fork_event().await.unwrap());
+        assert!(!state.remove_oldest_fork_event().await.unwrap());
+        assert_eq!(state.fork_event_count().await.unwrap(), 0);
+    }
+
+    async fn handshakes(state: &mut DynState) {
+        let peer = SocketAddr::from_str("1.2.3.4:7075").unwrap();
+        let other = SocketAddr::from_str("5.6.7.8:7075").unwrap();
+        let now = SystemTime::now();
+        let cookie = Cookie::random();
+
+        state
+            .set_cookie(peer, cookie.clone(), now - Duration::from_secs(60))
+            .await
+            .unwrap();
+        state
+            .set_cookie(other, Cookie::random(), now)
+            .await
+            .unwrap();
+        let (stored, _) = state.cookie_for_socket_addr(&peer).await.unwrap().unwrap();
+        assert_eq!(stored.as_bytes(), cookie.as_bytes());
+        assert_eq!(
+            state
+                .remove_stale_cookies(now - Duration::from_secs(30))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(state.cookie_for_socket_addr(&peer).await.unwrap().is_none());
+        state.remove_cookie(&other).await.unwrap();
+        assert!(state
+            .cookie_for_socket_addr(&other)
+            .await
+            .unwrap()
+            .is_none());
+
+        let node_id = Private::random().to_public().unwrap();
+        let proven = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        state
+            .set_node_id(peer, node_id.to_owned(), proven)
+            .await
+            .unwrap();
+        state
+            .set_node_id(other, node_id.to_owned(), proven)
+            .await
+            .unwrap();
+        let mut node_ids = state.node_ids().await.unwrap();
+        node_ids.sort();
+        assert_eq!(node_ids, vec![(peer, proven), (other, proven)]);
+        assert_eq!(
+            state.node_id_for_socket_addr(&peer).await.unwrap(),
+            Some(node_id.to_owned())
+        );
+        let mut socket_addrs = state.socket_addrs_for_node_id(&node_id).await.unwrap();
+        socket_addrs.sort();
+        assert_eq!(socket_addrs, vec![peer, other]);
+
+        state.remove_node_id(&peer).await.unwrap();
+        assert!(state
+            .node_id_for_socket_addr(&peer)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            state.socket_addrs_for_node_id(&node_id).await.unwrap(),
+            vec![other]
+        );
+    }
+
+    async fn telemetry(state: &mut DynState) {
+        let private = Private::random();
+        let mut telemetry = TelemetryAck {
+            signature: Signature::zero(),
+            node_id: private.to_public().unwrap(),
+            block_count: 1,
+            cemented_count: 1,
+            unchecked_count: 0,
+            account_count: 1,
+            bandwidth_cap: 0,
+            uptime: 60,
+            peer_count: 8,
+            protocol_version: 18,
+            genesis_block: Network::Live.genesis_hash(),
+            major_version: 22,
+            minor_version: 0,
+            patch_version: 0,
+            prerelease_version: 0,
+            maker: 0,
+            timestamp: 1614200740266,
+            active_difficulty: 0xfffffff800000000,
+            unknown_data: vec![],
+        };
+        telemetry.sign(&private).unwrap();
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(60);
+
+        state.add_telemetry(&telemetry, old).await.unwrap();
+        assert!(state
+            .telemetry(now - Duration::from_secs(30))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Newer telemetry from the same node replaces the old one.
+        telemetry.block_count = 2;
+        telemetry.sign(&private).unwrap();
+        state.add_telemetry(&telemetry, now).await.unwrap();
+        let stored = state.telemetry(old).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0.block_count, 2);
+        stored[0].0.verify_signature().unwrap();
+
+        assert_eq!(state.remove_stale_telemetry(old).await.unwrap(), 0);
+        assert_eq!(
+            state
+                .remove_stale_telemetry(now + Duration::from_secs(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(state.telemetry(old).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn telemetry_memory() {
+        telemetry(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn telemetry_sled() {
+        telemetry(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn handshakes_memory() {
+        handshakes(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn handshakes_sled() {
+        handshakes(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn unchecked_memory() {
+        unchecked(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn unchecked_sled() {
+        unchecked(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn online_reps_memory() {
+        online_reps(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn online_reps_sled() {
+        online_reps(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn elections_memory() {
+        elections(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn elections_sled() {
+        elections(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn commit_batch_memory() {
+        commit_batch(&mut MemoryState::new(Network::Live)).await;
+    }
+
+    #[tokio::test]
+    async fn commit_batch_sled() {
+        commit_batch(&mut SledDiskState::temporary(Network::Live).unwrap()).await;
+    }
 }
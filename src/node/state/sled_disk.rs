@@ -1,92 +1,1120 @@
-use crate::blocks::{Block, BlockHash};
+use crate::blocks::{Block, BlockHash, BlockHolder, Epoch};
 use crate::network::Network;
+use crate::node::controller::BlockOrigin;
 use crate::node::cookie::Cookie;
-use crate::node::state::State;
-use crate::Public;
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::node::state::{BatchOp, ConfirmationHeight, ForkEvent, Receivable, State, StateBatch};
+use crate::node::timestamp::Timestamp;
+use crate::node::wire::Wire;
+use crate::{Private, Public, Rai};
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
+use sled::transaction::ConflictableTransactionError;
+use sled::Transactional;
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Sled is an on disk key value pair.
 #[derive(Clone, Debug)]
 pub struct SledDiskState {
     network: Network,
     db: sled::Db,
+
+    /// Things the node has one of -> value, e.g. `NODE_KEY`, and counts of things in other trees
+    /// (big endian u64), e.g. `BLOCK_COUNT`, that are kept up to date by `commit`.
+    node: sled::Tree,
+
+    /// Socket address -> cookie followed by when it was created (big endian u64 seconds).
     cookies: sled::Tree,
+
+    /// Socket address -> node ID the peer proved in its handshake, followed by when it was proven
+    /// (big endian u64 seconds).
+    node_ids: sled::Tree,
+
+    /// Socket address as a string -> nothing.
     peers: sled::Tree,
+
+    /// Block hash -> block as JSON.
+    blocks: sled::Tree,
+
+    /// Block hash -> account, followed by the height of the block in the account chain (big
+    /// endian u64) and the epoch of the account as of the block.
+    block_hash_to_account: sled::Tree,
+
+    /// Root -> the block built on it, i.e. previous block hash -> next block hash, or account ->
+    /// open block hash.
+    successors: sled::Tree,
+
+    /// Account -> latest block hash followed by the modified time in seconds (big endian u64).
+    latest_block_hash: sled::Tree,
+
+    /// Account followed by the send block hash -> source account followed by the amount.
+    receivables: sled::Tree,
+
+    /// Block hash followed by the representative -> vote timestamp.
+    votes: sled::Tree,
+
+    /// Representative -> weight.
+    rep_weights: sled::Tree,
+
+    /// Representative -> when it was last seen voting in seconds (big endian u64).
+    online_reps: sled::Tree,
+
+    /// Account -> cemented frontier block hash followed by the height (big endian u64).
+    confirmation_heights: sled::Tree,
+
+    /// Election root followed by the candidate block hash -> block as JSON.
+    elections: sled::Tree,
+
+    /// Candidate block hash -> election root.
+    election_roots: sled::Tree,
+
+    /// Election root -> when the election started in seconds (big endian u64).
+    election_starts: sled::Tree,
+
+    /// Sequential ID (big endian u64) -> fork event as JSON.
+    fork_events: sled::Tree,
+
+    /// Missing dependency block hash followed by the block hash -> ID in `unchecked_order` (big
+    /// endian u64) followed by the block and origin as JSON.
+    unchecked: sled::Tree,
+
+    /// Sequential ID (big endian u64) -> key in `unchecked`, so the oldest block comes first.
+    unchecked_order: sled::Tree,
+
+    /// Node ID -> when the telemetry was received (big endian u64 seconds) followed by the
+    /// telemetry ack.
+    telemetry: sled::Tree,
 }
 
 impl SledDiskState {
-    pub fn new(network: Network) -> Self {
-        let path = format!("{:?}.db", network).to_ascii_lowercase();
-        let db: sled::Db =
-            sled::open(&path).unwrap_or_else(|_| panic!("Could not open database: {}", &path));
-        let cookies = db.open_tree("cookies").unwrap();
-        let peers = db.open_tree("peers").unwrap();
-        Self {
+    // Indexes of the trees written to in `commit`.
+    const BLOCKS: usize = 0;
+    const BLOCK_HASH_TO_ACCOUNT: usize = 1;
+    const LATEST_BLOCK_HASH: usize = 2;
+    const RECEIVABLES: usize = 3;
+    const REP_WEIGHTS: usize = 4;
+    const CONFIRMATION_HEIGHTS: usize = 5;
+    const SUCCESSORS: usize = 6;
+    const NODE: usize = 7;
+
+    /// Key in the `node` tree of the node ID's private key, in hex.
+    const NODE_KEY: &'static str = "node_key";
+
+    /// Keys in the `node` tree of how many blocks, accounts and cemented blocks there are.
+    const BLOCK_COUNT: &'static str = "block_count";
+    const ACCOUNT_COUNT: &'static str = "account_count";
+    const CEMENTED_COUNT: &'static str = "cemented_count";
+
+    /// Open (or create) the database for `network` inside `data_dir`. Only one process can have
+    /// the database open at a time.
+    pub fn new(network: Network, data_dir: &Path) -> anyhow::Result<Self> {
+        let path = data_dir.join(format!("{:?}.db", network).to_ascii_lowercase());
+        let db = sled::open(&path)
+            .map_err(|err| match err {
+                // Sled only tells us about its lock in the message.
+                sled::Error::Io(err) if err.to_string().contains("could not acquire lock") => {
+                    anyhow!("The database is in use by another process, e.g. a running node")
+                }
+                err => err.into(),
+            })
+            .with_context(|| format!("Could not open database: {:?}", &path))?;
+        Self::from_db(network, db)
+    }
+
+    /// A database that is removed when dropped, e.g. for tests.
+    pub fn temporary(network: Network) -> anyhow::Result<Self> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .context("Could not open temporary database")?;
+        Self::from_db(network, db)
+    }
+
+    fn from_db(network: Network, db: sled::Db) -> anyhow::Result<Self> {
+        Ok(Self {
             network,
+            node: db.open_tree("node")?,
+            cookies: db.open_tree("cookies")?,
+            node_ids: db.open_tree("node_ids")?,
+            peers: db.open_tree("peers")?,
+            blocks: db.open_tree("blocks")?,
+            block_hash_to_account: db.open_tree("block_hash_to_account")?,
+            successors: db.open_tree("successors")?,
+            latest_block_hash: db.open_tree("latest_block_hash")?,
+            receivables: db.open_tree("receivables")?,
+            votes: db.open_tree("votes")?,
+            rep_weights: db.open_tree("rep_weights")?,
+            online_reps: db.open_tree("online_reps")?,
+            confirmation_heights: db.open_tree("confirmation_heights")?,
+            elections: db.open_tree("elections")?,
+            election_roots: db.open_tree("election_roots")?,
+            election_starts: db.open_tree("election_starts")?,
+            fork_events: db.open_tree("fork_events")?,
+            unchecked: db.open_tree("unchecked")?,
+            unchecked_order: db.open_tree("unchecked_order")?,
+            telemetry: db.open_tree("telemetry")?,
             db,
-            cookies,
-            peers,
-        }
+        })
+    }
+
+    fn count(&self, key: &str) -> anyhow::Result<u64> {
+        let value = self
+            .node
+            .get(key)
+            .with_context(|| format!("Count {}", key))?;
+        Ok(Self::decode_count(value.as_deref()))
+    }
+
+    fn decode_count(value: Option<&[u8]>) -> u64 {
+        value
+            .and_then(|value| <[u8; 8]>::try_from(value).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0)
+    }
+
+    /// The height in a `confirmation_heights` value, or 0 if there isn't one.
+    fn decode_cemented_height(value: Option<&[u8]>) -> u64 {
+        Self::decode_count(value.and_then(|value| value.get(BlockHash::LEN..)))
+    }
+
+    fn decode_latest_block_hash(value: &[u8]) -> anyhow::Result<(BlockHash, SystemTime)> {
+        let hash = BlockHash::try_from(&value[0..BlockHash::LEN])?;
+        let secs = <[u8; 8]>::try_from(&value[BlockHash::LEN..])?;
+        let modified = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs));
+        Ok((hash, modified))
+    }
+
+    fn encode_latest_block_hash(hash: &BlockHash, modified: SystemTime) -> Vec<u8> {
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut value = hash.as_bytes().to_vec();
+        value.extend_from_slice(&secs.to_be_bytes());
+        value
+    }
+
+    fn decode_seen(value: &[u8]) -> anyhow::Result<SystemTime> {
+        let secs = <[u8; 8]>::try_from(value)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs)))
+    }
+
+    fn decode_telemetry_received(value: &[u8]) -> anyhow::Result<SystemTime> {
+        let secs = <[u8; 8]>::try_from(&value[..8])?;
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs)))
+    }
+
+    fn decode_node_id(value: &[u8]) -> anyhow::Result<Public> {
+        let node_id = value
+            .get(..Public::LEN)
+            .ok_or_else(|| anyhow!("Node ID record is too short: {:?}", value))?;
+        Ok(Public::try_from(node_id)?)
+    }
+
+    fn decode_node_id_proven(value: &[u8]) -> anyhow::Result<SystemTime> {
+        let secs = value
+            .get(Public::LEN..)
+            .ok_or_else(|| anyhow!("Node ID record is too short: {:?}", value))?;
+        let secs = <[u8; 8]>::try_from(secs)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs)))
+    }
+
+    fn decode_cookie(value: &[u8]) -> anyhow::Result<(Cookie, SystemTime)> {
+        let cookie = Cookie::try_from(&value[0..Cookie::LEN])?;
+        let secs = <[u8; 8]>::try_from(&value[Cookie::LEN..])?;
+        let created = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(secs));
+        Ok((cookie, created))
     }
 }
 
 #[async_trait]
 impl State for SledDiskState {
-    async fn add_block(&mut self, _block_holder: &Block) -> anyhow::Result<()> {
-        unimplemented!()
+    async fn commit(&mut self, batch: StateBatch) -> anyhow::Result<()> {
+        // Encode everything up front, since the transaction closure can be retried.
+        let mut writes: Vec<(usize, Vec<u8>, Option<Vec<u8>>)> = vec![];
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::AddBlock {
+                    hash,
+                    block,
+                    height,
+                    epoch,
+                } => {
+                    let json = serde_json::to_vec(&block)
+                        .with_context(|| format!("Encoding block {:?}", block))?;
+                    writes.push((Self::BLOCKS, hash.as_bytes().to_vec(), Some(json)));
+                    let mut value = block.account().as_bytes().to_vec();
+                    value.extend_from_slice(&height.to_be_bytes());
+                    value.push(epoch.as_u8());
+                    writes.push((
+                        Self::BLOCK_HASH_TO_ACCOUNT,
+                        hash.as_bytes().to_vec(),
+                        Some(value),
+                    ));
+                    writes.push((
+                        Self::SUCCESSORS,
+                        block.root().as_bytes().to_vec(),
+                        Some(hash.as_bytes().to_vec()),
+                    ));
+                }
+                BatchOp::RemoveBlock { hash, root } => {
+                    writes.push((Self::BLOCKS, hash.as_bytes().to_vec(), None));
+                    writes.push((Self::BLOCK_HASH_TO_ACCOUNT, hash.as_bytes().to_vec(), None));
+                    writes.push((Self::SUCCESSORS, root.as_bytes().to_vec(), None));
+                }
+                BatchOp::SetLatestBlockHash { account, hash } => writes.push((
+                    Self::LATEST_BLOCK_HASH,
+                    account.as_bytes().to_vec(),
+                    Some(Self::encode_latest_block_hash(&hash, SystemTime::now())),
+                )),
+                BatchOp::RemoveLatestBlockHash { account } => {
+                    writes.push((Self::LATEST_BLOCK_HASH, account.as_bytes().to_vec(), None))
+                }
+                BatchOp::AddReceivable {
+                    account,
+                    send_hash,
+                    receivable,
+                } => {
+                    let mut value = receivable.source.as_bytes().to_vec();
+                    value.extend_from_slice(&receivable.amount.to_vec());
+                    writes.push((
+                        Self::RECEIVABLES,
+                        receivable_key(&account, &send_hash),
+                        Some(value),
+                    ));
+                }
+                BatchOp::RemoveReceivable { account, send_hash } => writes.push((
+                    Self::RECEIVABLES,
+                    receivable_key(&account, &send_hash),
+                    None,
+                )),
+                BatchOp::SetRepWeight {
+                    representative,
+                    weight,
+                } => writes.push((
+                    Self::REP_WEIGHTS,
+                    representative.as_bytes().to_vec(),
+                    Some(weight.to_vec()),
+                )),
+                BatchOp::SetConfirmationHeight {
+                    account,
+                    confirmation_height,
+                } => {
+                    let mut value = confirmation_height.frontier.as_bytes().to_vec();
+                    value.extend_from_slice(&confirmation_height.height.to_be_bytes());
+                    writes.push((
+                        Self::CONFIRMATION_HEIGHTS,
+                        account.as_bytes().to_vec(),
+                        Some(value),
+                    ));
+                }
+            }
+        }
+
+        let trees = [
+            self.blocks.clone(),
+            self.block_hash_to_account.clone(),
+            self.latest_block_hash.clone(),
+            self.receivables.clone(),
+            self.rep_weights.clone(),
+            self.confirmation_heights.clone(),
+            self.successors.clone(),
+            self.node.clone(),
+        ];
+        trees
+            .as_ref()
+            .transaction(|trees| {
+                // Changes to the counts, from what each write replaced.
+                let (mut blocks, mut accounts, mut cemented) = (0i64, 0i64, 0i64);
+                for (tree, key, value) in &writes {
+                    let old = match value {
+                        Some(value) => trees[*tree].insert(key.as_slice(), value.as_slice())?,
+                        None => trees[*tree].remove(key.as_slice())?,
+                    };
+                    let added = value.is_some() as i64 - old.is_some() as i64;
+                    match *tree {
+                        Self::BLOCKS => blocks += added,
+                        Self::LATEST_BLOCK_HASH => accounts += added,
+                        Self::CONFIRMATION_HEIGHTS => {
+                            cemented += Self::decode_cemented_height(value.as_deref()) as i64
+                                - Self::decode_cemented_height(old.as_deref()) as i64
+                        }
+                        _ => {}
+                    }
+                }
+
+                let node = &trees[Self::NODE];
+                let counts = [
+                    (Self::BLOCK_COUNT, blocks),
+                    (Self::ACCOUNT_COUNT, accounts),
+                    (Self::CEMENTED_COUNT, cemented),
+                ];
+                for (key, change) in counts.iter() {
+                    if *change == 0 {
+                        continue;
+                    }
+                    let count = Self::decode_count(node.get(key)?.as_deref()) as i64 + change;
+                    node.insert(*key, &(count.max(0) as u64).to_be_bytes())?;
+                }
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| anyhow!("Committing batch: {:?}", err))?;
+        Ok(())
     }
 
-    async fn get_block_by_hash(&self, _hash: &BlockHash) -> anyhow::Result<Option<Block>> {
-        unimplemented!()
+    async fn get_block_by_hash(&self, hash: &BlockHash) -> anyhow::Result<Option<Block>> {
+        let context = || format!("Get block {:?}", hash);
+        let json = match self.blocks.get(hash.as_bytes()).with_context(context)? {
+            Some(json) => json,
+            None => return Ok(None),
+        };
+        Ok(Some(serde_json::from_slice(&json).with_context(context)?))
+    }
+
+    async fn block_height(&self, hash: &BlockHash) -> anyhow::Result<Option<u64>> {
+        let context = || format!("Block height for {:?}", hash);
+        let value = match self
+            .block_hash_to_account
+            .get(hash.as_bytes())
+            .with_context(context)?
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let height =
+            <[u8; 8]>::try_from(&value[Public::LEN..Public::LEN + 8]).with_context(context)?;
+        Ok(Some(u64::from_be_bytes(height)))
+    }
+
+    async fn successor(&self, root: &BlockHash) -> anyhow::Result<Option<BlockHash>> {
+        let context = || format!("Successor of {:?}", root);
+        Ok(
+            match self.successors.get(root.as_bytes()).with_context(context)? {
+                Some(hash) => Some(BlockHash::try_from(hash.as_ref()).with_context(context)?),
+                None => None,
+            },
+        )
+    }
+
+    async fn block_epoch(&self, hash: &BlockHash) -> anyhow::Result<Option<Epoch>> {
+        let context = || format!("Block epoch for {:?}", hash);
+        let value = match self
+            .block_hash_to_account
+            .get(hash.as_bytes())
+            .with_context(context)?
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        Ok(Some(
+            Epoch::try_from(value[Public::LEN + 8]).with_context(context)?,
+        ))
     }
 
     async fn get_latest_block_hash_for_account(
         &self,
-        _account: &Public,
+        account: &Public,
     ) -> anyhow::Result<Option<BlockHash>> {
-        unimplemented!()
+        let context = || format!("Latest block hash for {:?}", account);
+        let value = match self
+            .latest_block_hash
+            .get(account.as_bytes())
+            .with_context(context)?
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let (hash, _) = Self::decode_latest_block_hash(&value).with_context(context)?;
+        Ok(Some(hash))
+    }
+
+    async fn frontiers(
+        &self,
+        start: &Public,
+        modified_since: Option<SystemTime>,
+        count: usize,
+    ) -> anyhow::Result<Vec<(Public, BlockHash)>> {
+        let mut frontiers = vec![];
+        for entry in self.latest_block_hash.range(start.as_bytes()..) {
+            if frontiers.len() >= count {
+                break;
+            }
+            let (key, value) = entry?;
+            let (hash, modified) = Self::decode_latest_block_hash(&value)?;
+            if let Some(since) = modified_since {
+                if modified < since {
+                    continue;
+                }
+            }
+            frontiers.push((Public::try_from(key.as_ref())?, hash));
+        }
+        Ok(frontiers)
+    }
+
+    async fn receivables(&self, account: &Public) -> anyhow::Result<Vec<(BlockHash, Receivable)>> {
+        let mut receivables = vec![];
+        for entry in self.receivables.scan_prefix(account.as_bytes()) {
+            let (key, value) = entry?;
+            let hash = BlockHash::try_from(&key[Public::LEN..])?;
+            let source = Public::try_from(&value[0..Public::LEN])?;
+            let amount = Rai::try_from(&value[Public::LEN..])?;
+            receivables.push((hash, Receivable::new(source, amount)));
+        }
+        Ok(receivables)
+    }
+
+    async fn rep_weight(&self, representative: &Public) -> anyhow::Result<Rai> {
+        let context = || format!("Rep weight for {:?}", representative);
+        Ok(
+            match self
+                .rep_weights
+                .get(representative.as_bytes())
+                .with_context(context)?
+            {
+                Some(weight) => Rai::try_from(weight.as_ref()).with_context(context)?,
+                None => Rai::zero(),
+            },
+        )
+    }
+
+    async fn rep_weights(&self) -> anyhow::Result<Vec<(Public, Rai)>> {
+        let mut weights = vec![];
+        for entry in self.rep_weights.iter() {
+            let (key, value) = entry?;
+            let weight = Rai::try_from(value.as_ref())?;
+            if weight != Rai::zero() {
+                weights.push((Public::try_from(key.as_ref())?, weight));
+            }
+        }
+        Ok(weights)
+    }
+
+    async fn add_online_rep(
+        &mut self,
+        representative: &Public,
+        seen: SystemTime,
+    ) -> anyhow::Result<()> {
+        let secs = seen
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.online_reps
+            .insert(representative.as_bytes(), &secs.to_be_bytes())?;
+        Ok(())
+    }
+
+    async fn online_reps(&self, since: SystemTime) -> anyhow::Result<Vec<Public>> {
+        let mut reps = vec![];
+        for entry in self.online_reps.iter() {
+            let (key, value) = entry?;
+            if Self::decode_seen(&value)? >= since {
+                reps.push(Public::try_from(key.as_ref())?);
+            }
+        }
+        Ok(reps)
+    }
+
+    async fn remove_stale_online_reps(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for entry in self.online_reps.iter() {
+            let (key, value) = entry?;
+            if Self::decode_seen(&value)? < before {
+                self.online_reps.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
     }
 
     async fn account_for_block_hash(
         &mut self,
-        _block_hash: &BlockHash,
+        block_hash: &BlockHash,
     ) -> Result<Option<Public>, anyhow::Error> {
-        unimplemented!()
+        let context = || format!("Account for block hash {:?}", block_hash);
+        Ok(
+            match self
+                .block_hash_to_account
+                .get(block_hash.as_bytes())
+                .with_context(context)?
+            {
+                Some(value) => {
+                    let account = value
+                        .get(..Public::LEN)
+                        .ok_or_else(|| anyhow!("Account record is too short: {:?}", value))
+                        .with_context(context)?;
+                    Some(Public::try_from(account).with_context(context)?)
+                }
+                None => None,
+            },
+        )
+    }
+
+    async fn confirmation_height(
+        &self,
+        account: &Public,
+    ) -> anyhow::Result<Option<ConfirmationHeight>> {
+        let context = || format!("Confirmation height for {:?}", account);
+        let value = match self
+            .confirmation_heights
+            .get(account.as_bytes())
+            .with_context(context)?
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let frontier = BlockHash::try_from(&value[..BlockHash::LEN]).with_context(context)?;
+        let height = <[u8; 8]>::try_from(&value[BlockHash::LEN..]).with_context(context)?;
+        Ok(Some(ConfirmationHeight::new(
+            u64::from_be_bytes(height),
+            frontier,
+        )))
+    }
+
+    async fn block_count(&self) -> anyhow::Result<usize> {
+        Ok(self.count(Self::BLOCK_COUNT)? as usize)
+    }
+
+    async fn cemented_count(&self) -> anyhow::Result<u64> {
+        self.count(Self::CEMENTED_COUNT)
+    }
+
+    async fn account_count(&self) -> anyhow::Result<usize> {
+        Ok(self.count(Self::ACCOUNT_COUNT)? as usize)
     }
 
     async fn add_vote(
         &mut self,
-        _hash: &BlockHash,
-        _representative: &Public,
+        hash: &BlockHash,
+        representative: &Public,
+        timestamp: &Timestamp,
+    ) -> anyhow::Result<bool> {
+        let context = || format!("Add vote for {:?} by {:?}", hash, representative);
+        let mut key = hash.as_bytes().to_vec();
+        key.extend_from_slice(representative.as_bytes());
+
+        if let Some(existing) = self.votes.get(&key).with_context(context)? {
+            let existing = Timestamp::try_from(existing.as_ref()).with_context(context)?;
+            if &existing >= timestamp {
+                return Ok(false);
+            }
+        }
+        self.votes
+            .insert(key, timestamp.to_bytes().to_vec())
+            .with_context(context)?;
+        Ok(true)
+    }
+
+    async fn votes(&self, hash: &BlockHash) -> anyhow::Result<Vec<(Public, Timestamp)>> {
+        let context = || format!("Votes for {:?}", hash);
+        let mut votes = vec![];
+        for entry in self.votes.scan_prefix(hash.as_bytes()) {
+            let (key, value) = entry.with_context(context)?;
+            let rep = Public::try_from(&key[BlockHash::LEN..]).with_context(context)?;
+            let timestamp = Timestamp::try_from(value.as_ref()).with_context(context)?;
+            votes.push((rep, timestamp));
+        }
+        Ok(votes)
+    }
+
+    async fn add_election_candidate(
+        &mut self,
+        root: &BlockHash,
+        block: &Block,
+        now: SystemTime,
+    ) -> anyhow::Result<bool> {
+        let context = || format!("Add election candidate {:?} for {:?}", block, root);
+        let hash = block.hash().with_context(context)?;
+        if self
+            .election_roots
+            .contains_key(hash.as_bytes())
+            .with_context(context)?
+        {
+            return Ok(false);
+        }
+
+        let mut key = root.as_bytes().to_vec();
+        key.extend_from_slice(hash.as_bytes());
+        let json = serde_json::to_vec(block).with_context(context)?;
+        self.elections.insert(key, json).with_context(context)?;
+        self.election_roots
+            .insert(hash.as_bytes(), root.as_bytes())
+            .with_context(context)?;
+        if !self
+            .election_starts
+            .contains_key(root.as_bytes())
+            .with_context(context)?
+        {
+            let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            self.election_starts
+                .insert(root.as_bytes(), &secs.to_be_bytes())
+                .with_context(context)?;
+        }
+        Ok(true)
+    }
+
+    async fn election_candidates(&self, root: &BlockHash) -> anyhow::Result<Vec<Block>> {
+        let context = || format!("Election candidates for {:?}", root);
+        let mut blocks = vec![];
+        for entry in self.elections.scan_prefix(root.as_bytes()) {
+            let (_, json) = entry.with_context(context)?;
+            blocks.push(serde_json::from_slice(&json).with_context(context)?);
+        }
+        Ok(blocks)
+    }
+
+    async fn election_root(&self, hash: &BlockHash) -> anyhow::Result<Option<BlockHash>> {
+        let context = || format!("Election root for {:?}", hash);
+        Ok(
+            match self
+                .election_roots
+                .get(hash.as_bytes())
+                .with_context(context)?
+            {
+                Some(root) => Some(BlockHash::try_from(root.as_ref()).with_context(context)?),
+                None => None,
+            },
+        )
+    }
+
+    async fn active_elections(&self) -> anyhow::Result<Vec<BlockHash>> {
+        let mut roots: Vec<BlockHash> = vec![];
+        for entry in self.elections.iter() {
+            let (key, _) = entry?;
+            let root = BlockHash::try_from(&key[..BlockHash::LEN])?;
+            // Keys are sorted, so the candidates of an election are next to each other.
+            if roots.last() != Some(&root) {
+                roots.push(root);
+            }
+        }
+        Ok(roots)
+    }
+
+    async fn remove_election(&mut self, root: &BlockHash) -> anyhow::Result<()> {
+        let context = || format!("Remove election {:?}", root);
+        for entry in self.elections.scan_prefix(root.as_bytes()) {
+            let (key, _) = entry.with_context(context)?;
+            let hash = &key[BlockHash::LEN..];
+            self.election_roots.remove(hash).with_context(context)?;
+            for vote in self.votes.scan_prefix(hash) {
+                let (vote_key, _) = vote.with_context(context)?;
+                self.votes.remove(vote_key).with_context(context)?;
+            }
+            self.elections.remove(&key).with_context(context)?;
+        }
+        self.election_starts
+            .remove(root.as_bytes())
+            .with_context(context)?;
+        Ok(())
+    }
+
+    async fn remove_stale_elections(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let mut stale = vec![];
+        for entry in self.election_starts.iter() {
+            let (root, value) = entry?;
+            if Self::decode_seen(&value)? < before {
+                stale.push(BlockHash::try_from(root.as_ref())?);
+            }
+        }
+        for root in &stale {
+            self.remove_election(root).await?;
+        }
+        Ok(stale.len())
+    }
+
+    async fn add_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+        block: &BlockHolder,
+        origin: BlockOrigin,
     ) -> anyhow::Result<()> {
-        unimplemented!()
+        let context = || format!("Add unchecked {:?} waiting on {:?}", block, dependency);
+        let mut key = dependency.as_bytes().to_vec();
+        key.extend_from_slice(block.hash().with_context(context)?.as_bytes());
+        let id = self.db.generate_id().with_context(context)?;
+        let mut value = id.to_be_bytes().to_vec();
+        value.extend(serde_json::to_vec(&(block, origin)).with_context(context)?);
+        // Both trees are written together, so the eviction order always matches the entries.
+        (&self.unchecked, &self.unchecked_order)
+            .transaction(|(unchecked, unchecked_order)| {
+                if let Some(replaced) = unchecked.insert(key.as_slice(), value.as_slice())? {
+                    unchecked_order.remove(&replaced[..8])?;
+                }
+                unchecked_order.insert(&id.to_be_bytes(), key.as_slice())?;
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| anyhow!("Adding unchecked: {:?}", err))
+            .with_context(context)?;
+        Ok(())
+    }
+
+    async fn take_unchecked(
+        &mut self,
+        dependency: &BlockHash,
+    ) -> anyhow::Result<Vec<(BlockHolder, BlockOrigin)>> {
+        let context = || format!("Take unchecked waiting on {:?}", dependency);
+        let mut blocks = vec![];
+        let mut entries = vec![];
+        for entry in self.unchecked.scan_prefix(dependency.as_bytes()) {
+            let (key, value) = entry.with_context(context)?;
+            blocks.push(serde_json::from_slice(&value[8..]).with_context(context)?);
+            entries.push((key, value));
+        }
+        (&self.unchecked, &self.unchecked_order)
+            .transaction(|(unchecked, unchecked_order)| {
+                for (key, value) in &entries {
+                    unchecked.remove(key)?;
+                    unchecked_order.remove(&value[..8])?;
+                }
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| anyhow!("Taking unchecked: {:?}", err))
+            .with_context(context)?;
+        Ok(blocks)
     }
 
-    async fn set_cookie(&mut self, socket_addr: SocketAddr, cookie: Cookie) -> anyhow::Result<()> {
-        self.cookies
-            .insert(format!("{}", socket_addr), cookie.as_bytes())?;
+    async fn unchecked_count(&self) -> anyhow::Result<usize> {
+        Ok(self.unchecked.len())
+    }
+
+    async fn remove_oldest_unchecked(&mut self) -> anyhow::Result<bool> {
+        let context = || "Remove oldest unchecked";
+        let (id, key) = match self.unchecked_order.first().with_context(context)? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        (&self.unchecked, &self.unchecked_order)
+            .transaction(|(unchecked, unchecked_order)| {
+                unchecked.remove(&key)?;
+                unchecked_order.remove(&id)?;
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|err| anyhow!("Removing oldest unchecked: {:?}", err))
+            .with_context(context)?;
+        Ok(true)
+    }
+
+    async fn add_fork_event(&mut self, event: &ForkEvent) -> anyhow::Result<()> {
+        let context = || format!("Add fork event {:?}", event);
+        let id = self.db.generate_id().with_context(context)?;
+        let json = serde_json::to_vec(event).with_context(context)?;
+        self.fork_events
+            .insert(id.to_be_bytes(), json)
+            .with_context(context)?;
+        Ok(())
+    }
+
+    async fn fork_event_count(&self) -> anyhow::Result<usize> {
+        Ok(self.fork_events.len())
+    }
+
+    async fn remove_oldest_fork_event(&mut self) -> anyhow::Result<bool> {
+        Ok(self
+            .fork_events
+            .pop_min()
+            .context("Remove oldest fork event")?
+            .is_some())
+    }
+
+    async fn fork_events(&self) -> anyhow::Result<Vec<ForkEvent>> {
+        let mut events = vec![];
+        for entry in self.fork_events.iter() {
+            let (_, json) = entry?;
+            events.push(serde_json::from_slice(&json).context("Decoding fork event")?);
+        }
+        Ok(events)
+    }
+
+    async fn node_key(&self) -> anyhow::Result<Option<Private>> {
+        match self.node.get(Self::NODE_KEY)? {
+            Some(hex) => Ok(Some(
+                Private::from_str(&String::from_utf8_lossy(&hex)).context("Decoding node key")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_node_key(&mut self, private: &Private) -> anyhow::Result<()> {
+        self.node
+            .insert(Self::NODE_KEY, private.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    async fn set_cookie(
+        &mut self,
+        socket_addr: SocketAddr,
+        cookie: Cookie,
+        created: SystemTime,
+    ) -> anyhow::Result<()> {
+        let secs = created
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut value = cookie.as_bytes().to_vec();
+        value.extend_from_slice(&secs.to_be_bytes());
+        self.cookies.insert(format!("{}", socket_addr), value)?;
         Ok(())
     }
 
     async fn cookie_for_socket_addr(
         &self,
         socket_addr: &SocketAddr,
-    ) -> anyhow::Result<Option<Cookie>> {
+    ) -> anyhow::Result<Option<(Cookie, SystemTime)>> {
         let maybe_cookie = self.cookies.get(format!("{}", socket_addr))?;
         Ok(match maybe_cookie.as_ref() {
             None => None,
-            Some(c) => Some(Cookie::try_from(c.as_ref())?),
+            Some(c) => Some(Self::decode_cookie(c)?),
         })
     }
 
-    async fn add_peers(&mut self, _addresses: Vec<SocketAddr>) -> Result<(), anyhow::Error> {
-        unimplemented!()
+    async fn remove_cookie(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()> {
+        self.cookies.remove(format!("{}", socket_addr))?;
+        Ok(())
+    }
+
+    async fn remove_stale_cookies(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for entry in self.cookies.iter() {
+            let (key, value) = entry?;
+            let (_, created) = Self::decode_cookie(&value)?;
+            if created < before {
+                self.cookies.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn set_node_id(
+        &mut self,
+        socket_addr: SocketAddr,
+        node_id: Public,
+        proven: SystemTime,
+    ) -> anyhow::Result<()> {
+        let secs = proven
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut value = node_id.as_bytes().to_vec();
+        value.extend_from_slice(&secs.to_be_bytes());
+        self.node_ids.insert(format!("{}", socket_addr), value)?;
+        Ok(())
+    }
+
+    async fn node_id_for_socket_addr(
+        &self,
+        socket_addr: &SocketAddr,
+    ) -> anyhow::Result<Option<Public>> {
+        Ok(match self.node_ids.get(format!("{}", socket_addr))? {
+            Some(value) => Some(Self::decode_node_id(&value)?),
+            None => None,
+        })
+    }
+
+    async fn socket_addrs_for_node_id(&self, node_id: &Public) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut socket_addrs = vec![];
+        for entry in self.node_ids.iter() {
+            let (key, value) = entry?;
+            if &Self::decode_node_id(&value)? == node_id {
+                let address = String::from_utf8_lossy(&key);
+                socket_addrs.push(
+                    SocketAddr::from_str(&address)
+                        .with_context(|| format!("Invalid peer address: {}", address))?,
+                );
+            }
+        }
+        Ok(socket_addrs)
+    }
+
+    async fn node_ids(&self) -> anyhow::Result<Vec<(SocketAddr, SystemTime)>> {
+        let mut node_ids = vec![];
+        for entry in self.node_ids.iter() {
+            let (key, value) = entry?;
+            let address = String::from_utf8_lossy(&key);
+            let socket_addr = SocketAddr::from_str(&address)
+                .with_context(|| format!("Invalid peer address: {}", address))?;
+            node_ids.push((socket_addr, Self::decode_node_id_proven(&value)?));
+        }
+        Ok(node_ids)
+    }
+
+    async fn remove_node_id(&mut self, socket_addr: &SocketAddr) -> anyhow::Result<()> {
+        self.node_ids.remove(format!("{}", socket_addr))?;
+        Ok(())
+    }
+
+    async fn add_telemetry(
+        &mut self,
+        telemetry: &TelemetryAck,
+        received: SystemTime,
+    ) -> anyhow::Result<()> {
+        let secs = received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut value = secs.to_be_bytes().to_vec();
+        value.extend(telemetry.serialize());
+        self.telemetry.insert(telemetry.node_id.as_bytes(), value)?;
+        Ok(())
+    }
+
+    async fn telemetry(
+        &self,
+        since: SystemTime,
+    ) -> anyhow::Result<Vec<(TelemetryAck, SystemTime)>> {
+        let mut telemetry = vec![];
+        for entry in self.telemetry.iter() {
+            let (_, value) = entry?;
+            let received = Self::decode_telemetry_received(&value)?;
+            if received >= since {
+                telemetry.push((
+                    TelemetryAck::deserialize(None, &value[8..]).context("Decoding telemetry")?,
+                    received,
+                ));
+            }
+        }
+        Ok(telemetry)
+    }
+
+    async fn remove_stale_telemetry(&mut self, before: SystemTime) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for entry in self.telemetry.iter() {
+            let (key, value) = entry?;
+            if Self::decode_telemetry_received(&value)? < before {
+                self.telemetry.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn add_peers(&mut self, addresses: Vec<SocketAddr>) -> Result<(), anyhow::Error> {
+        for address in addresses {
+            self.peers.insert(format!("{}", address), vec![])?;
+        }
+        Ok(())
     }
 
     async fn peers(&self) -> Result<HashSet<SocketAddr>, anyhow::Error> {
-        unimplemented!()
+        let mut peers = HashSet::new();
+        for entry in self.peers.iter() {
+            let (key, _) = entry?;
+            let address = String::from_utf8_lossy(&key);
+            peers.insert(
+                SocketAddr::from_str(&address)
+                    .with_context(|| format!("Invalid peer address: {}", address))?,
+            );
+        }
+        Ok(peers)
+    }
+
+    async fn peer_count(&self) -> anyhow::Result<usize> {
+        Ok(self.peers.len())
+    }
+
+    async fn remove_peer(&mut self, address: &SocketAddr) -> anyhow::Result<()> {
+        self.peers.remove(format!("{}", address))?;
+        Ok(())
+    }
+}
+
+fn receivable_key(account: &Public, send_hash: &BlockHash) -> Vec<u8> {
+    let mut key = account.as_bytes().to_vec();
+    key.extend_from_slice(send_hash.as_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+    use std::fs;
+
+    fn temporary_data_dir() -> std::path::PathBuf {
+        let mut dir_name = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut dir_name);
+        std::env::temp_dir().join(format!("feeless-{}", hex::encode(dir_name)))
+    }
+
+    #[tokio::test]
+    async fn refuse_to_open_twice() {
+        let network = Network::Live;
+        let data_dir = temporary_data_dir();
+        let state = SledDiskState::new(network, &data_dir).unwrap();
+        let err = SledDiskState::new(network, &data_dir).unwrap_err();
+        assert!(format!("{:?}", err).contains("in use by another process"));
+
+        drop(state);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn survives_restart() {
+        let network = Network::Live;
+        let data_dir = temporary_data_dir();
+
+        let genesis = network.genesis_block();
+        let account = genesis.account().to_owned();
+        let hash = genesis.hash().unwrap().to_owned();
+        let peer = SocketAddr::from_str("1.2.3.4:7075").unwrap();
+        let node_key = Private::random();
+
+        {
+            let mut state = SledDiskState::new(network, &data_dir).unwrap();
+            state.add_block(&genesis).await.unwrap();
+            state
+                .add_vote(&hash, &account, &Timestamp::from_u64(1))
+                .await
+                .unwrap();
+            state.add_peers(vec![peer]).await.unwrap();
+            state.set_node_key(&node_key).await.unwrap();
+            state.db.flush_async().await.unwrap();
+        }
+
+        // Sled releases its lock from a background thread, shortly after the database is dropped.
+        let mut attempts = 0;
+        let mut state = loop {
+            match SledDiskState::new(network, &data_dir) {
+                Ok(state) => break state,
+                Err(_) if attempts < 100 => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(err) => panic!("{:?}", err),
+            }
+        };
+        assert_eq!(state.get_block_by_hash(&hash).await.unwrap(), Some(genesis));
+        assert_eq!(
+            state
+                .get_latest_block_hash_for_account(&account)
+                .await
+                .unwrap(),
+            Some(hash.to_owned())
+        );
+        assert_eq!(
+            state.account_for_block_hash(&hash).await.unwrap(),
+            Some(account.to_owned())
+        );
+        assert_eq!(state.block_height(&hash).await.unwrap(), Some(1));
+        assert_eq!(
+            state.frontiers(&account, None, 10).await.unwrap(),
+            vec![(account.to_owned(), hash.to_owned())]
+        );
+        assert!(state.peers().await.unwrap().contains(&peer));
+        assert_eq!(
+            state.node_key().await.unwrap().unwrap().to_string(),
+            node_key.to_string()
+        );
+        assert_eq!(
+            state.votes(&hash).await.unwrap(),
+            vec![(account, Timestamp::from_u64(1))]
+        );
+
+        drop(state);
+        fs::remove_dir_all(&data_dir).unwrap();
     }
 }
@@ -0,0 +1,257 @@
+use crate::network::Network;
+use crate::node::channel::Channels;
+use crate::node::header::{Extensions, Header, MessageType, VersionRange};
+use crate::node::messages::telemetry_ack::TelemetryAck;
+use crate::node::state::ArcState;
+use crate::node::wire::Wire;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+use tracing::{debug, warn};
+
+/// How often telemetry is requested from every channel. The reference node ignores requests that
+/// come more often than this.
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Telemetry older than this isn't included in aggregates, since the node may have gone away, and
+/// is removed from the state.
+pub const TELEMETRY_MAX_AGE: Duration = Duration::from_secs(3 * 60);
+
+/// Periodically asks every channel for telemetry. Controllers store the verified answers in the
+/// state, by node ID, and the collector removes them once they are older than `TELEMETRY_MAX_AGE`.
+pub struct TelemetryCollector {
+    network: Network,
+    state: ArcState,
+    channels: Channels,
+
+    /// The protocol versions this node supports, advertised in the requests.
+    versions: VersionRange,
+}
+
+impl TelemetryCollector {
+    pub fn new(
+        network: Network,
+        state: ArcState,
+        channels: Channels,
+        versions: VersionRange,
+    ) -> Self {
+        Self {
+            network,
+            state,
+            channels,
+            versions,
+        }
+    }
+
+    /// Runs until the node stops. Errors are logged, and the next tick tries again.
+    pub async fn run(self) {
+        let mut request = interval(TELEMETRY_INTERVAL);
+        // Channels ask for telemetry themselves when they connect.
+        request.tick().await;
+        loop {
+            request.tick().await;
+            self.request().await;
+            if let Err(err) = self.remove_stale().await {
+                warn!("Could not remove stale telemetry: {:?}", err);
+            }
+        }
+    }
+
+    async fn remove_stale(&self) -> anyhow::Result<()> {
+        let removed = self
+            .state
+            .lock()
+            .await
+            .remove_stale_telemetry(SystemTime::now() - TELEMETRY_MAX_AGE)
+            .await
+            .context("Removing stale telemetry")?;
+        if removed > 0 {
+            debug!("Removed telemetry of {} nodes", removed);
+        }
+        Ok(())
+    }
+
+    async fn request(&self) {
+        let mut header = Header::new(self.network, MessageType::TelemetryReq, Extensions::new());
+        header.set_versions(&self.versions, self.versions.max);
+        let sent = self.channels.broadcast(&header.serialize()).await;
+        debug!("Requested telemetry from {} peers", sent.len());
+    }
+}
+
+/// An overview of the network from the telemetry of its nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TelemetrySummary {
+    /// How many nodes the telemetry is from.
+    pub nodes: usize,
+
+    pub median_block_count: u64,
+    pub median_cemented_count: u64,
+    pub median_account_count: u64,
+    pub median_peer_count: u32,
+
+    /// The work difficulty nodes require, in hex like the reference node shows it.
+    pub median_active_difficulty: String,
+
+    /// How many nodes run each version, e.g. "22.1.0".
+    pub versions: BTreeMap<String, usize>,
+
+    /// How many nodes were made by each maker. The reference node is 0, or 1 when pruned.
+    pub makers: BTreeMap<u8, usize>,
+}
+
+impl TelemetrySummary {
+    pub fn new(telemetry: &[TelemetryAck]) -> Self {
+        let mut versions = BTreeMap::new();
+        let mut makers = BTreeMap::new();
+        for t in telemetry {
+            let version = format!(
+                "{}.{}.{}",
+                t.major_version, t.minor_version, t.patch_version
+            );
+            *versions.entry(version).or_default() += 1;
+            *makers.entry(t.maker).or_default() += 1;
+        }
+
+        Self {
+            nodes: telemetry.len(),
+            median_block_count: median(telemetry.iter().map(|t| t.block_count)),
+            median_cemented_count: median(telemetry.iter().map(|t| t.cemented_count)),
+            median_account_count: median(telemetry.iter().map(|t| t.account_count)),
+            median_peer_count: median(telemetry.iter().map(|t| t.peer_count)),
+            median_active_difficulty: format!(
+                "{:016x}",
+                median(telemetry.iter().map(|t| t.active_difficulty))
+            ),
+            versions,
+            makers,
+        }
+    }
+}
+
+/// The middle value, or the higher of the two middle values when there's an even amount. Zero
+/// when there are no values.
+fn median<T: Ord + Copy + Default>(values: impl Iterator<Item = T>) -> T {
+    let mut values: Vec<T> = values.collect();
+    values.sort_unstable();
+    values.get(values.len() / 2).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::controller::Packet;
+    use crate::node::header::Version;
+    use crate::node::state::{MemoryState, State};
+    use crate::{Private, Signature};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    fn telemetry(block_count: u64, major_version: u8, maker: u8) -> TelemetryAck {
+        TelemetryAck {
+            signature: Signature::zero(),
+            node_id: Private::random().to_public().unwrap(),
+            block_count,
+            cemented_count: block_count - 1,
+            unchecked_count: 0,
+            account_count: 1,
+            bandwidth_cap: 0,
+            uptime: 60,
+            peer_count: 8,
+            protocol_version: 18,
+            genesis_block: Network::Live.genesis_hash(),
+            major_version,
+            minor_version: 0,
+            patch_version: 0,
+            prerelease_version: 0,
+            maker,
+            timestamp: 1614200740266,
+            active_difficulty: 0xfffffff800000000,
+            unknown_data: vec![],
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let summary = TelemetrySummary::new(&[
+            telemetry(10, 22, 0),
+            telemetry(30, 22, 0),
+            telemetry(20, 21, 1),
+        ]);
+        assert_eq!(summary.nodes, 3);
+        assert_eq!(summary.median_block_count, 20);
+        assert_eq!(summary.median_cemented_count, 19);
+        assert_eq!(summary.median_peer_count, 8);
+        assert_eq!(summary.median_active_difficulty, "fffffff800000000");
+        assert_eq!(summary.versions["22.0.0"], 2);
+        assert_eq!(summary.versions["21.0.0"], 1);
+        assert_eq!(summary.makers[&0], 2);
+        assert_eq!(summary.makers[&1], 1);
+
+        let empty = TelemetrySummary::new(&[]);
+        assert_eq!(empty.nodes, 0);
+        assert_eq!(empty.median_block_count, 0);
+        assert!(empty.versions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn request() {
+        let network = Network::Live;
+        let channels = Channels::new();
+        let (tx, mut rx) = mpsc::channel::<Packet>(10);
+        channels
+            .add(SocketAddr::from_str("1.2.3.4:7075").unwrap(), tx)
+            .await;
+
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let versions = VersionRange::new(Version::V17, Version::V18).unwrap();
+        TelemetryCollector::new(network, state, channels, versions)
+            .request()
+            .await;
+        let data = rx.recv().await.unwrap().data;
+        let header = Header::deserialize(None, &data).unwrap();
+        assert_eq!(header.message_type(), MessageType::TelemetryReq);
+        assert_eq!(header.version_min(), Version::V17);
+        assert_eq!(header.version_max(), Version::V18);
+        assert_eq!(header.version_using(), Version::V18);
+    }
+
+    #[tokio::test]
+    async fn remove_stale() {
+        let network = Network::Live;
+        let state = Arc::new(Mutex::new(MemoryState::new(network)));
+        let stale = telemetry(10, 22, 0);
+        let fresh = telemetry(20, 22, 0);
+        let now = SystemTime::now();
+        {
+            let mut state = state.lock().await;
+            state
+                .add_telemetry(&stale, now - TELEMETRY_MAX_AGE * 2)
+                .await
+                .unwrap();
+            state.add_telemetry(&fresh, now).await.unwrap();
+        }
+
+        TelemetryCollector::new(
+            network,
+            state.clone(),
+            Channels::new(),
+            VersionRange::default(),
+        )
+        .remove_stale()
+        .await
+        .unwrap();
+        let stored = state
+            .lock()
+            .await
+            .telemetry(SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0.node_id, fresh.node_id);
+    }
+}
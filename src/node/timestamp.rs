@@ -3,7 +3,7 @@ use anyhow::Context;
 use std::convert::TryFrom;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
 pub struct Timestamp(u64);
 
 impl Timestamp {
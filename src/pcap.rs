@@ -220,6 +220,7 @@ impl PcapDump {
 
                     tokio::spawn(async move {
                         c.validate_handshakes = false;
+                        c.observe_only = true;
                         let result = c.run().await;
                         if let Err(err) = result {
                             error!("Error on pcap controller {:?}: {:#?}", peer_addr, err);
@@ -1,2 +1,2 @@
-mod difficulty;
+pub mod difficulty;
 pub mod work;
@@ -83,6 +83,20 @@ impl Work {
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// Legacy blocks (send, receive, open, change) encode work as a little endian integer,
+    /// unlike state blocks which are big endian like our internal representation.
+    pub fn from_le_slice(value: &[u8]) -> anyhow::Result<Self> {
+        let mut s = Work::try_from(value)?;
+        s.0.reverse();
+        Ok(s)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; Self::LEN] {
+        let mut b = self.0;
+        b.reverse();
+        b
+    }
 }
 
 impl std::fmt::Debug for Work {